@@ -0,0 +1,302 @@
+use crate::buffer::{Buffer, Pos};
+
+/// How many lines we search for the partner of a bracket
+const MAX_SEARCH_LINES: u64 = 10_000;
+
+/// An opening and a closing bracket, e.g. `('(', ')')`
+pub type Pair = (char, char);
+
+/// The brackets we always match
+const DEFAULT_PAIRS: &[Pair] = &[('(', ')'), ('[', ']'), ('{', '}')];
+
+/// The brackets to match: the default ones and the `surrounding_pairs` of the xi config. Pairs
+/// with the same opening and closing char (like quotes) can't be matched and are left out.
+pub fn pairs(surrounding_pairs: &[Vec<String>]) -> Vec<Pair> {
+    let mut pairs = DEFAULT_PAIRS.to_vec();
+    for pair in surrounding_pairs {
+        let mut chars = pair.iter().map(|s| {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some(c),
+                _ => None,
+            }
+        });
+        if let (Some(Some(open)), Some(Some(close))) = (chars.next(), chars.next()) {
+            if open != close && !pairs.contains(&(open, close)) {
+                pairs.push((open, close));
+            }
+        }
+    }
+    pairs
+}
+
+/// The result of looking for the partner of the bracket at the caret
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BracketMatch {
+    /// There's no bracket next to the caret, or it doesn't have a partner
+    None,
+    /// The bracket next to the caret and its partner
+    Found { bracket: Pos, partner: Pos },
+    /// We'd have to look at this line, but the buffer doesn't have it
+    Missing(u64),
+}
+
+/// The bracket right before or after `caret`. The one before it wins, like in most editors.
+fn bracket_at<B: Buffer>(buf: &B, caret: Pos, pairs: &[Pair]) -> Option<(Pos, char)> {
+    let text = buf.line(caret.line)?;
+    let col = caret.col as usize;
+    let before = text.get(..col).and_then(|t| t.char_indices().next_back());
+    let after = text
+        .get(col..)
+        .and_then(|t| t.chars().next())
+        .map(|c| (col, c));
+
+    let is_bracket = |c: char| pairs.iter().any(|(open, close)| c == *open || c == *close);
+    before
+        .into_iter()
+        .chain(after)
+        .find(|(_, c)| is_bracket(*c))
+        .map(|(ix, c)| (Pos::new(caret.line, ix as u64), c))
+}
+
+/// Finds the bracket next to `caret` and its partner. `style_at` tells which syntax style xi
+/// has given a char. Brackets whose style is different from the one at the caret are skipped,
+/// since they're in strings or comments (or the other way round).
+pub fn find_match<B, F>(buf: &B, caret: Pos, pairs: &[Pair], style_at: F) -> BracketMatch
+where
+    B: Buffer,
+    F: Fn(Pos) -> Option<usize>,
+{
+    let (bracket, c) = match bracket_at(buf, caret, pairs) {
+        Some(bracket) => bracket,
+        None => return BracketMatch::None,
+    };
+    match pairs.iter().find(|(open, close)| c == *open || c == *close) {
+        Some(&pair) => find_partner(buf, bracket, pair, c == pair.0, &style_at),
+        None => BracketMatch::None,
+    }
+}
+
+/// Finds the innermost pair of brackets around `caret`, e.g. to highlight the contents of a
+/// block. Like `find_match` this skips brackets in strings and comments once it has found the
+/// opening bracket, but since we don't know the style of the code around the caret, the opening
+/// bracket itself might be in a string.
+pub fn find_enclosing<B, F>(buf: &B, caret: Pos, pairs: &[Pair], style_at: F) -> BracketMatch
+where
+    B: Buffer,
+    F: Fn(Pos) -> Option<usize>,
+{
+    // How many closing brackets of each pair we've passed
+    let mut depths = vec![0; pairs.len()];
+    for i in 0..MAX_SEARCH_LINES.min(caret.line + 1) {
+        let line = caret.line - i;
+        let text = match buf.line(line) {
+            Some(text) => text,
+            None => return BracketMatch::Missing(line),
+        };
+        let text = if line == caret.line {
+            text.get(..caret.col as usize).unwrap_or(text)
+        } else {
+            text
+        };
+
+        for (ix, c) in text.char_indices().rev() {
+            for (pair, depth) in pairs.iter().zip(depths.iter_mut()) {
+                if c == pair.1 {
+                    *depth += 1;
+                } else if c == pair.0 && *depth > 0 {
+                    *depth -= 1;
+                } else if c == pair.0 {
+                    let bracket = Pos::new(line, ix as u64);
+                    return find_partner(buf, bracket, *pair, true, &style_at);
+                }
+            }
+        }
+    }
+    BracketMatch::None
+}
+
+/// Finds the partner of `bracket`, which is one of `pair`, by searching `forward` or backwards
+fn find_partner<B, F>(
+    buf: &B,
+    bracket: Pos,
+    pair: Pair,
+    forward: bool,
+    style_at: &F,
+) -> BracketMatch
+where
+    B: Buffer,
+    F: Fn(Pos) -> Option<usize>,
+{
+    let (open, close) = pair;
+    let style = style_at(bracket);
+    let mut depth = 0;
+    let n_lines = buf.n_lines();
+    for i in 0..MAX_SEARCH_LINES {
+        let line = if forward {
+            bracket.line + i
+        } else if i <= bracket.line {
+            bracket.line - i
+        } else {
+            break;
+        };
+        if line >= n_lines {
+            break;
+        }
+        let text = match buf.line(line) {
+            Some(text) => text,
+            None => return BracketMatch::Missing(line),
+        };
+
+        let mut chars: Vec<(usize, char)> = text
+            .char_indices()
+            .filter(|(ix, _)| {
+                if line != bracket.line {
+                    true
+                } else if forward {
+                    *ix as u64 > bracket.col
+                } else {
+                    (*ix as u64) < bracket.col
+                }
+            })
+            .collect();
+        if !forward {
+            chars.reverse();
+        }
+
+        for (ix, ch) in chars {
+            let pos = Pos::new(line, ix as u64);
+            if (ch != open && ch != close) || style_at(pos) != style {
+                continue;
+            }
+            if (ch == open) == forward {
+                depth += 1;
+            } else if depth == 0 {
+                return BracketMatch::Found {
+                    bracket,
+                    partner: pos,
+                };
+            } else {
+                depth -= 1;
+            }
+        }
+    }
+    BracketMatch::None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Lines(Vec<Option<&'static str>>);
+
+    impl Buffer for Lines {
+        fn line(&self, n: u64) -> Option<&str> {
+            self.0.get(n as usize).cloned().and_then(|l| l)
+        }
+
+        fn n_lines(&self) -> u64 {
+            self.0.len() as u64
+        }
+    }
+
+    #[test]
+    fn test_pairs() {
+        let surrounding_pairs = vec![
+            vec!["\"".to_string(), "\"".to_string()],
+            vec!["{".to_string(), "}".to_string()],
+            vec!["<".to_string(), ">".to_string()],
+            vec!["/*".to_string(), "*/".to_string()],
+        ];
+        assert_eq!(
+            pairs(&surrounding_pairs),
+            vec![('(', ')'), ('[', ']'), ('{', '}'), ('<', '>')]
+        );
+    }
+
+    #[test]
+    fn test_find_match() {
+        let buf = Lines(vec![
+            Some("fn main() {"),
+            Some("    let s = \"}\";"),
+            Some("    if x { y(); }"),
+            Some("}"),
+            None,
+            Some("(["),
+        ]);
+        let pairs = pairs(&[]);
+        // The `}` in the string has another style
+        let style_at = |pos: Pos| Some(if pos == Pos::new(1, 13) { 2 } else { 1 });
+        let found = |bracket: (u64, u64), partner: (u64, u64)| BracketMatch::Found {
+            bracket: Pos::new(bracket.0, bracket.1),
+            partner: Pos::new(partner.0, partner.1),
+        };
+
+        assert_eq!(
+            find_match(&buf, Pos::new(0, 11), &pairs, style_at),
+            found((0, 10), (3, 0))
+        );
+        assert_eq!(
+            find_match(&buf, Pos::new(3, 0), &pairs, style_at),
+            found((3, 0), (0, 10))
+        );
+        assert_eq!(
+            find_match(&buf, Pos::new(0, 7), &pairs, style_at),
+            found((0, 7), (0, 8))
+        );
+        assert_eq!(
+            find_match(&buf, Pos::new(2, 10), &pairs, style_at),
+            found((2, 9), (2, 16))
+        );
+        assert_eq!(
+            find_match(&buf, Pos::new(1, 4), &pairs, style_at),
+            BracketMatch::None
+        );
+        assert_eq!(
+            find_match(&buf, Pos::new(5, 2), &pairs, style_at),
+            BracketMatch::None
+        );
+        assert_eq!(
+            find_match(&buf, Pos::new(5, 0), &pairs, style_at),
+            BracketMatch::None
+        );
+
+        let buf = Lines(vec![Some("{"), None, Some("}")]);
+        assert_eq!(
+            find_match(&buf, Pos::new(0, 0), &pairs, style_at),
+            BracketMatch::Missing(1)
+        );
+    }
+
+    #[test]
+    fn test_find_enclosing() {
+        let buf = Lines(vec![
+            Some("fn main() {"),
+            Some("    let v = [1, (2), 3];"),
+            Some("}"),
+        ]);
+        let pairs = pairs(&[]);
+        let style_at = |_| None;
+        let found = |bracket: (u64, u64), partner: (u64, u64)| BracketMatch::Found {
+            bracket: Pos::new(bracket.0, bracket.1),
+            partner: Pos::new(partner.0, partner.1),
+        };
+
+        assert_eq!(
+            find_enclosing(&buf, Pos::new(1, 20), &pairs, style_at),
+            found((1, 12), (1, 22))
+        );
+        assert_eq!(
+            find_enclosing(&buf, Pos::new(1, 17), &pairs, style_at),
+            found((1, 16), (1, 18))
+        );
+        assert_eq!(
+            find_enclosing(&buf, Pos::new(1, 4), &pairs, style_at),
+            found((0, 10), (2, 0))
+        );
+        assert_eq!(
+            find_enclosing(&buf, Pos::new(0, 3), &pairs, style_at),
+            BracketMatch::None
+        );
+    }
+}
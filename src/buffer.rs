@@ -0,0 +1,20 @@
+/// A position in the document. Like in xi-editor's protocol `col` is a byte offset into the line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pos {
+    pub line: u64,
+    pub col: u64,
+}
+
+impl Pos {
+    pub fn new(line: u64, col: u64) -> Self {
+        Self { line, col }
+    }
+}
+
+/// Gives the editing features which work on the text (e.g. Vim mode, find, folding and bracket
+/// matching) access to the text of the document, e.g. the `LineCache`.
+pub trait Buffer {
+    /// The text of line `n` without its line ending, if we know it
+    fn line(&self, n: u64) -> Option<&str>;
+    fn n_lines(&self) -> u64;
+}
@@ -0,0 +1,104 @@
+use gettextrs::gettext;
+use gio::{SettingsExt, SettingsSchemaSource};
+use gtk::*;
+use log::debug;
+use std::cell::Cell;
+use std::rc::Rc;
+
+const DESKTOP_SCHEMA: &str = "org.gnome.desktop.interface";
+const COLOR_SCHEME_KEY: &str = "color-scheme";
+
+fn desktop_prefers_dark(settings: &gio::Settings) -> bool {
+    settings
+        .get_string(COLOR_SCHEME_KEY)
+        .map_or(false, |s| s.as_str() == "prefer-dark")
+}
+
+/// Tells us whether the desktop prefers a dark style. GNOME keeps that in the `color-scheme` key
+/// of its interface settings, elsewhere we go by GTK's `gtk-application-prefer-dark-theme`.
+///
+/// We also set `gtk-application-prefer-dark-theme` ourselves so the header bar matches a dark
+/// editor theme, which mustn't be mistaken for the desktop changing its mind.
+pub struct ColorScheme {
+    desktop_settings: Option<gio::Settings>,
+    gtk_settings: Option<gtk::Settings>,
+    /// What the desktop wants `gtk-application-prefer-dark-theme` to be, without our changes
+    system_prefers_dark: Rc<Cell<bool>>,
+    /// Set while we change `gtk-application-prefer-dark-theme`, so we ignore our own notifications
+    applying: Rc<Cell<bool>>,
+}
+
+impl ColorScheme {
+    pub fn new() -> Self {
+        let desktop_settings = SettingsSchemaSource::get_default()
+            .and_then(|source| source.lookup(DESKTOP_SCHEMA, true))
+            .filter(|schema| schema.has_key(COLOR_SCHEME_KEY))
+            .map(|_| gio::Settings::new(DESKTOP_SCHEMA));
+        if desktop_settings.is_none() {
+            debug!(
+                "{}",
+                gettext("The desktop doesn't have a colour scheme setting, going by GTK's")
+            );
+        }
+        let gtk_settings = gtk::Settings::get_default();
+        let system_prefers_dark = gtk_settings.as_ref().map_or(false, |s| {
+            s.get_property_gtk_application_prefer_dark_theme()
+        });
+
+        Self {
+            desktop_settings,
+            gtk_settings,
+            system_prefers_dark: Rc::new(Cell::new(system_prefers_dark)),
+            applying: Rc::new(Cell::new(false)),
+        }
+    }
+
+    /// Whether the desktop currently prefers a dark style
+    pub fn prefers_dark(&self) -> bool {
+        match &self.desktop_settings {
+            Some(settings) => desktop_prefers_dark(settings),
+            None => self.system_prefers_dark.get(),
+        }
+    }
+
+    /// Calls `f` with the new preference whenever the desktop switches between light and dark
+    pub fn connect_changed<F: Fn(bool) + 'static>(&self, f: F) {
+        if let Some(settings) = &self.desktop_settings {
+            settings.connect_changed(move |settings, key| {
+                if key == COLOR_SCHEME_KEY {
+                    f(desktop_prefers_dark(settings));
+                }
+            });
+        } else if let Some(gtk_settings) = &self.gtk_settings {
+            let applying = self.applying.clone();
+            let system_prefers_dark = self.system_prefers_dark.clone();
+            gtk_settings.connect_property_gtk_application_prefer_dark_theme_notify(move |s| {
+                if applying.get() {
+                    return;
+                }
+                let dark = s.get_property_gtk_application_prefer_dark_theme();
+                system_prefers_dark.set(dark);
+                f(dark);
+            });
+        }
+    }
+
+    /// Makes GTK use its dark variant, e.g. for the header bar, if the editor's theme is dark.
+    /// Otherwise we leave it the way the desktop wants it.
+    pub fn set_dark_variant(&self, editor_is_dark: bool) {
+        if let Some(gtk_settings) = &self.gtk_settings {
+            let dark = editor_is_dark || self.system_prefers_dark.get();
+            if gtk_settings.get_property_gtk_application_prefer_dark_theme() != dark {
+                self.applying.set(true);
+                gtk_settings.set_property_gtk_application_prefer_dark_theme(dark);
+                self.applying.set(false);
+            }
+        }
+    }
+}
+
+impl Default for ColorScheme {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,21 +1,43 @@
+use crate::brackets::{self, BracketMatch};
+use crate::buffer::{Buffer, Pos};
+use crate::errors::{ErrorDialog, ErrorMsg};
+use crate::file_search;
+use crate::find::{self, FindOptions};
+use crate::folding::{self, Folds};
+use crate::goto_line::{GotoLine, Position};
+use crate::html::{self, HtmlOptions, StyledLine};
+use crate::keymap::{is_modifier_key, KeyChord, Lookup, Modifiers, Preset, EDITOR_COMMANDS};
+use crate::kill_ring::KillRing;
 use crate::linecache::{Line, LineCache};
 use crate::main_win::MainState;
 use crate::pref_storage::*;
+use crate::print::PrintJob;
 use crate::rpc::Core;
-use crate::theme::{color_from_u32, set_margin_source_color, set_source_color, PangoColor};
+use crate::rtf;
+use crate::theme::{
+    color_from_u32, set_margin_source_color, set_source_color, LineStyle, PangoColor,
+};
+use crate::vim::{Action as VimAction, Mode, Vim, VimKey};
+use crate::whitespace::{self, DrawWhitespace, Marker};
 use cairo::Context;
-use gdk::enums::key;
 use gdk::*;
 use gettextrs::gettext;
+use gio::ActionGroupExt;
 use gtk::{self, *};
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 use pango::{self, ContextExt, LayoutExt, *};
 use pangocairo::functions::*;
+use regex::Regex;
 use serde_json::Value;
 use std::cell::RefCell;
 use std::cmp::{max, min};
-use std::rc::Rc;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 use std::u32;
+use syntect::highlighting::{Color, ThemeSettings};
 
 /// The `Font` Struct holds all information about the font used in the `EditView` for the editing area
 /// or the interface font (used for the linecount)
@@ -69,6 +91,54 @@ impl Font {
     }
 }
 
+/// How wide the overview ruler next to the vertical scrollbar is
+const OVERVIEW_WIDTH: i32 = 12;
+/// How wide the minimap is
+const MINIMAP_WIDTH: i32 = 100;
+/// How high a line is in the minimap
+const MINIMAP_LINE_HEIGHT: f64 = 2.0;
+/// How wide a char is in the minimap
+const MINIMAP_CHAR_WIDTH: f64 = 1.0;
+/// How many lines the minimap asks xi for at once if they aren't in the line cache
+const MINIMAP_CHUNK: u64 = 500;
+/// How many lines we ask xi for at once when looking for the partner of a bracket
+const BRACKET_CHUNK: u64 = 200;
+/// How far we look ahead for the indentation of blank lines when drawing indent guides
+const GUIDE_LOOKAHEAD: u64 = 100;
+
+/// The shape of the caret
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaretStyle {
+    /// A thin bar before the char at the cursor
+    Bar,
+    /// A block covering the char at the cursor
+    Block,
+    /// A line below the char at the cursor
+    Underline,
+}
+
+impl CaretStyle {
+    /// Returns the style with the `name` used in GSettings, falling back to `CaretStyle::Bar`
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "block" => CaretStyle::Block,
+            "underline" => CaretStyle::Underline,
+            _ => CaretStyle::Bar,
+        }
+    }
+}
+
+/// The formats we offer copied text in, as the `info` of the clipboard's `TargetEntry`s
+const CLIPBOARD_TEXT: u32 = 0;
+const CLIPBOARD_HTML: u32 = 1;
+const CLIPBOARD_RTF: u32 = 2;
+
+/// What to do with the whole document once xi has sent us all of its lines
+enum Export {
+    Print,
+    Html(PathBuf, HtmlOptions),
+}
+
 /// The ViewItem contains the drawing areas and scrollbars of the EditView.
 #[derive(Clone)]
 pub struct ViewItem {
@@ -76,6 +146,12 @@ pub struct ViewItem {
     linecount: DrawingArea,
     horiz_bar: Scrollbar,
     verti_bar: Scrollbar,
+    /// Shows where the find matches and cursors are in the whole document, next to `verti_bar`
+    overview: DrawingArea,
+    /// A scaled-down picture of the document, if the user has switched it on
+    minimap: DrawingArea,
+    /// The input method, for dead keys, compose sequences and e.g. CJK input
+    im_context: IMMulticontext,
 }
 
 impl ViewItem {
@@ -85,30 +161,64 @@ impl ViewItem {
         let linecount = DrawingArea::new();
         let horiz_bar = Scrollbar::new(Orientation::Horizontal, None::<&gtk::Adjustment>);
         let verti_bar = Scrollbar::new(Orientation::Vertical, None::<&gtk::Adjustment>);
+        let overview = DrawingArea::new();
+        overview.set_size_request(OVERVIEW_WIDTH, -1);
+        overview.set_events(EventMask::BUTTON_PRESS_MASK | EventMask::BUTTON_MOTION_MASK);
+        // Clicking the fold markers in the gutter folds and unfolds regions
+        linecount.set_events(EventMask::BUTTON_PRESS_MASK);
+        let minimap = DrawingArea::new();
+        minimap.set_size_request(MINIMAP_WIDTH, -1);
+        minimap.set_events(
+            EventMask::BUTTON_PRESS_MASK
+                | EventMask::BUTTON_RELEASE_MASK
+                | EventMask::BUTTON_MOTION_MASK,
+        );
+        minimap.set_no_show_all(true);
+        minimap.set_visible(get_show_minimap());
 
         edit_area.set_events(
             EventMask::BUTTON_PRESS_MASK
                 | EventMask::BUTTON_RELEASE_MASK
                 | EventMask::BUTTON_MOTION_MASK
                 | EventMask::SCROLL_MASK
-                | EventMask::SMOOTH_SCROLL_MASK,
+                | EventMask::SMOOTH_SCROLL_MASK
+                | EventMask::KEY_PRESS_MASK
+                | EventMask::KEY_RELEASE_MASK
+                | EventMask::FOCUS_CHANGE_MASK,
         );
         debug!("{}: {:?}", gettext("Events"), edit_area.get_events());
         edit_area.set_can_focus(true);
 
+        let im_context = IMMulticontext::new();
+        im_context.set_use_preedit(true);
+
         ViewItem {
             edit_area,
             linecount,
             horiz_bar,
             verti_bar,
+            overview,
+            minimap,
+            im_context,
         }
     }
 
     /// Sets up event listeners for the ViewItem
     fn connect_events(&self, edit_view: &Rc<RefCell<EditView>>) {
+        let im_context = &self.im_context;
+        let edit_area = &self.edit_area;
+
         self.edit_area
-            .connect_button_press_event(clone!(edit_view => move |_,eb| {
-                edit_view.borrow().handle_button_press(eb)
+            .connect_button_press_event(clone!(edit_view, im_context => move |_,eb| {
+                // Commit whatever the input method has at the old cursor position
+                im_context.reset();
+                edit_view.borrow_mut().reset_command_state();
+                edit_view.borrow_mut().handle_button_press(eb)
+            }));
+
+        self.edit_area
+            .connect_button_release_event(clone!(edit_view => move |_,eb| {
+                edit_view.borrow_mut().handle_button_release(eb)
             }));
 
         self.edit_area
@@ -126,16 +236,63 @@ impl ViewItem {
                 edit_view.borrow_mut().handle_drag(em)
             }));
 
-        self.edit_area.connect_realize(|w| {
-            // Set the text cursor
-            if let Some(disp) = DisplayManager::get().get_default_display() {
-                let cur = Cursor::new_for_display(&disp, CursorType::Xterm);
-                if let Some(win) = w.get_window() {
-                    win.set_cursor(&cur)
+        self.edit_area
+            .connect_key_release_event(clone!(im_context => move |_, ek| {
+                Inhibit(im_context.filter_keypress(ek))
+            }));
+
+        // The caret is hollow while we don't have the focus
+        self.edit_area
+            .connect_focus_in_event(clone!(edit_view, im_context => move |w, _| {
+                im_context.focus_in();
+                if let Ok(mut edit_view) = edit_view.try_borrow_mut() {
+                    edit_view.reset_caret_blink();
                 }
+                w.queue_draw();
+                Inhibit(false)
+            }));
+
+        self.edit_area
+            .connect_focus_out_event(clone!(im_context => move |w, _| {
+                im_context.focus_out();
+                w.queue_draw();
+                Inhibit(false)
+            }));
+
+        start_caret_blink(Rc::downgrade(edit_view));
+
+        // The input method might commit text while we're handling a key press, so collect it and
+        // send it to xi once the EditView isn't borrowed anymore
+        let im_commit = edit_view.borrow().im_commit.clone();
+        im_context.connect_commit(clone!(edit_view => move |_, text| {
+            im_commit.borrow_mut().push_str(text);
+            if let Ok(mut edit_view) = edit_view.try_borrow_mut() {
+                edit_view.flush_im_commit();
             }
-            w.grab_focus();
-        });
+        }));
+
+        im_context.connect_preedit_changed(clone!(edit_area => move |_| {
+            edit_area.queue_draw();
+        }));
+
+        self.edit_area
+            .connect_unrealize(clone!(im_context => move |_| {
+                im_context.set_client_window(None::<&gdk::Window>);
+            }));
+
+        self.edit_area
+            .connect_realize(clone!(im_context => move |w| {
+                im_context.set_client_window(w.get_window().as_ref());
+
+                // Set the text cursor
+                if let Some(disp) = DisplayManager::get().get_default_display() {
+                    let cur = Cursor::new_for_display(&disp, CursorType::Xterm);
+                    if let Some(win) = w.get_window() {
+                        win.set_cursor(&cur)
+                    }
+                }
+                w.grab_focus();
+            }));
 
         self.edit_area
             .connect_scroll_event(clone!(edit_view => move |_,es| {
@@ -153,10 +310,60 @@ impl ViewItem {
                 edit_view.borrow_mut().handle_linecount_draw(&ctx)
             }));
 
+        self.linecount
+            .connect_button_press_event(clone!(edit_view => move |_,eb| {
+                edit_view.borrow_mut().handle_linecount_click(eb.get_position().1)
+            }));
+
         self.verti_bar
             .connect_change_value(clone!(edit_view => move |_,_,value| {
                 edit_view.borrow_mut().vscrollbar_change_value(value)
             }));
+
+        // The overview and the minimap show which part of the document is visible
+        let overview = &self.overview;
+        let minimap = &self.minimap;
+        self.verti_bar.get_adjustment().connect_value_changed(
+            clone!(overview, minimap => move |_| {
+                overview.queue_draw();
+                minimap.queue_draw();
+            }),
+        );
+
+        self.overview
+            .connect_draw(clone!(edit_view => move |_,ctx| {
+                edit_view.borrow().handle_overview_draw(&ctx)
+            }));
+
+        self.overview
+            .connect_button_press_event(clone!(edit_view => move |_,eb| {
+                edit_view.borrow_mut().handle_overview_click(eb.get_position().1)
+            }));
+
+        self.overview
+            .connect_motion_notify_event(clone!(edit_view => move |_,em| {
+                edit_view.borrow_mut().handle_overview_click(em.get_position().1)
+            }));
+
+        self.minimap.connect_draw(clone!(edit_view => move |_,ctx| {
+            edit_view.borrow_mut().handle_minimap_draw(&ctx)
+        }));
+
+        self.minimap
+            .connect_button_press_event(clone!(edit_view => move |_,eb| {
+                edit_view.borrow_mut().handle_minimap_press(eb.get_position().1)
+            }));
+
+        self.minimap
+            .connect_motion_notify_event(clone!(edit_view => move |_,em| {
+                edit_view.borrow().handle_minimap_drag(em.get_position().1)
+            }));
+
+        self.minimap
+            .connect_button_release_event(clone!(edit_view => move |_,_| {
+                edit_view.borrow_mut().minimap_grab = None;
+                Inhibit(false)
+            }));
     }
 
     /// Gets the pango Context from the main drawing area.
@@ -167,6 +374,18 @@ impl ViewItem {
     }
 }
 
+/// Where a rectangular selection (Alt+drag or middle-drag) has been started
+#[derive(Clone, Copy, Debug)]
+struct BlockSelection {
+    line: u64,
+    col: u64,
+    /// The x coordinate in the document (not the widget), so the block stays put when scrolling
+    x: f64,
+    button: u32,
+    /// If the mouse has moved since the button has been pressed
+    dragged: bool,
+}
+
 /// The EditView is the part of gxi that does the actual editing. This is where you edit documents.
 pub struct EditView {
     core: Rc<RefCell<Core>>,
@@ -179,6 +398,75 @@ pub struct EditView {
     pub view_item: ViewItem,
     line_cache: LineCache,
     find_replace: FindReplace,
+    goto_line: GotoLine,
+    /// Where to jump to once xi has sent us the first update, e.g. for `gxi main.rs:120`
+    pending_position: Option<Position>,
+    /// The keys of a sequence like `<Primary>k <Primary>u` pressed so far
+    pending_keys: Vec<KeyChord>,
+    /// The `MainState::keymap_revision` of the keymap our key state belongs to
+    keymap_revision: u64,
+    /// The Vim-style modal editing layer, used if the keybinding preset is `Preset::Vim`
+    vim: Vim,
+    /// Shows the Vim mode we're in, e.g. "-- INSERT --", or if the Emacs mark is set
+    mode_label: Label,
+    /// The kill ring of the Emacs preset
+    kill_ring: KillRing,
+    /// If the Emacs mark is set. Movement commands extend the selection while it is.
+    mark_active: bool,
+    /// The last command we've run, e.g. to append consecutive kills to the same kill
+    last_command: Option<String>,
+    /// How many chars the last `yank` inserted, so `yank_pop` can replace them
+    last_yank_len: usize,
+    /// Text the input method has committed, but which we haven't sent to xi yet
+    im_commit: Rc<RefCell<String>>,
+    block_selection: Option<BlockSelection>,
+    /// The selection the "In selection" search toggle restricts searching to
+    find_regions: Vec<find::Region>,
+    /// Set while we wait for xi to send us all lines, so we know all of the selection to search in
+    pending_find_regions: bool,
+    /// Set while we wait for xi to send us all lines to replace all matches with their own
+    /// expansion of the replacement
+    pending_replace_all: bool,
+    /// The replacement "Find in Files" wants to make in this document while we wait for xi to
+    /// send us all lines
+    pending_replace: Option<(Regex, FindOptions, String, Vec<(u64, usize)>)>,
+    /// The matches inside of `find_regions`. We search those ourselves since xi can only search
+    /// the whole document.
+    find_matches: Vec<find::Region>,
+    /// The lines xi has found matches on, for the overview ruler
+    find_lines: Vec<u64>,
+    /// How many matches xi has found
+    find_count: usize,
+    /// Where the user has grabbed the viewport of the minimap while dragging it, relative to
+    /// the viewport's top
+    minimap_grab: Option<f64>,
+    /// The lines the minimap has last asked xi for, until xi has sent them
+    minimap_requested: Option<(u64, u64)>,
+    /// The folded regions, which are hidden behind a placeholder
+    folds: Folds,
+    /// The lines we've last asked xi for to find the partner of a bracket, until xi has sent them
+    bracket_requested: Option<(u64, u64)>,
+    /// The matching and the enclosing brackets of the cursor, which we highlight
+    bracket_highlights: (Vec<Pos>, Vec<Pos>),
+    /// The cursor position and `LineCache` revision `bracket_highlights` have been found for
+    bracket_highlights_for: Option<(Pos, u64)>,
+    /// Where the cursor was when the user wanted to jump to the partner of a bracket which is on
+    /// lines xi hasn't sent yet
+    pending_bracket_jump: Option<Pos>,
+    /// If the caret is shown right now, it's hidden every other half of a blink cycle
+    caret_visible: bool,
+    /// When the user has last typed or clicked. The caret doesn't blink right after that and
+    /// stops blinking after a while, like in GTK's text widgets.
+    caret_reset: Instant,
+    /// Set while we wait for xi to send us all lines to add a selection at the next occurrence of
+    /// the selected text
+    pending_next_occurrence: bool,
+    /// Set while we wait for xi to select the word under the cursor or to send us all lines, to
+    /// select all occurrences of the selected text
+    pending_all_occurrences: bool,
+    /// What the user wants to do with the whole document while we wait for xi to send us all of
+    /// its lines
+    pending_export: Option<Export>,
     edit_font: Font,
     interface_font: Font,
 }
@@ -194,7 +482,12 @@ impl EditView {
     ) -> Rc<RefCell<Self>> {
         let view_item = ViewItem::new();
         let find_replace = FindReplace::new();
+        let goto_line = GotoLine::new(&view_item.edit_area);
         let pango_ctx = view_item.get_pango_ctx();
+        let mode_label = Label::new(None);
+        mode_label.set_halign(Align::Start);
+        mode_label.set_margin_start(6);
+        mode_label.set_no_show_all(true);
 
         let edit_view = Rc::new(RefCell::new(EditView {
             core: core.clone(),
@@ -202,25 +495,58 @@ impl EditView {
             file_name,
             pristine: true,
             view_id: view_id.to_string(),
-            root_widget: EditView::setup_root_box(&view_item, &find_replace),
+            root_widget: EditView::setup_root_box(&view_item, &find_replace, &mode_label),
             top_bar: TopBar::new(),
             view_item: view_item.clone(),
             line_cache: LineCache::new(),
             edit_font: EditView::get_edit_font(&pango_ctx, &main_state.borrow().config),
             interface_font: EditView::get_interface_font(&pango_ctx),
             find_replace: find_replace.clone(),
+            goto_line: goto_line.clone(),
+            pending_position: None,
+            pending_keys: Vec::new(),
+            keymap_revision: main_state.borrow().keymap_revision,
+            vim: Vim::default(),
+            mode_label,
+            kill_ring: KillRing::default(),
+            mark_active: false,
+            last_command: None,
+            last_yank_len: 0,
+            im_commit: Default::default(),
+            block_selection: None,
+            find_regions: Vec::new(),
+            pending_find_regions: false,
+            pending_replace_all: false,
+            pending_replace: None,
+            find_matches: Vec::new(),
+            find_lines: Vec::new(),
+            find_count: 0,
+            minimap_grab: None,
+            minimap_requested: None,
+            folds: Folds::default(),
+            bracket_requested: None,
+            bracket_highlights: (Vec::new(), Vec::new()),
+            bracket_highlights_for: None,
+            pending_bracket_jump: None,
+            caret_visible: true,
+            caret_reset: Instant::now(),
+            pending_next_occurrence: false,
+            pending_all_occurrences: false,
+            pending_export: None,
         }));
 
         edit_view.borrow_mut().update_title();
+        edit_view.borrow().update_mode_label();
         crate::MainWin::set_language(&core, view_id, "Plain Text");
 
         view_item.connect_events(&edit_view);
         find_replace.connect_events(&edit_view);
+        goto_line.connect_events(&edit_view);
 
         edit_view
     }
 
-    fn setup_root_box(view_item: &ViewItem, find_replace: &FindReplace) -> Box {
+    fn setup_root_box(view_item: &ViewItem, find_replace: &FindReplace, mode_label: &Label) -> Box {
         let root_box = Box::new(Orientation::Vertical, 0);
         let hbox = Box::new(Orientation::Horizontal, 0);
         let vbox = Box::new(Orientation::Vertical, 0);
@@ -228,9 +554,12 @@ impl EditView {
         root_box.pack_start(&hbox, true, true, 0);
         hbox.pack_start(&view_item.linecount, false, false, 0);
         hbox.pack_start(&vbox, true, true, 0);
+        hbox.pack_start(&view_item.minimap, false, false, 0);
         hbox.pack_start(&view_item.verti_bar, false, false, 0);
+        hbox.pack_start(&view_item.overview, false, false, 0);
         vbox.pack_start(&view_item.edit_area, true, true, 0);
         vbox.pack_start(&view_item.horiz_bar, false, false, 0);
+        root_box.pack_start(mode_label, false, false, 0);
         root_box.show_all();
 
         root_box
@@ -293,6 +622,12 @@ struct FindReplace {
     search_entry: SearchEntry,
     go_down_button: Button,
     go_up_button: Button,
+    case_sensitive_button: ToggleButton,
+    regex_button: ToggleButton,
+    whole_words_button: ToggleButton,
+    in_selection_button: ToggleButton,
+    preserve_case_button: ToggleButton,
+    replace_preview_label: Label,
 }
 
 impl FindReplace {
@@ -311,6 +646,12 @@ impl FindReplace {
         let search_entry = builder.get_object("search_entry").unwrap();
         let go_down_button = builder.get_object("go_down_button").unwrap();
         let go_up_button = builder.get_object("go_up_button").unwrap();
+        let case_sensitive_button = builder.get_object("case_sensitive_button").unwrap();
+        let regex_button = builder.get_object("regex_button").unwrap();
+        let whole_words_button = builder.get_object("whole_words_button").unwrap();
+        let in_selection_button = builder.get_object("in_selection_button").unwrap();
+        let preserve_case_button = builder.get_object("preserve_case_button").unwrap();
+        let replace_preview_label = builder.get_object("replace_preview_label").unwrap();
 
         replace_expander.connect_property_expanded_notify(clone!(replace_revealer => move|w| {
             if w.get_expanded() {
@@ -331,9 +672,35 @@ impl FindReplace {
             search_entry,
             go_down_button,
             go_up_button,
+            case_sensitive_button,
+            regex_button,
+            whole_words_button,
+            in_selection_button,
+            preserve_case_button,
+            replace_preview_label,
         }
     }
 
+    /// The state of the toggle buttons
+    fn options(&self) -> FindOptions {
+        FindOptions {
+            case_sensitive: self.case_sensitive_button.get_active(),
+            regex: self.regex_button.get_active(),
+            whole_words: self.whole_words_button.get_active(),
+            in_selection: self.in_selection_button.get_active(),
+            preserve_case: self.preserve_case_button.get_active(),
+        }
+    }
+
+    fn set_options(&self, options: FindOptions) {
+        self.case_sensitive_button
+            .set_active(options.case_sensitive);
+        self.regex_button.set_active(options.regex);
+        self.whole_words_button.set_active(options.whole_words);
+        self.in_selection_button.set_active(options.in_selection);
+        self.preserve_case_button.set_active(options.preserve_case);
+    }
+
     /// Sets up event listeners
     fn connect_events(&self, ev: &Rc<RefCell<EditView>>) {
         self.search_entry
@@ -353,13 +720,20 @@ impl FindReplace {
                 ev.borrow().stop_search();
             }));
 
+        // `replace_status` sets the text while the EditView is borrowed
+        self.replace_entry.connect_changed(clone!(ev => move |_| {
+            if let Ok(ev) = ev.try_borrow() {
+                ev.update_replace_preview();
+            }
+        }));
+
         self.replace_button.connect_clicked(clone!(ev => move |_| {
-            ev.borrow().replace();
+            ev.borrow_mut().replace();
         }));
 
         self.replace_all_button
             .connect_clicked(clone!(ev => move |_| {
-                ev.borrow().replace_all();
+                ev.borrow_mut().replace_all();
             }));
 
         self.go_down_button.connect_clicked(clone!(ev => move |_| {
@@ -369,6 +743,22 @@ impl FindReplace {
         self.go_up_button.connect_clicked(clone!(ev => move |_| {
             ev.borrow_mut().find_prev();
         }));
+
+        // The buttons are also toggled by `sync_find_options` while the EditView is borrowed, it
+        // searches again by itself then.
+        for button in &[
+            &self.case_sensitive_button,
+            &self.regex_button,
+            &self.whole_words_button,
+            &self.in_selection_button,
+            &self.preserve_case_button,
+        ] {
+            button.connect_toggled(clone!(ev => move |_| {
+                if let Ok(mut ev) = ev.try_borrow_mut() {
+                    ev.find_options_changed();
+                }
+            }));
+        }
     }
 }
 
@@ -379,16 +769,21 @@ impl EditView {
         self.update_title();
     }
 
-    /// Update the title of the EditView to the currently set file_name
-    fn update_title(&self) {
-        let title = match self.file_name {
+    /// The name of the file without its directory, or "Untitled"
+    fn display_name(&self) -> String {
+        match self.file_name {
             Some(ref f) => f
                 .split(::std::path::MAIN_SEPARATOR)
                 .last()
                 .unwrap_or(&gettext("Untitled"))
                 .to_string(),
             None => gettext("Untitled"),
-        };
+        }
+    }
+
+    /// Update the title of the EditView to the currently set file_name
+    fn update_title(&self) {
+        let title = self.display_name();
 
         let mut full_title = String::new();
         if !self.pristine {
@@ -463,7 +858,15 @@ impl EditView {
     /// is pristine (_does not_ has unsaved changes) and queue a new draw of the EditView.
     pub fn update(&mut self, params: &Value) {
         let update = &params["update"];
-        self.line_cache.apply_update(update);
+        let cursors = self.cursors();
+        let copied = self.line_cache.apply_update(update);
+        self.folds.apply_update(&copied);
+        self.reveal_cursors();
+        // Don't hide the caret while it's moving
+        if self.cursors() != cursors {
+            self.reset_caret_blink();
+        }
+        self.vim.cursor_synced();
 
         // let (text_width, text_height) = self.get_text_size();
         // debug!("{}{}", text_width, text_height);
@@ -475,13 +878,7 @@ impl EditView {
         // }
 
         // update scrollbars to the new text width and height
-        let (_, text_height) = self.get_text_size();
-        let vadj = self.view_item.verti_bar.get_adjustment();
-        vadj.set_lower(0_f64);
-        vadj.set_upper(text_height as f64);
-        if vadj.get_value() + vadj.get_page_size() > vadj.get_upper() {
-            vadj.set_value(vadj.get_upper() - vadj.get_page_size())
-        }
+        self.update_text_height();
 
         // let hadj = self.view_item.horiz_bar.get_adjustment();
         // hadj.set_lower(0f64);
@@ -499,8 +896,170 @@ impl EditView {
 
         // self.change_scrollbar_visibility();
 
+        if let Some(position) = self.pending_position.take() {
+            self.goto_position(position);
+        }
+
+        // The selection might have moved to another match
+        self.update_replace_preview();
+
+        // Ask for the lines of the minimap again if xi drops them later on
+        if let Some((first, last)) = self.minimap_requested {
+            if (first..last).all(|line| self.line_cache.get_line(line).is_some()) {
+                self.minimap_requested = None;
+            }
+        }
+        if let Some((first, last)) = self.bracket_requested {
+            if (first..last).all(|line| self.line_cache.get_line(line).is_some()) {
+                self.bracket_requested = None;
+            }
+        }
+        self.update_bracket_highlights();
+        if self.pending_find_regions && self.has_all_lines() {
+            self.pending_find_regions = false;
+            if self.find_replace.options().in_selection {
+                self.find_regions = self.selected_regions();
+                self.search_changed(self.search_text());
+            }
+        }
+        if self.pending_replace_all && self.has_all_lines() {
+            self.pending_replace_all = false;
+            self.replace_all();
+        }
+        if self.pending_replace.is_some() && self.has_all_lines() {
+            if let Some((regex, options, replace_chars, hits)) = self.pending_replace.take() {
+                self.replace_hits(&regex, &options, &replace_chars, &hits);
+            }
+        }
+        if self.pending_next_occurrence && self.has_all_lines() {
+            self.pending_next_occurrence = false;
+            self.select_next_occurrence();
+        }
+        if self.pending_all_occurrences && self.has_all_lines() {
+            self.pending_all_occurrences = false;
+            self.add_all_occurrences();
+        }
+        if self.pending_export.is_some() && self.has_all_lines() {
+            if let Some(export) = self.pending_export.take() {
+                self.run_export(export);
+            }
+        }
+        // Finish jumping to a bracket once xi has sent its line, unless the cursor has moved since
+        if let Some(pos) = self.pending_bracket_jump.take() {
+            if self.cursor_position() == Some(pos) {
+                self.go_to_matching_bracket();
+            }
+        }
+
         self.view_item.edit_area.queue_draw();
         self.view_item.linecount.queue_draw();
+        self.view_item.overview.queue_draw();
+        self.view_item.minimap.queue_draw();
+    }
+
+    /// If xi has sent us every line of the document
+    fn has_all_lines(&self) -> bool {
+        let height = self.line_cache.height();
+        height == 0 || self.line_cache.get_missing(0, height).is_empty()
+    }
+
+    pub fn print(&mut self) {
+        self.export(Export::Print);
+    }
+
+    pub fn export_html(&mut self, path: PathBuf, options: HtmlOptions) {
+        self.export(Export::Html(path, options));
+    }
+
+    /// xi only sends us the lines we've asked for, so if some are missing we ask for all of them
+    /// and export the document once xi has sent them
+    fn export(&mut self, export: Export) {
+        if self.has_all_lines() {
+            self.run_export(export);
+        } else {
+            self.pending_export = Some(export);
+            self.core
+                .borrow()
+                .request_lines(&self.view_id, 0, self.line_cache.height());
+        }
+    }
+
+    fn run_export(&self, export: Export) {
+        match export {
+            Export::Print => self.start_print(),
+            Export::Html(path, options) => self.write_html(&path, options),
+        }
+    }
+
+    fn write_html(&self, path: &Path, options: HtmlOptions) {
+        let lines = html::join_lines(
+            (0..self.line_cache.height()).filter_map(|n| self.line_cache.get_line(n)),
+        );
+        let font_family = self
+            .edit_font
+            .font_desc
+            .get_family()
+            .map(|f| f.to_string())
+            .unwrap_or_default();
+        let document = {
+            let main_state = self.main_state.borrow();
+            html::document(
+                &self.display_name(),
+                &lines,
+                &main_state.styles,
+                &main_state.theme,
+                &font_family,
+                options,
+            )
+        };
+
+        debug!("{} {:?}", gettext("Exporting HTML to"), path);
+        if let Err(e) = std::fs::write(path, document) {
+            let msg = format!(
+                "{} '{}': {}",
+                gettext("Couldn't export the document to"),
+                path.display(),
+                e
+            );
+            ErrorDialog::new(ErrorMsg { msg, fatal: false }).show_all();
+        }
+    }
+
+    fn start_print(&self) {
+        let lines = html::join_lines(
+            (0..self.line_cache.height()).filter_map(|n| self.line_cache.get_line(n)),
+        );
+        let job = PrintJob::new(
+            self.file_name.clone(),
+            self.edit_font.font_desc.clone(),
+            lines,
+            &self.main_state.borrow().styles,
+        );
+
+        let parent = self
+            .root_widget
+            .get_toplevel()
+            .and_then(|w| w.downcast::<Window>().ok());
+        // The print dialog runs a main loop of its own, so don't show it while our caller still
+        // has the EditView borrowed
+        let mut job = Some(job);
+        gtk::idle_add(move || {
+            if let Some(job) = job.take() {
+                job.run(parent.as_ref());
+            }
+            glib::Continue(false)
+        });
+    }
+
+    /// Sets the range of the vertical scrollbar to the height of the document
+    fn update_text_height(&self) {
+        let (_, text_height) = self.get_text_size();
+        let vadj = self.view_item.verti_bar.get_adjustment();
+        vadj.set_lower(0_f64);
+        vadj.set_upper(text_height as f64);
+        if vadj.get_value() + vadj.get_page_size() > vadj.get_upper() {
+            vadj.set_value(vadj.get_upper() - vadj.get_page_size())
+        }
     }
 
     fn change_scrollbar_visibility(&self) {
@@ -543,7 +1102,9 @@ impl EditView {
         if y < 0.0 {
             y = 0.0;
         }
-        let line_num = (y / self.edit_font.font_height) as u64;
+        let line_num = self
+            .folds
+            .row_to_line((y / self.edit_font.font_height) as u64);
         let index = if let Some(line) = self.line_cache.get_line(line_num) {
             let pango_ctx = self.view_item.get_pango_ctx();
 
@@ -553,7 +1114,7 @@ impl EditView {
         } else {
             0
         };
-        (index as u64, (y / self.edit_font.font_height) as u64)
+        (index as u64, line_num)
     }
 
     /// Allocate the space our DrawingArea needs.
@@ -602,7 +1163,7 @@ impl EditView {
     fn get_text_size(&self) -> (f64, f64) {
         let da_width = f64::from(self.view_item.edit_area.get_allocated_width());
         let da_height = f64::from(self.view_item.edit_area.get_allocated_height());
-        let num_lines = self.line_cache.height();
+        let num_lines = self.folds.n_rows(self.line_cache.height());
 
         let all_text_height =
             num_lines as f64 * self.edit_font.font_height + self.edit_font.font_descent;
@@ -626,6 +1187,18 @@ impl EditView {
     pub fn handle_da_draw(&mut self, cr: &Context) -> Inhibit {
         const CURSOR_WIDTH: f64 = 2.0;
 
+        // Vim's normal and visual modes show a block covering the char under the cursor
+        let caret_style = if self.main_state.borrow().keymap.preset() == Preset::Vim
+            && self.vim.mode() != Mode::Insert
+        {
+            CaretStyle::Block
+        } else {
+            CaretStyle::from_name(&get_caret_style())
+        };
+        let has_focus = self.view_item.edit_area.has_focus();
+
+        let (matching_brackets, enclosing_brackets) = self.bracket_highlights.clone();
+
         // let foreground = self.main_state.borrow().theme.foreground;
         let theme = &self.main_state.borrow().theme;
 
@@ -639,7 +1212,7 @@ impl EditView {
         // font_options.set_hint_style(HintStyle::Full);
 
         // let (text_width, text_height) = self.get_text_size();
-        let num_lines = self.line_cache.height();
+        let num_rows = self.folds.n_rows(self.line_cache.height());
 
         let vadj = self.view_item.verti_bar.get_adjustment();
         let hadj = self.view_item.horiz_bar.get_adjustment();
@@ -654,10 +1227,10 @@ impl EditView {
             hadj.get_upper()
         );
 
-        let first_line = (vadj.get_value() / self.edit_font.font_height) as u64;
-        let last_line =
+        let first_row = (vadj.get_value() / self.edit_font.font_height) as u64;
+        let last_row =
             ((vadj.get_value() + f64::from(da_height)) / self.edit_font.font_height) as u64 + 1;
-        let last_line = min(last_line, num_lines);
+        let last_row = min(last_row, num_rows);
 
         let pango_ctx = self.view_item.get_pango_ctx();
         pango_ctx.set_font_description(&self.edit_font.font_desc);
@@ -686,50 +1259,65 @@ impl EditView {
             cr.fill();
         }
 
-        set_source_color(cr, theme.foreground);
-
         // Highlight cursor lines
-        // for i in first_line..last_line {
-        //     cr.set_source_rgba(0.8, 0.8, 0.8, 1.0);
-        //     if let Some(line) = self.line_cache.get_line(i) {
-
-        //         if !line.cursor().is_empty() {
-        //             cr.set_source_rgba(0.23, 0.23, 0.23, 1.0);
-        //             cr.rectangle(0f64,
-        //                 font_extents.height*((i+1) as f64) - font_extents.ascent - vadj.get_value(),
-        //                 da_width as f64,
-        //                 font_extents.ascent + font_extents.descent);
-        //             cr.fill();
-        //         }
-        //     }
-        // }
+        if theme.line_highlight.is_some() {
+            set_source_color(cr, theme.line_highlight);
+            for row in first_row..last_row {
+                let line = self.line_cache.get_line(self.folds.row_to_line(row));
+                if line.map_or(false, |l| !l.cursor().is_empty()) {
+                    cr.rectangle(
+                        0.0,
+                        self.edit_font.font_height * (row as f64) - vadj.get_value(),
+                        f64::from(da_width),
+                        self.edit_font.font_ascent + self.edit_font.font_descent,
+                    );
+                }
+            }
+            cr.fill();
+        }
+
+        if get_draw_indent_guides() {
+            self.draw_indent_guides(cr, theme, first_row, last_row);
+        }
+
+        set_source_color(cr, theme.foreground);
 
         // This can't be 0, otherwise our Scrollbar bugs out. pango::SCALE is the smallest number it accepts
         let mut max_width = pango::SCALE;
 
         let main_state = self.main_state.borrow();
+        // The preedit string of the input method is drawn at the first cursor
+        let first_cursor = self.cursor_position();
+        let draw_whitespace = DrawWhitespace::from_name(&get_draw_whitespace());
+        let faded_foreground = theme.foreground.map(|c| Color { a: 80, ..c });
 
-        for i in first_line..last_line {
+        for row in first_row..last_row {
+            let i = self.folds.row_to_line(row);
             // Keep track of the starting x position
             if let Some(line) = self.line_cache.get_line(i) {
                 cr.move_to(
                     -hadj.get_value(),
-                    self.edit_font.font_height * (i as f64) - vadj.get_value(),
+                    self.edit_font.font_height * (row as f64) - vadj.get_value(),
                 );
 
                 let pango_ctx = self.view_item.get_pango_ctx();
 
                 let layout = self.create_layout_for_line(&pango_ctx, &main_state, line);
+                self.highlight_find_matches(&layout, &main_state, i);
+                let preedit = first_cursor.filter(|pos| pos.line == i).and_then(|pos| {
+                    let (len, cursor) = self.insert_preedit(&layout, pos.col as usize)?;
+                    Some((pos.col, len as u64, cursor as u64))
+                });
                 max_width = max(max_width, layout.get_extents().1.width);
                 // debug!("width={}", layout.get_extents().1.width);
                 update_layout(cr, &layout);
                 show_layout(cr, &layout);
 
-                // Well this is stupid, but (for some reason) Pango gets the width of "·" wrong!
-                // It only thinks that the width of that char is 5, when it actually is 10 (like all
-                // other chars. So we have to replace it with some other char here to trick Pango into
-                // drawing the cursor at the correct position later on
-                layout.set_text(&layout.get_text().unwrap().replace("·", " "));
+                if self.folds.fold_at(i).is_some() {
+                    let x = f64::from(layout.get_extents().1.width / pango::SCALE);
+                    self.draw_fold_placeholder(cr, &pango_ctx, theme, x - hadj.get_value(), row);
+                    set_source_color(cr, theme.foreground);
+                }
 
                 let layout_line = layout.get_line(0);
                 if layout_line.is_none() {
@@ -737,21 +1325,136 @@ impl EditView {
                 }
                 let layout_line = layout_line.unwrap();
 
+                self.draw_selection_border(cr, theme, &layout_line, line, row);
+
+                // Things after the preedit string have moved along with it
+                let layout_index = |index: u64| match preedit {
+                    Some((at, len, _)) if index >= at => index + len,
+                    _ => index,
+                };
+
+                let markers = whitespace::markers(line.text(), draw_whitespace);
+                if !markers.is_empty() {
+                    set_source_color(cr, theme.guide.or(faded_foreground));
+                    for (ix, marker) in markers {
+                        let index = layout_index(ix as u64);
+                        self.draw_whitespace_marker(
+                            cr,
+                            &layout_line,
+                            line.text(),
+                            index,
+                            marker,
+                            row,
+                        );
+                    }
+                }
+
+                let brackets = matching_brackets
+                    .iter()
+                    .map(|pos| (pos, theme.brackets_foreground.or(theme.foreground), false))
+                    .chain(
+                        enclosing_brackets
+                            .iter()
+                            .map(|pos| (pos, theme.bracket_contents_foreground, true)),
+                    );
+                for (pos, color, underline) in brackets.filter(|(pos, _, _)| pos.line == i) {
+                    self.draw_bracket_mark(
+                        cr,
+                        color,
+                        &layout_line,
+                        line.text(),
+                        layout_index(pos.col),
+                        row,
+                        underline,
+                    );
+                }
+
                 // Set cursor color
                 set_source_color(cr, theme.caret);
 
                 for c in line.cursor() {
-                    let x = layout_line.index_to_x(*c as i32, false) / pango::SCALE;
+                    // Cursors behind the preedit string move along with it, the one it's inserted
+                    // at shows where the input method's cursor is
+                    let (index, caret_style) = match preedit {
+                        Some((at, _, cursor)) if *c == at => (at + cursor, CaretStyle::Bar),
+                        Some((at, len, _)) if *c > at => (*c + len, caret_style),
+                        _ => (*c, caret_style),
+                    };
+                    let x = layout_line.index_to_x(index as i32, false) / pango::SCALE;
+                    // The char under the cursor, which block and underline carets cover
+                    let ch = line
+                        .text()
+                        .get(*c as usize..)
+                        .and_then(|t| t.chars().next())
+                        .filter(|ch| *ch != '\n' && *ch != '\r');
+                    let char_width = match ch {
+                        Some(ch) => {
+                            let next = index as usize + ch.len_utf8();
+                            f64::from(layout_line.index_to_x(next as i32, false) / pango::SCALE - x)
+                        }
+                        None => self.edit_font.font_width,
+                    };
+                    let x = f64::from(x) - hadj.get_value();
+                    let y = (((self.edit_font.font_ascent + self.edit_font.font_descent) as u64)
+                        * row) as f64
+                        - vadj.get_value();
+                    let height = self.edit_font.font_ascent + self.edit_font.font_descent;
+
+                    // Tell the input method where the cursor is, so it can place its candidate window
+                    if first_cursor == Some(Pos::new(i, *c)) {
+                        self.view_item
+                            .im_context
+                            .set_cursor_location(&gdk::Rectangle {
+                                x: x as i32,
+                                y: y as i32,
+                                width: char_width as i32,
+                                height: height as i32,
+                            });
+                    }
+
+                    // Like in terminals the cursor of a view without focus is a hollow block
+                    if !has_focus {
+                        cr.set_line_width(1.0);
+                        cr.rectangle(x + 0.5, y + 0.5, char_width - 1.0, height - 1.0);
+                        cr.stroke();
+                        continue;
+                    }
+                    if !self.caret_visible {
+                        continue;
+                    }
+
                     // Draw the cursor
-                    cr.rectangle(
-                        (f64::from(x)) - hadj.get_value(),
-                        (((self.edit_font.font_ascent + self.edit_font.font_descent) as u64) * i)
-                            as f64
-                            - vadj.get_value(),
-                        CURSOR_WIDTH,
-                        self.edit_font.font_ascent + self.edit_font.font_descent,
-                    );
+                    match caret_style {
+                        CaretStyle::Bar => cr.rectangle(x, y, CURSOR_WIDTH, height),
+                        CaretStyle::Underline => {
+                            cr.rectangle(x, y + height - CURSOR_WIDTH, char_width, CURSOR_WIDTH)
+                        }
+                        CaretStyle::Block => cr.rectangle(x, y, char_width, height),
+                    }
                     cr.fill();
+
+                    // Draw the char under a block again in the background colour, so it's
+                    // inverted. It keeps the weight, slant and underline of its style.
+                    if let (CaretStyle::Block, Some(ch)) = (caret_style, ch) {
+                        let layout = pango::Layout::new(&pango_ctx);
+                        layout.set_font_description(&self.edit_font.font_desc);
+                        layout.set_text(&ch.to_string());
+                        let attr_list = pango::AttrList::new();
+                        let main_state = self.main_state.borrow();
+                        let style = line
+                            .style_at(*c as usize)
+                            .and_then(|id| main_state.styles.get(&id));
+                        insert_font_attributes(&attr_list, style, 0, ch.len_utf8() as u32);
+                        layout.set_attributes(&attr_list);
+                        set_source_color(cr, theme.background);
+                        cr.move_to(
+                            x,
+                            self.edit_font.font_height * (row as f64) - vadj.get_value(),
+                        );
+                        update_layout(cr, &layout);
+                        show_layout(cr, &layout);
+                        set_source_color(cr, theme.caret);
+                    }
                 }
             }
         }
@@ -761,76 +1464,850 @@ impl EditView {
         Inhibit(false)
     }
 
-    /// This draws the linecount. We have this as our own widget to make sure we don't mess up text
-    /// selection etc.
-    pub fn handle_linecount_draw(&mut self, cr: &Context) -> Inhibit {
-        let theme = &self.main_state.borrow().theme;
-        let linecount_height = self.view_item.linecount.get_allocated_height();
+    /// Draws a box around the bracket at byte `index` of the line in `row`, or a line below it if
+    /// `underline` is set, to show which brackets belong together
+    #[allow(clippy::too_many_arguments)]
+    fn draw_bracket_mark(
+        &self,
+        cr: &Context,
+        color: Option<Color>,
+        layout_line: &pango::LayoutLine,
+        text: &str,
+        index: u64,
+        row: u64,
+        underline: bool,
+    ) {
+        let len = text
+            .get(index as usize..)
+            .and_then(|t| t.chars().next())
+            .map_or(1, char::len_utf8);
+        let x = layout_line.index_to_x(index as i32, false) / pango::SCALE;
+        let next = layout_line.index_to_x(index as i32 + len as i32, false) / pango::SCALE;
+        let width = f64::from(next - x);
+        let x = f64::from(x) - self.view_item.horiz_bar.get_value();
+        let y = self.edit_font.font_height * (row as f64) - self.view_item.verti_bar.get_value();
+        let height = self.edit_font.font_ascent + self.edit_font.font_descent;
+
+        set_source_color(cr, color);
+        cr.set_line_width(1.0);
+        // Half a pixel in, so the lines are sharp
+        if underline {
+            cr.move_to(x, y + height - 0.5);
+            cr.line_to(x + width, y + height - 0.5);
+        } else {
+            cr.rectangle(x + 0.5, y + 0.5, width - 1.0, height - 1.0);
+        }
+        cr.stroke();
+    }
 
-        let num_lines = self.line_cache.height();
+    /// Draws the marker for the whitespace at byte `index` of the line in `row`: a dot for spaces,
+    /// an arrow for tabs, a circle for non-breaking spaces and a return arrow for line endings.
+    /// The markers are drawn over the text, so the layout keeps the real whitespace.
+    fn draw_whitespace_marker(
+        &self,
+        cr: &Context,
+        layout_line: &pango::LayoutLine,
+        text: &str,
+        index: u64,
+        marker: Marker,
+        row: u64,
+    ) {
+        let x = layout_line.index_to_x(index as i32, false) / pango::SCALE;
+        // Line endings aren't part of the layout, they get a cell after the text
+        let width = match marker {
+            Marker::LineEnding => self.edit_font.font_width,
+            _ => {
+                let len = text
+                    .get(index as usize..)
+                    .and_then(|t| t.chars().next())
+                    .map_or(1, char::len_utf8);
+                let next = layout_line.index_to_x(index as i32 + len as i32, false) / pango::SCALE;
+                f64::from(next - x)
+            }
+        };
+        let x = f64::from(x) - self.view_item.horiz_bar.get_value();
+        let y = self.edit_font.font_height * (row as f64) - self.view_item.verti_bar.get_value();
+        let height = self.edit_font.font_ascent + self.edit_font.font_descent;
+        let center_x = x + width / 2.0;
+        let center_y = y + height / 2.0;
+        let unit = self.edit_font.font_width / 4.0;
+
+        cr.set_line_width(1.0);
+        match marker {
+            Marker::Space => {
+                cr.arc(center_x, center_y, unit.max(2.0) / 2.0, 0.0, 2.0 * PI);
+                cr.fill();
+            }
+            Marker::NonBreakingSpace => {
+                cr.arc(center_x, center_y, unit, 0.0, 2.0 * PI);
+                cr.stroke();
+            }
+            Marker::Tab => {
+                let end = x + width - unit;
+                cr.move_to(x + unit, center_y);
+                cr.line_to(end, center_y);
+                cr.move_to(end - unit, center_y - unit);
+                cr.line_to(end, center_y);
+                cr.line_to(end - unit, center_y + unit);
+                cr.stroke();
+            }
+            Marker::LineEnding => {
+                let left = x + unit;
+                let right = x + width - unit;
+                cr.move_to(right, center_y - 2.0 * unit);
+                cr.line_to(right, center_y);
+                cr.line_to(left, center_y);
+                cr.move_to(left + unit, center_y - unit);
+                cr.line_to(left, center_y);
+                cr.line_to(left + unit, center_y + unit);
+                cr.stroke();
+            }
+        }
+    }
 
-        let vadj = self.view_item.verti_bar.get_adjustment();
+    /// Draws the theme's selection border around the selections on the line in `row`
+    fn draw_selection_border(
+        &self,
+        cr: &Context,
+        theme: &ThemeSettings,
+        layout_line: &pango::LayoutLine,
+        line: &Line,
+        row: u64,
+    ) {
+        if theme.selection_border.is_none() {
+            return;
+        }
+        let y = self.edit_font.font_height * (row as f64) - self.view_item.verti_bar.get_value();
+        let height = self.edit_font.font_ascent + self.edit_font.font_descent;
 
-        let first_line = (vadj.get_value() / self.edit_font.font_height) as u64;
-        let last_line = ((vadj.get_value() + f64::from(linecount_height))
-            / self.edit_font.font_height) as u64
-            + 1;
-        let last_line = min(last_line, num_lines);
+        set_source_color(cr, theme.selection_border);
+        cr.set_line_width(1.0);
+        let mut ix = 0;
+        for style in &line.styles {
+            let start = ix + style.start;
+            let end = start + style.len as i64;
+            ix = end;
+            // xi styles selections with style 0
+            if style.id != 0 || style.len == 0 {
+                continue;
+            }
 
-        let pango_ctx = self.view_item.get_pango_ctx();
+            let x = layout_line.index_to_x(start as i32, false) / pango::SCALE;
+            let end_x = layout_line.index_to_x(end as i32, false) / pango::SCALE;
+            cr.rectangle(
+                f64::from(x) - self.view_item.horiz_bar.get_value() + 0.5,
+                y + 0.5,
+                f64::from(end_x - x) - 1.0,
+                height - 1.0,
+            );
+        }
+        cr.stroke();
+    }
 
-        // Make the linecount at least 6 chars big
-        let linecount_width = if format!("  {}  ", last_line).len() > 6 {
-            let width = self.interface_font.font_width * format!("  {}  ", last_line).len() as f64;
-            // Make sure the linecount_width is even to properly center the line number
-            if width % 2.0 == 0.0 {
-                width
-            } else {
-                width + 1.0
+    /// Draws the indent guides of the rows from `first_row` to `last_row`, one every `tab_size`
+    /// columns of indentation. The guide of the block the cursor is in is drawn in the theme's
+    /// colour for the active guide. Themes without guide colours get faint ones in the text's
+    /// colour.
+    fn draw_indent_guides(
+        &self,
+        cr: &Context,
+        theme: &ThemeSettings,
+        first_row: u64,
+        last_row: u64,
+    ) {
+        let guide = theme
+            .guide
+            .or_else(|| theme.foreground.map(|c| Color { a: 40, ..c }));
+        let active_guide = theme.active_guide.or(guide);
+        let tab_size = u64::from(max(
+            self.main_state.borrow().config.borrow().config.tab_size,
+            1,
+        ));
+        let height = self.line_cache.height();
+        // Blank lines get the indentation of the next line that isn't blank, so the guides don't
+        // have gaps
+        let indent_of = |line: u64| {
+            for n in line..min(line + GUIDE_LOOKAHEAD, height) {
+                if let Some(indent) = folding::indent(self.line_cache.line(n)?, tab_size) {
+                    return Some(indent);
+                }
             }
-        } else {
-            self.interface_font.font_width * 6.0
+            None
         };
 
-        // Draw linecount background
-        set_source_color(cr, theme.background);
-        cr.rectangle(0.0, 0.0, linecount_width, f64::from(linecount_height));
-        cr.fill();
+        let first_line = self.folds.row_to_line(first_row);
+        let last_line = self.folds.row_to_line(last_row);
+        // The column of the active guide and the lines it spans
+        let active = self.cursor_position().and_then(|pos| {
+            let indent = indent_of(pos.line)?;
+            if indent == 0 {
+                return None;
+            }
+            let col = (indent - 1) / tab_size * tab_size;
+            let in_block = |line: &u64| indent_of(*line).map_or(false, |indent| indent > col);
+            let first = (first_line..pos.line)
+                .rev()
+                .take_while(in_block)
+                .last()
+                .unwrap_or(pos.line);
+            let last = (pos.line + 1..last_line)
+                .take_while(in_block)
+                .last()
+                .unwrap_or(pos.line);
+            Some((col, first, last))
+        });
+
+        cr.set_line_width(1.0);
+        for row in first_row..last_row {
+            let line = self.folds.row_to_line(row);
+            let indent = match indent_of(line) {
+                Some(indent) => indent,
+                None => continue,
+            };
+            let y =
+                self.edit_font.font_height * (row as f64) - self.view_item.verti_bar.get_value();
+
+            for col in (0..indent).step_by(tab_size as usize) {
+                let is_active = active.map_or(false, |(active_col, first, last)| {
+                    col == active_col && line >= first && line <= last
+                });
+                let color = if is_active { active_guide } else { guide };
+                let x = (self.edit_font.font_width * col as f64).floor() + 0.5
+                    - self.view_item.horiz_bar.get_value();
+                set_source_color(cr, color);
+                cr.move_to(x, y);
+                cr.line_to(x, y + self.edit_font.font_height);
+                cr.stroke();
+            }
+        }
+    }
+
+    /// Draws the placeholder of a folded region at `x` in `row`, after the line starting the
+    /// region
+    fn draw_fold_placeholder(
+        &self,
+        cr: &Context,
+        pango_ctx: &pango::Context,
+        theme: &ThemeSettings,
+        x: f64,
+        row: u64,
+    ) {
+        let layout = pango::Layout::new(pango_ctx);
+        layout.set_text("⋯");
+        let width = f64::from(layout.get_extents().1.width / pango::SCALE);
+        let x = x + self.edit_font.font_width;
+        let y = self.edit_font.font_height * (row as f64) - self.view_item.verti_bar.get_value();
+        let padding = self.edit_font.font_width / 2.0;
+
+        set_source_color(cr, theme.foreground);
+        cr.rectangle(
+            x,
+            y,
+            width + 2.0 * padding,
+            self.edit_font.font_ascent + self.edit_font.font_descent,
+        );
+        cr.save();
+        cr.clip();
+        cr.paint_with_alpha(0.15);
+        cr.restore();
+
+        cr.move_to(x + padding, y);
+        update_layout(cr, &layout);
+        show_layout(cr, &layout);
+    }
+
+    /// Highlights the matches of a search restricted to the selection, which xi doesn't know
+    /// about, like xi highlights the others.
+    fn highlight_find_matches(&self, layout: &pango::Layout, main_state: &MainState, line: u64) {
+        let style = match main_state.styles.get(&1) {
+            Some(style) => style,
+            None => return,
+        };
+        let background = style.bg_color.map(|c| PangoColor::from(color_from_u32(c)));
+        let foreground = style.fg_color.map(|c| PangoColor::from(color_from_u32(c)));
+        let attr_list = layout.get_attributes().unwrap_or_else(AttrList::new);
+        for (start, end) in self.find_matches.iter().filter(|(s, _)| s.line == line) {
+            let attrs = background
+                .map(|c| Attribute::new_background(c.r, c.g, c.b).unwrap())
+                .into_iter()
+                .chain(foreground.map(|c| Attribute::new_foreground(c.r, c.g, c.b).unwrap()));
+            for mut attr in attrs {
+                attr.set_start_index(start.col as u32);
+                attr.set_end_index(end.col as u32);
+                attr_list.insert(attr);
+            }
+        }
+        layout.set_attributes(&attr_list);
+    }
+
+    /// Draws the overview ruler, which shows the matches of the current search and the cursors in
+    /// the whole document, and which part of it is visible.
+    pub fn handle_overview_draw(&self, cr: &Context) -> Inhibit {
+        // How high the marks are, so they're still visible in long documents
+        const MARK_HEIGHT: f64 = 2.0;
+
+        let main_state = self.main_state.borrow();
+        let theme = &main_state.theme;
+        let width = f64::from(self.view_item.overview.get_allocated_width());
+        let height = f64::from(self.view_item.overview.get_allocated_height());
+        let n_lines = max(self.line_cache.height(), 1) as f64;
+        let line_y = |line: u64| (line as f64 / n_lines * height).min(height - MARK_HEIGHT);
+
+        set_margin_source_color(cr, theme.background);
+        cr.rectangle(0.0, 0.0, width, height);
+        cr.fill();
+
+        // The visible part of the document
+        let vadj = self.view_item.verti_bar.get_adjustment();
+        if vadj.get_upper() > 0.0 {
+            set_source_color(cr, theme.accent.or(theme.foreground));
+            cr.rectangle(
+                0.0,
+                vadj.get_value() / vadj.get_upper() * height,
+                width,
+                vadj.get_page_size() / vadj.get_upper() * height,
+            );
+            cr.save();
+            cr.clip();
+            cr.paint_with_alpha(0.1);
+            cr.restore();
+        }
+
+        set_source_color(cr, theme.find_highlight.or(theme.selection));
+        for line in self.match_lines() {
+            cr.rectangle(0.0, line_y(line), width, MARK_HEIGHT);
+        }
+        cr.fill();
+
+        // We only know the cursors on the lines in the cache
+        set_source_color(cr, theme.caret);
+        for (ix, line) in self.line_cache.lines.iter().enumerate() {
+            if line.as_ref().map_or(false, |l| !l.cursor().is_empty()) {
+                let line = ix as u64 + self.line_cache.n_invalid_before;
+                cr.rectangle(width / 2.0, line_y(line), width / 2.0, MARK_HEIGHT);
+            }
+        }
+        cr.fill();
+
+        Inhibit(false)
+    }
+
+    /// The lines with matches of the current search, if the search bar is open
+    fn match_lines(&self) -> Vec<u64> {
+        if !self.find_replace.search_bar.get_search_mode() {
+            return Vec::new();
+        }
+        let match_lines = self.find_matches.iter().map(|(start, _)| start.line);
+        self.find_lines.iter().cloned().chain(match_lines).collect()
+    }
+
+    /// Scrolls to the part of the document the user clicked at in the overview ruler. Clicks close
+    /// to a match jump to its line.
+    pub fn handle_overview_click(&mut self, y: f64) -> Inhibit {
+        let height = f64::from(self.view_item.overview.get_allocated_height());
+        if height <= 0.0 {
+            return Inhibit(false);
+        }
+        let n_lines = max(self.line_cache.height(), 1) as f64;
+        let lines_per_px = n_lines / height;
+
+        let clicked = (y.max(0.0) * lines_per_px) as u64;
+        let line = self
+            .match_lines()
+            .into_iter()
+            .min_by_key(|line| (*line as i64 - clicked as i64).abs())
+            .filter(|line| ((*line as f64 - clicked as f64).abs() / lines_per_px) < 3.0)
+            .unwrap_or(clicked);
+
+        let vadj = self.view_item.verti_bar.get_adjustment();
+        let row = self.folds.line_to_row(line);
+        let value = self.edit_font.font_height * row as f64 - vadj.get_page_size() / 2.0;
+        vadj.set_value(
+            value
+                .max(vadj.get_lower())
+                .min(vadj.get_upper() - vadj.get_page_size()),
+        );
+        self.update_visible_scroll_region();
+        self.view_item.edit_area.queue_draw();
+        self.view_item.linecount.queue_draw();
+
+        Inhibit(false)
+    }
+
+    /// Shows or hides the minimap, depending on what the user has chosen in the preferences
+    pub fn update_minimap_visibility(&self) {
+        self.view_item.minimap.set_visible(get_show_minimap());
+    }
+
+    /// Where the minimap is scrolled to and where its viewport is. Returns the first line the
+    /// minimap shows (which may be a fraction of a line), the y coordinate and height of the
+    /// viewport and how high the lines of the document are in the minimap.
+    ///
+    /// If the document doesn't fit into the minimap, it scrolls along proportionally to the
+    /// editing area, so the first and last lines of the document are at its top and bottom.
+    fn minimap_geometry(&self) -> (f64, f64, f64, f64) {
+        let height = f64::from(self.view_item.minimap.get_allocated_height());
+        let n_lines = self.line_cache.height() as f64;
+        let vadj = self.view_item.verti_bar.get_adjustment();
+
+        let scroll_range = vadj.get_upper() - vadj.get_page_size();
+        let fraction = if scroll_range > 0.0 {
+            (vadj.get_value() / scroll_range).max(0.0).min(1.0)
+        } else {
+            0.0
+        };
+        let content_height = (n_lines * MINIMAP_LINE_HEIGHT).min(height);
+        let viewport_height =
+            (vadj.get_page_size() / self.edit_font.font_height * MINIMAP_LINE_HEIGHT).min(height);
+        let first_line = fraction * (n_lines - height / MINIMAP_LINE_HEIGHT).max(0.0);
+        let viewport_y = fraction * (content_height - viewport_height).max(0.0);
+
+        (first_line, viewport_y, viewport_height, content_height)
+    }
+
+    /// Draws the minimap. Every char is a small block in the colour xi has highlighted it with.
+    /// Lines which aren't in the line cache are requested from xi, so they're drawn once xi has
+    /// sent them.
+    pub fn handle_minimap_draw(&mut self, cr: &Context) -> Inhibit {
+        let width = f64::from(self.view_item.minimap.get_allocated_width());
+        let height = f64::from(self.view_item.minimap.get_allocated_height());
+        let (first_line, viewport_y, viewport_height, _) = self.minimap_geometry();
+        let first = first_line as u64;
+        let last = min(
+            self.line_cache.height(),
+            first + (height / MINIMAP_LINE_HEIGHT) as u64 + 2,
+        );
+
+        if let Some(missing) = (first..last).find(|line| self.line_cache.get_line(*line).is_none())
+        {
+            let lines = (missing, min(last, missing + MINIMAP_CHUNK));
+            if self.minimap_requested != Some(lines) {
+                trace!(
+                    "{} {:?}",
+                    gettext("Requesting lines for the minimap"),
+                    lines
+                );
+                self.core
+                    .borrow()
+                    .request_lines(&self.view_id, lines.0, lines.1);
+                self.minimap_requested = Some(lines);
+            }
+        }
+
+        let main_state = self.main_state.borrow();
+        let theme = &main_state.theme;
+        let tab_size = u64::from(max(main_state.config.borrow().config.tab_size, 1));
+
+        set_source_color(cr, theme.background);
+        cr.rectangle(0.0, 0.0, width, height);
+        cr.fill();
+
+        let max_cols = (width / MINIMAP_CHAR_WIDTH) as u64;
+        for i in first..last {
+            if let Some(line) = self.line_cache.get_line(i) {
+                let y = (i as f64 - first_line) * MINIMAP_LINE_HEIGHT;
+                self.draw_minimap_line(cr, &main_state, line, y, tab_size, max_cols);
+            }
+        }
+
+        // The part of the document that's visible in the editing area
+        set_source_color(cr, theme.accent.or(theme.foreground));
+        cr.rectangle(0.0, viewport_y, width, viewport_height);
+        cr.save();
+        cr.clip();
+        cr.paint_with_alpha(0.1);
+        cr.restore();
+
+        Inhibit(false)
+    }
+
+    /// Draws a line of the minimap at `y`. Consecutive chars with the same colour are drawn as
+    /// one block, whitespace is left empty.
+    fn draw_minimap_line(
+        &self,
+        cr: &Context,
+        main_state: &MainState,
+        line: &Line,
+        y: f64,
+        tab_size: u64,
+        max_cols: u64,
+    ) {
+        // The foreground colour of every style span, the selection and find highlights only
+        // have a background
+        let mut spans = Vec::new();
+        let mut ix = 0;
+        for style in &line.styles {
+            let start = ix + style.start;
+            ix = start + style.len as i64;
+            if style.id > 1 {
+                if let Some(color) = main_state.styles.get(&style.id).and_then(|s| s.fg_color) {
+                    spans.push((start as usize, ix as usize, color));
+                }
+            }
+        }
+        let color_at = |index: usize| {
+            spans
+                .iter()
+                .find(|(start, end, _)| *start <= index && index < *end)
+                .map(|(_, _, color)| color_from_u32(*color))
+                .or(main_state.theme.foreground)
+        };
+
+        let mut col = 0;
+        // The column and colour of the block we're drawing
+        let mut block: Option<(u64, Color)> = None;
+        for (index, ch) in line.text().char_indices() {
+            if col >= max_cols {
+                break;
+            }
+
+            let color = if ch.is_whitespace() {
+                None
+            } else {
+                color_at(index)
+            };
+            if block.map(|(_, c)| Some(c)) != Some(color) {
+                if let Some((start, c)) = block.take() {
+                    self.fill_minimap_block(cr, c, start, col, y);
+                }
+                block = color.map(|c| (col, c));
+            }
+
+            col = if ch == '\t' {
+                (col / tab_size + 1) * tab_size
+            } else {
+                col + 1
+            };
+        }
+        if let Some((start, c)) = block {
+            self.fill_minimap_block(cr, c, start, min(col, max_cols), y);
+        }
+    }
+
+    fn fill_minimap_block(&self, cr: &Context, color: Color, start: u64, end: u64, y: f64) {
+        set_source_color(cr, Some(color));
+        cr.rectangle(
+            start as f64 * MINIMAP_CHAR_WIDTH,
+            y,
+            (end - start) as f64 * MINIMAP_CHAR_WIDTH,
+            MINIMAP_LINE_HEIGHT * 0.75,
+        );
+        cr.fill();
+    }
+
+    /// Starts dragging the viewport of the minimap. Clicks outside of it move it there first.
+    pub fn handle_minimap_press(&mut self, y: f64) -> Inhibit {
+        let (_, viewport_y, viewport_height, _) = self.minimap_geometry();
+        let grab = if y >= viewport_y && y <= viewport_y + viewport_height {
+            y - viewport_y
+        } else {
+            viewport_height / 2.0
+        };
+        self.minimap_grab = Some(grab);
+        self.handle_minimap_drag(y)
+    }
+
+    /// Scrolls the editing area so the viewport of the minimap follows the mouse
+    pub fn handle_minimap_drag(&self, y: f64) -> Inhibit {
+        let grab = match self.minimap_grab {
+            Some(grab) => grab,
+            None => return Inhibit(false),
+        };
+        let (_, _, viewport_height, content_height) = self.minimap_geometry();
+        let vadj = self.view_item.verti_bar.get_adjustment();
+
+        let range = content_height - viewport_height;
+        let fraction = if range > 0.0 {
+            ((y - grab) / range).max(0.0).min(1.0)
+        } else {
+            0.0
+        };
+        vadj.set_value(fraction * (vadj.get_upper() - vadj.get_page_size()).max(0.0));
+        self.update_visible_scroll_region();
+        self.view_item.edit_area.queue_draw();
+        self.view_item.linecount.queue_draw();
+
+        Inhibit(false)
+    }
+
+    /// This draws the linecount. We have this as our own widget to make sure we don't mess up text
+    /// selection etc.
+    pub fn handle_linecount_draw(&mut self, cr: &Context) -> Inhibit {
+        let theme = &self.main_state.borrow().theme;
+        let linecount_height = self.view_item.linecount.get_allocated_height();
+
+        let num_lines = self.line_cache.height();
+        let num_rows = self.folds.n_rows(num_lines);
+
+        let vadj = self.view_item.verti_bar.get_adjustment();
+
+        let first_row = (vadj.get_value() / self.edit_font.font_height) as u64;
+        let last_row = ((vadj.get_value() + f64::from(linecount_height))
+            / self.edit_font.font_height) as u64
+            + 1;
+        let last_row = min(last_row, num_rows);
+        let first_line = self.folds.row_to_line(first_row);
+        let last_line = min(self.folds.row_to_line(last_row), num_lines);
+
+        let pango_ctx = self.view_item.get_pango_ctx();
+        let tab_size = u64::from(max(
+            self.main_state.borrow().config.borrow().config.tab_size,
+            1,
+        ));
+
+        // Make the linecount at least 6 chars big
+        let linecount_width = if format!("  {}  ", last_line).len() > 6 {
+            let width = self.interface_font.font_width * format!("  {}  ", last_line).len() as f64;
+            // Make sure the linecount_width is even to properly center the line number
+            if width % 2.0 == 0.0 {
+                width
+            } else {
+                width + 1.0
+            }
+        } else {
+            self.interface_font.font_width * 6.0
+        };
+
+        // The fold markers are drawn right of the line numbers
+        let marker_width = self.interface_font.font_width * 2.0;
+
+        // Draw linecount background
+        set_source_color(cr, theme.gutter.or(theme.background));
+        cr.rectangle(
+            0.0,
+            0.0,
+            linecount_width + marker_width,
+            f64::from(linecount_height),
+        );
+        cr.fill();
 
         //FIXME: Xi sends us the 'ln' (logical linenumber) param for this, but that isn't updated on every draw!
         let mut current_line = first_line;
         let center_diff = (self.edit_font.font_height - self.interface_font.font_height) / 2.0;
 
-        set_source_color(cr, theme.foreground);
-        for i in first_line..last_line {
+        for row in first_row..last_row {
+            let i = self.folds.row_to_line(row);
             // Keep track of the starting x position
             if let Some(line) = self.line_cache.get_line(i) {
-                cr.move_to(
-                    0.0,
-                    self.edit_font.font_height * (i as f64) - vadj.get_value() + center_diff,
-                );
+                let y = self.edit_font.font_height * (row as f64) - vadj.get_value() + center_diff;
+                cr.move_to(0.0, y);
                 if line.line_num().is_some() {
                     current_line += 1
                 }
 
+                // The number of the line with the cursor stands out from the others
+                let is_current = !line.cursor().is_empty();
+                if is_current {
+                    set_source_color(cr, theme.foreground);
+                } else {
+                    set_source_color(cr, theme.gutter_foreground.or(theme.foreground));
+                }
                 let linecount_layout = self.create_layout_for_linecount(
                     &pango_ctx,
                     &self.main_state.borrow(),
                     current_line,
                     linecount_width as usize,
+                    is_current,
                 );
                 update_layout(cr, &linecount_layout);
                 show_layout(cr, &linecount_layout);
+                set_source_color(cr, theme.gutter_foreground.or(theme.foreground));
+
+                let marker = match self.folds.fold_at(i) {
+                    Some(fold) => {
+                        // The hidden lines don't get a row, but they still count
+                        current_line += fold.n_hidden();
+                        Some("▸")
+                    }
+                    None if folding::can_fold(&self.line_cache, i, tab_size) => Some("▾"),
+                    None => None,
+                };
+                if let Some(marker) = marker {
+                    let layout = pango::Layout::new(&pango_ctx);
+                    layout.set_font_description(&self.interface_font.font_desc);
+                    layout.set_text(marker);
+                    cr.move_to(linecount_width, y);
+                    update_layout(cr, &layout);
+                    show_layout(cr, &layout);
+                }
             }
         }
 
         // Set the appropriate size for the linecount DrawingArea, otherwise it's only 1 px wide.
         self.view_item
             .linecount
-            .set_size_request(linecount_width as i32, linecount_height);
+            .set_size_request((linecount_width + marker_width) as i32, linecount_height);
+        Inhibit(false)
+    }
+
+    /// Folds or unfolds the region starting at the row the user has clicked at in the gutter.
+    pub fn handle_linecount_click(&mut self, y: f64) -> Inhibit {
+        let vadj = self.view_item.verti_bar.get_adjustment();
+        let row = ((y + vadj.get_value()) / self.edit_font.font_height) as u64;
+        let line = self.folds.row_to_line(row);
+        self.toggle_fold(line);
         Inhibit(false)
     }
 
+    /// Unfolds the region starting at `line` if it's folded, or folds it otherwise
+    fn toggle_fold(&mut self, line: u64) {
+        if !self.folds.unfold(line) {
+            let tab_size = u64::from(max(
+                self.main_state.borrow().config.borrow().config.tab_size,
+                1,
+            ));
+            let fold = match folding::foldable_range(&self.line_cache, line, tab_size) {
+                Some(fold) => fold,
+                None => return,
+            };
+            self.folds.add(fold);
+
+            // Move cursors out of the region, otherwise we'd unfold it again right away
+            if let Some(pos) = self.cursor_position() {
+                if fold.hides(pos.line) {
+                    let len = self.line_cache.line(line).map_or(0, str::len);
+                    self.core
+                        .borrow()
+                        .gesture_point_select(&self.view_id, line, len as u64);
+                }
+            }
+        }
+
+        self.update_text_height();
+        self.update_visible_scroll_region();
+        self.view_item.edit_area.queue_draw();
+        self.view_item.linecount.queue_draw();
+        self.view_item.minimap.queue_draw();
+    }
+
+    /// Unfolds the regions the cursors have moved into, e.g. by searching
+    fn reveal_cursors(&mut self) {
+        let n_invalid_before = self.line_cache.n_invalid_before;
+        for (ix, line) in self.line_cache.lines.iter().enumerate() {
+            if line.as_ref().map_or(false, |l| !l.cursor().is_empty()) {
+                self.folds.reveal(ix as u64 + n_invalid_before);
+            }
+        }
+    }
+
+    /// Finds the brackets to highlight again if the cursor has moved or xi has changed the lines.
+    /// Searching them can take a while, so we don't do it every time we draw.
+    fn update_bracket_highlights(&mut self) {
+        let key = self
+            .cursor_position()
+            .map(|caret| (caret, self.line_cache.revision()));
+        if key != self.bracket_highlights_for {
+            self.bracket_highlights = self.find_bracket_highlights();
+            self.bracket_highlights_for = key;
+        }
+    }
+
+    /// The brackets to highlight: the bracket next to the (first) cursor and its partner, or, if
+    /// there's none and the theme has a colour for it, the brackets around the cursor.
+    fn find_bracket_highlights(&mut self) -> (Vec<Pos>, Vec<Pos>) {
+        if let BracketMatch::Found { bracket, partner } = self.matching_bracket() {
+            return (vec![bracket, partner], Vec::new());
+        }
+        if self
+            .main_state
+            .borrow()
+            .theme
+            .bracket_contents_foreground
+            .is_none()
+        {
+            return (Vec::new(), Vec::new());
+        }
+
+        let caret = match self.cursor_position() {
+            Some(caret) => caret,
+            None => return (Vec::new(), Vec::new()),
+        };
+        let line_cache = &self.line_cache;
+        let style_at = |pos: Pos| {
+            line_cache
+                .get_line(pos.line)
+                .and_then(|line| line.style_at(pos.col as usize))
+        };
+        // Unlike for the bracket at the cursor we don't ask xi for missing lines here, this
+        // would fetch the whole document if the cursor isn't in a block
+        match brackets::find_enclosing(line_cache, caret, &self.bracket_pairs(), style_at) {
+            BracketMatch::Found { bracket, partner } => (Vec::new(), vec![bracket, partner]),
+            _ => (Vec::new(), Vec::new()),
+        }
+    }
+
+    /// The brackets we match, including the `surrounding_pairs` of the xi config
+    fn bracket_pairs(&self) -> Vec<brackets::Pair> {
+        let main_state = self.main_state.borrow();
+        let config = main_state.config.borrow();
+        brackets::pairs(&config.config.surrounding_pairs)
+    }
+
+    /// Looks for the partner of the bracket next to the (first) cursor. If it might be on lines
+    /// which aren't in the line cache we ask xi for them.
+    fn matching_bracket(&mut self) -> BracketMatch {
+        let caret = match self.cursor_position() {
+            Some(caret) => caret,
+            None => return BracketMatch::None,
+        };
+        let pairs = self.bracket_pairs();
+        let line_cache = &self.line_cache;
+        let style_at = |pos: Pos| {
+            line_cache
+                .get_line(pos.line)
+                .and_then(|line| line.style_at(pos.col as usize))
+        };
+
+        let bracket_match = brackets::find_match(line_cache, caret, &pairs, style_at);
+        if let BracketMatch::Missing(line) = bracket_match {
+            let lines = if line > caret.line {
+                (line, min(line + BRACKET_CHUNK, self.line_cache.height()))
+            } else {
+                ((line + 1).saturating_sub(BRACKET_CHUNK), line + 1)
+            };
+            if self.bracket_requested != Some(lines) {
+                trace!(
+                    "{} {:?}",
+                    gettext("Requesting lines to find the matching bracket"),
+                    lines
+                );
+                self.core
+                    .borrow()
+                    .request_lines(&self.view_id, lines.0, lines.1);
+                self.bracket_requested = Some(lines);
+            }
+        }
+        bracket_match
+    }
+
+    /// Moves the cursor to the partner of the bracket next to it. It stays on the same side of
+    /// the bracket, so jumping twice gets back to where we started.
+    fn go_to_matching_bracket(&mut self) {
+        let caret = self.cursor_position();
+        match self.matching_bracket() {
+            BracketMatch::Found { bracket, partner } => {
+                let text = self.line_cache.line(partner.line).unwrap_or_default();
+                let after = caret.map_or(false, |caret| caret.col > bracket.col);
+                let col = if after {
+                    text[partner.col as usize..]
+                        .chars()
+                        .next()
+                        .map_or(partner.col, |c| partner.col + c.len_utf8() as u64)
+                } else {
+                    partner.col
+                };
+                let char_col = text[..col as usize].chars().count() as u64;
+
+                self.core
+                    .borrow()
+                    .gesture_point_select(&self.view_id, partner.line, col);
+                self.scroll_to(partner.line, char_col);
+            }
+            BracketMatch::Missing(_) => self.pending_bracket_jump = caret,
+            BracketMatch::None => (),
+        }
+    }
+
     /// Creates a pango layout for a particular linecount (the count on the left) in the linecache
     fn create_layout_for_linecount(
         &self,
@@ -838,6 +2315,7 @@ impl EditView {
         _main_state: &MainState,
         n: u64,
         padding: usize,
+        bold: bool,
     ) -> pango::Layout {
         let line_view = format!(
             "{:^offset$}",
@@ -848,6 +2326,11 @@ impl EditView {
         layout.set_alignment(pango::Alignment::Center);
         layout.set_font_description(&self.interface_font.font_desc);
         layout.set_text(line_view.as_str());
+        if bold {
+            let attr_list = pango::AttrList::new();
+            attr_list.insert(Attribute::new_weight(pango::Weight::Bold).unwrap());
+            layout.set_attributes(&attr_list);
+        }
         layout
     }
 
@@ -879,95 +2362,21 @@ impl EditView {
             &line.text()
         };
 
-        // Replace spaces with '·'. Do this here since we only want
-        // to draw this, we don't want to save the file like that.
-        let line_view = if get_draw_trailing_spaces_schema() && line_view.ends_with(' ') {
-            // Replace tabs here to make sure trim_end doesn't remove them
-            let last_char = line_view.replace("\t", "a").trim_end().len();
-            let (line_view_without_space, spaces) = line_view.split_at(last_char);
-            let space_range = std::ops::Range {
-                start: 0,
-                end: spaces.len(),
-            };
-            let highlighted_spaces: String = space_range.map(|_| "·").collect();
-
-            format!("{}{}", line_view_without_space, highlighted_spaces)
-        } else {
-            line_view.to_string()
-        };
-
         // let layout = create_layout(cr).unwrap();
         let layout = pango::Layout::new(pango_ctx);
         layout.set_font_description(&self.edit_font.font_desc);
-        layout.set_text(&line_view);
-
-        let mut ix = 0;
-        let attr_list = pango::AttrList::new();
-        for style in &line.styles {
-            let start_index = (ix + style.start) as u32;
-            let end_index = (ix + style.start + style.len as i64) as u32;
-            let line_style = main_state.styles.get(&style.id);
-
-            if let Some(foreground) = line_style.and_then(|s| s.fg_color) {
-                let pango_color = PangoColor::from(color_from_u32(foreground));
-                let mut attr =
-                    Attribute::new_foreground(pango_color.r, pango_color.g, pango_color.b).unwrap();
-                attr.set_start_index(start_index);
-                attr.set_end_index(end_index);
-                attr_list.insert(attr);
-            }
-
-            if let Some(background) = line_style.and_then(|s| s.bg_color) {
-                let pango_color = PangoColor::from(color_from_u32(background));
-                let mut attr =
-                    Attribute::new_background(pango_color.r, pango_color.g, pango_color.b).unwrap();
-                attr.set_start_index(start_index);
-                attr.set_end_index(end_index);
-                attr_list.insert(attr);
-            }
-
-            if let Some(weight) = line_style.and_then(|s| s.weight) {
-                let mut attr =
-                    Attribute::new_weight(pango::Weight::__Unknown(weight as i32)).unwrap();
-                attr.set_start_index(start_index);
-                attr.set_end_index(end_index);
-                attr_list.insert(attr);
-            }
-
-            if let Some(italic) = line_style.and_then(|s| s.italic) {
-                let mut attr = if italic {
-                    Attribute::new_style(pango::Style::Italic).unwrap()
-                } else {
-                    Attribute::new_style(pango::Style::Normal).unwrap()
-                };
-                attr.set_start_index(start_index);
-                attr.set_end_index(end_index);
-                attr_list.insert(attr);
-            }
+        layout.set_text(line_view);
 
-            if let Some(underline) = line_style.and_then(|s| s.underline) {
-                let mut attr = if underline {
-                    Attribute::new_underline(pango::Underline::Single).unwrap()
-                } else {
-                    Attribute::new_underline(pango::Underline::None).unwrap()
-                };
-                attr.set_start_index(start_index);
-                attr.set_end_index(end_index);
-                attr_list.insert(attr);
-            }
-
-            ix += style.start + style.len as i64;
-        }
-
-        layout.set_attributes(&attr_list);
+        layout.set_attributes(&line_attributes(line, &main_state.styles));
         layout
     }
 
     /// Scrolls vertically to the line specified and horizontally to the column specified.
     pub fn scroll_to(&mut self, line: u64, col: u64) {
         {
+            let row = self.folds.line_to_row(line);
             let cur_top =
-                self.edit_font.font_height * ((line + 1) as f64) - self.edit_font.font_ascent;
+                self.edit_font.font_height * ((row + 1) as f64) - self.edit_font.font_ascent;
             let cur_bottom = cur_top + self.edit_font.font_ascent + self.edit_font.font_descent;
             let vadj = self.view_item.verti_bar.get_adjustment();
             if cur_top < vadj.get_value() {
@@ -997,10 +2406,45 @@ impl EditView {
         }
     }
 
+    /// Shows or hides the caret, called every half of a blink cycle. Following the GTK settings
+    /// the caret doesn't blink for a cycle after the user has typed, and stops blinking after
+    /// `gtk-cursor-blink-timeout` seconds.
+    fn blink_caret(&mut self) {
+        let (blink, blink_time, blink_timeout) =
+            gtk::Settings::get_default().map_or((false, 0, 0), |settings| {
+                (
+                    settings.get_property_gtk_cursor_blink(),
+                    settings.get_property_gtk_cursor_blink_time(),
+                    settings.get_property_gtk_cursor_blink_timeout(),
+                )
+            });
+        let elapsed = self.caret_reset.elapsed();
+        let visible = !blink
+            || !self.view_item.edit_area.has_focus()
+            || elapsed < Duration::from_millis(max(blink_time, 0) as u64)
+            || elapsed > Duration::from_secs(max(blink_timeout, 0) as u64)
+            || !self.caret_visible;
+
+        if visible != self.caret_visible {
+            self.caret_visible = visible;
+            self.view_item.edit_area.queue_draw();
+        }
+    }
+
+    /// Shows the caret and restarts blinking, e.g. because the user has typed something
+    fn reset_caret_blink(&mut self) {
+        self.caret_reset = Instant::now();
+        if !self.caret_visible {
+            self.caret_visible = true;
+            self.view_item.edit_area.queue_draw();
+        }
+    }
+
     /// Handles button presses such as Shift, Ctrl etc. and primary pasting (i.e. via Ctrl+V, not
     /// via middle mouse click).
-    pub fn handle_button_press(&self, eb: &EventButton) -> Inhibit {
+    pub fn handle_button_press(&mut self, eb: &EventButton) -> Inhibit {
         self.view_item.edit_area.grab_focus();
+        self.reset_caret_blink();
 
         let (x, y) = eb.get_position();
         let (col, line) = {
@@ -1008,9 +2452,28 @@ impl EditView {
             self.da_px_to_cell(&main_state, x, y)
         };
 
-        match eb.get_button() {
+        // Alt+drag and middle-drag select a rectangular block
+        let button = eb.get_button();
+        self.block_selection =
+            if (button == 1 && eb.get_state().contains(ModifierType::MOD1_MASK)) || button == 2 {
+                Some(BlockSelection {
+                    line,
+                    col,
+                    x: x + self.view_item.horiz_bar.get_adjustment().get_value(),
+                    button,
+                    dragged: false,
+                })
+            } else {
+                None
+            };
+
+        match button {
             1 => {
-                if eb.get_state().contains(ModifierType::SHIFT_MASK) {
+                if self.block_selection.is_some() {
+                    self.core
+                        .borrow()
+                        .gesture_point_select(&self.view_id, line, col);
+                } else if eb.get_state().contains(ModifierType::SHIFT_MASK) {
                     self.core
                         .borrow()
                         .gesture_range_select(&self.view_id, line, col);
@@ -1032,18 +2495,93 @@ impl EditView {
                         .gesture_point_select(&self.view_id, line, col);
                 }
             }
-            2 => {
-                self.do_paste_primary(&self.view_id, line, col);
-            }
+            // Pasting the primary selection happens on release, unless the user drags a block
             _ => {}
         }
         Inhibit(false)
     }
 
+    /// Pastes the primary selection if the middle button has been clicked without dragging.
+    pub fn handle_button_release(&mut self, eb: &EventButton) -> Inhibit {
+        if let Some(block) = self.block_selection.take() {
+            if block.button == 2 && eb.get_button() == 2 && !block.dragged {
+                self.do_paste_primary(&self.view_id, block.line, block.col);
+            }
+        }
+        Inhibit(false)
+    }
+
+    /// Selects the rectangle between where the block selection started and `x`|`y`, with one
+    /// selection per line. The columns are mapped to each line with Pango, so tabs and wide chars
+    /// end up in the right place.
+    fn select_block(&mut self, x: f64, y: f64) {
+        let block = match self.block_selection.as_mut() {
+            Some(block) => {
+                block.dragged = true;
+                *block
+            }
+            None => return,
+        };
+
+        let (_, line) = {
+            let main_state = self.main_state.borrow();
+            self.da_px_to_cell(&main_state, x, y)
+        };
+        let x = x + self.view_item.horiz_bar.get_adjustment().get_value();
+        let (left, right) = if x < block.x {
+            (x, block.x)
+        } else {
+            (block.x, x)
+        };
+
+        let main_state = self.main_state.borrow();
+        let pango_ctx = self.view_item.get_pango_ctx();
+        let core = self.core.borrow();
+        let mut first = true;
+        for n in min(line, block.line)..=max(line, block.line) {
+            // We can't map the columns of lines xi hasn't sent us yet
+            let line = match self.line_cache.get_line(n) {
+                Some(line) if !self.folds.is_hidden(n) => line,
+                _ => continue,
+            };
+            let layout = self.create_layout_for_line(&pango_ctx, &main_state, line);
+            let start = Self::layout_x_to_index(&layout, left);
+            let end = Self::layout_x_to_index(&layout, right);
+
+            if first {
+                core.gesture_point_select(&self.view_id, n, start);
+                first = false;
+            } else {
+                core.gesture_add_sel(&self.view_id, n, start);
+            }
+            core.drag(&self.view_id, n, end);
+        }
+    }
+
+    /// Maps the x coordinate to the byte index of the closest char boundary in `layout`
+    fn layout_x_to_index(layout: &pango::Layout, x: f64) -> u64 {
+        let (_, index, trailing) = layout.xy_to_index(x as i32 * pango::SCALE, 0);
+        let index = index as usize;
+        if trailing > 0 {
+            // The click was on the right half of the char, use the index after it
+            let text = layout.get_text().map(|t| t.to_string()).unwrap_or_default();
+            text.get(index..)
+                .and_then(|t| t.chars().next())
+                .map_or(index, |c| index + c.len_utf8()) as u64
+        } else {
+            index as u64
+        }
+    }
+
     /// Handle selecting line(s) by dragging the mouse across them while having the left mouse
     /// button clicked.
     pub fn handle_drag(&mut self, em: &EventMotion) -> Inhibit {
         let (x, y) = em.get_position();
+        if self.block_selection.is_some() {
+            self.select_block(x, y);
+            return Inhibit(false);
+        }
+
         let (col, line) = {
             let main_state = self.main_state.borrow();
             self.da_px_to_cell(&main_state, x, y)
@@ -1106,147 +2644,601 @@ impl EditView {
             gettext("unicode"),
             ::gdk::keyval_to_unicode(ek.get_keyval())
         );
-        let view_id = &self.view_id;
-        let ch = ::gdk::keyval_to_unicode(ek.get_keyval());
+        self.reset_caret_blink();
+        self.sync_keymap();
+        let key_name = ::gdk::keyval_name(::gdk::keyval_to_lower(ek.get_keyval()));
+        let state = ek.get_state();
+        let mods = Modifiers {
+            ctrl: state.contains(ModifierType::CONTROL_MASK),
+            shift: state.contains(ModifierType::SHIFT_MASK),
+            alt: state.contains(ModifierType::MOD1_MASK),
+            super_: state.intersects(ModifierType::SUPER_MASK | ModifierType::META_MASK),
+        };
 
-        let alt = ek.get_state().contains(ModifierType::MOD1_MASK);
-        let ctrl = ek.get_state().contains(ModifierType::CONTROL_MASK);
-        let meta = ek.get_state().contains(ModifierType::META_MASK);
-        let shift = ek.get_state().contains(ModifierType::SHIFT_MASK);
-        let norm = !alt && !ctrl && !meta;
+        // Let the input method handle keys the keymap doesn't bind for dead keys, compose
+        // sequences and e.g. CJK input. While the user is composing something it gets all keys,
+        // e.g. Return to accept the text. It sends us the text it produces via its commit signal.
+        let is_bound = !self.pending_keys.is_empty()
+            || key_name.as_ref().map_or(false, |key_name| {
+                let chord = KeyChord::new(key_name, mods);
+                match self.main_state.borrow().keymap.lookup(&[chord]) {
+                    Lookup::Unbound => false,
+                    _ => true,
+                }
+            });
+        if (!is_bound || self.is_composing())
+            && self.accepts_text_input()
+            && self.view_item.im_context.filter_keypress(ek)
+        {
+            self.flush_im_commit();
+            return Inhibit(true);
+        }
 
-        match ek.get_keyval() {
-            key::Delete if norm => self.core.borrow().delete_forward(view_id),
-            key::BackSpace if norm => self.core.borrow().delete_backward(view_id),
-            key::Return | key::KP_Enter => {
-                self.core.borrow().insert_newline(&view_id);
-            }
-            key::Tab if norm && !shift => self.core.borrow().insert_tab(view_id),
-            key::Up if norm && !shift => self.core.borrow().move_up(view_id),
-            key::Down if norm && !shift => self.core.borrow().move_down(view_id),
-            key::Left if norm && !shift => self.core.borrow().move_left(view_id),
-            key::Right if norm && !shift => self.core.borrow().move_right(view_id),
-            key::Up if norm && shift => {
-                self.core.borrow().move_up_and_modify_selection(view_id);
-            }
-            key::Down if norm && shift => {
-                self.core.borrow().move_down_and_modify_selection(view_id);
+        let key_name = match key_name {
+            Some(key_name) => key_name,
+            None => return Inhibit(false),
+        };
+        // Pressing Ctrl on its own shouldn't abort a sequence like `<Primary>k <Primary>u`
+        if is_modifier_key(&key_name) {
+            return Inhibit(false);
+        }
+        let chord = KeyChord::new(&key_name, mods);
+        let ch = ::gdk::keyval_to_unicode(ek.get_keyval())
+            .filter(|c| *c >= '\u{0020}' && !mods.ctrl && !mods.alt && !mods.super_);
+
+        // Keys which continue a sequence of the keymap go to the keymap, even in Vim's normal mode
+        let preset = self.main_state.borrow().keymap.preset();
+        if preset == Preset::Vim && self.pending_keys.is_empty() {
+            let actions = self.vim.handle_key(
+                VimKey { chord, ch },
+                self.cursor_position(),
+                &self.line_cache,
+            );
+            for action in actions {
+                self.run_vim_action(action);
             }
-            key::Left if norm && shift => {
-                self.core.borrow().move_left_and_modify_selection(view_id);
+            self.update_mode_label();
+            self.view_item.edit_area.queue_draw();
+            return Inhibit(true);
+        }
+
+        let inhibit = self.handle_chord(chord, ch);
+        self.update_mode_label();
+        inhibit
+    }
+
+    /// Looks up the key in the keymap and runs the command it's bound to, or inserts its char.
+    fn handle_chord(&mut self, chord: KeyChord, ch: Option<char>) -> Inhibit {
+        self.pending_keys.push(chord);
+
+        let main_state = self.main_state.clone();
+        let command = match main_state.borrow().keymap.lookup(&self.pending_keys) {
+            Lookup::Command(command) => command.to_string(),
+            Lookup::Prefix => {
+                trace!("{}", gettext("Waiting for the next key of the sequence"));
+                return Inhibit(true);
             }
-            key::Right if norm && shift => {
-                self.core.borrow().move_right_and_modify_selection(view_id);
+            Lookup::Unbound => {
+                let in_sequence = self.pending_keys.len() > 1;
+                self.pending_keys.clear();
+                if in_sequence {
+                    debug!("{}", gettext("Key sequence isn't bound to anything"));
+                    return Inhibit(true);
+                }
+                self.reset_command_state();
+                return self.insert_char(ch);
             }
-            key::Left if ctrl && !shift => {
-                self.core.borrow().move_word_left(view_id);
+        };
+
+        self.pending_keys.clear();
+        self.run_command(&command);
+        Inhibit(true)
+    }
+
+    /// Inserts the char of a key press which isn't bound to a command in the keymap
+    fn insert_char(&self, ch: Option<char>) -> Inhibit {
+        match ch {
+            Some(c) => {
+                debug!("inserting key");
+                self.core.borrow().insert(&self.view_id, &c.to_string());
             }
-            key::Right if ctrl && !shift => {
-                self.core.borrow().move_word_right(view_id);
+            None => {
+                debug!("unhandled key");
             }
-            key::Left if ctrl && shift => {
+        }
+        Inhibit(true)
+    }
+
+    /// If key presses should insert text right now. In Vim's normal and visual modes keys are
+    /// commands, so they mustn't go to the input method.
+    fn accepts_text_input(&self) -> bool {
+        self.main_state.borrow().keymap.preset() != Preset::Vim || self.vim.mode() == Mode::Insert
+    }
+
+    /// Whether the user is composing text with the input method right now
+    fn is_composing(&self) -> bool {
+        !self.view_item.im_context.get_preedit_string().0.is_empty()
+    }
+
+    /// Sends the text the input method has committed to xi
+    fn flush_im_commit(&mut self) {
+        let text = self.im_commit.replace(String::new());
+        if text.is_empty() {
+            return;
+        }
+
+        debug!(
+            "{}: {}",
+            gettext("Inserting text from the input method"),
+            text
+        );
+        self.reset_command_state();
+        self.vim.record_text(&text);
+        self.core.borrow().insert(&self.view_id, &text);
+    }
+
+    /// Inserts the preedit string of the input method (the text the user is still composing)
+    /// into `layout` at the byte `index`. Returns the length of the preedit string and the
+    /// position of the input method's cursor in it, both in bytes.
+    fn insert_preedit(&self, layout: &pango::Layout, index: usize) -> Option<(usize, usize)> {
+        let (preedit, preedit_attrs, cursor_pos) = self.view_item.im_context.get_preedit_string();
+        let text = layout.get_text()?;
+        if preedit.is_empty() || index > text.len() || !text.is_char_boundary(index) {
+            return None;
+        }
+
+        let attr_list = layout.get_attributes().unwrap_or_else(pango::AttrList::new);
+        attr_list.splice(&preedit_attrs, index as i32, preedit.len() as i32);
+        layout.set_text(&format!("{}{}{}", &text[..index], preedit, &text[index..]));
+        layout.set_attributes(&attr_list);
+
+        let cursor = preedit
+            .char_indices()
+            .nth(cursor_pos as usize)
+            .map_or(preedit.len(), |(i, _)| i);
+        Some((preedit.len(), cursor))
+    }
+
+    /// Carries out what the Vim layer wants us to do in response to a key press
+    fn run_vim_action(&mut self, action: VimAction) {
+        trace!("{}: {:?}", gettext("Running Vim action"), action);
+        let view_id = self.view_id.clone();
+
+        match action {
+            VimAction::Command(command) => self.run_command(command),
+            VimAction::MoveTo(pos) => {
                 self.core
                     .borrow()
-                    .move_word_left_and_modify_selection(view_id);
+                    .gesture_point_select(&view_id, pos.line, pos.col);
+                self.scroll_to(pos.line, pos.col);
             }
-            key::Right if ctrl && shift => {
-                self.core
-                    .borrow()
-                    .move_word_right_and_modify_selection(view_id);
+            VimAction::Select(start, end) => {
+                let core = self.core.borrow();
+                core.gesture_point_select(&view_id, start.line, start.col);
+                core.drag(&view_id, end.line, end.col);
+            }
+            VimAction::Cut(kind) => {
+                if let Some(text) = self.core.borrow_mut().cut(&view_id) {
+                    self.vim.set_register(text, kind);
+                }
             }
-            key::Home if norm && !shift => {
-                self.core.borrow().move_to_left_end_of_line(view_id);
+            VimAction::Copy(kind) => {
+                if let Some(text) = self.core.borrow_mut().copy(&view_id) {
+                    self.vim.set_register(text, kind);
+                }
             }
-            key::End if norm && !shift => {
-                self.core.borrow().move_to_right_end_of_line(view_id);
+            VimAction::Insert(text) => self.core.borrow().insert(&view_id, &text),
+            VimAction::Passthrough(key) => {
+                self.handle_chord(key.chord, key.ch);
             }
-            key::Home if norm && shift => {
-                self.core
-                    .borrow()
-                    .move_to_left_end_of_line_and_modify_selection(view_id);
+        }
+    }
+
+    /// Shows the Vim mode we're in, or hides the label if Vim-style editing is off
+    fn update_mode_label(&self) {
+        let preset = self.main_state.borrow().keymap.preset();
+        if preset == Preset::Emacs && self.mark_active {
+            self.mode_label.set_text(&gettext("Mark set"));
+            self.mode_label.show();
+            return;
+        } else if preset != Preset::Vim {
+            self.mode_label.hide();
+            return;
+        }
+
+        let text = match self.vim.mode() {
+            Mode::Normal => gettext("-- NORMAL --"),
+            Mode::Insert => gettext("-- INSERT --"),
+            Mode::Visual => gettext("-- VISUAL --"),
+            Mode::VisualLine => gettext("-- VISUAL LINE --"),
+        };
+        self.mode_label.set_text(&text);
+        self.mode_label.show();
+    }
+
+    /// Resets our key state if the keymap has been reloaded since we've last looked at it, e.g.
+    /// because the user has picked another preset while another tab was shown.
+    pub fn sync_keymap(&mut self) {
+        let revision = self.main_state.borrow().keymap_revision;
+        if revision != self.keymap_revision {
+            self.keymap_revision = revision;
+            self.keymap_changed();
+        }
+    }
+
+    /// Called when the keymap has been reloaded, e.g. because the user chose another preset
+    fn keymap_changed(&mut self) {
+        self.vim = Vim::default();
+        self.pending_keys.clear();
+        self.reset_command_state();
+        self.update_mode_label();
+        self.view_item.edit_area.queue_draw();
+    }
+
+    /// Runs `command`, which is either one of `keymap::EDITOR_COMMANDS` or an `app.*` action.
+    pub fn run_command(&mut self, command: &str) {
+        trace!("{}: {}", gettext("Running command"), command);
+        let previous_command = self.last_command.replace(command.to_string());
+        let follows = |c: &str| previous_command.as_ref().map_or(false, |p| p == c);
+
+        // While the Emacs mark is set movement commands extend the region
+        let modify_selection = format!("{}_and_modify_selection", command);
+        let is_movement = EDITOR_COMMANDS.contains(&modify_selection.as_str())
+            || command.ends_with("_and_modify_selection");
+        let command = if self.mark_active && EDITOR_COMMANDS.contains(&modify_selection.as_str()) {
+            modify_selection.as_str()
+        } else {
+            command
+        };
+        if !is_movement && command != "set_mark" {
+            self.mark_active = false;
+        }
+
+        let view_id = &self.view_id.clone();
+        match command {
+            "add_selection_above" => self.core.borrow().add_selection_above(view_id),
+            "add_selection_below" => self.core.borrow().add_selection_below(view_id),
+            "copy" => self.do_copy(view_id),
+            "copy_region" => self.kill_region(false),
+            "cut" => self.do_cut(view_id),
+            "paste" => self.do_paste(view_id),
+            "delete_backward" => self.core.borrow().delete_backward(view_id),
+            "delete_forward" => self.core.borrow().delete_forward(view_id),
+            "go_to_matching_bracket" => self.go_to_matching_bracket(),
+            "insert_newline" => self.core.borrow().insert_newline(view_id),
+            "indent" => self.core.borrow().indent(view_id),
+            "insert_tab" => self.core.borrow().insert_tab(view_id),
+            "keyboard_quit" => self.core.borrow().collapse_selections(view_id),
+            "kill_line" => self.kill_line(follows("kill_line")),
+            "kill_region" => self.kill_region(true),
+            "move_down" => self.core.borrow().move_down(view_id),
+            "move_down_and_modify_selection" => {
+                self.core.borrow().move_down_and_modify_selection(view_id)
+            }
+            "move_left" => self.core.borrow().move_left(view_id),
+            "move_left_and_modify_selection" => {
+                self.core.borrow().move_left_and_modify_selection(view_id)
+            }
+            "move_right" => self.core.borrow().move_right(view_id),
+            "move_right_and_modify_selection" => {
+                self.core.borrow().move_right_and_modify_selection(view_id)
+            }
+            "move_to_beginning_of_document" => {
+                self.core.borrow().move_to_beginning_of_document(view_id)
+            }
+            "move_to_beginning_of_document_and_modify_selection" => self
+                .core
+                .borrow()
+                .move_to_beginning_of_document_and_modify_selection(view_id),
+            "move_to_end_of_document" => self.core.borrow().move_to_end_of_document(view_id),
+            "move_to_end_of_document_and_modify_selection" => self
+                .core
+                .borrow()
+                .move_to_end_of_document_and_modify_selection(view_id),
+            "move_to_left_end_of_line" => self.core.borrow().move_to_left_end_of_line(view_id),
+            "move_to_left_end_of_line_and_modify_selection" => self
+                .core
+                .borrow()
+                .move_to_left_end_of_line_and_modify_selection(view_id),
+            "move_to_right_end_of_line" => self.core.borrow().move_to_right_end_of_line(view_id),
+            "move_to_right_end_of_line_and_modify_selection" => self
+                .core
+                .borrow()
+                .move_to_right_end_of_line_and_modify_selection(view_id),
+            "move_up" => self.core.borrow().move_up(view_id),
+            "move_up_and_modify_selection" => {
+                self.core.borrow().move_up_and_modify_selection(view_id)
+            }
+            "move_word_left" => self.core.borrow().move_word_left(view_id),
+            "move_word_left_and_modify_selection" => self
+                .core
+                .borrow()
+                .move_word_left_and_modify_selection(view_id),
+            "move_word_right" => self.core.borrow().move_word_right(view_id),
+            "move_word_right_and_modify_selection" => self
+                .core
+                .borrow()
+                .move_word_right_and_modify_selection(view_id),
+            "outdent" => self.core.borrow().outdent(view_id),
+            "page_down" => self.core.borrow().page_down(view_id),
+            "page_down_and_modify_selection" => {
+                self.core.borrow().page_down_and_modify_selection(view_id)
+            }
+            "page_up" => self.core.borrow().page_up(view_id),
+            "page_up_and_modify_selection" => {
+                self.core.borrow().page_up_and_modify_selection(view_id)
+            }
+            "redo" => self.core.borrow().redo(view_id),
+            "select_all" => self.core.borrow().select_all(view_id),
+            "select_all_occurrences" => self.select_all_occurrences(),
+            "select_next_occurrence" => self.select_next_occurrence(),
+            "selection_into_lines" => self.core.borrow().selection_into_lines(view_id),
+            "set_mark" => {
+                // The region starts at the cursor, drop the current selection
+                self.core.borrow().collapse_selections(view_id);
+                self.mark_active = true;
             }
-            key::End if norm && shift => {
-                self.core
-                    .borrow()
-                    .move_to_right_end_of_line_and_modify_selection(view_id);
+            "toggle_fold" => {
+                let line = self.cursor_line();
+                self.toggle_fold(line);
             }
-            key::Home if ctrl && !shift => {
-                self.core.borrow().move_to_beginning_of_document(view_id);
+            "undo" => self.core.borrow().undo(view_id),
+            "yank" => self.yank(),
+            "yank_pop" => {
+                if follows("yank") || follows("yank_pop") {
+                    self.yank_pop();
+                } else {
+                    debug!("{}", gettext("The previous command wasn't a yank"));
+                    self.last_command = None;
+                }
             }
-            key::End if ctrl && !shift => {
-                self.core.borrow().move_to_end_of_document(view_id);
+            action if action.starts_with("app.") => {
+                // The action might need this EditView, which our caller still has borrowed
+                let action = action["app.".len()..].to_string();
+                glib::idle_add(move || {
+                    if let Some(app) = gio::Application::get_default() {
+                        app.activate_action(&action, None);
+                    }
+                    glib::Continue(false)
+                });
             }
-            key::Home if ctrl && shift => {
+            _ => warn!("{}: {}", gettext("Unknown command"), command),
+        }
+    }
+
+    /// Checks if one of the lines in the cache contains a selection, which xi styles with style 0
+    fn has_selection(&self) -> bool {
+        self.line_cache
+            .lines
+            .iter()
+            .flatten()
+            .any(|line| line.styles.iter().any(|s| s.id == 0 && s.len > 0))
+    }
+
+    /// Selects the word under the cursor, or adds a selection at the next occurrence of the
+    /// selected text if there's a selection already (Ctrl+D). We search the occurrences
+    /// ourselves, so if some lines are missing we ask xi for them and search once it has sent them.
+    fn select_next_occurrence(&mut self) {
+        match self.cursor_position() {
+            Some(pos) if !self.has_selection() => {
                 self.core
                     .borrow()
-                    .move_to_beginning_of_document_and_modify_selection(view_id);
+                    .gesture_word_select(&self.view_id, pos.line, pos.col);
             }
-            key::End if ctrl && shift => {
+            _ if !self.has_all_lines() => {
+                self.pending_next_occurrence = true;
                 self.core
                     .borrow()
-                    .move_to_end_of_document_and_modify_selection(view_id);
-            }
-            key::Page_Up if norm && !shift => {
-                self.core.borrow().page_up(view_id);
-            }
-            key::Page_Down if norm && !shift => {
-                self.core.borrow().page_down(view_id);
-            }
-            key::Page_Up if norm && shift => {
-                self.core.borrow().page_up_and_modify_selection(view_id);
-            }
-            key::Page_Down if norm && shift => {
-                self.core.borrow().page_down_and_modify_selection(view_id);
+                    .request_lines(&self.view_id, 0, self.line_cache.height());
             }
             _ => {
-                if let Some(ch) = ch {
-                    match ch {
-                        'a' if ctrl => {
-                            self.core.borrow().select_all(view_id);
-                        }
-                        'c' if ctrl => {
-                            self.do_copy(view_id);
-                        }
-                        'v' if ctrl => {
-                            self.do_paste(view_id);
-                        }
-                        't' if ctrl => {
-                            // TODO new tab
-                        }
-                        'x' if ctrl => {
-                            self.do_cut(view_id);
-                        }
-                        'z' if ctrl => {
-                            self.core.borrow().undo(view_id);
-                        }
-                        'Z' if ctrl && shift => {
-                            self.core.borrow().redo(view_id);
-                        }
-                        c if (norm) && c >= '\u{0020}' => {
-                            debug!("inserting key");
-                            self.core.borrow().insert(view_id, &c.to_string());
-                        }
-                        _ => {
-                            debug!("unhandled key: {:?}", ch);
-                        }
-                    }
+                let selected = self.selected_regions();
+                let next = selected.last().and_then(|&(start, end)| {
+                    let needle = self.selected_text(start, end)?;
+                    find::next_occurrence(needle, end, &selected, &self.line_cache)
+                });
+                if let Some((start, end)) = next {
+                    let core = self.core.borrow();
+                    core.gesture_add_sel(&self.view_id, start.line, start.col);
+                    core.drag(&self.view_id, end.line, end.col);
                 }
             }
+        }
+    }
+
+    /// Selects all occurrences of the selected text, or of the word under the cursor if nothing
+    /// is selected. Unlike xi's `find_all` this leaves the user's search alone.
+    fn select_all_occurrences(&mut self) {
+        match self.cursor_position() {
+            // Select the word first, we add its occurrences once xi has told us about it
+            Some(pos) if !self.has_selection() => {
+                self.core
+                    .borrow()
+                    .gesture_word_select(&self.view_id, pos.line, pos.col);
+                self.pending_all_occurrences = true;
+            }
+            _ if !self.has_all_lines() => self.pending_all_occurrences = true,
+            _ => self.add_all_occurrences(),
+        }
+        if self.pending_all_occurrences && !self.has_all_lines() {
+            self.core
+                .borrow()
+                .request_lines(&self.view_id, 0, self.line_cache.height());
+        }
+    }
+
+    /// Adds a selection at every occurrence of the (last) selected text which isn't selected yet
+    fn add_all_occurrences(&self) {
+        let selected = self.selected_regions();
+        let occurrences = selected
+            .last()
+            .and_then(|&(start, end)| self.selected_text(start, end))
+            .map(|needle| find::occurrences(needle, &self.line_cache))
+            .unwrap_or_default();
+        let core = self.core.borrow();
+        for (start, end) in occurrences {
+            if !selected.contains(&(start, end)) {
+                core.gesture_add_sel(&self.view_id, start.line, start.col);
+                core.drag(&self.view_id, end.line, end.col);
+            }
+        }
+    }
+
+    /// The text of a selection on a single line, which is what we search occurrences of
+    fn selected_text(&self, start: Pos, end: Pos) -> Option<&str> {
+        self.line_cache
+            .line(start.line)?
+            .get(start.col as usize..end.col as usize)
+    }
+
+    /// Forgets the Emacs mark and the last command, e.g. when the user clicks somewhere
+    fn reset_command_state(&mut self) {
+        self.mark_active = false;
+        self.last_command = None;
+    }
+
+    /// Kills the rest of the line, or the line ending if the cursor is at the end of the line.
+    fn kill_line(&mut self, append: bool) {
+        let at_line_end = self.cursor_position().map_or(false, |pos| {
+            self.line_cache
+                .line(pos.line)
+                .map_or(false, |text| pos.col as usize >= text.len())
+        });
+
+        if at_line_end {
+            self.core
+                .borrow()
+                .move_right_and_modify_selection(&self.view_id);
+        } else {
+            self.core
+                .borrow()
+                .move_to_right_end_of_line_and_modify_selection(&self.view_id);
+        }
+
+        let text = self.core.borrow_mut().cut(&self.view_id);
+        if let Some(text) = text {
+            self.kill(&text, append);
+        }
+    }
+
+    /// Kills (`cut` is true) or copies the region onto the kill ring
+    fn kill_region(&mut self, cut: bool) {
+        let text = if cut {
+            self.core.borrow_mut().cut(&self.view_id)
+        } else {
+            self.core.borrow_mut().copy(&self.view_id)
         };
-        Inhibit(true)
+        if let Some(text) = text {
+            self.kill(&text, false);
+        }
+        if !cut {
+            self.core.borrow().collapse_selections(&self.view_id);
+        }
+    }
+
+    /// Puts killed text onto the kill ring and the clipboard
+    fn kill(&mut self, text: &str, append: bool) {
+        self.kill_ring.kill(text, append);
+        if let Some(latest) = self.kill_ring.yank() {
+            Clipboard::get(&SELECTION_CLIPBOARD).set_text(latest);
+        }
+    }
+
+    /// Inserts the latest kill
+    fn yank(&mut self) {
+        if let Some(text) = self.kill_ring.yank() {
+            self.last_yank_len = text.chars().count();
+            self.core.borrow().insert(&self.view_id, text);
+        }
+    }
+
+    /// Replaces the text the last yank inserted with the kill before it
+    fn yank_pop(&mut self) {
+        if let Some(text) = self.kill_ring.yank_pop() {
+            let core = self.core.borrow();
+            for _ in 0..self.last_yank_len {
+                core.move_left_and_modify_selection(&self.view_id);
+            }
+            core.insert(&self.view_id, text);
+            self.last_yank_len = text.chars().count();
+        }
     }
 
     /// Copies text to the clipboard
     fn do_cut(&self, view_id: &str) {
+        // Take the styles of the selection before xi removes it
+        let selection = html::selected_text(self.line_cache.lines.iter().flatten());
         if let Some(text) = self.core.borrow_mut().cut(view_id) {
-            Clipboard::get(&SELECTION_CLIPBOARD).set_text(&text);
+            self.set_clipboard(&text, &selection);
         }
     }
 
     /// Copies text to the clipboard
     fn do_copy(&self, view_id: &str) {
+        let selection = html::selected_text(self.line_cache.lines.iter().flatten());
         if let Some(text) = self.core.borrow_mut().copy(view_id) {
-            Clipboard::get(&SELECTION_CLIPBOARD).set_text(&text);
+            self.set_clipboard(&text, &selection);
+        }
+    }
+
+    /// Puts `text` onto the clipboard. If it's the `selection` we have in the cache, it's offered
+    /// as HTML and RTF too, so pasting it into other apps keeps the syntax highlighting.
+    fn set_clipboard(&self, text: &str, selection: &StyledLine) {
+        let clipboard = Clipboard::get(&SELECTION_CLIPBOARD);
+        // The selection might be on lines xi hasn't sent us
+        if selection.text != text {
+            clipboard.set_text(text);
+            return;
+        }
+
+        let font_family = self
+            .edit_font
+            .font_desc
+            .get_family()
+            .map(|f| f.to_string())
+            .unwrap_or_default();
+        let font_size = self.edit_font.font_desc.get_size() / pango::SCALE;
+        let (html, rtf) = {
+            let main_state = self.main_state.borrow();
+            let (styles, theme) = (&main_state.styles, &main_state.theme);
+            (
+                html::fragment(selection, styles, theme, &font_family),
+                rtf::document(selection, styles, theme, &font_family, font_size),
+            )
+        };
+        let text = text.to_string();
+
+        let targets = [
+            TargetEntry::new("text/html", TargetFlags::empty(), CLIPBOARD_HTML),
+            TargetEntry::new("text/rtf", TargetFlags::empty(), CLIPBOARD_RTF),
+            TargetEntry::new("application/rtf", TargetFlags::empty(), CLIPBOARD_RTF),
+            TargetEntry::new("UTF8_STRING", TargetFlags::empty(), CLIPBOARD_TEXT),
+            TargetEntry::new(
+                "text/plain;charset=utf-8",
+                TargetFlags::empty(),
+                CLIPBOARD_TEXT,
+            ),
+            TargetEntry::new("text/plain", TargetFlags::empty(), CLIPBOARD_TEXT),
+            TargetEntry::new("STRING", TargetFlags::empty(), CLIPBOARD_TEXT),
+            TargetEntry::new("TEXT", TargetFlags::empty(), CLIPBOARD_TEXT),
+        ];
+        let set = clipboard.set_with_data(
+            &targets,
+            clone!(text => move |_, data, info| {
+                let rich_text = match info {
+                    CLIPBOARD_HTML => &html,
+                    CLIPBOARD_RTF => &rtf,
+                    _ => {
+                        data.set_text(&text);
+                        return;
+                    }
+                };
+                data.set(&data.get_target(), 8, rich_text.as_bytes());
+            }),
+        );
+        if !set {
+            warn!("{}", gettext("Couldn't offer the copied text as rich text"));
+            clipboard.set_text(&text);
         }
     }
 
@@ -1280,7 +3272,7 @@ impl EditView {
     }
 
     /// Opens the find dialog (Ctrl+F)
-    pub fn start_search(&self) {
+    pub fn start_search(&mut self) {
         if self.find_replace.search_bar.get_search_mode() {
             self.stop_search();
         } else {
@@ -1288,16 +3280,85 @@ impl EditView {
             self.find_replace.replace_expander.set_expanded(false);
             self.find_replace.replace_revealer.set_reveal_child(false);
             self.find_replace.search_entry.grab_focus();
-            if let Some(needle) = self.find_replace.search_entry.get_text() {
-                self.core
-                    .borrow()
-                    .find(&self.view_id, &needle, false, Some(false));
+            self.sync_find_options();
+        }
+    }
+
+    /// Opens the "Go to line" popover (Ctrl+L)
+    pub fn start_goto_line(&self) {
+        self.goto_line.show(&self.view_item.edit_area);
+    }
+
+    /// Jumps to `position` once the document has been loaded
+    pub fn set_pending_position(&mut self, position: Position) {
+        self.pending_position = Some(position);
+    }
+
+    /// Moves the cursor to `position` and scrolls there
+    pub fn goto_position(&mut self, position: Position) {
+        let (line, col) = position.resolve(self.cursor_line(), self.line_cache.height());
+
+        // xi expects the column as a byte offset, but the user counts chars. If the line isn't
+        // in the cache (yet) we can only hope that it's ASCII.
+        let byte_col = match self.line_cache.get_line(line) {
+            Some(l) => {
+                let text = l.text().trim_end_matches(|c| c == '\n' || c == '\r');
+                text.char_indices()
+                    .nth(col as usize)
+                    .map_or(text.len(), |(i, _)| i) as u64
+            }
+            None => col,
+        };
+
+        debug!("{}: {}:{}", gettext("Moving cursor to"), line, byte_col);
+        self.core
+            .borrow()
+            .gesture_point_select(&self.view_id, line, byte_col);
+        self.scroll_to(line, col);
+    }
+
+    /// The line the (first) cursor is on. If it isn't in the cache we use the first visible line.
+    fn cursor_line(&self) -> u64 {
+        self.line_cache
+            .lines
+            .iter()
+            .position(|l| l.as_ref().map_or(false, |l| !l.cursor().is_empty()))
+            .map(|ix| ix as u64 + self.line_cache.n_invalid_before)
+            .unwrap_or_else(|| {
+                let vadj = self.view_item.verti_bar.get_adjustment();
+                self.folds
+                    .row_to_line((vadj.get_value() / self.edit_font.font_height) as u64)
+            })
+    }
+
+    /// The positions of all cursors in the line cache
+    fn cursors(&self) -> Vec<Pos> {
+        let n_invalid_before = self.line_cache.n_invalid_before;
+        let mut cursors = Vec::new();
+        for (ix, line) in self.line_cache.lines.iter().enumerate() {
+            if let Some(line) = line {
+                let line_num = ix as u64 + n_invalid_before;
+                cursors.extend(line.cursor().iter().map(|col| Pos::new(line_num, *col)));
             }
         }
+        cursors
+    }
+
+    /// Where the (first) cursor is, if it's in the cache. The column is a byte offset.
+    fn cursor_position(&self) -> Option<Pos> {
+        let n_invalid_before = self.line_cache.n_invalid_before;
+        self.line_cache
+            .lines
+            .iter()
+            .enumerate()
+            .find_map(|(ix, line)| {
+                let col = *line.as_ref()?.cursor().first()?;
+                Some(Pos::new(ix as u64 + n_invalid_before, col))
+            })
     }
 
     /// Opens the replace dialog (Ctrl+R)
-    pub fn start_replace(&self) {
+    pub fn start_replace(&mut self) {
         if self.find_replace.replace_revealer.get_child_revealed() {
             self.stop_search()
         } else {
@@ -1305,11 +3366,99 @@ impl EditView {
             self.find_replace.replace_expander.set_expanded(true);
             self.find_replace.replace_revealer.set_reveal_child(true);
             self.find_replace.search_entry.grab_focus();
+            self.sync_find_options();
+        }
+    }
+
+    /// Applies the search toggles of the window (which might have been changed in another tab)
+    /// and searches again.
+    fn sync_find_options(&mut self) {
+        let options = self.main_state.borrow().find_options;
+        if options.in_selection && !self.find_replace.options().in_selection {
+            self.capture_find_regions();
+        }
+        self.find_replace.set_options(options);
+        self.search_changed(self.search_text());
+        self.core.borrow().highlight_find(&self.view_id, true);
+    }
+
+    /// Called when one of the search toggles has been clicked
+    fn find_options_changed(&mut self) {
+        let options = self.find_replace.options();
+        if options.in_selection && !self.main_state.borrow().find_options.in_selection {
+            self.capture_find_regions();
+        }
+        self.main_state.borrow_mut().find_options = options;
+        self.search_changed(self.search_text());
+    }
+
+    fn search_text(&self) -> Option<String> {
+        self.find_replace
+            .search_entry
+            .get_text()
+            .map(|t| t.to_string())
+    }
+
+    /// Remembers the selection the search is restricted to. xi only tells us which parts of the
+    /// lines we've asked for are selected, so if some are missing we ask for all of them and
+    /// search again once xi has sent them.
+    fn capture_find_regions(&mut self) {
+        self.find_regions = self.selected_regions();
+        if !self.has_all_lines() {
+            self.pending_find_regions = true;
+            self.core
+                .borrow()
+                .request_lines(&self.view_id, 0, self.line_cache.height());
+        }
+    }
+
+    /// The parts of the lines in the cache which are selected, which xi styles with style 0
+    fn selected_regions(&self) -> Vec<find::Region> {
+        let mut regions = Vec::new();
+        for (ix, line) in self.line_cache.lines.iter().enumerate() {
+            let line_num = ix as u64 + self.line_cache.n_invalid_before;
+            if let Some(line) = line {
+                let mut col = 0;
+                for style in &line.styles {
+                    let start = col + style.start;
+                    col = start + style.len as i64;
+                    if style.id == 0 && style.len > 0 {
+                        regions.push((
+                            Pos::new(line_num, start as u64),
+                            Pos::new(line_num, col as u64),
+                        ));
+                    }
+                }
+            }
+        }
+        regions
+    }
+
+    /// If we search the selection ourselves instead of letting xi search the document
+    fn searching_in_selection(&self) -> bool {
+        self.find_replace.options().in_selection && !self.find_regions.is_empty()
+    }
+
+    fn set_find_status(&self, matches: usize) {
+        self.find_replace
+            .find_status_label
+            .set_text(&format!("{} Results", matches));
+        self.find_replace.find_status_label.set_tooltip_text(None);
+    }
+
+    /// Selects the next (or previous) match inside of the selection the search is restricted to
+    fn select_match(&self, pos: Pos, backwards: bool) {
+        if let Some((start, end)) = find::next_match(&self.find_matches, pos, backwards) {
+            let core = self.core.borrow();
+            core.gesture_point_select(&self.view_id, start.line, start.col);
+            core.drag(&self.view_id, end.line, end.col);
         }
     }
 
     /// Closes the find/replace dialog
     pub fn stop_search(&self) {
+        self.core.borrow().highlight_find(&self.view_id, false);
+        self.view_item.overview.queue_draw();
         self.find_replace.search_bar.set_search_mode(false);
         self.find_replace.replace_expander.set_expanded(false);
         self.find_replace.replace_revealer.set_reveal_child(false);
@@ -1317,14 +3466,30 @@ impl EditView {
     }
 
     /// Displays how many matches have been found in the find/replace dialog.
-    pub fn find_status(&self, queries: &Value) {
+    pub fn find_status(&mut self, queries: &Value) {
+        // We count the matches in the selection ourselves
+        if self.searching_in_selection() {
+            return;
+        }
+
+        // xi counts the lines from 1
+        self.find_lines = queries
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|query| query["lines"].as_array())
+            .flatten()
+            .filter_map(|line| line.as_u64())
+            .map(|line| line.saturating_sub(1))
+            .collect();
+        self.view_item.overview.queue_draw();
+
         if let Some(queries) = queries.as_array() {
             for query in queries {
                 if let Some(query_obj) = query.as_object() {
                     if let Some(matches) = query_obj["matches"].as_u64() {
-                        self.find_replace
-                            .find_status_label
-                            .set_text(&format!("{} Results", matches));
+                        self.find_count = matches as usize;
+                        self.set_find_status(self.find_count);
                     }
                 }
                 debug!("query {}", query);
@@ -1333,50 +3498,509 @@ impl EditView {
     }
 
     /// Displays what chars will be replaced in the replace dialog
-    //TODO: Handle preserve_case
     pub fn replace_status(&self, status: &Value) {
         if let Some(chars) = status["chars"].as_str() {
             self.find_replace.replace_entry.set_text(chars);
         }
+        if let Some(preserve_case) = status["preserve_case"].as_bool() {
+            self.find_replace
+                .preserve_case_button
+                .set_active(preserve_case);
+        }
+        self.update_replace_preview();
     }
 
     /// Go to the next match in the find/replace dialog
     pub fn find_next(&self) {
-        self.core
-            .borrow()
-            .find_next(&self.view_id, Some(true), Some(true));
+        if self.searching_in_selection() {
+            let pos = self.cursor_position().unwrap_or_else(|| Pos::new(0, 0));
+            self.select_match(pos, false);
+        } else {
+            self.core
+                .borrow()
+                .find_next(&self.view_id, Some(true), Some(true));
+        }
     }
 
     /// Go the to previous match in the find/replace dialog
     pub fn find_prev(&self) {
-        self.core.borrow().find_previous(&self.view_id, Some(true));
+        if self.searching_in_selection() {
+            let pos = self.cursor_position().unwrap_or_else(|| Pos::new(0, 0));
+            self.select_match(pos, true);
+        } else {
+            self.core.borrow().find_previous(&self.view_id, Some(true));
+        }
     }
 
-    /// Tells xi-editor that we're searching for a different string (or none) now
-    pub fn search_changed(&self, s: Option<String>) {
+    /// Tells xi-editor that we're searching for a different string (or none) now. Invalid regexes
+    /// are reported in the search bar instead.
+    pub fn search_changed(&mut self, s: Option<String>) {
         let needle = s.unwrap_or_default();
-        self.core
-            .borrow()
-            .find(&self.view_id, &needle, false, Some(false));
+        let options = self.find_replace.options();
+        self.find_matches.clear();
+        self.find_lines.clear();
+        self.update_replace_preview();
+
+        let regex = match options.build_regex(&needle) {
+            Ok(regex) => regex,
+            Err(e) => {
+                // Clear the old matches, they don't belong to what's in the search entry anymore
+                self.core
+                    .borrow()
+                    .find(&self.view_id, "", false, Some(false), Some(false));
+                let label = &self.find_replace.find_status_label;
+                label.set_text(&gettext("Invalid regex"));
+                label.set_tooltip_text(Some(e.to_string().as_str()));
+                return;
+            }
+        };
+
+        if self.searching_in_selection() {
+            self.core
+                .borrow()
+                .find(&self.view_id, "", false, Some(false), Some(false));
+            if !needle.is_empty() {
+                self.find_matches =
+                    find::find_in_regions(&regex, &self.find_regions, &self.line_cache);
+            }
+            self.set_find_status(self.find_matches.len());
+            self.view_item.edit_area.queue_draw();
+            self.view_item.overview.queue_draw();
+        } else {
+            self.core.borrow().find(
+                &self.view_id,
+                &needle,
+                options.case_sensitive,
+                Some(options.regex),
+                Some(options.whole_words),
+            );
+        }
     }
 
     /// Replace _one_ match with the replacement string
-    pub fn replace(&self) {
-        if let Some(replace_chars) = self.find_replace.replace_entry.get_text() {
+    pub fn replace(&mut self) {
+        let replace_chars = match self.find_replace.replace_entry.get_text() {
+            Some(chars) => chars.to_string(),
+            None => return,
+        };
+        let options = self.find_replace.options();
+
+        if self.searching_in_selection() {
+            // Only replace if a match is selected, like xi does
+            let cursor = self.cursor_position();
+            let current = self
+                .find_matches
+                .iter()
+                .position(|(_, end)| Some(*end) == cursor);
+            match current {
+                Some(ix) => {
+                    let (start, end) = self.find_matches[ix];
+                    let replacement = self.replacement_for(&options, &replace_chars, (start, end));
+                    self.find_matches.remove(ix);
+                    self.core.borrow().insert(&self.view_id, &replacement);
+                    // xi hasn't told us about the edit yet, so move the matches after the
+                    // replaced one ourselves
+                    let delta = replacement.len() as i64 - (end.col - start.col) as i64;
+                    find::shift(&mut self.find_matches, end, delta);
+                    find::shift(&mut self.find_regions, end, delta);
+                    self.set_find_status(self.find_matches.len());
+                    let pos = Pos::new(start.line, start.col + replacement.len() as u64);
+                    self.select_match(pos, false);
+                }
+                None => self.find_next(),
+            }
+        } else if options.expands_replacement(&replace_chars) {
+            if let Some(regex) = self.find_regex() {
+                self.replace_next_expanded(&options, &regex, &replace_chars);
+                self.restore_replace_chars(&options, &replace_chars);
+            }
+        } else {
             self.core
                 .borrow()
-                .replace(&self.view_id, replace_chars.as_str(), false);
+                .replace(&self.view_id, &replace_chars, options.preserve_case);
             self.core.borrow().replace_next(&self.view_id);
         }
     }
 
     /// Replace _all_ matches with the replacement string
-    pub fn replace_all(&self) {
-        if let Some(replace_chars) = self.find_replace.replace_entry.get_text() {
+    pub fn replace_all(&mut self) {
+        let replace_chars = match self.find_replace.replace_entry.get_text() {
+            Some(chars) => chars.to_string(),
+            None => return,
+        };
+        let options = self.find_replace.options();
+
+        if self.searching_in_selection() {
+            let matches = std::mem::replace(&mut self.find_matches, Vec::new());
+            let replacements: Vec<String> = matches
+                .iter()
+                .map(|m| self.replacement_for(&options, &replace_chars, *m))
+                .collect();
+            self.apply_replacements(&matches, &replacements);
+
+            for ((start, end), replacement) in matches.into_iter().zip(replacements).rev() {
+                let delta = replacement.len() as i64 - (end.col - start.col) as i64;
+                find::shift(&mut self.find_regions, end, delta);
+            }
+            self.set_find_status(0);
+        } else if options.expands_replacement(&replace_chars) {
+            // xi only knows one replacement for all matches, so we search the document ourselves
+            // and need all of its lines for that
+            if !self.has_all_lines() {
+                self.pending_replace_all = true;
+                self.core
+                    .borrow()
+                    .request_lines(&self.view_id, 0, self.line_cache.height());
+                return;
+            }
+            if let Some(regex) = self.find_regex() {
+                let regions: Vec<find::Region> = (0..self.line_cache.height())
+                    .map(|line| (Pos::new(line, 0), Pos::new(line, u64::max_value())))
+                    .collect();
+                let matches = find::find_in_regions(&regex, &regions, &self.line_cache);
+                let replacements: Vec<String> = matches
+                    .iter()
+                    .map(|m| self.replacement_for(&options, &replace_chars, *m))
+                    .collect();
+                self.apply_replacements(&matches, &replacements);
+                self.restore_replace_chars(&options, &replace_chars);
+            }
+        } else {
             self.core
                 .borrow()
-                .replace(&self.view_id, replace_chars.as_str(), false);
+                .replace(&self.view_id, &replace_chars, options.preserve_case);
             self.core.borrow().replace_all(&self.view_id);
         }
     }
+
+    /// Replaces each of the sorted `matches` with its replacement in a single edit, so it's a
+    /// single undo step. If the replacements differ, the text from the first to the last match
+    /// is replaced with its replaced version, so all lines in between have to be in the cache.
+    fn apply_replacements(&self, matches: &[find::Region], replacements: &[String]) {
+        let (first, last) = match (matches.first(), matches.last()) {
+            (Some(first), Some(last)) => (first.0, last.1),
+            _ => return,
+        };
+
+        let core = self.core.borrow();
+        if replacements.iter().all(|r| *r == replacements[0]) {
+            for (ix, (start, end)) in matches.iter().enumerate() {
+                if ix == 0 {
+                    core.gesture_point_select(&self.view_id, start.line, start.col);
+                } else {
+                    core.gesture_add_sel(&self.view_id, start.line, start.col);
+                }
+                core.drag(&self.view_id, end.line, end.col);
+            }
+            core.insert(&self.view_id, &replacements[0]);
+        } else if let Some(text) = self.replaced_text(matches, replacements) {
+            core.gesture_point_select(&self.view_id, first.line, first.col);
+            core.drag(&self.view_id, last.line, last.col);
+            core.insert(&self.view_id, &text);
+        }
+    }
+
+    /// The text from the start of the first of the sorted `matches` to the end of the last one,
+    /// with each match replaced by its replacement. None if some of the lines aren't cached.
+    fn replaced_text(&self, matches: &[find::Region], replacements: &[String]) -> Option<String> {
+        let mut text = String::new();
+        // Copies the text between `from` and `to`, keeping the line endings like they are
+        let push_between = |text: &mut String, from: Pos, to: Pos| -> Option<()> {
+            for line in from.line..=to.line {
+                let line_text = self.line_cache.get_line(line)?.text();
+                let start = if line == from.line {
+                    from.col as usize
+                } else {
+                    0
+                };
+                let end = if line == to.line {
+                    to.col as usize
+                } else {
+                    line_text.len()
+                };
+                text.push_str(line_text.get(start..end)?);
+            }
+            Some(())
+        };
+
+        let mut pos = matches.first()?.0;
+        for ((start, end), replacement) in matches.iter().zip(replacements) {
+            push_between(&mut text, pos, *start)?;
+            text.push_str(replacement);
+            pos = *end;
+        }
+        Some(text)
+    }
+
+    /// The regex of the current search, if it's valid
+    fn find_regex(&self) -> Option<Regex> {
+        let needle = self.search_text()?;
+        self.find_replace.options().build_regex(&needle).ok()
+    }
+
+    /// The replacement for one of the matches in the selection we search ourselves
+    fn replacement_for(
+        &self,
+        options: &FindOptions,
+        replace_chars: &str,
+        m: find::Region,
+    ) -> String {
+        let (start, end) = m;
+        let matched = self
+            .line_cache
+            .line(start.line)
+            .and_then(|text| text.get(start.col as usize..end.col as usize));
+        match (matched, self.find_regex()) {
+            (Some(matched), Some(regex)) => {
+                options.expand_replacement(&regex, matched, replace_chars)
+            }
+            _ => replace_chars.to_string(),
+        }
+    }
+
+    /// Selects the next match (or keeps the selected one) and replaces it with its expansion of
+    /// `replace_chars`. Returns false if there's no match left.
+    fn replace_next_expanded(
+        &self,
+        options: &FindOptions,
+        regex: &Regex,
+        replace_chars: &str,
+    ) -> bool {
+        let mut core = self.core.borrow_mut();
+        core.find_next(&self.view_id, Some(true), Some(true));
+        let matched = match core.copy(&self.view_id) {
+            Some(ref matched) if !matched.is_empty() => matched.clone(),
+            _ => return false,
+        };
+
+        let replacement = options.expand_replacement(regex, &matched, replace_chars);
+        core.replace(&self.view_id, &replacement, options.preserve_case);
+        core.replace_next(&self.view_id);
+        true
+    }
+
+    /// The text of the whole document, if xi has sent us all of its lines
+    pub fn contents(&self) -> Option<String> {
+        if !self.has_all_lines() {
+            return None;
+        }
+        (0..self.line_cache.height())
+            .map(|n| self.line_cache.get_line(n).map(Line::text))
+            .collect()
+    }
+
+    /// Replaces the matches of `regex` which start at one of the `hits` like
+    /// `file_search::replace_text`. This is how "Find in Files" replaces in files that are open,
+    /// so the user can undo it. Returns how many matches have been replaced and the new text of
+    /// the document, or None if we have to wait for xi to send us all lines first.
+    pub fn replace_hits(
+        &mut self,
+        regex: &Regex,
+        options: &FindOptions,
+        replace_chars: &str,
+        hits: &[(u64, usize)],
+    ) -> Option<(usize, String)> {
+        let contents = match self.contents() {
+            Some(contents) => contents,
+            None => {
+                self.pending_replace = Some((
+                    regex.clone(),
+                    *options,
+                    replace_chars.to_string(),
+                    hits.to_vec(),
+                ));
+                self.core
+                    .borrow()
+                    .request_lines(&self.view_id, 0, self.line_cache.height());
+                return None;
+            }
+        };
+
+        let (replaced, count) =
+            file_search::replace_text(&contents, regex, options, replace_chars, hits);
+        self.set_contents(&contents, &replaced);
+        Some((count, replaced))
+    }
+
+    /// Changes the document from `old` to `new` in a single edit, which only replaces the part
+    /// that differs. The selections are kept where they are.
+    fn set_contents(&self, old: &str, new: &str) {
+        let (start, end, text) = match find::changed_span(old, new) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut selections = self.selected_regions();
+        if selections.is_empty() {
+            selections.extend(self.cursor_position().map(|pos| (pos, pos)));
+        }
+
+        let core = self.core.borrow();
+        core.gesture_point_select(&self.view_id, start.line, start.col);
+        core.drag(&self.view_id, end.line, end.col);
+        core.insert(&self.view_id, &text);
+
+        for (ix, (sel_start, sel_end)) in selections.into_iter().enumerate() {
+            let sel_start = find::move_past_edit(sel_start, start, end, &text);
+            let sel_end = find::move_past_edit(sel_end, start, end, &text);
+            if ix == 0 {
+                core.gesture_point_select(&self.view_id, sel_start.line, sel_start.col);
+            } else {
+                core.gesture_add_sel(&self.view_id, sel_start.line, sel_start.col);
+            }
+            core.drag(&self.view_id, sel_end.line, sel_end.col);
+        }
+    }
+
+    /// Tells xi about the replacement the user has entered again after we've replaced matches with
+    /// their expansions, so its `replace_status` doesn't overwrite the replace entry.
+    fn restore_replace_chars(&self, options: &FindOptions, replace_chars: &str) {
+        self.core
+            .borrow()
+            .replace(&self.view_id, replace_chars, options.preserve_case);
+    }
+
+    /// The text of the selection, if it's on a single line in the cache
+    fn selected_text(&self) -> Option<&str> {
+        match self.selected_regions().as_slice() {
+            [(start, end)] => self
+                .line_cache
+                .line(start.line)?
+                .get(start.col as usize..end.col as usize),
+            _ => None,
+        }
+    }
+
+    /// Shows what the selected match would be replaced with in the replace bar
+    fn update_replace_preview(&self) {
+        let preview = self.replace_preview().unwrap_or_default();
+        self.find_replace.replace_preview_label.set_text(&preview);
+    }
+
+    fn replace_preview(&self) -> Option<String> {
+        if !self.find_replace.replace_revealer.get_reveal_child() {
+            return None;
+        }
+        let replace_chars = self.find_replace.replace_entry.get_text()?;
+        let regex = self.find_regex()?;
+        let matched = self.selected_text()?;
+
+        // Only preview if the whole selection is a match
+        let m = regex.find(matched)?;
+        if m.start() != 0 || m.end() != matched.len() {
+            return None;
+        }
+
+        let replacement =
+            self.find_replace
+                .options()
+                .expand_replacement(&regex, matched, &replace_chars);
+        Some(format!("{} → {}", matched, replacement))
+    }
+}
+
+/// Blinks the caret of the `EditView` like GTK's text widgets do. The timer stops once the
+/// `EditView` is gone, and starts over if the user changes `gtk-cursor-blink-time`.
+fn start_caret_blink(edit_view: Weak<RefCell<EditView>>) {
+    let blink_time = caret_blink_time();
+    gtk::timeout_add(max(blink_time / 2, 50) as u32, move || {
+        let ev = match edit_view.upgrade() {
+            Some(ev) => ev,
+            None => return glib::Continue(false),
+        };
+        if caret_blink_time() != blink_time {
+            start_caret_blink(edit_view.clone());
+            return glib::Continue(false);
+        }
+        if let Ok(mut ev) = ev.try_borrow_mut() {
+            ev.blink_caret();
+        }
+        glib::Continue(true)
+    });
+}
+
+/// How long a blink cycle of the caret takes, in milliseconds
+fn caret_blink_time() -> i32 {
+    gtk::Settings::get_default().map_or(1200, |settings| {
+        settings.get_property_gtk_cursor_blink_time()
+    })
+}
+
+/// Builds the Pango attributes for the syntax highlighting xi has sent for `line`, so the text is
+/// drawn in the theme's colours
+pub fn line_attributes(line: &Line, styles: &HashMap<usize, LineStyle>) -> pango::AttrList {
+    span_attributes(&line.style_spans(), styles)
+}
+
+/// Builds the Pango attributes for `spans` of styles, given as `(start, end, style)` byte ranges
+/// like in `html::StyledLine`
+pub fn span_attributes(
+    spans: &[(usize, usize, usize)],
+    styles: &HashMap<usize, LineStyle>,
+) -> pango::AttrList {
+    let attr_list = pango::AttrList::new();
+    for &(start, end, id) in spans {
+        let start_index = start as u32;
+        let end_index = end as u32;
+        let line_style = styles.get(&id);
+
+        if let Some(foreground) = line_style.and_then(|s| s.fg_color) {
+            let pango_color = PangoColor::from(color_from_u32(foreground));
+            let mut attr =
+                Attribute::new_foreground(pango_color.r, pango_color.g, pango_color.b).unwrap();
+            attr.set_start_index(start_index);
+            attr.set_end_index(end_index);
+            attr_list.insert(attr);
+        }
+
+        if let Some(background) = line_style.and_then(|s| s.bg_color) {
+            let pango_color = PangoColor::from(color_from_u32(background));
+            let mut attr =
+                Attribute::new_background(pango_color.r, pango_color.g, pango_color.b).unwrap();
+            attr.set_start_index(start_index);
+            attr.set_end_index(end_index);
+            attr_list.insert(attr);
+        }
+
+        insert_font_attributes(&attr_list, line_style, start_index, end_index);
+    }
+
+    attr_list
+}
+
+/// Adds the weight, slant and underline of `line_style` to `attr_list`, from byte `start_index`
+/// to `end_index`
+fn insert_font_attributes(
+    attr_list: &pango::AttrList,
+    line_style: Option<&LineStyle>,
+    start_index: u32,
+    end_index: u32,
+) {
+    if let Some(weight) = line_style.and_then(|s| s.weight) {
+        let mut attr = Attribute::new_weight(pango::Weight::__Unknown(weight as i32)).unwrap();
+        attr.set_start_index(start_index);
+        attr.set_end_index(end_index);
+        attr_list.insert(attr);
+    }
+
+    if let Some(italic) = line_style.and_then(|s| s.italic) {
+        let mut attr = if italic {
+            Attribute::new_style(pango::Style::Italic).unwrap()
+        } else {
+            Attribute::new_style(pango::Style::Normal).unwrap()
+        };
+        attr.set_start_index(start_index);
+        attr.set_end_index(end_index);
+        attr_list.insert(attr);
+    }
+
+    if let Some(underline) = line_style.and_then(|s| s.underline) {
+        let mut attr = if underline {
+            Attribute::new_underline(pango::Underline::Single).unwrap()
+        } else {
+            Attribute::new_underline(pango::Underline::None).unwrap()
+        };
+        attr.set_start_index(start_index);
+        attr.set_end_index(end_index);
+        attr_list.insert(attr);
+    }
 }
@@ -14,6 +14,10 @@ pub enum Error {
     DeToml(String),
     #[fail(display = "Failed to serialize config TOML! Error: {}", _0)]
     SerToml(String),
+    #[fail(display = "Failed to parse the keymap! Error: {}", _0)]
+    Keymap(String),
+    #[fail(display = "Failed to import the file! Error: {}", _0)]
+    Import(String),
 }
 
 impl From<std::io::Error> for Error {
@@ -0,0 +1,324 @@
+use crate::find::FindOptions;
+use crate::gitignore::GitIgnore;
+use gettextrs::gettext;
+use log::{debug, trace};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tempfile::NamedTempFile;
+
+/// Files bigger than this are most likely not source code, so we don't search them
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// Like git we only look for NUL bytes at the start of a file to check if it's binary
+const BINARY_CHECK_LEN: usize = 8000;
+/// How much of the line before a match we keep for displaying it in the results
+const MAX_CONTEXT_LEN: usize = 40;
+/// How much of the line after a match we keep for displaying it in the results
+const MAX_PREVIEW_LEN: usize = 200;
+
+/// A match found by searching a file
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineMatch {
+    /// The line of the match, starting at 0
+    pub line: u64,
+    /// The column of the match in chars, starting at 0
+    pub col: u64,
+    /// Where the match starts in the line, in bytes
+    pub start: usize,
+    /// Where the match ends in the line, in bytes
+    pub end: usize,
+    /// The text before the match, without leading whitespace
+    pub before: String,
+    pub matched: String,
+    /// The text after the match, without the line ending
+    pub after: String,
+}
+
+impl LineMatch {
+    /// The line the match is on, shortened for displaying it
+    pub fn text(&self) -> String {
+        format!("{}{}{}", self.before, self.matched, self.after)
+    }
+}
+
+/// Checks if `bytes`, the contents of a file, look like a binary file
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes[..bytes.len().min(BINARY_CHECK_LEN)].contains(&0)
+}
+
+/// Finds all matches of `regex` in `text`. Matches can't span multiple lines.
+pub fn search_text(regex: &Regex, text: &str) -> Vec<LineMatch> {
+    let mut matches = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        for m in regex.find_iter(line).filter(|m| !m.as_str().is_empty()) {
+            let before = line[..m.start()].trim_start();
+            let before_len = before.chars().count();
+            let before = if before_len > MAX_CONTEXT_LEN {
+                let skipped: String = before.chars().skip(before_len - MAX_CONTEXT_LEN).collect();
+                format!("…{}", skipped)
+            } else {
+                before.to_string()
+            };
+
+            matches.push(LineMatch {
+                line: n as u64,
+                col: line[..m.start()].chars().count() as u64,
+                start: m.start(),
+                end: m.end(),
+                before,
+                matched: m.as_str().to_string(),
+                after: line[m.end()..].chars().take(MAX_PREVIEW_LEN).collect(),
+            });
+        }
+    }
+    matches
+}
+
+/// Searches a single file. Returns `None` if it can't be read, is too big or is binary.
+pub fn search_file(regex: &Regex, path: &Path) -> Option<Vec<LineMatch>> {
+    let size = fs::metadata(path).ok()?.len();
+    if size > MAX_FILE_SIZE {
+        debug!(
+            "{}: {:?}",
+            gettext("Not searching file because it's too big"),
+            path
+        );
+        return None;
+    }
+
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            debug!("{} {:?}: {}", gettext("Failed to read"), path, e);
+            return None;
+        }
+    };
+    if is_binary(&bytes) {
+        trace!("{}: {:?}", gettext("Not searching binary file"), path);
+        return None;
+    }
+
+    Some(search_text(regex, &String::from_utf8_lossy(&bytes)))
+}
+
+/// `path` with symlinks and `..` resolved, so different ways of writing the same path compare
+/// equal. Paths which can't be resolved, e.g. because the file doesn't exist, are kept as they are.
+pub fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Searches all files below `root` which aren't ignored by its `.gitignore`. `on_file` is called
+/// with the matches of every file that has some. Stops early if `cancel` is set, in which case
+/// `false` is returned. Meant to be run on its own thread.
+///
+/// `buffers` has the text of the files that are open in the editor, which we search instead of
+/// what's on disk. Files it maps to `None` are skipped, since we don't know their text. Its paths
+/// have to be `canonical_path`s.
+pub fn search_dir<F>(
+    root: &Path,
+    regex: &Regex,
+    buffers: &HashMap<PathBuf, Option<String>>,
+    cancel: &AtomicBool,
+    mut on_file: F,
+) -> bool
+where
+    F: FnMut(PathBuf, Vec<LineMatch>),
+{
+    for path in GitIgnore::new(root).walk() {
+        if cancel.load(Ordering::Relaxed) {
+            debug!("{}: {:?}", gettext("Search has been cancelled"), root);
+            return false;
+        }
+
+        let buffer = if buffers.is_empty() {
+            None
+        } else {
+            buffers.get(&canonical_path(&path))
+        };
+        let matches = match buffer {
+            Some(Some(text)) => Some(search_text(regex, text)),
+            Some(None) => None,
+            None => search_file(regex, &path),
+        };
+        if let Some(matches) = matches {
+            if !matches.is_empty() {
+                on_file(path, matches);
+            }
+        }
+    }
+    true
+}
+
+/// Replaces the matches of `regex` in `text` which start at one of the `hits`, given as line
+/// and byte offset like in `LineMatch`. Returns the new text and how many matches have been
+/// replaced, which is less than the number of hits if `text` has changed since it was searched.
+pub fn replace_text(
+    text: &str,
+    regex: &Regex,
+    options: &FindOptions,
+    replacement: &str,
+    hits: &[(u64, usize)],
+) -> (String, usize) {
+    let hits: HashSet<_> = hits.iter().cloned().collect();
+    let mut replaced = String::with_capacity(text.len());
+    let mut count = 0;
+    let mut rest = text;
+    let mut n = 0;
+
+    while !rest.is_empty() {
+        let (line, tail) = rest.split_at(rest.find('\n').map_or(rest.len(), |ix| ix + 1));
+        // Like `str::lines` we don't search the line ending
+        let mut content_len = line.len();
+        if line.ends_with('\n') {
+            content_len -= 1;
+            if line[..content_len].ends_with('\r') {
+                content_len -= 1;
+            }
+        }
+
+        let mut last = 0;
+        for m in regex.find_iter(&line[..content_len]) {
+            if !m.as_str().is_empty() && hits.contains(&(n, m.start())) {
+                replaced.push_str(&line[last..m.start()]);
+                replaced.push_str(&options.expand_replacement(regex, m.as_str(), replacement));
+                last = m.end();
+                count += 1;
+            }
+        }
+        replaced.push_str(&line[last..]);
+
+        rest = tail;
+        n += 1;
+    }
+    (replaced, count)
+}
+
+/// Replaces the `hits` in the file at `path` like `replace_text`, and saves it with
+/// `write_atomically`. Returns how many matches have been replaced.
+pub fn replace_in_file(
+    path: &Path,
+    regex: &Regex,
+    options: &FindOptions,
+    replacement: &str,
+    hits: &[(u64, usize)],
+) -> io::Result<usize> {
+    let text = String::from_utf8(fs::read(path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let (replaced, count) = replace_text(&text, regex, options, replacement, hits);
+    if count > 0 {
+        write_atomically(path, &replaced)?;
+    }
+    Ok(count)
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it to `path` afterwards, so
+/// `path` is never left half written.
+pub fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut file = NamedTempFile::new_in(dir)?;
+    file.write_all(contents.as_bytes())?;
+    // The temporary file is only accessible by us, so keep the permissions of the old file
+    if let Ok(metadata) = fs::metadata(path) {
+        file.as_file().set_permissions(metadata.permissions())?;
+    }
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_binary() {
+        assert!(!is_binary(b""));
+        assert!(!is_binary("fn main() {}\nü\n".as_bytes()));
+        assert!(is_binary(b"\x7fELF\x02\x01\x01\x00"));
+    }
+
+    #[test]
+    fn test_search_text() {
+        let regex = Regex::new("foo").unwrap();
+        let matches = search_text(&regex, "foo\n    bär foo foo\r\nbar\n");
+        assert_eq!(
+            matches,
+            vec![
+                LineMatch {
+                    line: 0,
+                    col: 0,
+                    start: 0,
+                    end: 3,
+                    before: "".to_string(),
+                    matched: "foo".to_string(),
+                    after: "".to_string(),
+                },
+                LineMatch {
+                    line: 1,
+                    col: 8,
+                    start: 9,
+                    end: 12,
+                    before: "bär ".to_string(),
+                    matched: "foo".to_string(),
+                    after: " foo".to_string(),
+                },
+                LineMatch {
+                    line: 1,
+                    col: 12,
+                    start: 13,
+                    end: 16,
+                    before: "bär foo ".to_string(),
+                    matched: "foo".to_string(),
+                    after: "".to_string(),
+                },
+            ]
+        );
+        assert_eq!(matches[1].text(), "bär foo foo");
+        assert!(search_text(&Regex::new("x*").unwrap(), "abc").is_empty());
+    }
+
+    #[test]
+    fn test_replace_text() {
+        let options = FindOptions {
+            regex: true,
+            ..Default::default()
+        };
+        let regex = options.build_regex(r"(\w+)=1").unwrap();
+        let text = "a=1 b=1\r\nc=1\n\nd=1";
+        let hits = [(0, 4), (1, 0), (3, 0), (5, 0)];
+
+        let (replaced, count) = replace_text(text, &regex, &options, "$1=2", &hits);
+        assert_eq!(replaced, "a=1 b=2\r\nc=2\n\nd=2");
+        assert_eq!(count, 3);
+        assert_eq!(replace_text(text, &regex, &options, "x", &[]).0, text);
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        fs::write(&path, "old").unwrap();
+
+        write_atomically(&path, "new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_canonical_path() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let path = dir.path().join("file.txt");
+        fs::write(&path, "").unwrap();
+
+        assert_eq!(
+            canonical_path(&dir.path().join("sub/../file.txt")),
+            canonical_path(&path)
+        );
+        let missing = dir.path().join("missing/../file.txt");
+        assert_eq!(canonical_path(&missing), missing);
+    }
+}
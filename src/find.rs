@@ -0,0 +1,466 @@
+use crate::buffer::{Buffer, Pos};
+use regex::{Regex, RegexBuilder};
+
+/// The toggle buttons of the search bar. They're shared by all `EditView`s of a window.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FindOptions {
+    pub case_sensitive: bool,
+    pub regex: bool,
+    pub whole_words: bool,
+    /// Only search inside the text that was selected when the toggle was switched on
+    pub in_selection: bool,
+    /// Give replacements the case of the text they replace
+    pub preserve_case: bool,
+}
+
+impl FindOptions {
+    /// Builds the regex matching `needle` with these options. xi-editor silently ignores
+    /// invalid patterns, so we use this to tell the user what's wrong with theirs.
+    pub fn build_regex(&self, needle: &str) -> Result<Regex, regex::Error> {
+        let pattern = if self.regex {
+            needle.to_string()
+        } else {
+            regex::escape(needle)
+        };
+        let pattern = if self.whole_words {
+            format!(r"\b(?:{})\b", pattern)
+        } else {
+            pattern
+        };
+
+        RegexBuilder::new(&pattern)
+            .case_insensitive(!self.case_sensitive)
+            .build()
+    }
+
+    /// If every match needs its own replacement, so xi can't replace them by itself
+    pub fn expands_replacement(&self, replacement: &str) -> bool {
+        self.preserve_case || (self.regex && replacement.contains('$'))
+    }
+
+    /// The replacement for `matched`, which `regex` has matched. In regex mode `$1` and `${name}`
+    /// are replaced with the capture groups.
+    pub fn expand_replacement(&self, regex: &Regex, matched: &str, replacement: &str) -> String {
+        let mut expanded = String::new();
+        match regex.captures(matched) {
+            Some(captures) if self.regex => captures.expand(replacement, &mut expanded),
+            _ => expanded.push_str(replacement),
+        }
+
+        if self.preserve_case {
+            apply_case(matched, &expanded)
+        } else {
+            expanded
+        }
+    }
+}
+
+/// Gives `replacement` the case of `original`: all uppercase, all lowercase or capitalized.
+/// Otherwise `replacement` is kept like it is.
+fn apply_case(original: &str, replacement: &str) -> String {
+    let has_upper = original.chars().any(char::is_uppercase);
+    let has_lower = original.chars().any(char::is_lowercase);
+    let capitalized = original.chars().next().map_or(false, char::is_uppercase)
+        && !original.chars().skip(1).any(char::is_uppercase);
+
+    if has_upper && !has_lower {
+        replacement.to_uppercase()
+    } else if has_lower && !has_upper {
+        replacement.to_lowercase()
+    } else if capitalized {
+        let mut chars = replacement.chars();
+        chars
+            .next()
+            .map(|c| c.to_uppercase().chain(chars).collect())
+            .unwrap_or_default()
+    } else {
+        replacement.to_string()
+    }
+}
+
+/// A part of a single line, from `start` to `end` (exclusive)
+pub type Region = (Pos, Pos);
+
+/// Finds all non-empty matches of `regex` which lie inside of `regions`. Matches can't span
+/// multiple lines, and lines `buf` doesn't know are skipped.
+pub fn find_in_regions<B: Buffer>(regex: &Regex, regions: &[Region], buf: &B) -> Vec<Region> {
+    let mut matches = Vec::new();
+    for (start, end) in regions {
+        let text = match buf.line(start.line) {
+            Some(text) => text,
+            None => continue,
+        };
+        let from = (start.col as usize).min(text.len());
+        let to = (end.col as usize).min(text.len()).max(from);
+        let text = match text.get(from..to) {
+            Some(text) => text,
+            None => continue,
+        };
+
+        matches.extend(
+            regex
+                .find_iter(text)
+                .filter(|m| !m.as_str().is_empty())
+                .map(|m| {
+                    (
+                        Pos::new(start.line, (from + m.start()) as u64),
+                        Pos::new(start.line, (from + m.end()) as u64),
+                    )
+                }),
+        );
+    }
+    matches.sort();
+    matches
+}
+
+/// The first match starting at or after the cursor `pos`, or the last one ending before it if
+/// `backwards` is set. Since a selected match has the cursor at its end this skips the current
+/// match in both directions. Wraps around at the start and end of `matches`, which have to be sorted.
+pub fn next_match(matches: &[Region], pos: Pos, backwards: bool) -> Option<Region> {
+    if backwards {
+        matches
+            .iter()
+            .rev()
+            .find(|(_, end)| *end < pos)
+            .or_else(|| matches.last())
+            .cloned()
+    } else {
+        matches
+            .iter()
+            .find(|(start, _)| *start >= pos)
+            .or_else(|| matches.first())
+            .cloned()
+    }
+}
+
+/// The next occurrence of `needle` after `pos` which isn't `selected` already, wrapping around at
+/// the end of the document. Only the lines `buf` knows are searched. This is for adding a
+/// selection at the next occurrence of the selected text, which mustn't touch the user's search.
+pub fn next_occurrence<B: Buffer>(
+    needle: &str,
+    pos: Pos,
+    selected: &[Region],
+    buf: &B,
+) -> Option<Region> {
+    if needle.is_empty() || buf.n_lines() == 0 {
+        return None;
+    }
+
+    let n_lines = buf.n_lines();
+    // Visit the line of `pos` twice, once for what's after it and once after wrapping around
+    (0..=n_lines).find_map(|i| {
+        let line = (pos.line + i) % n_lines;
+        let text = buf.line(line)?;
+        text.match_indices(needle)
+            .map(|(ix, m)| {
+                (
+                    Pos::new(line, ix as u64),
+                    Pos::new(line, (ix + m.len()) as u64),
+                )
+            })
+            .filter(|(start, _)| i > 0 || *start >= pos)
+            .find(|region| !selected.contains(region))
+    })
+}
+
+/// All occurrences of `needle` in the lines `buf` knows, in document order
+pub fn occurrences<B: Buffer>(needle: &str, buf: &B) -> Vec<Region> {
+    if needle.is_empty() {
+        return Vec::new();
+    }
+
+    (0..buf.n_lines())
+        .filter_map(|line| buf.line(line).map(|text| (line, text)))
+        .flat_map(|(line, text)| {
+            text.match_indices(needle).map(move |(ix, m)| {
+                (
+                    Pos::new(line, ix as u64),
+                    Pos::new(line, (ix + m.len()) as u64),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Moves the positions in `regions` which are on the same line as `at` and not before it by
+/// `delta` bytes, e.g. after `at` has been replaced by text of a different length.
+pub fn shift(regions: &mut [Region], at: Pos, delta: i64) {
+    let shift_pos = |pos: &mut Pos| {
+        if pos.line == at.line && pos.col >= at.col {
+            pos.col = (pos.col as i64 + delta).max(0) as u64;
+        }
+    };
+    for (start, end) in regions {
+        shift_pos(start);
+        shift_pos(end);
+    }
+}
+
+/// The part of `old` which has to be replaced to turn it into `new`: where it starts and ends in
+/// `old`, and the text to replace it with. None if the texts are the same.
+pub fn changed_span(old: &str, new: &str) -> Option<(Pos, Pos, String)> {
+    if old == new {
+        return None;
+    }
+
+    let mut prefix = old
+        .bytes()
+        .zip(new.bytes())
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(prefix) || !new.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let mut suffix = old
+        .bytes()
+        .rev()
+        .zip(new.bytes().rev())
+        .take(old.len().min(new.len()) - prefix)
+        .take_while(|(a, b)| a == b)
+        .count();
+    while !old.is_char_boundary(old.len() - suffix) || !new.is_char_boundary(new.len() - suffix) {
+        suffix -= 1;
+    }
+
+    Some((
+        pos_at(old, prefix),
+        pos_at(old, old.len() - suffix),
+        new[prefix..new.len() - suffix].to_string(),
+    ))
+}
+
+/// The position of the byte offset `ix` in `text`
+fn pos_at(text: &str, ix: usize) -> Pos {
+    let before = &text[..ix];
+    let col = before.rfind('\n').map_or(ix, |newline| ix - newline - 1);
+    Pos::new(before.matches('\n').count() as u64, col as u64)
+}
+
+/// Where `pos` is after the text from `start` to `end` has been replaced with `text`. Positions
+/// inside of the replaced text are moved to the end of `text`.
+pub fn move_past_edit(pos: Pos, start: Pos, end: Pos, text: &str) -> Pos {
+    if pos <= start {
+        return pos;
+    }
+
+    let inserted = pos_at(text, text.len());
+    let new_end = if inserted.line == 0 {
+        Pos::new(start.line, start.col + inserted.col)
+    } else {
+        Pos::new(start.line + inserted.line, inserted.col)
+    };
+    if pos <= end {
+        new_end
+    } else if pos.line == end.line {
+        Pos::new(new_end.line, new_end.col + pos.col - end.col)
+    } else {
+        Pos::new(pos.line - end.line + new_end.line, pos.col)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Lines(Vec<&'static str>);
+
+    impl Buffer for Lines {
+        fn line(&self, n: u64) -> Option<&str> {
+            self.0.get(n as usize).cloned()
+        }
+
+        fn n_lines(&self) -> u64 {
+            self.0.len() as u64
+        }
+    }
+
+    #[test]
+    fn test_build_regex() {
+        let mut options = FindOptions::default();
+        assert!(options.build_regex("a.b").unwrap().is_match("A.B"));
+        assert!(!options.build_regex("a.b").unwrap().is_match("axb"));
+        assert!(options.build_regex("(").is_ok());
+
+        options.case_sensitive = true;
+        assert!(!options.build_regex("a.b").unwrap().is_match("A.B"));
+
+        options.regex = true;
+        assert!(options.build_regex("a.b").unwrap().is_match("axb"));
+        assert!(options.build_regex("(").is_err());
+
+        options.whole_words = true;
+        assert!(options.build_regex("a|b").unwrap().is_match("x a"));
+        assert!(!options.build_regex("a|b").unwrap().is_match("xa"));
+    }
+
+    #[test]
+    fn test_expand_replacement() {
+        let mut options = FindOptions::default();
+        let regex = options.build_regex("foo").unwrap();
+        assert!(!options.expands_replacement("$1"));
+        assert_eq!(options.expand_replacement(&regex, "Foo", "$1bar"), "$1bar");
+
+        options.regex = true;
+        let regex = options.build_regex(r"(\w+)=(?P<value>\d+)").unwrap();
+        assert!(options.expands_replacement("${value}"));
+        assert!(!options.expands_replacement("value"));
+        assert_eq!(
+            options.expand_replacement(&regex, "a=1", "${value}=$1"),
+            "1=a"
+        );
+
+        options.preserve_case = true;
+        assert!(options.expands_replacement("value"));
+        let regex = options.build_regex("foo").unwrap();
+        assert_eq!(
+            options.expand_replacement(&regex, "FOO", "barBaz"),
+            "BARBAZ"
+        );
+        assert_eq!(
+            options.expand_replacement(&regex, "foo", "barBaz"),
+            "barbaz"
+        );
+        assert_eq!(
+            options.expand_replacement(&regex, "Foo", "barBaz"),
+            "BarBaz"
+        );
+        assert_eq!(
+            options.expand_replacement(&regex, "fOo", "barBaz"),
+            "barBaz"
+        );
+    }
+
+    #[test]
+    fn test_find_in_regions() {
+        let buf = Lines(vec!["foo bar foo", "foo", "bär foo"]);
+        let regex = FindOptions::default().build_regex("foo").unwrap();
+        let regions = [
+            (Pos::new(2, 0), Pos::new(2, 8)),
+            (Pos::new(0, 2), Pos::new(0, 11)),
+            (Pos::new(5, 0), Pos::new(5, 3)),
+        ];
+        let matches = find_in_regions(&regex, &regions, &buf);
+        assert_eq!(
+            matches,
+            vec![
+                (Pos::new(0, 8), Pos::new(0, 11)),
+                (Pos::new(2, 5), Pos::new(2, 8)),
+            ]
+        );
+
+        assert_eq!(
+            next_match(&matches, Pos::new(0, 0), false),
+            Some(matches[0])
+        );
+        assert_eq!(
+            next_match(&matches, Pos::new(0, 11), false),
+            Some(matches[1])
+        );
+        assert_eq!(
+            next_match(&matches, Pos::new(2, 8), false),
+            Some(matches[0])
+        );
+        assert_eq!(next_match(&matches, Pos::new(2, 8), true), Some(matches[0]));
+        assert_eq!(
+            next_match(&matches, Pos::new(0, 11), true),
+            Some(matches[1])
+        );
+        assert_eq!(next_match(&[], Pos::new(0, 0), true), None);
+    }
+
+    #[test]
+    fn test_shift() {
+        let mut regions = [
+            (Pos::new(0, 2), Pos::new(0, 5)),
+            (Pos::new(0, 8), Pos::new(0, 11)),
+            (Pos::new(1, 8), Pos::new(1, 11)),
+        ];
+        shift(&mut regions, Pos::new(0, 5), -2);
+        assert_eq!(
+            regions,
+            [
+                (Pos::new(0, 2), Pos::new(0, 3)),
+                (Pos::new(0, 6), Pos::new(0, 9)),
+                (Pos::new(1, 8), Pos::new(1, 11)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_next_occurrence() {
+        let buf = Lines(vec!["foo bar foo", "", "foo"]);
+        let first = (Pos::new(0, 0), Pos::new(0, 3));
+        let second = (Pos::new(0, 8), Pos::new(0, 11));
+        let third = (Pos::new(2, 0), Pos::new(2, 3));
+
+        assert_eq!(
+            next_occurrence("foo", first.1, &[first], &buf),
+            Some(second)
+        );
+        assert_eq!(
+            next_occurrence("foo", second.1, &[first, second], &buf),
+            Some(third)
+        );
+        // Wraps around, but skips what's selected already
+        assert_eq!(
+            next_occurrence("foo", third.1, &[second, third], &buf),
+            Some(first)
+        );
+        assert_eq!(
+            next_occurrence("foo", third.1, &[first, second, third], &buf),
+            None
+        );
+        assert_eq!(next_occurrence("", first.1, &[first], &buf), None);
+    }
+
+    #[test]
+    fn test_occurrences() {
+        let buf = Lines(vec!["foo bar foo", "", "foo"]);
+        assert_eq!(
+            occurrences("foo", &buf),
+            vec![
+                (Pos::new(0, 0), Pos::new(0, 3)),
+                (Pos::new(0, 8), Pos::new(0, 11)),
+                (Pos::new(2, 0), Pos::new(2, 3)),
+            ]
+        );
+        assert_eq!(occurrences("", &buf), vec![]);
+    }
+
+    #[test]
+    fn test_changed_span() {
+        let old = "foo bar\nbaz foo\n";
+        let new = "foo qux\nbaz qux\n";
+        let (start, end, text) = changed_span(old, new).unwrap();
+        assert_eq!(start, Pos::new(0, 4));
+        assert_eq!(end, Pos::new(1, 7));
+        assert_eq!(text, "qux\nbaz qux");
+        assert_eq!(changed_span(old, old), None);
+        // Doesn't split chars which only differ in their last byte
+        assert_eq!(
+            changed_span("aä", "aö"),
+            Some((Pos::new(0, 1), Pos::new(0, 3), "ö".to_string()))
+        );
+
+        assert_eq!(
+            move_past_edit(Pos::new(0, 2), start, end, &text),
+            Pos::new(0, 2)
+        );
+        assert_eq!(
+            move_past_edit(Pos::new(1, 2), start, end, &text),
+            Pos::new(1, 7)
+        );
+        assert_eq!(
+            move_past_edit(Pos::new(1, 8), start, end, &text),
+            Pos::new(1, 8)
+        );
+        assert_eq!(
+            move_past_edit(Pos::new(0, 7), Pos::new(0, 4), Pos::new(0, 7), "quux"),
+            Pos::new(0, 8)
+        );
+        assert_eq!(
+            move_past_edit(Pos::new(3, 1), Pos::new(0, 4), Pos::new(1, 7), "x"),
+            Pos::new(2, 1)
+        );
+    }
+}
@@ -0,0 +1,801 @@
+use crate::file_search::{self, LineMatch};
+use crate::find::FindOptions;
+use crate::goto_line::{LineTarget, Position};
+use crate::main_win::MainWin;
+use gettextrs::gettext;
+use glib::{markup_escape_text, MainContext};
+use gtk::*;
+use log::{debug, warn};
+use regex::Regex;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+const COLUMN_TEXT: u32 = 0;
+const COLUMN_PATH: u32 = 1;
+const COLUMN_LINE: u32 = 2;
+const COLUMN_COL: u32 = 3;
+const COLUMN_CHECKED: u32 = 4;
+const COLUMN_INCONSISTENT: u32 = 5;
+
+/// What the search thread sends to the panel
+enum SearchMsg {
+    /// The matches in a file
+    File(PathBuf, Vec<LineMatch>),
+    /// All files have been searched
+    Done,
+}
+
+/// The "Find in Files" panel below the editor, which searches all files in a folder. The search
+/// runs on its own thread and streams its results into the panel, grouped by file.
+///
+/// In replace mode every result shows what it would be replaced with, and the user can pick the
+/// files and matches to replace with the checkboxes in front of them.
+pub struct FindInFiles {
+    pub container: Box,
+    search_entry: SearchEntry,
+    folder_button: FileChooserButton,
+    case_sensitive_button: ToggleButton,
+    regex_button: ToggleButton,
+    whole_words_button: ToggleButton,
+    replace_mode_button: ToggleButton,
+    stop_button: Button,
+    status_label: Label,
+    replace_revealer: Revealer,
+    replace_entry: Entry,
+    preserve_case_button: ToggleButton,
+    replace_all_button: Button,
+    tree_view: TreeView,
+    tree_store: TreeStore,
+    toggle_column: TreeViewColumn,
+    /// The folder the current results are from
+    root: Option<PathBuf>,
+    /// The regex and options of the current results
+    search: Option<(Regex, FindOptions)>,
+    /// The matches of every file, in the order of the file rows
+    results: Vec<(PathBuf, Vec<LineMatch>)>,
+    /// Set to stop the running search
+    cancel: Option<Arc<AtomicBool>>,
+    n_files: usize,
+    n_matches: usize,
+    /// The window we're in, to search the text of its open files instead of what's on disk
+    main_win: Weak<RefCell<MainWin>>,
+}
+
+impl FindInFiles {
+    pub fn new() -> Rc<RefCell<Self>> {
+        let search_entry = SearchEntry::new();
+        search_entry.set_placeholder_text(gettext("Find in files").as_str());
+        search_entry.set_hexpand(true);
+
+        let toggle = |label: &str, tooltip: &str| {
+            let button = ToggleButton::new_with_label(label);
+            button.set_tooltip_text(Some(tooltip));
+            button
+        };
+        let case_sensitive_button = toggle("Aa", &gettext("Match case"));
+        let regex_button = toggle(".*", &gettext("Regular expression"));
+        let whole_words_button = toggle("\\b", &gettext("Whole word"));
+        let toggle_box = Box::new(Orientation::Horizontal, 0);
+        toggle_box.get_style_context().add_class("linked");
+        toggle_box.pack_start(&case_sensitive_button, false, false, 0);
+        toggle_box.pack_start(&regex_button, false, false, 0);
+        toggle_box.pack_start(&whole_words_button, false, false, 0);
+
+        let folder_button = FileChooserButton::new(
+            &gettext("Search in Folder"),
+            FileChooserAction::SelectFolder,
+        );
+        folder_button.set_tooltip_text(Some(gettext("The folder to search in").as_str()));
+
+        let replace_mode_button = ToggleButton::new();
+        replace_mode_button.set_image(Some(&Image::new_from_icon_name(
+            Some("edit-find-replace-symbolic"),
+            IconSize::Button,
+        )));
+        replace_mode_button.set_tooltip_text(Some(gettext("Replace").as_str()));
+
+        let stop_button = Button::new_from_icon_name("process-stop-symbolic", IconSize::Button);
+        stop_button.set_tooltip_text(Some(gettext("Stop searching").as_str()));
+        stop_button.set_sensitive(false);
+        let close_button = Button::new_from_icon_name("window-close-symbolic", IconSize::Button);
+        close_button.set_relief(ReliefStyle::None);
+        close_button.set_tooltip_text(Some(gettext("Close").as_str()));
+
+        let search_box = Box::new(Orientation::Horizontal, 6);
+        search_box.pack_start(&search_entry, true, true, 0);
+        search_box.pack_start(&toggle_box, false, false, 0);
+        search_box.pack_start(&folder_button, false, false, 0);
+        search_box.pack_start(&replace_mode_button, false, false, 0);
+        search_box.pack_start(&stop_button, false, false, 0);
+        search_box.pack_start(&close_button, false, false, 0);
+
+        let replace_entry = Entry::new();
+        replace_entry.set_placeholder_text(gettext("Replace with").as_str());
+        replace_entry.set_hexpand(true);
+        let preserve_case_button = toggle("AB", &gettext("Preserve case"));
+        let replace_all_button = Button::new_with_label(&gettext("Replace Selected"));
+        replace_all_button.set_tooltip_text(Some(
+            gettext("Replace the checked matches in all files").as_str(),
+        ));
+        replace_all_button.set_sensitive(false);
+
+        let replace_box = Box::new(Orientation::Horizontal, 6);
+        replace_box.pack_start(&replace_entry, true, true, 0);
+        replace_box.pack_start(&preserve_case_button, false, false, 0);
+        replace_box.pack_start(&replace_all_button, false, false, 0);
+        let replace_revealer = Revealer::new();
+        replace_revealer.add(&replace_box);
+
+        let status_label = Label::new(None);
+        status_label.set_xalign(0.0);
+
+        let tree_store = TreeStore::new(&[
+            Type::String,
+            Type::String,
+            Type::U64,
+            Type::U64,
+            Type::Bool,
+            Type::Bool,
+        ]);
+        let tree_view = TreeView::new_with_model(&tree_store);
+        tree_view.set_headers_visible(false);
+
+        let toggle_column = TreeViewColumn::new();
+        let toggle_cell = CellRendererToggle::new();
+        toggle_column.pack_start(&toggle_cell, false);
+        toggle_column.add_attribute(&toggle_cell, "active", COLUMN_CHECKED as i32);
+        toggle_column.add_attribute(&toggle_cell, "inconsistent", COLUMN_INCONSISTENT as i32);
+        toggle_column.set_visible(false);
+        tree_view.append_column(&toggle_column);
+
+        let column = TreeViewColumn::new();
+        let cell = CellRendererText::new();
+        cell.set_property_ellipsize(pango::EllipsizeMode::End);
+        column.pack_start(&cell, true);
+        column.add_attribute(&cell, "markup", COLUMN_TEXT as i32);
+        tree_view.append_column(&column);
+        tree_view.set_expander_column(Some(&column));
+
+        let scrolled_window = ScrolledWindow::new(None::<&Adjustment>, None::<&Adjustment>);
+        scrolled_window.set_policy(PolicyType::Never, PolicyType::Automatic);
+        scrolled_window.set_size_request(-1, 150);
+        scrolled_window.add(&tree_view);
+
+        let container = Box::new(Orientation::Vertical, 6);
+        container.set_border_width(6);
+        container.pack_start(&search_box, false, false, 0);
+        container.pack_start(&replace_revealer, false, false, 0);
+        container.pack_start(&status_label, false, false, 0);
+        container.pack_start(&scrolled_window, true, true, 0);
+        // The panel is only shown once the user wants to search
+        container.show_all();
+        container.hide();
+        container.set_no_show_all(true);
+
+        let find_in_files = Rc::new(RefCell::new(Self {
+            container: container.clone(),
+            search_entry: search_entry.clone(),
+            folder_button: folder_button.clone(),
+            case_sensitive_button: case_sensitive_button.clone(),
+            regex_button: regex_button.clone(),
+            whole_words_button: whole_words_button.clone(),
+            replace_mode_button: replace_mode_button.clone(),
+            stop_button: stop_button.clone(),
+            status_label,
+            replace_revealer,
+            replace_entry: replace_entry.clone(),
+            preserve_case_button: preserve_case_button.clone(),
+            replace_all_button: replace_all_button.clone(),
+            tree_view,
+            tree_store: tree_store.clone(),
+            toggle_column,
+            root: None,
+            search: None,
+            results: Vec::new(),
+            cancel: None,
+            n_files: 0,
+            n_matches: 0,
+            main_win: Weak::new(),
+        }));
+
+        // Searching a whole folder is too expensive to do it on every key press
+        search_entry.connect_activate(clone!(find_in_files => move |_| {
+            Self::start_search(&find_in_files);
+        }));
+
+        search_entry.connect_stop_search(clone!(find_in_files => move |_| {
+            find_in_files.borrow_mut().cancel_search();
+        }));
+
+        for button in &[&case_sensitive_button, &regex_button, &whole_words_button] {
+            button.connect_toggled(clone!(find_in_files => move |_| {
+                Self::start_search(&find_in_files);
+            }));
+        }
+
+        folder_button.connect_file_set(clone!(find_in_files => move |_| {
+            Self::start_search(&find_in_files);
+        }));
+
+        stop_button.connect_clicked(clone!(find_in_files => move |_| {
+            find_in_files.borrow_mut().cancel_search();
+        }));
+
+        close_button.connect_clicked(clone!(find_in_files => move |_| {
+            find_in_files.borrow_mut().hide();
+        }));
+
+        replace_mode_button.connect_toggled(clone!(find_in_files => move |button| {
+            let this = find_in_files.borrow();
+            this.replace_revealer.set_reveal_child(button.get_active());
+            this.toggle_column.set_visible(button.get_active());
+            this.update_previews();
+            if button.get_active() {
+                this.replace_entry.grab_focus();
+            }
+        }));
+
+        replace_entry.connect_changed(clone!(find_in_files => move |_| {
+            find_in_files.borrow().update_previews();
+        }));
+
+        preserve_case_button.connect_toggled(clone!(find_in_files => move |_| {
+            find_in_files.borrow().update_previews();
+        }));
+
+        toggle_cell.connect_toggled(clone!(tree_store => move |_, path| {
+            toggle_row(&tree_store, &path);
+        }));
+
+        find_in_files
+    }
+
+    /// Sets up event listeners which need access to the `MainWin`.
+    pub fn connect_events(find_in_files: &Rc<RefCell<Self>>, main_win: &Rc<RefCell<MainWin>>) {
+        find_in_files.borrow_mut().main_win = Rc::downgrade(main_win);
+        let tree_view = find_in_files.borrow().tree_view.clone();
+        let replace_all_button = find_in_files.borrow().replace_all_button.clone();
+
+        replace_all_button.connect_clicked(clone!(find_in_files, main_win => move |_| {
+            Self::replace_checked(&find_in_files, &main_win);
+        }));
+
+        tree_view.connect_row_activated(clone!(find_in_files, main_win => move |tv, path, _| {
+            let target = find_in_files.borrow().target_at(path);
+            match target {
+                Some((file, Some(position))) => {
+                    debug!("{}: {}", gettext("Opening search result in"), file);
+                    MainWin::open_file_at(&main_win, &file, Some(position));
+                }
+                Some((_, None)) => {
+                    if tv.row_expanded(path) {
+                        tv.collapse_row(path);
+                    } else {
+                        tv.expand_row(path, false);
+                    }
+                }
+                None => (),
+            }
+        }));
+    }
+
+    /// Shows the panel. If the user hasn't chosen a folder yet we search in `root`, the folder
+    /// that's open in the sidebar.
+    pub fn show(&self, root: Option<&Path>) {
+        if self.folder_button.get_filename().is_none() {
+            if let Some(root) = root {
+                self.folder_button.set_filename(root);
+            }
+        }
+        self.container.show();
+        self.search_entry.grab_focus();
+    }
+
+    pub fn hide(&mut self) {
+        self.cancel_search();
+        self.container.hide();
+    }
+
+    fn options(&self) -> FindOptions {
+        FindOptions {
+            case_sensitive: self.case_sensitive_button.get_active(),
+            regex: self.regex_button.get_active(),
+            whole_words: self.whole_words_button.get_active(),
+            ..Default::default()
+        }
+    }
+
+    /// Starts searching the folder on a new thread, cancelling the previous search.
+    fn start_search(find_in_files: &Rc<RefCell<Self>>) {
+        Self::search_with(find_in_files, HashMap::new());
+    }
+
+    /// Like `start_search`, but searches the text in `buffers` instead of the files on disk. Open
+    /// files are searched like `MainWin::open_buffers` says, unless they're in `buffers`.
+    fn search_with(
+        find_in_files: &Rc<RefCell<Self>>,
+        mut buffers: HashMap<PathBuf, Option<String>>,
+    ) {
+        let mut this = find_in_files.borrow_mut();
+        this.cancel_search();
+        this.tree_store.clear();
+        this.results.clear();
+        this.search = None;
+        this.replace_all_button.set_sensitive(false);
+        this.n_files = 0;
+        this.n_matches = 0;
+        this.status_label.set_tooltip_text(None);
+
+        let needle = this
+            .search_entry
+            .get_text()
+            .map(|t| t.to_string())
+            .unwrap_or_default();
+        if needle.is_empty() {
+            this.status_label.set_text("");
+            return;
+        }
+        let root = match this.folder_button.get_filename() {
+            Some(root) => root,
+            None => {
+                this.status_label
+                    .set_text(&gettext("Choose a folder to search in"));
+                return;
+            }
+        };
+        let options = this.options();
+        let regex = match options.build_regex(&needle) {
+            Ok(regex) => regex,
+            Err(e) => {
+                this.status_label.set_text(&gettext("Invalid regex"));
+                this.status_label
+                    .set_tooltip_text(Some(e.to_string().as_str()));
+                return;
+            }
+        };
+
+        debug!("{} {:?}: {}", gettext("Searching in"), root, needle);
+        this.status_label.set_text(&gettext("Searching…"));
+        this.stop_button.set_sensitive(true);
+        this.root = Some(root.clone());
+        this.search = Some((regex.clone(), options));
+        let cancel = Arc::new(AtomicBool::new(false));
+        this.cancel = Some(cancel.clone());
+
+        if let Some(main_win) = this.main_win.upgrade() {
+            if let Ok(main_win) = main_win.try_borrow() {
+                for (path, text) in main_win.open_buffers() {
+                    buffers.entry(path).or_insert(text);
+                }
+            }
+        }
+
+        let (tx, rx) = MainContext::channel::<SearchMsg>(glib::PRIORITY_DEFAULT);
+        let thread_cancel = cancel.clone();
+        thread::spawn(move || {
+            // Sending only fails if the panel is gone, and then nobody cares about the results
+            file_search::search_dir(&root, &regex, &buffers, &thread_cancel, |path, matches| {
+                let _ = tx.send(SearchMsg::File(path, matches));
+            });
+            let _ = tx.send(SearchMsg::Done);
+        });
+
+        rx.attach(
+            &MainContext::default(),
+            clone!(find_in_files => move |msg| {
+                // Results of a search that has been cancelled or replaced by a newer one
+                if cancel.load(Ordering::Relaxed) {
+                    return glib::source::Continue(false);
+                }
+
+                let mut this = find_in_files.borrow_mut();
+                match msg {
+                    SearchMsg::File(path, matches) => {
+                        this.add_results(&path, &matches);
+                        glib::source::Continue(true)
+                    }
+                    SearchMsg::Done => {
+                        this.cancel = None;
+                        this.stop_button.set_sensitive(false);
+                        this.replace_all_button.set_sensitive(!this.results.is_empty());
+                        this.update_status(false);
+                        glib::source::Continue(false)
+                    }
+                }
+            }),
+        );
+    }
+
+    /// Stops the running search, if any
+    fn cancel_search(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            cancel.store(true, Ordering::Relaxed);
+            self.stop_button.set_sensitive(false);
+            self.update_status(true);
+        }
+    }
+
+    fn update_status(&self, cancelled: bool) {
+        let status = format!(
+            "{} {}, {} {}",
+            self.n_matches,
+            gettext("results"),
+            self.n_files,
+            gettext("files")
+        );
+        if cancelled {
+            self.status_label
+                .set_text(&format!("{} ({})", status, gettext("cancelled")));
+        } else {
+            self.status_label.set_text(&status);
+        }
+    }
+
+    /// Adds a row for `path` with a child row for every match
+    fn add_results(&mut self, path: &Path, matches: &[LineMatch]) {
+        let path_str = path.to_string_lossy().into_owned();
+        let rel_path = self
+            .root
+            .as_ref()
+            .and_then(|root| path.strip_prefix(root).ok())
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned();
+
+        let parent = self.tree_store.insert_with_values(
+            None,
+            None,
+            &[COLUMN_TEXT, COLUMN_PATH, COLUMN_CHECKED],
+            &[
+                &markup_escape_text(&format!("{} ({})", rel_path, matches.len())).as_str(),
+                &path_str,
+                &true,
+            ],
+        );
+        for m in matches {
+            self.tree_store.insert_with_values(
+                Some(&parent),
+                None,
+                &[
+                    COLUMN_TEXT,
+                    COLUMN_PATH,
+                    COLUMN_LINE,
+                    COLUMN_COL,
+                    COLUMN_CHECKED,
+                ],
+                &[&self.match_markup(m), &path_str, &m.line, &m.col, &true],
+            );
+        }
+        if let Some(tree_path) = self.tree_store.get_path(&parent) {
+            self.tree_view.expand_row(&tree_path, false);
+        }
+
+        self.results.push((path.to_path_buf(), matches.to_vec()));
+        self.n_files += 1;
+        self.n_matches += matches.len();
+        self.status_label.set_text(&format!(
+            "{} ({} {}, {} {})",
+            gettext("Searching…"),
+            self.n_matches,
+            gettext("results"),
+            self.n_files,
+            gettext("files")
+        ));
+    }
+
+    fn replace_chars(&self) -> String {
+        self.replace_entry
+            .get_text()
+            .map(|t| t.to_string())
+            .unwrap_or_default()
+    }
+
+    /// The options and regex of the current results, with the preserve case option of the
+    /// replace bar
+    fn replace_options(&self) -> Option<(&Regex, FindOptions)> {
+        self.search.as_ref().map(|(regex, options)| {
+            let options = FindOptions {
+                preserve_case: self.preserve_case_button.get_active(),
+                ..*options
+            };
+            (regex, options)
+        })
+    }
+
+    /// The row of a match. In replace mode the match is struck out and followed by its
+    /// replacement, like in a diff.
+    fn match_markup(&self, m: &LineMatch) -> String {
+        let replacement = match self.replace_options() {
+            Some((regex, options)) if self.replace_mode_button.get_active() => {
+                Some(options.expand_replacement(regex, &m.matched, &self.replace_chars()))
+            }
+            _ => None,
+        };
+
+        let matched = match replacement {
+            Some(replacement) => format!(
+                "<span background=\"#e01b24\" bgalpha=\"30%\" strikethrough=\"true\">{}</span>\
+                 <span background=\"#2ec27e\" bgalpha=\"30%\">{}</span>",
+                markup_escape_text(&m.matched),
+                markup_escape_text(&replacement)
+            ),
+            None => format!("<b>{}</b>", markup_escape_text(&m.matched)),
+        };
+        format!(
+            "{}: {}{}{}",
+            m.line + 1,
+            markup_escape_text(&m.before),
+            matched,
+            markup_escape_text(&m.after)
+        )
+    }
+
+    /// Updates the rows of all matches after the replacement has changed
+    fn update_previews(&self) {
+        self.tree_store.foreach(|_, path, iter| {
+            if let [file_ix, match_ix] = path.get_indices().as_slice() {
+                if let Some(m) = self
+                    .results
+                    .get(*file_ix as usize)
+                    .and_then(|(_, matches)| matches.get(*match_ix as usize))
+                {
+                    self.tree_store
+                        .set_value(iter, COLUMN_TEXT, &self.match_markup(m).to_value());
+                }
+            }
+            false
+        });
+    }
+
+    /// The matches of the `file_ix`th file which the user has checked
+    fn checked_matches(&self, file_ix: usize) -> Vec<&LineMatch> {
+        let matches = &self.results[file_ix].1;
+        let mut checked = Vec::new();
+        let mut iter = self
+            .tree_store
+            .iter_nth_child(None, file_ix as i32)
+            .and_then(|parent| self.tree_store.iter_children(Some(&parent)));
+        for m in matches {
+            let it = match iter {
+                Some(ref it) => it,
+                None => break,
+            };
+            let is_checked = self
+                .tree_store
+                .get_value(it, COLUMN_CHECKED as i32)
+                .get::<bool>()
+                .unwrap_or(false);
+            if is_checked {
+                checked.push(m);
+            }
+            if !self.tree_store.iter_next(it) {
+                iter = None;
+            }
+        }
+        checked
+    }
+
+    /// Replaces the checked matches. Files which are open are changed through their `EditView`,
+    /// so the user can undo the replacements there, all others are saved right away. Tells the
+    /// user what has been replaced and which files failed afterwards.
+    fn replace_checked(find_in_files: &Rc<RefCell<Self>>, main_win: &Rc<RefCell<MainWin>>) {
+        // xi hasn't applied the replacements in open files yet when we search again, so we search
+        // their new text instead
+        let mut buffers = HashMap::new();
+        let (n_replaced, n_files, open_files, failures) = {
+            let this = find_in_files.borrow();
+            let (regex, options) = match this.replace_options() {
+                Some(search) => search,
+                None => return,
+            };
+            let replace_chars = this.replace_chars();
+
+            let mut n_replaced = 0;
+            let mut n_files = 0;
+            let mut open_files = 0;
+            let mut failures = Vec::new();
+            for (file_ix, (path, _)) in this.results.iter().enumerate() {
+                let checked = this.checked_matches(file_ix);
+                if checked.is_empty() {
+                    continue;
+                }
+
+                let hits: Vec<_> = checked.iter().map(|m| (m.line, m.start)).collect();
+                let edit_view = main_win.borrow().edit_view_for_file(path);
+                let result = match edit_view {
+                    Some(edit_view) => {
+                        open_files += 1;
+                        let replaced = edit_view.borrow_mut().replace_hits(
+                            regex,
+                            &options,
+                            &replace_chars,
+                            &hits,
+                        );
+                        match replaced {
+                            Some((count, contents)) => {
+                                buffers.insert(file_search::canonical_path(path), Some(contents));
+                                Ok(count)
+                            }
+                            // The view replaces them once xi has sent it all lines, until then
+                            // we don't know its text
+                            None => {
+                                buffers.insert(file_search::canonical_path(path), None);
+                                n_replaced += checked.len();
+                                n_files += 1;
+                                continue;
+                            }
+                        }
+                    }
+                    None => {
+                        file_search::replace_in_file(path, regex, &options, &replace_chars, &hits)
+                    }
+                };
+
+                match result {
+                    Ok(count) => {
+                        if count > 0 {
+                            n_replaced += count;
+                            n_files += 1;
+                        }
+                        if count < checked.len() {
+                            failures.push(format!(
+                                "{}: {} {}",
+                                path.display(),
+                                checked.len() - count,
+                                gettext("matches have changed since the search")
+                            ));
+                        }
+                    }
+                    Err(e) => {
+                        warn!("{} {:?}: {}", gettext("Failed to replace in"), path, e);
+                        failures.push(format!("{}: {}", path.display(), e));
+                    }
+                }
+            }
+            (n_replaced, n_files, open_files, failures)
+        };
+
+        find_in_files
+            .borrow()
+            .show_summary(n_replaced, n_files, open_files, &failures);
+        // The results are outdated now
+        Self::search_with(find_in_files, buffers);
+    }
+
+    fn show_summary(
+        &self,
+        n_replaced: usize,
+        n_files: usize,
+        open_files: usize,
+        failures: &[String],
+    ) {
+        let mut summary = format!(
+            "{} {} {} {} {}.",
+            gettext("Replaced"),
+            n_replaced,
+            gettext("matches in"),
+            n_files,
+            gettext("files")
+        );
+        if open_files > 0 {
+            summary.push_str(&format!(
+                " {} {} {}",
+                gettext("Changes to the"),
+                open_files,
+                gettext("open files have to be saved.")
+            ));
+        }
+
+        let window = self
+            .container
+            .get_toplevel()
+            .and_then(|w| w.downcast::<Window>().ok());
+        let (message_type, text) = if failures.is_empty() {
+            (MessageType::Info, summary)
+        } else {
+            (
+                MessageType::Warning,
+                format!(
+                    "{}\n\n{}:\n{}",
+                    summary,
+                    gettext("Some matches couldn't be replaced"),
+                    failures.join("\n")
+                ),
+            )
+        };
+        let dialog = MessageDialog::new(
+            window.as_ref(),
+            DialogFlags::MODAL,
+            message_type,
+            ButtonsType::Ok,
+            &text,
+        );
+        dialog.connect_response(|dialog, _| dialog.destroy());
+        dialog.show_all();
+    }
+
+    /// The file of a row and, if it's a match, where the match is
+    fn target_at(&self, path: &TreePath) -> Option<(String, Option<Position>)> {
+        let iter = self.tree_store.get_iter(path)?;
+        let file = self
+            .tree_store
+            .get_value(&iter, COLUMN_PATH as i32)
+            .get::<String>()?;
+        if self.tree_store.iter_depth(&iter) == 0 {
+            return Some((file, None));
+        }
+
+        let line = self
+            .tree_store
+            .get_value(&iter, COLUMN_LINE as i32)
+            .get::<u64>()?;
+        let col = self
+            .tree_store
+            .get_value(&iter, COLUMN_COL as i32)
+            .get::<u64>()?;
+        Some((
+            file,
+            Some(Position {
+                line: LineTarget::Absolute(line + 1),
+                col: Some(col + 1),
+            }),
+        ))
+    }
+}
+
+/// Checks or unchecks the row at `path`. Checking a file row checks all its matches, and a file
+/// row is shown as inconsistent if only some of its matches are checked.
+fn toggle_row(tree_store: &TreeStore, path: &TreePath) {
+    let iter = match tree_store.get_iter(path) {
+        Some(iter) => iter,
+        None => return,
+    };
+    let is_checked = |iter: &TreeIter| {
+        tree_store
+            .get_value(iter, COLUMN_CHECKED as i32)
+            .get::<bool>()
+            .unwrap_or(false)
+    };
+    let inconsistent = tree_store
+        .get_value(&iter, COLUMN_INCONSISTENT as i32)
+        .get::<bool>()
+        .unwrap_or(false);
+    let checked = inconsistent || !is_checked(&iter);
+    tree_store.set(
+        &iter,
+        &[COLUMN_CHECKED, COLUMN_INCONSISTENT],
+        &[&checked, &false],
+    );
+
+    if let Some(child) = tree_store.iter_children(Some(&iter)) {
+        loop {
+            tree_store.set_value(&child, COLUMN_CHECKED, &checked.to_value());
+            if !tree_store.iter_next(&child) {
+                break;
+            }
+        }
+    } else if let Some(parent) = tree_store.iter_parent(&iter) {
+        let n_children = tree_store.iter_n_children(Some(&parent));
+        let mut n_checked = 0;
+        if let Some(child) = tree_store.iter_children(Some(&parent)) {
+            loop {
+                if is_checked(&child) {
+                    n_checked += 1;
+                }
+                if !tree_store.iter_next(&child) {
+                    break;
+                }
+            }
+        }
+        tree_store.set(
+            &parent,
+            &[COLUMN_CHECKED, COLUMN_INCONSISTENT],
+            &[&(n_checked > 0), &(n_checked > 0 && n_checked < n_children)],
+        );
+    }
+}
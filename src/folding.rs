@@ -0,0 +1,370 @@
+use crate::buffer::Buffer;
+
+/// How many lines we look at to find the end of a region, so huge files don't stall drawing
+const MAX_FOLD_LINES: u64 = 10_000;
+
+/// A folded region. `start` stays visible and shows a placeholder, the lines after it up to
+/// and including `end` are hidden.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fold {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Fold {
+    /// How many lines the fold hides
+    pub fn n_hidden(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn hides(&self, line: u64) -> bool {
+        line > self.start && line <= self.end
+    }
+
+    fn overlaps(&self, other: &Fold) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+}
+
+/// Lines an update has kept, which might have moved: `n` lines which were at `old` are at `new`
+/// now.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Copied {
+    pub old: u64,
+    pub new: u64,
+    pub n: u64,
+}
+
+/// The width of the indentation of `text`, or `None` if it's blank
+pub fn indent(text: &str, tab_size: u64) -> Option<u64> {
+    let mut width = 0;
+    for c in text.chars() {
+        match c {
+            ' ' => width += 1,
+            '\t' => width = (width / tab_size + 1) * tab_size,
+            c if c.is_whitespace() => (),
+            _ => return Some(width),
+        }
+    }
+    None
+}
+
+/// How many brackets `text` opens which it doesn't close itself. Closing brackets without an
+/// opening one are ignored.
+fn unclosed_brackets(text: &str) -> usize {
+    text.chars().fold(0, |depth, c| match c {
+        '{' | '[' | '(' => depth + 1,
+        '}' | ']' | ')' => depth.saturating_sub(1),
+        _ => depth,
+    })
+}
+
+/// The line which closes the first bracket `line` leaves open. `None` if there's none or if we
+/// don't know all lines up to it.
+fn closing_bracket_line<B: Buffer>(buf: &B, line: u64) -> Option<u64> {
+    // The region ends once all brackets `line` leaves open are closed again
+    let unclosed = unclosed_brackets(buf.line(line)?);
+    if unclosed == 0 {
+        return None;
+    }
+
+    let mut depth = unclosed as u64;
+    let last = buf.n_lines().min(line + MAX_FOLD_LINES);
+    for n in line + 1..last {
+        for c in buf.line(n)?.chars() {
+            match c {
+                '{' | '[' | '(' => depth += 1,
+                '}' | ']' | ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(n);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    None
+}
+
+/// The region starting at `line` which can be folded. If `line` opens a bracket the region ends
+/// before the line that closes it, otherwise it contains the following lines which are indented
+/// more than `line`. Returns `None` if `line` doesn't start a region or if the lines it covers
+/// aren't in `buf`.
+pub fn foldable_range<B: Buffer>(buf: &B, line: u64, tab_size: u64) -> Option<Fold> {
+    let text = buf.line(line)?;
+    if let Some(close) = closing_bracket_line(buf, line) {
+        return if close > line + 1 {
+            Some(Fold {
+                start: line,
+                end: close - 1,
+            })
+        } else {
+            None
+        };
+    }
+
+    let base = indent(text, tab_size)?;
+    let mut end = None;
+    let last = buf.n_lines().min(line + MAX_FOLD_LINES);
+    for n in line + 1..last {
+        match indent(buf.line(n)?, tab_size) {
+            Some(width) if width > base => end = Some(n),
+            Some(_) => break,
+            // Blank lines belong to the region if it continues after them
+            None => (),
+        }
+    }
+    end.map(|end| Fold { start: line, end })
+}
+
+/// A quick check if `line` starts a region that can be folded, for drawing the markers in the
+/// gutter. Unlike `foldable_range` it only looks at the next non-blank line.
+pub fn can_fold<B: Buffer>(buf: &B, line: u64, tab_size: u64) -> bool {
+    let text = match buf.line(line) {
+        Some(text) => text,
+        None => return false,
+    };
+    if unclosed_brackets(text) > 0 {
+        return true;
+    }
+
+    let base = match indent(text, tab_size) {
+        Some(base) => base,
+        None => return false,
+    };
+    let last = buf.n_lines().min(line + MAX_FOLD_LINES);
+    for n in line + 1..last {
+        match buf.line(n).map(|text| indent(text, tab_size)) {
+            Some(Some(width)) => return width > base,
+            Some(None) => (),
+            None => return false,
+        }
+    }
+    false
+}
+
+/// The folded regions of a document. They're sorted and don't overlap.
+///
+/// Since hidden lines don't take up space, the lines of the document and the rows of the
+/// `EditView` are different things. This maps between them.
+#[derive(Debug, Default)]
+pub struct Folds {
+    folds: Vec<Fold>,
+}
+
+impl Folds {
+    pub fn iter(&self) -> impl Iterator<Item = &Fold> {
+        self.folds.iter()
+    }
+
+    /// Folds `fold`, replacing the folds which overlap it
+    pub fn add(&mut self, fold: Fold) {
+        self.folds.retain(|f| !f.overlaps(&fold));
+        let ix = self
+            .folds
+            .iter()
+            .position(|f| f.start > fold.start)
+            .unwrap_or(self.folds.len());
+        self.folds.insert(ix, fold);
+    }
+
+    /// The fold which starts at `line`
+    pub fn fold_at(&self, line: u64) -> Option<Fold> {
+        self.folds.iter().find(|f| f.start == line).cloned()
+    }
+
+    /// Unfolds the fold starting at `line`. Returns false if there's none.
+    pub fn unfold(&mut self, line: u64) -> bool {
+        let len = self.folds.len();
+        self.folds.retain(|f| f.start != line);
+        self.folds.len() != len
+    }
+
+    /// Unfolds the fold which hides `line`, e.g. because the cursor has moved there. Returns
+    /// false if `line` isn't hidden.
+    pub fn reveal(&mut self, line: u64) -> bool {
+        let len = self.folds.len();
+        self.folds.retain(|f| !f.hides(line));
+        self.folds.len() != len
+    }
+
+    pub fn is_hidden(&self, line: u64) -> bool {
+        self.folds.iter().any(|f| f.hides(line))
+    }
+
+    /// The row `line` is shown in. Hidden lines are in the row of their fold.
+    pub fn line_to_row(&self, line: u64) -> u64 {
+        let mut hidden = 0;
+        for fold in &self.folds {
+            if fold.end < line {
+                hidden += fold.n_hidden();
+            } else if fold.hides(line) {
+                return fold.start - hidden;
+            } else {
+                break;
+            }
+        }
+        line - hidden
+    }
+
+    /// The line shown in `row`
+    pub fn row_to_line(&self, row: u64) -> u64 {
+        let mut line = row;
+        for fold in &self.folds {
+            if fold.start < line {
+                line += fold.n_hidden();
+            } else {
+                break;
+            }
+        }
+        line
+    }
+
+    /// How many rows a document with `n_lines` lines takes up
+    pub fn n_rows(&self, n_lines: u64) -> u64 {
+        let hidden: u64 = self
+            .folds
+            .iter()
+            .filter(|f| f.start < n_lines)
+            .map(|f| f.end.min(n_lines - 1) - f.start)
+            .sum();
+        n_lines - hidden
+    }
+
+    /// Moves the folds along with the lines an update has kept. Folds whose hidden lines haven't
+    /// been kept together, because lines have been inserted or removed between them, are
+    /// unfolded.
+    pub fn apply_update(&mut self, copied: &[Copied]) {
+        let new_line = |line: u64| {
+            copied
+                .iter()
+                .find(|c| line >= c.old && line < c.old + c.n)
+                .map(|c| (line - c.old + c.new) as i64 - line as i64)
+        };
+
+        let mut folds = Vec::with_capacity(self.folds.len());
+        for fold in &self.folds {
+            match (new_line(fold.start + 1), new_line(fold.end)) {
+                (Some(first), Some(last)) if first == last => {
+                    let start = (fold.start as i64 + first) as u64;
+                    folds.push(Fold {
+                        start,
+                        end: start + fold.n_hidden(),
+                    });
+                }
+                _ => (),
+            }
+        }
+        self.folds = folds;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Lines(Vec<&'static str>);
+
+    impl Buffer for Lines {
+        fn line(&self, n: u64) -> Option<&str> {
+            self.0.get(n as usize).cloned()
+        }
+
+        fn n_lines(&self) -> u64 {
+            self.0.len() as u64
+        }
+    }
+
+    #[test]
+    fn test_foldable_range() {
+        let buf = Lines(vec![
+            "fn main() {",
+            "    let x = (1,",
+            "        2);",
+            "",
+            "    if x {}",
+            "}",
+            "def foo():",
+            "\tpass",
+            "",
+            "\treturn",
+            "",
+            "bar = [] + [",
+            "]",
+        ]);
+        let fold = |start, end| Some(Fold { start, end });
+
+        assert_eq!(foldable_range(&buf, 0, 4), fold(0, 4));
+        assert_eq!(foldable_range(&buf, 1, 4), None);
+        assert_eq!(foldable_range(&buf, 4, 4), None);
+        assert_eq!(foldable_range(&buf, 6, 4), fold(6, 9));
+        assert_eq!(foldable_range(&buf, 8, 4), None);
+        assert_eq!(foldable_range(&buf, 11, 4), None);
+        assert_eq!(foldable_range(&buf, 20, 4), None);
+
+        assert!(can_fold(&buf, 0, 4));
+        assert!(can_fold(&buf, 6, 4));
+        assert!(!can_fold(&buf, 4, 4));
+        assert!(!can_fold(&buf, 7, 4));
+    }
+
+    #[test]
+    fn test_rows() {
+        let mut folds = Folds::default();
+        folds.add(Fold { start: 2, end: 5 });
+        folds.add(Fold { start: 10, end: 11 });
+
+        assert_eq!(folds.line_to_row(2), 2);
+        assert_eq!(folds.line_to_row(4), 2);
+        assert_eq!(folds.line_to_row(6), 3);
+        assert_eq!(folds.line_to_row(11), 7);
+        assert_eq!(folds.line_to_row(12), 8);
+        assert_eq!(folds.row_to_line(2), 2);
+        assert_eq!(folds.row_to_line(3), 6);
+        assert_eq!(folds.row_to_line(7), 10);
+        assert_eq!(folds.row_to_line(8), 12);
+        assert_eq!(folds.n_rows(20), 16);
+        assert!(folds.is_hidden(5));
+        assert!(!folds.is_hidden(2));
+
+        // Folding the outer region replaces the inner one
+        folds.add(Fold { start: 1, end: 6 });
+        assert_eq!(folds.iter().count(), 2);
+        assert!(folds.reveal(3));
+        assert!(!folds.reveal(3));
+        assert!(folds.unfold(10));
+        assert_eq!(folds.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_apply_update() {
+        let mut folds = Folds::default();
+        folds.add(Fold { start: 2, end: 5 });
+        folds.add(Fold { start: 10, end: 12 });
+        folds.add(Fold { start: 20, end: 22 });
+
+        // A line has been inserted before the first fold and one inside of the second
+        folds.apply_update(&[
+            Copied {
+                old: 0,
+                new: 0,
+                n: 1,
+            },
+            Copied {
+                old: 1,
+                new: 2,
+                n: 11,
+            },
+            Copied {
+                old: 12,
+                new: 14,
+                n: 20,
+            },
+        ]);
+        assert_eq!(
+            folds.iter().cloned().collect::<Vec<_>>(),
+            vec![Fold { start: 3, end: 6 }, Fold { start: 22, end: 24 }]
+        );
+    }
+}
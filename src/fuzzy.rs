@@ -0,0 +1,153 @@
+use std::cmp::Ordering;
+
+const BONUS_BOUNDARY: i64 = 16;
+const BONUS_CAMEL: i64 = 12;
+const BONUS_CONSECUTIVE: i64 = 16;
+const BONUS_BASENAME: i64 = 10;
+const BONUS_RECENT: i64 = 30;
+const PENALTY_GAP_START: i64 = 3;
+const PENALTY_GAP: i64 = 1;
+const MAX_GAP_PENALTY: i64 = 10;
+
+/// Scores how well `query` matches `candidate`. Every char of the query has to appear in the
+/// candidate in the same order (ignoring case), otherwise `None` is returned. Matches at the
+/// start of words, consecutive matches and matches in the file name (instead of the directory)
+/// get a higher score.
+pub fn score(query: &str, candidate: &str) -> Option<i64> {
+    let chars: Vec<char> = candidate.chars().collect();
+    let query: Vec<char> = query.chars().filter(|c| !c.is_whitespace()).collect();
+    let basename_start = chars
+        .iter()
+        .rposition(|c| *c == '/' || *c == std::path::MAIN_SEPARATOR)
+        .map_or(0, |i| i + 1);
+
+    let first = match query.first() {
+        Some(first) => *first,
+        None => return Some(-(chars.len() as i64 / 8)),
+    };
+
+    // Matching greedily from the first occurrence of the query's first char would miss better
+    // matches later on (e.g. "rpc" in "src/rpc.rs"), so try every occurrence.
+    (0..chars.len())
+        .filter(|i| chars_eq(chars[*i], first))
+        .filter_map(|start| score_from(&chars, basename_start, &query, start))
+        .max()
+        // Prefer shorter paths if everything else is equal
+        .map(|score| score - chars.len() as i64 / 8)
+}
+
+/// Greedily matches `query` against `chars`, starting at `start`.
+fn score_from(chars: &[char], basename_start: usize, query: &[char], start: usize) -> Option<i64> {
+    let mut score = 0;
+    let mut pos = start;
+    let mut prev_match: Option<usize> = None;
+
+    for q in query {
+        let found = (pos..chars.len()).find(|i| chars_eq(chars[*i], *q))?;
+
+        if found == 0 || is_separator(chars[found - 1]) {
+            score += BONUS_BOUNDARY;
+        } else if chars[found].is_uppercase() && chars[found - 1].is_lowercase() {
+            score += BONUS_CAMEL;
+        }
+
+        match prev_match {
+            Some(prev) if prev + 1 == found => score += BONUS_CONSECUTIVE,
+            Some(prev) => {
+                score -= PENALTY_GAP_START
+                    + std::cmp::min((found - prev - 1) as i64 * PENALTY_GAP, MAX_GAP_PENALTY)
+            }
+            None => score -= std::cmp::min(found as i64 * PENALTY_GAP, MAX_GAP_PENALTY),
+        }
+
+        if found >= basename_start {
+            score += BONUS_BASENAME;
+        }
+
+        prev_match = Some(found);
+        pos = found + 1;
+    }
+
+    Some(score)
+}
+
+fn chars_eq(a: char, b: char) -> bool {
+    a == b || a.to_lowercase().eq(b.to_lowercase())
+}
+
+fn is_separator(c: char) -> bool {
+    match c {
+        '/' | '\\' | '_' | '-' | '.' | ' ' => true,
+        _ => false,
+    }
+}
+
+/// Returns the indices of the `candidates` matching `query`, best match first. Candidates which
+/// are part of `recent` (most recently used first) are ranked higher.
+pub fn rank(query: &str, candidates: &[String], recent: &[String]) -> Vec<usize> {
+    let recency_bonus = |candidate: &String| {
+        recent
+            .iter()
+            .position(|r| r == candidate)
+            .map_or(0, |i| BONUS_RECENT - std::cmp::min(i as i64, BONUS_RECENT))
+    };
+
+    let mut matches: Vec<(i64, usize)> = candidates
+        .iter()
+        .enumerate()
+        .filter_map(|(i, candidate)| {
+            score(query, candidate).map(|score| (score + recency_bonus(candidate), i))
+        })
+        .collect();
+
+    matches.sort_by(|(score_a, a), (score_b, b)| match score_b.cmp(score_a) {
+        Ordering::Equal => candidates[*a].cmp(&candidates[*b]),
+        ordering => ordering,
+    });
+
+    matches.into_iter().map(|(_, i)| i).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_score() {
+        assert!(score("mw", "src/main_win.rs").is_some());
+        assert!(score("MW", "src/main_win.rs").is_some());
+        assert!(score("wm", "src/main_win.rs").is_none());
+        assert!(score("xyz", "src/main_win.rs").is_none());
+        assert!(score("", "src/main_win.rs").is_some());
+
+        // Word boundaries beat matches in the middle of words
+        assert!(score("ev", "src/edit_view.rs") > score("ev", "src/prev.rs"));
+        // Consecutive matches beat scattered ones
+        assert!(score("main", "main.rs") > score("main", "m_a_i_n.rs"));
+        // Matches in the file name beat matches in the directory
+        assert!(score("rpc", "src/rpc.rs") > score("rpc", "rpc/config.rs"));
+    }
+
+    #[test]
+    fn test_rank() {
+        let candidates = strings(&[
+            "src/some_markdown_writer.rs",
+            "src/main_win.rs",
+            "README.md",
+        ]);
+        assert_eq!(rank("mw", &candidates, &[]), vec![1, 0]);
+        assert_eq!(rank("readme", &candidates, &[]), vec![2]);
+
+        // Recently used files win if the match quality is similar
+        let candidates = strings(&["src/foo/mod.rs", "src/bar/mod.rs"]);
+        assert_eq!(rank("mod", &candidates, &[]), vec![1, 0]);
+        assert_eq!(
+            rank("mod", &candidates, &strings(&["src/foo/mod.rs"])),
+            vec![0, 1]
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use log::{debug, trace};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A single line of a `.gitignore` file.
+#[derive(Clone, Debug, PartialEq)]
+struct Pattern {
+    glob: String,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl Pattern {
+    /// Parses a line of a `.gitignore`. Returns `None` for comments and blank lines.
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = if line.starts_with('!') {
+            (true, &line[1..])
+        } else {
+            (false, line.trim_start_matches('\\'))
+        };
+
+        let (dir_only, line) = if line.ends_with('/') {
+            (true, line.trim_end_matches('/'))
+        } else {
+            (false, line)
+        };
+
+        if line.is_empty() {
+            return None;
+        }
+
+        // A pattern containing a slash (other than a trailing one) is relative to the root
+        let anchored = line.contains('/');
+
+        Some(Self {
+            glob: line.trim_start_matches('/').to_string(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, rel_path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        if self.anchored {
+            glob_match(self.glob.as_bytes(), rel_path.as_bytes())
+        } else {
+            let name = rel_path.rsplit('/').next().unwrap_or(rel_path);
+            glob_match(self.glob.as_bytes(), name.as_bytes())
+        }
+    }
+}
+
+/// Matches `text` against a gitignore style glob. `*` and `?` don't match `/`, `**` matches
+/// everything (including nothing at all if it's followed by a `/`).
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            if rest.first() == Some(&b'/') {
+                // `**/` matches zero or more directories
+                let rest = &rest[1..];
+                glob_match(rest, text)
+                    || text
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, c)| **c == b'/')
+                        .any(|(i, _)| glob_match(rest, &text[i + 1..]))
+            } else {
+                (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+            }
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => match text.first() {
+            Some(c) if *c != b'/' => glob_match(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'[') => {
+            if let Some(end) = pattern.iter().skip(1).position(|c| *c == b']') {
+                let class = &pattern[1..=end];
+                let rest = &pattern[end + 2..];
+                match text.first() {
+                    Some(c) if *c != b'/' && class_matches(class, *c) => {
+                        glob_match(rest, &text[1..])
+                    }
+                    _ => false,
+                }
+            } else {
+                text.first() == Some(&b'[') && glob_match(&pattern[1..], &text[1..])
+            }
+        }
+        Some(b'\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &text[1..])
+        }
+        Some(p) => text.first() == Some(p) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Checks if `c` is part of a character class like `[a-z]` or `[!abc]`
+fn class_matches(class: &[u8], c: u8) -> bool {
+    let (negated, class) = match class.first() {
+        Some(b'!') | Some(b'^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            if class[i] <= c && c <= class[i + 2] {
+                found = true;
+            }
+            i += 3;
+        } else {
+            if class[i] == c {
+                found = true;
+            }
+            i += 1;
+        }
+    }
+
+    found != negated
+}
+
+/// Decides which files of a project should be hidden from the user, following the rules in
+/// the `.gitignore`s of the project. Every directory's `.gitignore` applies to the paths below
+/// it and overrides the ones of its parents. The `.git` directory itself is always ignored.
+#[derive(Clone, Debug)]
+pub struct GitIgnore {
+    root: PathBuf,
+    /// The patterns of the `.gitignore`s we've read so far, by the directory they're in
+    patterns: HashMap<PathBuf, Vec<Pattern>>,
+}
+
+impl GitIgnore {
+    /// Reads the `.gitignore` in `root`, if there is one. The ones in subdirectories are read
+    /// once we get to them.
+    pub fn new(root: &Path) -> Self {
+        let mut gitignore = Self {
+            root: root.to_path_buf(),
+            patterns: HashMap::new(),
+        };
+        gitignore.load(root);
+        gitignore
+    }
+
+    pub fn from_str(root: &Path, contents: &str) -> Self {
+        let mut gitignore = Self {
+            root: root.to_path_buf(),
+            patterns: HashMap::new(),
+        };
+        gitignore.insert(root, contents);
+        gitignore
+    }
+
+    fn insert(&mut self, dir: &Path, contents: &str) {
+        self.patterns.insert(
+            dir.to_path_buf(),
+            contents.lines().filter_map(Pattern::parse).collect(),
+        );
+    }
+
+    /// Reads the `.gitignore`s of `dir` and its parents up to the root, unless we've done so
+    /// already.
+    fn load(&mut self, dir: &Path) {
+        if !dir.starts_with(&self.root) {
+            return;
+        }
+        for dir in dir.ancestors() {
+            if !self.patterns.contains_key(dir) {
+                let contents = fs::read_to_string(dir.join(".gitignore")).unwrap_or_default();
+                debug!("Loaded .gitignore of {:?}", dir);
+                self.insert(dir, &contents);
+            }
+            if dir == self.root {
+                break;
+            }
+        }
+    }
+
+    /// Forgets the `.gitignore` of `dir`, so it's read again the next time it's needed.
+    pub fn reload(&mut self, dir: &Path) {
+        self.patterns.remove(dir);
+    }
+
+    /// Checks if `path` (or one of its parent directories) is ignored.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let rel_path = match path.strip_prefix(&self.root) {
+            Ok(p) => p,
+            Err(_) => return false,
+        };
+        let components: Vec<_> = rel_path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+
+        if components.iter().any(|c| c == ".git") {
+            return true;
+        }
+
+        // If a parent directory is excluded, git doesn't allow re-including its children
+        (1..=components.len())
+            .any(|n| self.matches(&components[..n], n < components.len() || is_dir))
+    }
+
+    /// The `.gitignore` closest to the path decides, and within it later patterns override
+    /// earlier ones, so the last matching pattern wins.
+    fn matches(&self, components: &[String], is_dir: bool) -> bool {
+        for depth in (0..components.len()).rev() {
+            let dir = components[..depth]
+                .iter()
+                .fold(self.root.clone(), |dir, c| dir.join(c));
+            let pattern = self.patterns.get(&dir).and_then(|patterns| {
+                let rel_path = components[depth..].join("/");
+                patterns.iter().rev().find(|p| p.matches(&rel_path, is_dir))
+            });
+            if let Some(pattern) = pattern {
+                return !pattern.negated;
+            }
+        }
+        false
+    }
+
+    /// Returns the entries of `dir` which aren't ignored, directories first and sorted by name.
+    pub fn read_dir(&mut self, dir: &Path) -> Vec<(PathBuf, bool)> {
+        self.load(dir);
+        let mut entries: Vec<(PathBuf, bool)> = match fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(Result::ok)
+                .map(|e| {
+                    let is_dir = e.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                    (e.path(), is_dir)
+                })
+                .filter(|(path, is_dir)| !self.is_ignored(path, *is_dir))
+                .collect(),
+            Err(e) => {
+                debug!("Failed to read dir {:?}: {}", dir, e);
+                Vec::new()
+            }
+        };
+
+        entries.sort_by(|(a, a_dir), (b, b_dir)| {
+            b_dir.cmp(a_dir).then_with(|| {
+                a.file_name()
+                    .map(|n| n.to_string_lossy().to_lowercase())
+                    .cmp(&b.file_name().map(|n| n.to_string_lossy().to_lowercase()))
+            })
+        });
+
+        entries
+    }
+
+    /// Recursively collects all files below the root which aren't ignored.
+    pub fn walk(&mut self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        let mut dirs = vec![self.root.clone()];
+
+        while let Some(dir) = dirs.pop() {
+            trace!("Walking {:?}", dir);
+            for (path, is_dir) in self.read_dir(&dir) {
+                if is_dir {
+                    dirs.push(path);
+                } else {
+                    files.push(path);
+                }
+            }
+        }
+
+        files
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        assert!(glob_match(b"*.rs", b"main.rs"));
+        assert!(!glob_match(b"*.rs", b"src/main.rs"));
+        assert!(glob_match(b"**/*.rs", b"src/main.rs"));
+        assert!(glob_match(b"**/*.rs", b"main.rs"));
+        assert!(glob_match(b"src/**", b"src/a/b"));
+        assert!(glob_match(b"a/**/b", b"a/b"));
+        assert!(glob_match(b"a/**/b", b"a/x/y/b"));
+        assert!(glob_match(b"file?.[ch]", b"file1.c"));
+        assert!(!glob_match(b"file?.[!ch]", b"file1.c"));
+        assert!(glob_match(b"[a-c]x", b"bx"));
+    }
+
+    #[test]
+    fn test_gitignore() {
+        let root = Path::new("/project");
+        let gitignore = GitIgnore::from_str(
+            root,
+            "# build output\n/target\n*.log\n!keep.log\nnode_modules/\ndocs/*.html\n",
+        );
+
+        assert!(gitignore.is_ignored(&root.join("target"), true));
+        assert!(gitignore.is_ignored(&root.join("target/debug/gxi"), false));
+        assert!(!gitignore.is_ignored(&root.join("src/target"), true));
+        assert!(gitignore.is_ignored(&root.join("src/debug.log"), false));
+        assert!(!gitignore.is_ignored(&root.join("keep.log"), false));
+        assert!(gitignore.is_ignored(&root.join("a/node_modules/x.js"), false));
+        assert!(!gitignore.is_ignored(&root.join("node_modules"), false));
+        assert!(gitignore.is_ignored(&root.join("docs/index.html"), false));
+        assert!(!gitignore.is_ignored(&root.join("docs/api/index.html"), false));
+        assert!(gitignore.is_ignored(&root.join(".git/HEAD"), false));
+        assert!(!gitignore.is_ignored(&root.join("src/main.rs"), false));
+    }
+
+    #[test]
+    fn test_nested_gitignore() {
+        let root = Path::new("/project");
+        let mut gitignore = GitIgnore::from_str(root, "*.log\n/build\n");
+        gitignore.insert(&root.join("web"), "dist/\n!debug.log\n/build\n");
+
+        assert!(gitignore.is_ignored(&root.join("web/dist/app.js"), false));
+        assert!(!gitignore.is_ignored(&root.join("dist/app.js"), false));
+        assert!(gitignore.is_ignored(&root.join("error.log"), false));
+        assert!(gitignore.is_ignored(&root.join("web/error.log"), false));
+        assert!(!gitignore.is_ignored(&root.join("web/debug.log"), false));
+        assert!(gitignore.is_ignored(&root.join("debug.log"), false));
+        // Patterns with a slash are relative to the directory of their .gitignore
+        assert!(gitignore.is_ignored(&root.join("web/build"), true));
+        assert!(!gitignore.is_ignored(&root.join("web/src/build"), true));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::edit_view::EditView;
+use gettextrs::gettext;
+use gtk::*;
+use log::debug;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// The line part of a "Go to line" query.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LineTarget {
+    /// A line number as displayed in the gutter (1-based)
+    Absolute(u64),
+    /// A jump relative to the line the cursor is on, e.g. `+20` or `-5`
+    Relative(i64),
+}
+
+/// A location the user wants to jump to, parsed from `line[:col]`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    pub line: LineTarget,
+    /// The column, 1-based and counted in chars
+    pub col: Option<u64>,
+}
+
+impl Position {
+    /// Parses `120`, `120:4`, `+20`, `-5` and `-5:3`. Returns `None` if `s` isn't a position.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let mut parts = s.splitn(2, ':');
+        let line = parts.next()?.trim();
+        let col = match parts.next() {
+            Some(col) => Some(col.trim().parse::<u64>().ok()?),
+            None => None,
+        };
+
+        let line = if line.starts_with('+') || line.starts_with('-') {
+            // `parse::<i64>` would accept `+20`, but also `+-20`, so check the sign ourselves
+            let digits = &line[1..];
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            let n = digits.parse::<i64>().ok()?;
+            LineTarget::Relative(if line.starts_with('-') { -n } else { n })
+        } else {
+            LineTarget::Absolute(line.parse::<u64>().ok()?)
+        };
+
+        Some(Self { line, col })
+    }
+
+    /// Resolves the position to a 0-based (line, col) pair, clamping the line to the
+    /// `n_lines` of the document. `current_line` is the 0-based line the cursor is on.
+    pub fn resolve(&self, current_line: u64, n_lines: u64) -> (u64, u64) {
+        let last_line = n_lines.saturating_sub(1) as i64;
+        let line = match self.line {
+            LineTarget::Absolute(line) => line as i64 - 1,
+            LineTarget::Relative(offset) => current_line as i64 + offset,
+        };
+        let line = if line < 0 {
+            0
+        } else if line > last_line {
+            last_line
+        } else {
+            line
+        };
+
+        (line as u64, self.col.unwrap_or(1).saturating_sub(1))
+    }
+}
+
+/// Splits a file name given on the command line like `main.rs:120:4` into the file name and
+/// the position to jump to. If there's no position in `arg` it's returned unchanged.
+pub fn split_file_position(arg: &str) -> (&str, Option<Position>) {
+    // Try the longer `file:line:col` form first, then `file:line`
+    for n in &[2, 1] {
+        let mut split = arg.rsplitn(n + 1, ':');
+        let positions: Vec<&str> = (&mut split).take(*n).collect();
+        if let Some(file) = split.next() {
+            if file.is_empty()
+                || positions
+                    .iter()
+                    .any(|p| p.starts_with('+') || p.starts_with('-'))
+            {
+                continue;
+            }
+            let position: Vec<&str> = positions.into_iter().rev().collect();
+            if let Some(position) = Position::parse(&position.join(":")) {
+                return (file, Some(position));
+            }
+        }
+    }
+
+    (arg, None)
+}
+
+/// The "Go to line" popover (Ctrl+L) of an `EditView`
+#[derive(Clone)]
+pub struct GotoLine {
+    pub popover: Popover,
+    entry: Entry,
+}
+
+impl GotoLine {
+    pub fn new(relative_to: &DrawingArea) -> Self {
+        let popover = Popover::new(Some(relative_to));
+        popover.set_position(PositionType::Bottom);
+
+        let entry = Entry::new();
+        entry.set_placeholder_text(gettext("Line[:Column], e.g. 120:4 or +20").as_str());
+        entry.set_width_chars(30);
+        entry.set_activates_default(false);
+
+        let label = Label::new(Some(gettext("Go to line").as_str()));
+        let hbox = Box::new(Orientation::Horizontal, 6);
+        hbox.set_border_width(6);
+        hbox.pack_start(&label, false, false, 0);
+        hbox.pack_start(&entry, true, true, 0);
+        hbox.show_all();
+        popover.add(&hbox);
+
+        Self { popover, entry }
+    }
+
+    /// Sets up event listeners
+    pub fn connect_events(&self, ev: &Rc<RefCell<EditView>>) {
+        let popover = self.popover.clone();
+        self.entry.connect_activate(clone!(ev => move |w| {
+            let text = w.get_text().map(|s| s.to_string()).unwrap_or_default();
+            if let Some(position) = Position::parse(&text) {
+                debug!("{}: {:?}", gettext("Going to position"), position);
+                popover.popdown();
+                ev.borrow_mut().goto_position(position);
+            } else {
+                w.get_style_context().add_class("error");
+            }
+        }));
+
+        self.entry.connect_changed(|w| {
+            w.get_style_context().remove_class("error");
+        });
+
+        self.popover.connect_closed(clone!(ev => move |_| {
+            if let Ok(ev) = ev.try_borrow() {
+                ev.view_item.edit_area.grab_focus();
+            }
+        }));
+    }
+
+    /// Shows the popover centered at the top of the editing area
+    pub fn show(&self, edit_area: &DrawingArea) {
+        let rect = gdk::Rectangle {
+            x: edit_area.get_allocated_width() / 2,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        self.popover.set_pointing_to(&rect);
+        self.entry.set_text("");
+        self.popover.popup();
+        self.entry.grab_focus();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Position::parse("120"),
+            Some(Position {
+                line: LineTarget::Absolute(120),
+                col: None
+            })
+        );
+        assert_eq!(
+            Position::parse(" 120:4 "),
+            Some(Position {
+                line: LineTarget::Absolute(120),
+                col: Some(4)
+            })
+        );
+        assert_eq!(
+            Position::parse("+20"),
+            Some(Position {
+                line: LineTarget::Relative(20),
+                col: None
+            })
+        );
+        assert_eq!(
+            Position::parse("-5:3"),
+            Some(Position {
+                line: LineTarget::Relative(-5),
+                col: Some(3)
+            })
+        );
+        assert_eq!(Position::parse(""), None);
+        assert_eq!(Position::parse("+"), None);
+        assert_eq!(Position::parse("+-5"), None);
+        assert_eq!(Position::parse("12:"), None);
+        assert_eq!(Position::parse("abc"), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let pos = |s| Position::parse(s).unwrap();
+        assert_eq!(pos("120:4").resolve(0, 200), (119, 3));
+        assert_eq!(pos("120").resolve(0, 50), (49, 0));
+        assert_eq!(pos("0").resolve(10, 50), (0, 0));
+        assert_eq!(pos("+20").resolve(10, 50), (30, 0));
+        assert_eq!(pos("-5").resolve(3, 50), (0, 0));
+        assert_eq!(pos("+5").resolve(3, 0), (0, 0));
+    }
+
+    #[test]
+    fn test_split_file_position() {
+        let pos = |s| Position::parse(s);
+        assert_eq!(
+            split_file_position("main.rs:120:4"),
+            ("main.rs", pos("120:4"))
+        );
+        assert_eq!(split_file_position("main.rs:120"), ("main.rs", pos("120")));
+        assert_eq!(split_file_position("main.rs"), ("main.rs", None));
+        assert_eq!(split_file_position("a:b.rs:7"), ("a:b.rs", pos("7")));
+        assert_eq!(split_file_position("main.rs:+5"), ("main.rs:+5", None));
+        assert_eq!(split_file_position(":5"), (":5", None));
+    }
+}
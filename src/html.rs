@@ -0,0 +1,417 @@
+use crate::linecache::Line;
+use crate::theme::{color_from_u32, LineStyle};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use syntect::highlighting::{Color, ThemeSettings};
+
+/// What to put into an exported HTML document besides the text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HtmlOptions {
+    pub line_numbers: bool,
+    /// Use the background and foreground of the theme instead of the browser's colours
+    pub background: bool,
+}
+
+/// A line of the document with the syntax styles of its text as `(start, end, style)` byte
+/// ranges. If xi wraps lines, it sends the parts of a line as separate lines, which are joined
+/// again here.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StyledLine {
+    pub number: u64,
+    pub text: String,
+    pub spans: Vec<(usize, usize, usize)>,
+}
+
+/// Joins the lines of the `LineCache` into `StyledLine`s for exporting or printing them, leaving
+/// out the selection and find highlights
+pub fn join_lines<'a, I>(lines: I) -> Vec<StyledLine>
+where
+    I: IntoIterator<Item = &'a Line>,
+{
+    let mut joined: Vec<StyledLine> = Vec::new();
+    for line in lines {
+        let text = line.text().trim_end_matches(|c| c == '\n' || c == '\r');
+        let offset = match (line.line_num(), joined.last()) {
+            (None, Some(last)) => last.text.len(),
+            (number, _) => {
+                joined.push(StyledLine {
+                    number: number.unwrap_or(joined.len() as u64 + 1),
+                    text: String::new(),
+                    spans: Vec::new(),
+                });
+                0
+            }
+        };
+        let last = joined.last_mut().unwrap();
+        last.text.push_str(text);
+        last.spans.extend(
+            line.style_spans()
+                .into_iter()
+                .map(|(start, end, id)| (start, end.min(text.len()), id))
+                .filter(|&(start, end, id)| id > 1 && start < end)
+                .map(|(start, end, id)| (start + offset, end + offset, id)),
+        );
+    }
+    joined
+}
+
+/// Escapes the chars which have a meaning in HTML
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A colour as CSS, e.g. `#ff8000`
+pub fn css_color(c: Color) -> String {
+    if c.a == 255 {
+        format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b)
+    } else {
+        format!(
+            "rgba({}, {}, {}, {:.3})",
+            c.r,
+            c.g,
+            c.b,
+            f64::from(c.a) / 255.0
+        )
+    }
+}
+
+/// The CSS declarations for a style xi has sent us, e.g. `color: #ff8000; font-style: italic;`
+pub fn css(style: &LineStyle) -> String {
+    let mut css = Vec::new();
+    if let Some(fg) = style.fg_color {
+        css.push(format!("color: {};", css_color(color_from_u32(fg))));
+    }
+    if let Some(bg) = style.bg_color {
+        css.push(format!(
+            "background-color: {};",
+            css_color(color_from_u32(bg))
+        ));
+    }
+    if let Some(weight) = style.weight {
+        css.push(format!("font-weight: {};", weight));
+    }
+    if style.italic == Some(true) {
+        css.push("font-style: italic;".to_string());
+    }
+    if style.underline == Some(true) {
+        css.push("text-decoration: underline;".to_string());
+    }
+    css.join(" ")
+}
+
+/// Writes the escaped text of `line` to `out`, wrapping each styled span in a `<span>` with the
+/// attributes `attributes` returns for its style
+pub fn write_line<F>(out: &mut String, line: &StyledLine, attributes: F)
+where
+    F: Fn(usize) -> Option<String>,
+{
+    let mut ix = 0;
+    for &(start, end, id) in &line.spans {
+        if start < ix {
+            continue;
+        }
+        let (before, styled) = match (line.text.get(ix..start), line.text.get(start..end)) {
+            (Some(before), Some(styled)) => (before, styled),
+            _ => continue,
+        };
+        out.push_str(&escape(before));
+        match attributes(id) {
+            Some(attributes) => {
+                let _ = write!(out, "<span {}>{}</span>", attributes, escape(styled));
+            }
+            None => out.push_str(&escape(styled)),
+        }
+        ix = end;
+    }
+    out.push_str(&escape(line.text.get(ix..).unwrap_or_default()));
+}
+
+/// A standalone HTML document of `lines`, highlighted with the `styles` of the theme
+pub fn document(
+    title: &str,
+    lines: &[StyledLine],
+    styles: &HashMap<usize, LineStyle>,
+    theme: &ThemeSettings,
+    font_family: &str,
+    options: HtmlOptions,
+) -> String {
+    let mut out = String::new();
+    out.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    let _ = writeln!(out, "<title>{}</title>", escape(title));
+
+    out.push_str("<style>\n");
+    let _ = writeln!(
+        out,
+        "pre {{ font-family: \"{}\", monospace; }}",
+        font_family.replace('"', "")
+    );
+    if options.background {
+        let mut body = Vec::new();
+        if let Some(bg) = theme.background {
+            body.push(format!("background-color: {};", css_color(bg)));
+        }
+        if let Some(fg) = theme.foreground {
+            body.push(format!("color: {};", css_color(fg)));
+        }
+        let _ = writeln!(out, "body {{ {} }}", body.join(" "));
+    }
+    if options.line_numbers {
+        let color = theme
+            .gutter_foreground
+            .filter(|_| options.background)
+            .map_or_else(
+                || "opacity: 0.5;".to_string(),
+                |fg| format!("color: {};", css_color(fg)),
+            );
+        let _ = writeln!(out, ".ln {{ {} user-select: none; }}", color);
+    }
+    // Only the styles which are actually used
+    let ids: BTreeSet<usize> = lines
+        .iter()
+        .flat_map(|l| l.spans.iter().map(|&(_, _, id)| id))
+        .collect();
+    for id in ids {
+        if let Some(style) = styles.get(&id) {
+            let _ = writeln!(out, ".s{} {{ {} }}", id, css(style));
+        }
+    }
+    out.push_str("</style>\n</head>\n<body>\n<pre>");
+
+    let digits = lines.last().map_or(1, |l| l.number.to_string().len());
+    for line in lines {
+        if options.line_numbers {
+            let _ = write!(
+                out,
+                "<span class=\"ln\">{:>width$} </span>",
+                line.number,
+                width = digits
+            );
+        }
+        write_line(&mut out, line, |id| {
+            if styles.contains_key(&id) {
+                Some(format!("class=\"s{}\"", id))
+            } else {
+                None
+            }
+        });
+        out.push('\n');
+    }
+    out.push_str("</pre>\n</body>\n</html>\n");
+    out
+}
+
+/// The selected parts of `lines`, which xi styles with style 0, joined together with the
+/// syntax styles of their text, e.g. for copying them with their highlighting
+pub fn selected_text<'a, I>(lines: I) -> StyledLine
+where
+    I: IntoIterator<Item = &'a Line>,
+{
+    let mut selection = StyledLine {
+        number: 0,
+        text: String::new(),
+        spans: Vec::new(),
+    };
+    for line in lines {
+        let spans = line.style_spans();
+        for &(sel_start, sel_end, id) in &spans {
+            if id != 0 || sel_start >= sel_end {
+                continue;
+            }
+            let text = match line.text().get(sel_start..sel_end) {
+                Some(text) => text,
+                None => continue,
+            };
+            let offset = selection.text.len();
+            selection.text.push_str(text);
+            selection.spans.extend(
+                spans
+                    .iter()
+                    .filter(|&&(_, _, id)| id > 1)
+                    .map(|&(start, end, id)| (start.max(sel_start), end.min(sel_end), id))
+                    .filter(|&(start, end, _)| start < end)
+                    .map(|(start, end, id)| {
+                        (start - sel_start + offset, end - sel_start + offset, id)
+                    }),
+            );
+        }
+    }
+    selection
+}
+
+/// An HTML fragment of `selection` for the clipboard. The styles are inlined, since the apps
+/// we paste into usually drop stylesheets.
+pub fn fragment(
+    selection: &StyledLine,
+    styles: &HashMap<usize, LineStyle>,
+    theme: &ThemeSettings,
+    font_family: &str,
+) -> String {
+    let mut pre = vec![format!(
+        "font-family: &quot;{}&quot;, monospace;",
+        escape(&font_family.replace('"', ""))
+    )];
+    if let Some(bg) = theme.background {
+        pre.push(format!("background-color: {};", css_color(bg)));
+    }
+    if let Some(fg) = theme.foreground {
+        pre.push(format!("color: {};", css_color(fg)));
+    }
+
+    let mut out = format!("<meta charset=\"utf-8\"><pre style=\"{}\">", pre.join(" "));
+    write_line(&mut out, selection, |id| {
+        styles
+            .get(&id)
+            .map(|style| format!("style=\"{}\"", css(style)))
+    });
+    out.push_str("</pre>");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn styles() -> HashMap<usize, LineStyle> {
+        let mut styles = HashMap::new();
+        styles.insert(
+            2,
+            LineStyle {
+                fg_color: Some(0xffff_8000),
+                bg_color: None,
+                weight: Some(700),
+                italic: Some(true),
+                underline: None,
+            },
+        );
+        styles
+    }
+
+    #[test]
+    fn test_join_lines() {
+        let lines = vec![
+            Line::from_json(
+                &json!({"text": "let a", "ln": 1, "styles": [0, 3, 2, 0, 5, 0]}),
+                Some(1),
+            ),
+            Line::from_json(&json!({"text": " = \"<b>\";\n", "styles": [3, 5, 2]}), None),
+            Line::from_json(&json!({"text": "\n", "ln": 2}), Some(2)),
+        ];
+        assert_eq!(
+            join_lines(&lines),
+            vec![
+                StyledLine {
+                    number: 1,
+                    text: "let a = \"<b>\";".to_string(),
+                    spans: vec![(0, 3, 2), (8, 13, 2)],
+                },
+                StyledLine {
+                    number: 2,
+                    text: String::new(),
+                    spans: Vec::new(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_document() {
+        let lines = vec![
+            StyledLine {
+                number: 9,
+                text: "let s = \"<b>\";".to_string(),
+                spans: vec![(0, 3, 2), (8, 13, 3)],
+            },
+            StyledLine {
+                number: 10,
+                text: "}".to_string(),
+                spans: Vec::new(),
+            },
+        ];
+        let theme = ThemeSettings {
+            background: Some(Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 255,
+            }),
+            ..ThemeSettings::default()
+        };
+        let options = HtmlOptions {
+            line_numbers: true,
+            background: true,
+        };
+        let html = document("a<b", &lines, &styles(), &theme, "Mono", options);
+
+        assert!(html.contains("<title>a&lt;b</title>"));
+        assert!(html.contains("body { background-color: #000000; }"));
+        assert!(html.contains(".s2 { color: #ff8000; font-weight: 700; font-style: italic; }"));
+        assert!(!html.contains(".s3"));
+        assert!(html.contains(
+            "<span class=\"ln\"> 9 </span><span class=\"s2\">let</span> s = &quot;&lt;b&gt;&quot;;\n"
+        ));
+        assert!(html.contains("<span class=\"ln\">10 </span>}\n"));
+
+        let html = document(
+            "",
+            &lines,
+            &styles(),
+            &theme,
+            "Mono",
+            HtmlOptions {
+                line_numbers: false,
+                background: false,
+            },
+        );
+        assert!(!html.contains("body {"));
+        assert!(!html.contains("class=\"ln\""));
+    }
+
+    #[test]
+    fn test_selected_text() {
+        let lines = vec![
+            // "a" and the line ending are selected, "let" has style 2
+            Line::from_json(
+                &json!({"text": "let a\n", "styles": [0, 3, 2, 1, 2, 0]}),
+                Some(1),
+            ),
+            // "b <c" is selected, "<c" has style 2
+            Line::from_json(
+                &json!({"text": "b <c>\n", "styles": [0, 4, 0, -2, 2, 2]}),
+                Some(2),
+            ),
+        ];
+        let selection = selected_text(&lines);
+        assert_eq!(selection.text, "a\nb <c");
+        assert_eq!(selection.spans, vec![(4, 6, 2)]);
+
+        let html = fragment(&selection, &styles(), &ThemeSettings::default(), "Mono");
+        assert_eq!(
+            html,
+            "<meta charset=\"utf-8\"><pre style=\"font-family: &quot;Mono&quot;, monospace;\">a\nb \
+             <span style=\"color: #ff8000; font-weight: 700; font-style: italic;\">&lt;c</span></pre>"
+        );
+    }
+
+    #[test]
+    fn test_css_color() {
+        let color = |a| Color {
+            r: 255,
+            g: 16,
+            b: 0,
+            a,
+        };
+        assert_eq!(css_color(color(255)), "#ff1000");
+        assert_eq!(css_color(color(0)), "rgba(255, 16, 0, 0.000)");
+    }
+}
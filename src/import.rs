@@ -0,0 +1,65 @@
+use crate::errors::Error;
+use std::fs;
+use std::path::Path;
+use syntect::highlighting::ThemeSet;
+
+/// The dir in the config dir xi loads the user's `.tmTheme` files from. xi names the themes
+/// after their file names and tells us about new ones with `available_themes`.
+pub const THEMES_DIR: &str = "themes";
+
+/// Checks that `file` is a theme syntect can parse and copies it into the themes dir in
+/// `config_dir`. Returns the name the theme shows up as.
+pub fn import(config_dir: &Path, file: &Path) -> Result<String, Error> {
+    let file_name = file
+        .file_name()
+        .ok_or_else(|| Error::Import(format!("'{}' isn't a file", file.display())))?;
+    let extension = file
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if extension != "tmtheme" {
+        return Err(Error::Import(format!(
+            "'{}' isn't a .tmTheme file",
+            file.display()
+        )));
+    }
+
+    ThemeSet::get_theme(file).map_err(|e| Error::Import(e.to_string()))?;
+    let name = file.file_stem().unwrap_or(file_name).to_string_lossy();
+
+    let dir = config_dir.join(THEMES_DIR);
+    fs::create_dir_all(&dir)?;
+    fs::copy(file, dir.join(file_name))?;
+    Ok(name.into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_import() {
+        let src = tempdir().unwrap();
+        let config_dir = tempdir().unwrap();
+
+        // Broken files aren't copied
+        let theme = src.path().join("Broken.tmTheme");
+        fs::write(&theme, "<plist>").unwrap();
+        assert!(import(config_dir.path(), &theme).is_err());
+        assert!(!config_dir
+            .path()
+            .join(THEMES_DIR)
+            .join("Broken.tmTheme")
+            .exists());
+
+        // xi doesn't load syntax definitions from the config dir
+        let syntax = src.path().join("foo.sublime-syntax");
+        fs::write(&syntax, "%YAML 1.2\n---\nname: Foo\nscope: source.foo\n").unwrap();
+        assert!(import(config_dir.path(), &syntax).is_err());
+
+        let text = src.path().join("notes.txt");
+        fs::write(&text, "").unwrap();
+        assert!(import(config_dir.path(), &text).is_err());
+    }
+}
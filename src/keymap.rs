@@ -0,0 +1,481 @@
+use crate::errors::Error;
+use log::{debug, trace};
+use serde_derive::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// The keybindings gxi ships with
+pub const DEFAULT_KEYMAP: &str = include_str!("keymaps/default.toml");
+/// The bindings of the Emacs preset, applied on top of the default keymap
+pub const EMACS_KEYMAP: &str = include_str!("keymaps/emacs.toml");
+
+/// Accelerators actions get if the keymap doesn't bind them to any key, as long as the key isn't
+/// used for something else
+const FALLBACK_ACCELS: &[(&str, &str)] = &[("app.print", "<Primary>p")];
+
+/// The commands of the `EditView` which can be bound to keys, apart from `app.*` actions.
+pub const EDITOR_COMMANDS: &[&str] = &[
+    "add_selection_above",
+    "add_selection_below",
+    "copy",
+    "copy_region",
+    "cut",
+    "delete_backward",
+    "delete_forward",
+    "go_to_matching_bracket",
+    "indent",
+    "insert_newline",
+    "insert_tab",
+    "keyboard_quit",
+    "kill_line",
+    "kill_region",
+    "move_down",
+    "move_down_and_modify_selection",
+    "move_left",
+    "move_left_and_modify_selection",
+    "move_right",
+    "move_right_and_modify_selection",
+    "move_to_beginning_of_document",
+    "move_to_beginning_of_document_and_modify_selection",
+    "move_to_end_of_document",
+    "move_to_end_of_document_and_modify_selection",
+    "move_to_left_end_of_line",
+    "move_to_left_end_of_line_and_modify_selection",
+    "move_to_right_end_of_line",
+    "move_to_right_end_of_line_and_modify_selection",
+    "move_up",
+    "move_up_and_modify_selection",
+    "move_word_left",
+    "move_word_left_and_modify_selection",
+    "move_word_right",
+    "move_word_right_and_modify_selection",
+    "outdent",
+    "page_down",
+    "page_down_and_modify_selection",
+    "page_up",
+    "page_up_and_modify_selection",
+    "paste",
+    "redo",
+    "select_all",
+    "select_all_occurrences",
+    "select_next_occurrence",
+    "selection_into_lines",
+    "set_mark",
+    "toggle_fold",
+    "undo",
+    "yank",
+    "yank_pop",
+];
+
+/// The modifiers held down while pressing a key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+    pub super_: bool,
+}
+
+/// A single key press, e.g. `<Primary><Shift>z`. `key` is the name GDK uses for the key
+/// (see `gdk::keyval_name`), letters are always lower case.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct KeyChord {
+    pub key: String,
+    pub mods: Modifiers,
+}
+
+impl KeyChord {
+    pub fn new(key: &str, mods: Modifiers) -> Self {
+        let mut mods = mods;
+        let mut chars = key.chars();
+        let key = match (chars.next(), chars.next()) {
+            // `Z` is the same as `<Shift>z`
+            (Some(c), None) if c.is_uppercase() => {
+                mods.shift = true;
+                c.to_lowercase().collect()
+            }
+            _ => key.to_string(),
+        };
+
+        Self { key, mods }
+    }
+
+    /// Parses a chord in the syntax of GTK accelerators, e.g. `<Primary><Shift>z` or `F9`.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let mut mods = Modifiers::default();
+        let mut rest = s.trim();
+
+        while rest.starts_with('<') {
+            let end = rest
+                .find('>')
+                .ok_or_else(|| Error::Keymap(format!("Unterminated modifier in '{}'", s)))?;
+            match rest[1..end].to_lowercase().as_str() {
+                "primary" | "control" | "ctrl" | "ctl" => mods.ctrl = true,
+                "shift" | "shft" => mods.shift = true,
+                "alt" | "mod1" => mods.alt = true,
+                "super" | "meta" => mods.super_ = true,
+                modifier => {
+                    return Err(Error::Keymap(format!(
+                        "Unknown modifier '{}' in '{}'",
+                        modifier, s
+                    )))
+                }
+            }
+            rest = &rest[end + 1..];
+        }
+
+        if rest.is_empty() || rest.contains(char::is_whitespace) {
+            return Err(Error::Keymap(format!("Invalid key in '{}'", s)));
+        }
+
+        Ok(Self::new(rest, mods))
+    }
+
+    /// Parses a sequence of chords separated by spaces, e.g. `<Primary>k <Primary>u`.
+    pub fn parse_sequence(s: &str) -> Result<Vec<Self>, Error> {
+        let chords = s
+            .split_whitespace()
+            .map(Self::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if chords.is_empty() {
+            Err(Error::Keymap(format!("Empty key sequence '{}'", s)))
+        } else {
+            Ok(chords)
+        }
+    }
+
+    /// Turns the chord back into a GTK accelerator for `set_accels_for_action`.
+    pub fn to_accel(&self) -> String {
+        let mut accel = String::new();
+        if self.mods.ctrl {
+            accel.push_str("<Primary>");
+        }
+        if self.mods.shift {
+            accel.push_str("<Shift>");
+        }
+        if self.mods.alt {
+            accel.push_str("<Alt>");
+        }
+        if self.mods.super_ {
+            accel.push_str("<Super>");
+        }
+        accel.push_str(&self.key);
+        accel
+    }
+}
+
+/// Checks if the key with the GDK name `key` is a modifier. Pressing these on their own
+/// shouldn't interrupt a key sequence.
+pub fn is_modifier_key(key: &str) -> bool {
+    match key {
+        "Shift_L" | "Shift_R" | "Control_L" | "Control_R" | "Alt_L" | "Alt_R" | "Meta_L"
+        | "Meta_R" | "Super_L" | "Super_R" | "Hyper_L" | "Hyper_R" | "Caps_Lock"
+        | "ISO_Level3_Shift" | "ISO_Level5_Shift" | "Num_Lock" => true,
+        _ => false,
+    }
+}
+
+/// The result of looking up the keys pressed so far in the `Keymap`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Lookup<'a> {
+    /// The keys are bound to this command
+    Command(&'a str),
+    /// The keys are the start of a longer sequence, wait for more keys
+    Prefix,
+    /// Nothing is bound to the keys
+    Unbound,
+}
+
+/// The set of keybindings the user's keymap is applied on top of
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    Default,
+    /// Vim-style modal editing, see `vim::Vim`. The keymap is still used in insert mode.
+    Vim,
+    /// Emacs-style bindings with a kill ring and a mark, see `keymaps/emacs.toml`
+    Emacs,
+}
+
+impl Preset {
+    /// Returns the preset with the `name` used in GSettings, falling back to `Preset::Default`
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "vim" => Preset::Vim,
+            "emacs" => Preset::Emacs,
+            _ => Preset::Default,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Default => "default",
+            Preset::Vim => "vim",
+            Preset::Emacs => "emacs",
+        }
+    }
+
+    /// The bindings the preset adds to the default keymap
+    fn keymap(self) -> Option<&'static str> {
+        match self {
+            Preset::Emacs => Some(EMACS_KEYMAP),
+            Preset::Default | Preset::Vim => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct KeymapFile {
+    #[serde(default)]
+    bindings: BTreeMap<String, String>,
+}
+
+/// Maps key sequences to commands. Commands are either the name of an editor command
+/// (see `EDITOR_COMMANDS`) or an action of the application, like `app.save`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    bindings: HashMap<Vec<KeyChord>, String>,
+    preset: Preset,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let mut keymap = Self {
+            bindings: HashMap::new(),
+            preset: Preset::Default,
+        };
+        keymap
+            .add_toml(DEFAULT_KEYMAP)
+            .expect("The default keymap is invalid");
+        keymap
+    }
+}
+
+impl Keymap {
+    /// Loads the default keymap with the bindings of `preset` and applies the user's changes from
+    /// the keymap at `path`, if it exists.
+    pub fn load(path: &Path, preset: Preset) -> Result<Self, Error> {
+        let mut keymap = Self::default();
+        keymap.preset = preset;
+        if let Some(toml) = preset.keymap() {
+            keymap
+                .add_toml(toml)
+                .expect("The keymap of the preset is invalid");
+        }
+
+        if path.exists() {
+            debug!("Loading keymap {:?}", path);
+            keymap.add_toml(&fs::read_to_string(path)?)?;
+        }
+
+        Ok(keymap)
+    }
+
+    /// Adds the bindings in `toml` to the keymap, overriding existing bindings of the same keys.
+    /// Keys bound to an empty string are unbound.
+    pub fn add_toml(&mut self, toml: &str) -> Result<(), Error> {
+        let file: KeymapFile = toml::from_str(toml)?;
+
+        for (keys, command) in file.bindings {
+            let chords = KeyChord::parse_sequence(&keys)?;
+            if command.is_empty() {
+                trace!("Unbinding {}", keys);
+                self.bindings.remove(&chords);
+            } else if command.starts_with("app.") || EDITOR_COMMANDS.contains(&command.as_str()) {
+                trace!("Binding {} to {}", keys, command);
+                self.bindings.insert(chords, command);
+            } else {
+                return Err(Error::Keymap(format!(
+                    "Unknown command '{}' for '{}'",
+                    command, keys
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn preset(&self) -> Preset {
+        self.preset
+    }
+
+    /// Looks up the keys pressed so far.
+    pub fn lookup(&self, keys: &[KeyChord]) -> Lookup {
+        if let Some(command) = self.bindings.get(keys) {
+            Lookup::Command(command)
+        } else if self
+            .bindings
+            .keys()
+            .any(|k| k.len() > keys.len() && k.starts_with(keys))
+        {
+            Lookup::Prefix
+        } else {
+            Lookup::Unbound
+        }
+    }
+
+    /// Returns the accelerators of all `app.*` actions which are bound to a single chord, and the
+    /// `FALLBACK_ACCELS` which are free. Sequences can't be expressed as GTK accelerators, the
+    /// `EditView` handles those itself.
+    pub fn app_accels(&self) -> BTreeMap<String, Vec<String>> {
+        let mut accels: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+        for (keys, command) in &self.bindings {
+            if command.starts_with("app.") && keys.len() == 1 {
+                accels
+                    .entry(command.clone())
+                    .or_default()
+                    .push(keys[0].to_accel());
+            }
+        }
+
+        for (action, accel) in FALLBACK_ACCELS {
+            let chord = KeyChord::parse(accel).expect("Invalid fallback accelerator");
+            if !accels.contains_key(*action) && self.lookup(&[chord]) == Lookup::Unbound {
+                accels.insert(action.to_string(), vec![accel.to_string()]);
+            }
+        }
+
+        for accels in accels.values_mut() {
+            accels.sort();
+        }
+
+        accels
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chord(s: &str) -> KeyChord {
+        KeyChord::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_chord() {
+        let ctrl_shift_z = KeyChord {
+            key: "z".to_string(),
+            mods: Modifiers {
+                ctrl: true,
+                shift: true,
+                ..Modifiers::default()
+            },
+        };
+        assert_eq!(chord("<Primary><Shift>z"), ctrl_shift_z);
+        assert_eq!(chord("<Control>Z"), ctrl_shift_z);
+        assert_eq!(chord("<ctrl><shift>z"), ctrl_shift_z);
+        assert_eq!(chord("F9").key, "F9");
+        assert_eq!(chord("F9").mods, Modifiers::default());
+        assert!(chord("<Alt>Left").mods.alt);
+
+        assert!(KeyChord::parse("<Hyper>a").is_err());
+        assert!(KeyChord::parse("<Primary>").is_err());
+        assert!(KeyChord::parse("<Primary").is_err());
+        assert!(KeyChord::parse_sequence("  ").is_err());
+        assert_eq!(
+            KeyChord::parse_sequence("<Primary>k <Primary>u").unwrap(),
+            vec![chord("<Primary>k"), chord("<Primary>u")]
+        );
+
+        assert_eq!(chord("<Control><Shift>z").to_accel(), "<Primary><Shift>z");
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut keymap = Keymap::default();
+        keymap
+            .add_toml(
+                r#"
+                [bindings]
+                "<Primary>k <Primary>u" = "undo"
+                "<Primary>z" = ""
+                "<Alt>s" = "app.save"
+                "#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            keymap.lookup(&[chord("<Primary>c")]),
+            Lookup::Command("copy")
+        );
+        assert_eq!(keymap.lookup(&[chord("<Primary>k")]), Lookup::Prefix);
+        assert_eq!(
+            keymap.lookup(&[chord("<Primary>k"), chord("<Primary>u")]),
+            Lookup::Command("undo")
+        );
+        assert_eq!(
+            keymap.lookup(&[chord("<Primary>k"), chord("<Primary>x")]),
+            Lookup::Unbound
+        );
+        assert_eq!(keymap.lookup(&[chord("<Primary>z")]), Lookup::Unbound);
+        assert_eq!(
+            keymap.lookup(&[chord("<Primary>Z")]),
+            Lookup::Command("redo")
+        );
+
+        let accels = keymap.app_accels();
+        assert_eq!(accels["app.save"], vec!["<Alt>s", "<Primary>s"]);
+        assert_eq!(accels["app.show_sidebar"], vec!["F9"]);
+        // Ctrl+P opens files quickly, so printing doesn't get it unless the user frees it
+        assert!(!accels.contains_key("app.print"));
+        keymap
+            .add_toml("[bindings]\n\"<Primary>p\" = \"\"")
+            .unwrap();
+        assert_eq!(keymap.app_accels()["app.print"], vec!["<Primary>p"]);
+
+        assert!(keymap
+            .add_toml("[bindings]\n\"<Primary>y\" = \"does_not_exist\"")
+            .is_err());
+        assert!(keymap
+            .add_toml("[bindings]\n\"<Bogus>y\" = \"undo\"")
+            .is_err());
+        assert!(keymap.add_toml("not toml").is_err());
+    }
+
+    #[test]
+    fn test_preset() {
+        assert_eq!(Preset::from_name("vim"), Preset::Vim);
+        assert_eq!(Preset::from_name("bogus"), Preset::Default);
+        assert_eq!(Preset::from_name(Preset::Vim.name()), Preset::Vim);
+
+        let keymap = Keymap::load(Path::new("/does/not/exist"), Preset::Vim).unwrap();
+        assert_eq!(keymap.preset(), Preset::Vim);
+        assert_eq!(Keymap::default().preset(), Preset::Default);
+    }
+
+    #[test]
+    fn test_emacs_preset() {
+        let default = Keymap::default();
+        let emacs = Keymap::load(Path::new("/does/not/exist"), Preset::Emacs).unwrap();
+
+        // None of the default Ctrl-letter bindings may fire in Emacs mode
+        for (keys, command) in &default.bindings {
+            let chord = &keys[0];
+            if keys.len() == 1 && chord.mods.ctrl && chord.key.len() == 1 {
+                assert_ne!(emacs.lookup(keys), Lookup::Command(command), "{:?}", keys);
+            }
+        }
+
+        assert_eq!(
+            emacs.lookup(&[chord("<Primary>a")]),
+            Lookup::Command("move_to_left_end_of_line")
+        );
+        assert_eq!(emacs.lookup(&[chord("<Primary>x")]), Lookup::Prefix);
+        assert_eq!(
+            emacs.lookup(&[chord("<Primary>x"), chord("<Primary>s")]),
+            Lookup::Command("app.save")
+        );
+        assert_eq!(emacs.lookup(&[chord("<Primary>c")]), Lookup::Unbound);
+        // Keys without Ctrl keep working
+        assert_eq!(
+            emacs.lookup(&[chord("Return")]),
+            Lookup::Command("insert_newline")
+        );
+
+        let accels = emacs.app_accels();
+        assert!(!accels.contains_key("app.save"));
+        assert_eq!(accels["app.find"], vec!["<Primary>r", "<Primary>s"]);
+    }
+}
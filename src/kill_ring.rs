@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+
+/// How many kills the `KillRing` remembers by default, like Emacs' `kill-ring-max`
+pub const DEFAULT_CAPACITY: usize = 60;
+
+/// The kill ring of the Emacs keybinding preset. Killed text is pushed onto it, `yank` inserts
+/// the latest kill and `yank_pop` cycles through the older ones.
+#[derive(Debug)]
+pub struct KillRing {
+    entries: VecDeque<String>,
+    capacity: usize,
+    /// The entry the last yank inserted, counted from the latest kill
+    yank_index: usize,
+}
+
+impl Default for KillRing {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl KillRing {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity: capacity.max(1),
+            yank_index: 0,
+        }
+    }
+
+    /// Adds killed text to the ring. If `append` is true (e.g. for consecutive `kill_line`s) the
+    /// text is added to the latest kill instead, so a single yank brings all of it back.
+    pub fn kill(&mut self, text: &str, append: bool) {
+        if text.is_empty() {
+            return;
+        }
+
+        match self.entries.front_mut() {
+            Some(latest) if append => latest.push_str(text),
+            _ => {
+                self.entries.push_front(text.to_string());
+                self.entries.truncate(self.capacity);
+            }
+        }
+        self.yank_index = 0;
+    }
+
+    /// Returns the latest kill
+    pub fn yank(&mut self) -> Option<&str> {
+        self.yank_index = 0;
+        self.entries.front().map(String::as_str)
+    }
+
+    /// Returns the kill before the one yanked last, wrapping around to the latest kill
+    pub fn yank_pop(&mut self) -> Option<&str> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        self.yank_index = (self.yank_index + 1) % self.entries.len();
+        self.entries.get(self.yank_index).map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kill_ring() {
+        let mut ring = KillRing::new(3);
+        assert_eq!(ring.yank(), None);
+        assert_eq!(ring.yank_pop(), None);
+
+        ring.kill("one", false);
+        ring.kill("two", false);
+        ring.kill("\n", true);
+        ring.kill("", false);
+        assert_eq!(ring.yank(), Some("two\n"));
+        assert_eq!(ring.yank_pop(), Some("one"));
+        assert_eq!(ring.yank_pop(), Some("two\n"));
+
+        ring.kill("three", false);
+        ring.kill("four", false);
+        assert_eq!(ring.yank(), Some("four"));
+        assert_eq!(ring.yank_pop(), Some("three"));
+        assert_eq!(ring.yank_pop(), Some("two\n"));
+        assert_eq!(ring.yank_pop(), Some("four"));
+    }
+}
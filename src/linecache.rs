@@ -1,3 +1,5 @@
+use crate::buffer::Buffer;
+use crate::folding::Copied;
 use log::{error, trace};
 use serde_json::Value;
 use std::cmp::min;
@@ -78,6 +80,30 @@ impl Line {
     pub fn line_num(&self) -> &Option<u64> {
         &self.line_num
     }
+
+    /// The styles xi has given the text as `(start, end, style)` byte ranges. xi sends the start
+    /// of each span relative to the end of the previous one.
+    pub fn style_spans(&self) -> Vec<(usize, usize, usize)> {
+        let mut ix = 0;
+        let mut spans = Vec::with_capacity(self.styles.len());
+        for span in &self.styles {
+            let start = ix + span.start;
+            let end = start + span.len as i64;
+            spans.push((start.max(0) as usize, end.max(0) as usize, span.id));
+            ix = end;
+        }
+        spans
+    }
+
+    /// The syntax style xi has given the byte at `index`. The selection (style 0) and find
+    /// highlights (style 1) are ignored.
+    pub fn style_at(&self, index: usize) -> Option<usize> {
+        self.style_spans()
+            .into_iter()
+            .rev()
+            .find(|&(start, end, id)| id > 1 && start <= index && index < end)
+            .map(|(_, _, id)| id)
+    }
 }
 
 #[derive(Debug)]
@@ -86,6 +112,8 @@ pub struct LineCache {
     pub n_invalid_before: u64,
     pub lines: Vec<Option<Line>>,
     pub n_invalid_after: u64,
+    /// Counts the updates, so things computed from the lines know when they're outdated
+    revision: u64,
 }
 
 impl LineCache {
@@ -95,8 +123,14 @@ impl LineCache {
             n_invalid_before: 0,
             lines: Vec::new(),
             n_invalid_after: 0,
+            revision: 0,
         }
     }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
     pub fn height(&self) -> u64 {
         self.n_invalid_before + self.lines.len() as u64 + self.n_invalid_after
     }
@@ -151,10 +185,16 @@ impl LineCache {
         }
         ret
     }
-    pub fn apply_update(&mut self, update: &Value) {
+    /// Applies an update from xi. Returns which lines have been kept, so things that are bound
+    /// to lines (e.g. folds) can move along with them.
+    pub fn apply_update(&mut self, update: &Value) -> Vec<Copied> {
         let mut new_invalid_before = 0;
         let mut new_lines: Vec<Option<Line>> = Vec::new();
         let mut new_invalid_after = 0;
+        let mut copied = Vec::new();
+        // The lines skipped and invalidated since the last copy or insert
+        let mut n_skipped = 0;
+        let mut n_invalidated = 0;
 
         let mut old_ix = 0_u64;
 
@@ -165,6 +205,7 @@ impl LineCache {
             match op_type.as_str().unwrap() {
                 "invalidate" => {
                     trace!("invalidate n={}", n);
+                    n_invalidated += n;
                     if new_lines.is_empty() {
                         new_invalid_before += n;
                     } else {
@@ -173,6 +214,10 @@ impl LineCache {
                 }
                 "ins" => {
                     trace!("ins n={}", n);
+                    let new_ix = new_invalid_before + new_lines.len() as u64 + new_invalid_after;
+                    copied.extend(unsent_run(old_ix, new_ix, n_skipped, n_invalidated));
+                    n_skipped = 0;
+                    n_invalidated = 0;
                     for _ in 0..new_invalid_after {
                         new_lines.push(None);
                     }
@@ -191,6 +236,15 @@ impl LineCache {
                 }
                 "copy" => {
                     trace!("copy n={}", n);
+                    let new_ix = new_invalid_before + new_lines.len() as u64 + new_invalid_after;
+                    copied.extend(unsent_run(old_ix, new_ix, n_skipped, n_invalidated));
+                    n_skipped = 0;
+                    n_invalidated = 0;
+                    copied.push(Copied {
+                        old: old_ix,
+                        new: new_ix,
+                        n,
+                    });
 
                     for _ in 0..new_invalid_after {
                         new_lines.push(None);
@@ -241,15 +295,47 @@ impl LineCache {
                 }
                 "skip" => {
                     trace!("skip n={}", n);
+                    n_skipped += n;
                     old_ix += n;
                 }
                 _ => {}
             }
         }
+        let new_ix = new_invalid_before + new_lines.len() as u64 + new_invalid_after;
+        copied.extend(unsent_run(old_ix, new_ix, n_skipped, n_invalidated));
         self.n_invalid_before = new_invalid_before;
         self.lines = new_lines;
         self.n_invalid_after = new_invalid_after;
+        self.revision += 1;
         //debug!("lc after update {:?}", self);
+        copied
+    }
+}
+
+/// xi doesn't send us the lines outside of the ones we've asked for, every update skips and
+/// invalidates them again. If as many lines have been invalidated as have been skipped before
+/// `old_ix` and `new_ix`, we count them as kept, so e.g. folds hiding lines we don't know
+/// survive edits elsewhere.
+fn unsent_run(old_ix: u64, new_ix: u64, n_skipped: u64, n_invalidated: u64) -> Option<Copied> {
+    if n_skipped > 0 && n_skipped == n_invalidated {
+        Some(Copied {
+            old: old_ix - n_skipped,
+            new: new_ix - n_invalidated,
+            n: n_skipped,
+        })
+    } else {
+        None
+    }
+}
+
+impl Buffer for LineCache {
+    fn line(&self, n: u64) -> Option<&str> {
+        self.get_line(n)
+            .map(|line| line.text().trim_end_matches(|c| c == '\n' || c == '\r'))
+    }
+
+    fn n_lines(&self) -> u64 {
+        self.height()
     }
 }
 
@@ -291,7 +377,7 @@ mod test {
             ]
         }));
 
-        linecache.apply_update(&json!({
+        let copied = linecache.apply_update(&json!({
             "ops": [
                 {"n":10,"op":"invalidate"},
                 {"n":10,"op":"invalidate"},
@@ -321,7 +407,37 @@ mod test {
         assert_eq!(linecache.get_line(32).unwrap().text(), "32\n");
         assert_eq!(linecache.get_line(52).unwrap().text(), "52\n");
         assert!(linecache.get_line(53).is_none());
+        let run = |old, new, n| Copied { old, new, n };
+        // The runs of lines we don't know count as kept as well, since their number is the same
+        assert_eq!(
+            copied,
+            vec![
+                run(0, 0, 20),
+                run(20, 20, 2),
+                run(22, 22, 10),
+                run(32, 32, 3),
+                run(35, 35, 10),
+                run(45, 45, 4),
+                run(49, 49, 5),
+            ]
+        );
+
+        assert_eq!(linecache.revision(), 2);
 
         println!("LINE CACHE: {:?}", linecache);
     }
+
+    #[test]
+    fn test_style_at() {
+        // A string in style 2 with the selection (style 0) inside of it
+        let line = Line::from_json(
+            &json!({"text": "let s = \"}\";\n", "styles": [8, 3, 2, -2, 1, 0]}),
+            None,
+        );
+        assert_eq!(line.style_at(7), None);
+        assert_eq!(line.style_at(9), Some(2));
+        assert_eq!(line.style_at(10), Some(2));
+        assert_eq!(line.style_at(11), None);
+        assert_eq!(line.style_spans(), vec![(8, 11, 2), (9, 10, 0)]);
+    }
 }
@@ -53,10 +53,18 @@
 //! gxi also contains some more minor modules, please see their documentation for more info:
 //!
 //! - [AboutWin](about_win/struct.AboutWin.html)
+//! - [ColorScheme](color_scheme/struct.ColorScheme.html)
 //! - [Config](pref_storage/struct.Config.html) and [XiConfig](pref_storage/struct.XiConfig.html)
 //! - [ErrWin](errors/struct.ErrorDialog.html)
+//! - [FindInFiles](find_in_files/struct.FindInFiles.html)
+//! - [FindOptions](find/struct.FindOptions.html)
+//! - [Keymap](keymap/struct.Keymap.html)
+//! - [KillRing](kill_ring/struct.KillRing.html)
 //! - [PrefsWin](prefs_win/struct.PrefsWin.html)
+//! - [ProjectTree](project_tree/struct.ProjectTree.html)
+//! - [QuickOpen](quick_open/struct.QuickOpen.html)
 //! - [SharedQueue](shared_queue/struct.SharedQueue.html)
+//! - [Vim](vim/struct.Vim.html)
 //!
 //! I can very much recommend you to look at [the following tutorial](https://mmstick.github.io/gtkrs-tutorials/) if you don't
 //! know gtk-rs yet!
@@ -70,32 +78,54 @@
 mod macros;
 
 mod about_win;
+mod brackets;
+mod buffer;
+mod color_scheme;
 mod edit_view;
 mod errors;
+mod file_search;
+mod find;
+mod find_in_files;
+mod folding;
+mod fuzzy;
+mod gitignore;
 mod globals;
+mod goto_line;
+mod html;
+mod import;
+mod keymap;
+mod kill_ring;
 mod linecache;
 mod main_win;
 mod pref_storage;
 mod prefs_win;
+mod print;
+mod project_tree;
+mod quick_open;
 mod rpc;
+mod rtf;
 mod shared_queue;
 mod theme;
+mod vim;
+mod whitespace;
 mod xi_thread;
 
 use crate::errors::ErrorMsg;
+use crate::goto_line::split_file_position;
 use crate::main_win::MainWin;
-use crate::pref_storage::Config;
+use crate::pref_storage::{migrate_draw_trailing_spaces, Config};
 use crate::rpc::Core;
 use crate::shared_queue::{CoreMsg, SharedQueue};
 use gettextrs::{gettext, TextDomain, TextDomainError};
-use gio::{ApplicationExt, ApplicationExtManual, ApplicationFlags, FileExt};
-use glib::MainContext;
+use gio::{ActionGroupExt, ApplicationExt, ApplicationExtManual, ApplicationFlags, FileExt};
+use glib::{MainContext, ToVariant};
 use gtk::Application;
 use human_panic::setup_panic;
 use log::{debug, info, warn};
 use serde_json::{json, Value};
 use std::cell::RefCell;
-use std::env::args;
+use std::env::{args, current_dir};
+use std::path::Path;
 use std::rc::Rc;
 
 fn main() {
@@ -146,6 +176,8 @@ fn main() {
             Err(TextDomainError::InvalidLocale(locale)) => warn!("Invalid locale {}", locale),
         }
 
+        migrate_draw_trailing_spaces();
+
         core.client_started(&config_dir, include_str!(concat!(env!("OUT_DIR"), "/plugin-dir.in")));
 
         MainWin::new(
@@ -167,18 +199,45 @@ fn main() {
             move |value| {
                 shared_queue.add_core_msg(CoreMsg::NewViewReply{
                     file_name: None,
+                    position: None,
                     value: value.clone(),
                 })
             }
         );
     }));
 
-    application.connect_open(clone!(shared_queue, core => move |_,files,_| {
+    application.connect_open(clone!(shared_queue, core => move |application,files,_| {
         debug!("{}", gettext("Opening new file"));
 
+        let mut opened_file = false;
         for file in files {
-            if let Some(path) = file.get_path() {
+            // GIO mistakes `main.rs:120` for an URI with the scheme `main.rs`, so it has no path
+            let path = file.get_path().or_else(|| {
+                let uri = file.get_uri();
+                split_file_position(&uri)
+                    .1
+                    .and_then(|_| current_dir().ok())
+                    .map(|dir| dir.join(&*uri))
+            });
+            if let Some(path) = path {
+                if path.is_dir() {
+                    application.activate_action(
+                        "open_folder_path",
+                        Some(&path.to_string_lossy().to_variant()),
+                    );
+                    continue;
+                }
+                opened_file = true;
+
                 let path = path.to_string_lossy().into_owned();
+                // `gxi main.rs:120:4` opens main.rs and jumps to line 120, column 4. Don't split
+                // the name of a file which actually contains a colon though.
+                let (path, position) = if Path::new(&path).exists() {
+                    (path, None)
+                } else {
+                    let (file, position) = split_file_position(&path);
+                    (file.to_string(), position)
+                };
 
                 let mut params = json!({});
                 params["file_path"] = json!(path);
@@ -188,12 +247,18 @@ fn main() {
                     move |value| {
                         shared_queue.add_core_msg(CoreMsg::NewViewReply{
                             file_name: Some(path),
+                            position,
                         value: value.clone(),
                         })
                     }
                 );
             }
         }
+
+        // Only folders have been passed to us, open an empty document next to them
+        if !opened_file {
+            application.activate();
+        }
     }));
 
     application.connect_shutdown(move |_| {
@@ -1,21 +1,37 @@
 use crate::about_win::AboutWin;
+use crate::color_scheme::ColorScheme;
 use crate::edit_view::EditView;
-use crate::errors::{ErrorDialog, ErrorMsg};
-use crate::pref_storage::Config;
+use crate::errors::{Error, ErrorDialog, ErrorMsg};
+use crate::file_search::canonical_path;
+use crate::find::FindOptions;
+use crate::find_in_files::FindInFiles;
+use crate::goto_line::Position;
+use crate::html::HtmlOptions;
+use crate::keymap::{Keymap, Preset};
+use crate::pref_storage::{
+    get_dark_theme_name, get_follow_system_theme, get_html_background, get_html_line_numbers,
+    get_light_theme_name, get_theme_schema, Config,
+};
 use crate::prefs_win::PrefsWin;
+use crate::project_tree::ProjectTree;
+use crate::quick_open::QuickOpen;
 use crate::rpc::Core;
 use crate::shared_queue::{CoreMsg, SharedQueue};
-use crate::theme::{u32_from_color, LineStyle};
+use crate::theme::{is_dark, u32_from_color, LineStyle};
 use crossbeam_deque::Worker;
 use gettextrs::gettext;
-use gio::{ActionMapExt, SimpleAction};
-use glib::MainContext;
+use gio::{
+    ActionExt, ActionGroupExt, ActionMapExt, FileExt, FileMonitor, FileMonitorEvent,
+    FileMonitorExt, FileMonitorFlags, SimpleAction,
+};
+use glib::{MainContext, ToVariant};
 use gtk::*;
 use log::{debug, error, trace, warn};
 use serde_derive::*;
 use serde_json::{self, json, Value};
 use std::cell::RefCell;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::thread;
 use syntect::highlighting::ThemeSettings;
@@ -51,12 +67,19 @@ pub struct MeasureWidth {
 pub struct MainState {
     pub themes: Vec<String>,
     pub theme_name: String,
+    /// Whether the desktop prefers a dark style, for following it with the light and dark themes
+    pub prefers_dark: bool,
     pub theme: ThemeSettings,
     pub styles: HashMap<usize, LineStyle>,
     pub fonts: Vec<String>,
     pub avail_languages: Vec<String>,
     pub selected_language: String,
     pub config: Rc<RefCell<Config>>,
+    pub keymap: Keymap,
+    /// Bumped whenever the keymap is reloaded, so every `EditView` notices it
+    pub keymap_revision: u64,
+    /// The state of the search bar's toggle buttons, so every tab of the window uses the same
+    pub find_options: FindOptions,
 }
 
 pub struct MainWin {
@@ -69,8 +92,32 @@ pub struct MainWin {
     w_to_ev: HashMap<Widget, Rc<RefCell<EditView>>>,
     view_id_to_w: HashMap<String, Widget>,
     state: Rc<RefCell<MainState>>,
+    project_tree: Rc<RefCell<ProjectTree>>,
+    find_in_files: Rc<RefCell<FindInFiles>>,
+    /// The files the user has opened or switched to, most recently used first
+    recent_files: Vec<String>,
+    /// Watches the user's keymap so we can reload it when it changes
+    keymap_monitor: Option<FileMonitor>,
+    /// The `avail_languages` which have been added to the syntax combo box already
+    listed_languages: HashSet<String>,
+    /// Tells us when the desktop switches between light and dark
+    color_scheme: ColorScheme,
 }
 
+/// The theme the user wants, which depends on the desktop's style if they follow it
+fn wanted_theme_name(prefers_dark: bool) -> String {
+    if !get_follow_system_theme() {
+        get_theme_schema()
+    } else if prefers_dark {
+        get_dark_theme_name()
+    } else {
+        get_light_theme_name()
+    }
+}
+
+/// How many files we remember for ranking the results of the quick open dialog
+const MAX_RECENT_FILES: usize = 50;
+
 const GLADE_SRC: &str = include_str!("ui/gxi.glade");
 
 impl MainWin {
@@ -86,8 +133,24 @@ impl MainWin {
         let window: ApplicationWindow = builder.get_object("appwindow").unwrap();
         let notebook: Notebook = builder.get_object("notebook").unwrap();
         let syntax_combo_box: ComboBoxText = builder.get_object("syntax_combo_box").unwrap();
+        let sidebar_box: Box = builder.get_object("sidebar_box").unwrap();
+
+        let project_tree = ProjectTree::new(&window);
+        sidebar_box.pack_start(&project_tree.borrow().container, true, true, 0);
+
+        let editor_paned: Paned = builder.get_object("editor_paned").unwrap();
+        let find_in_files = FindInFiles::new();
+        editor_paned.pack2(&find_in_files.borrow().container, false, false);
 
-        let theme_name = crate::pref_storage::get_theme_schema();
+        let keymap_path = config.borrow().keymap_path();
+        let keymap = Self::load_keymap(&config.borrow()).unwrap_or_else(|e| {
+            Self::show_keymap_error(&e);
+            Keymap::default()
+        });
+
+        let color_scheme = ColorScheme::new();
+        let prefers_dark = color_scheme.prefers_dark();
+        let theme_name = wanted_theme_name(prefers_dark);
         debug!("{}: {}", gettext("Theme name"), &theme_name);
 
         let main_win = Rc::new(RefCell::new(Self {
@@ -102,13 +165,55 @@ impl MainWin {
             state: Rc::new(RefCell::new(MainState {
                 themes: Default::default(),
                 theme_name,
+                prefers_dark,
                 theme: Default::default(),
                 styles: Default::default(),
                 fonts: Default::default(),
                 avail_languages: Default::default(),
                 selected_language: Default::default(),
                 config: config.clone(),
+                keymap,
+                keymap_revision: 0,
+                find_options: Default::default(),
             })),
+            project_tree: project_tree.clone(),
+            find_in_files: find_in_files.clone(),
+            recent_files: Vec::new(),
+            keymap_monitor: None,
+            listed_languages: HashSet::new(),
+            color_scheme,
+        }));
+
+        main_win
+            .borrow()
+            .color_scheme
+            .connect_changed(clone!(main_win => move |prefers_dark| {
+                debug!("{}: {}", gettext("Desktop prefers a dark style"), prefers_dark);
+                let win = main_win.borrow();
+                win.state.borrow_mut().prefers_dark = prefers_dark;
+                Self::apply_theme(&win.core, &win.state);
+            }));
+
+        ProjectTree::connect_events(&project_tree, &main_win);
+        FindInFiles::connect_events(&find_in_files, &main_win);
+
+        // Switching tabs counts as using a file for the quick open dialog's ranking. The main
+        // window is already borrowed if we switch tabs ourselves, those callers take care of it.
+        notebook.connect_switch_page(clone!(main_win => move |_, w, _| {
+            if let Ok(mut win) = main_win.try_borrow_mut() {
+                // The minimap might have been switched on or off while another tab was shown
+                if let Some(ev) = win.w_to_ev.get(w) {
+                    ev.borrow().update_minimap_visibility();
+                    // The keybinding preset might have been changed while another tab was shown
+                    if let Ok(mut ev) = ev.try_borrow_mut() {
+                        ev.sync_keymap();
+                    }
+                }
+                let file_name = win.w_to_ev.get(w).and_then(|ev| ev.borrow().file_name.clone());
+                if let Some(file_name) = file_name {
+                    win.add_recent_file(&file_name);
+                }
+            }
         }));
 
         let (msg_tx, msg_rx) = MainContext::channel::<CoreMsg>(glib::PRIORITY_HIGH);
@@ -186,6 +291,50 @@ impl MainWin {
             }));
             application.add_action(&open_action);
         }
+        {
+            let open_folder_action = SimpleAction::new("open_folder", None);
+            open_folder_action.connect_activate(clone!(main_win => move |_,_| {
+                Self::handle_open_folder_button(&main_win);
+            }));
+            application.add_action(&open_folder_action);
+        }
+        {
+            // Used to open folders passed to us on the command line
+            let open_folder_path_action = SimpleAction::new(
+                "open_folder_path",
+                Some(&glib::VariantTy::new("s").unwrap()),
+            );
+            open_folder_path_action.connect_activate(clone!(main_win => move |_, param| {
+                if let Some(path) = param.as_ref().and_then(|p| p.get_str()) {
+                    Self::open_folder(&main_win, Path::new(path));
+                }
+            }));
+            application.add_action(&open_folder_path_action);
+        }
+        {
+            let quick_open_action = SimpleAction::new("quick_open", None);
+            quick_open_action.connect_activate(clone!(main_win => move |_,_| {
+                Self::quick_open(&main_win);
+            }));
+            application.add_action(&quick_open_action);
+        }
+        {
+            let show_sidebar_action =
+                SimpleAction::new_stateful("show_sidebar", None, &false.to_variant());
+            show_sidebar_action.connect_change_state(move |action, value| {
+                if let Some(value) = value.as_ref() {
+                    action.set_state(value);
+                    let value: bool = value.get().unwrap();
+                    debug!("{}: {}", gettext("Show sidebar"), value);
+                    if value {
+                        sidebar_box.show();
+                    } else {
+                        sidebar_box.hide();
+                    }
+                }
+            });
+            application.add_action(&show_sidebar_action);
+        }
         {
             let new_action = SimpleAction::new("new", None);
             new_action.connect_activate(clone!(main_win => move |_,_| {
@@ -214,6 +363,13 @@ impl MainWin {
             }));
             application.add_action(&find_action);
         }
+        {
+            let goto_line_action = SimpleAction::new("goto_line", None);
+            goto_line_action.connect_activate(clone!(main_win => move |_,_| {
+                Self::goto_line(&main_win);
+            }));
+            application.add_action(&goto_line_action);
+        }
         {
             let replace_action = SimpleAction::new("replace", None);
             replace_action.connect_activate(clone!(main_win => move |_,_| {
@@ -221,6 +377,13 @@ impl MainWin {
             }));
             application.add_action(&replace_action);
         }
+        {
+            let find_in_files_action = SimpleAction::new("find_in_files", None);
+            find_in_files_action.connect_activate(clone!(main_win => move |_,_| {
+                Self::find_in_files(&main_win);
+            }));
+            application.add_action(&find_in_files_action);
+        }
         {
             let save_action = SimpleAction::new("save", None);
             save_action.connect_activate(clone!(main_win => move |_,_| {
@@ -235,6 +398,20 @@ impl MainWin {
             }));
             application.add_action(&save_as_action);
         }
+        {
+            let print_action = SimpleAction::new("print", None);
+            print_action.connect_activate(clone!(main_win => move |_,_| {
+                Self::print(&main_win);
+            }));
+            application.add_action(&print_action);
+        }
+        {
+            let export_html_action = SimpleAction::new("export_html", None);
+            export_html_action.connect_activate(clone!(main_win => move |_,_| {
+                Self::export_html(&main_win);
+            }));
+            application.add_action(&export_html_action);
+        }
         {
             let close_action = SimpleAction::new("close", None);
             close_action.connect_activate(clone!(main_win => move |_,_| {
@@ -265,7 +442,7 @@ impl MainWin {
                 "auto_indent",
                 None,
                 &config.borrow().config.auto_indent.to_variant(),
-            );;
+            );
 
             auto_indent_action.connect_change_state(clone!(config => move |action, value| {
                 if let Some(value) = value.as_ref() {
@@ -285,7 +462,7 @@ impl MainWin {
                 "insert_spaces",
                 None,
                 &config.borrow().config.translate_tabs_to_spaces.to_variant(),
-            );;
+            );
             space_indent_action.connect_change_state(move |action, value| {
                 if let Some(value) = value.as_ref() {
                     action.set_state(value);
@@ -302,15 +479,9 @@ impl MainWin {
             application.add_action(&space_indent_action);
         }
 
-        /* Put keyboard shortcuts here*/
-        if let Some(app) = window.get_application() {
-            app.set_accels_for_action("app.find", &["<Primary>f"]);
-            app.set_accels_for_action("app.save", &["<Primary>s"]);
-            app.set_accels_for_action("app.new", &["<Primary>n"]);
-            app.set_accels_for_action("app.open", &["<Primary>o"]);
-            app.set_accels_for_action("app.quit", &["<Primary>q"]);
-            app.set_accels_for_action("app.replace", &["<Primary>r"]);
-        }
+        /* Keyboard shortcuts are defined in the keymap, see keymaps/default.toml */
+        Self::apply_keymap_accels(application, &main_win.borrow().state.borrow().keymap);
+        Self::watch_keymap(&main_win, &keymap_path);
 
         window.show_all();
 
@@ -332,9 +503,11 @@ impl MainWin {
     pub fn handle_msg(main_win: Rc<RefCell<Self>>, msg: CoreMsg) {
         trace!("{}: {:?}", gettext("Handling CoreMsg"), msg);
         match msg {
-            CoreMsg::NewViewReply { file_name, value } => {
-                Self::new_view_response(&main_win, file_name, &value)
-            }
+            CoreMsg::NewViewReply {
+                file_name,
+                position,
+                value,
+            } => Self::new_view_response(&main_win, file_name, position, &value),
             CoreMsg::Notification { method, params, id } => {
                 match method.as_ref() {
                     "alert" => main_win.borrow_mut().alert(&params),
@@ -383,6 +556,8 @@ impl MainWin {
                 }
             }
         }
+        // The desktop might have switched between light and dark since we've started
+        state.theme_name = wanted_theme_name(state.prefers_dark);
 
         if !state.themes.contains(&state.theme_name) {
             error!(
@@ -404,6 +579,18 @@ impl MainWin {
             .send_notification("set_theme", &json!({ "theme_name": state.theme_name }));
     }
 
+    /// Switches to the theme the user wants for the desktop's current style. This only sends
+    /// `set_theme` if the theme actually changes, so we don't end up switching back and forth.
+    pub fn apply_theme(core: &Rc<RefCell<Core>>, state: &Rc<RefCell<MainState>>) {
+        let mut state = state.borrow_mut();
+        let theme_name = wanted_theme_name(state.prefers_dark);
+        if theme_name != state.theme_name && state.themes.contains(&theme_name) {
+            debug!("{}: {}", gettext("Switching theme to"), theme_name);
+            core.borrow().set_theme(&theme_name);
+            state.theme_name = theme_name;
+        }
+    }
+
     pub fn theme_changed(&mut self, params: &Value) {
         let theme_settings = params["theme"].clone();
         let theme: ThemeSettings = match serde_json::from_value(theme_settings) {
@@ -425,9 +612,22 @@ impl MainWin {
             underline: None,
         };
 
+        let find_highlight_style = LineStyle {
+            fg_color: theme.find_highlight_foreground.map(u32_from_color),
+            bg_color: theme.find_highlight.map(u32_from_color),
+            weight: None,
+            italic: None,
+            underline: None,
+        };
+
+        // Match the header bar to the editor
+        self.color_scheme
+            .set_dark_variant(theme.background.map_or(false, is_dark));
+
         let mut state = self.state.borrow_mut();
         state.theme = theme;
         state.styles.insert(0, selection_style);
+        state.styles.insert(1, find_highlight_style);
     }
 
     pub fn available_plugins(&mut self, params: &Value) {
@@ -631,6 +831,225 @@ impl MainWin {
         fcn.run();
     }
 
+    /// Display the FileChooserNative for selecting a folder which is then shown in the sidebar.
+    /// This may call the GTK main loop.  There must not be any RefCell borrows out while this
+    /// function runs.
+    pub fn handle_open_folder_button(main_win: &Rc<RefCell<Self>>) {
+        let fcn = FileChooserNative::new(
+            Some(gettext("Open a folder").as_str()),
+            Some(&main_win.borrow().window),
+            FileChooserAction::SelectFolder,
+            Some(gettext("Open").as_str()),
+            Some(gettext("Cancel").as_str()),
+        );
+        fcn.set_transient_for(Some(&main_win.borrow().window.clone()));
+
+        fcn.connect_response(clone!(main_win => move |fcd, res| {
+            debug!(
+                "{}: {:#?}",
+                gettext("FileChooserNative open folder response"),
+                res
+            );
+
+            if res == ResponseType::Accept {
+                if let Some(folder) = fcd.get_filename() {
+                    Self::open_folder(&main_win, &folder);
+                }
+            }
+        }));
+
+        fcn.run();
+    }
+
+    /// Shows `path` in the sidebar's project tree and reveals the sidebar.
+    pub fn open_folder(main_win: &Rc<RefCell<Self>>, path: &Path) {
+        let (project_tree, builder, window) = {
+            let win = main_win.borrow();
+            (
+                win.project_tree.clone(),
+                win.builder.clone(),
+                win.window.clone(),
+            )
+        };
+
+        project_tree.borrow_mut().open_folder(path);
+
+        let header_bar: HeaderBar = builder.get_object("header_bar").unwrap();
+        header_bar.set_subtitle(
+            path.file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .as_ref()
+                .map(String::as_str),
+        );
+
+        if let Some(action) = window
+            .get_application()
+            .and_then(|app| app.lookup_action("show_sidebar"))
+        {
+            action.change_state(&true.to_variant());
+        }
+    }
+
+    /// Registers the accelerators of all `app.*` actions which are bound to a single key chord in
+    /// the `keymap`, and removes those of actions that aren't bound anymore.
+    fn apply_keymap_accels(application: &Application, keymap: &Keymap) {
+        let accels = keymap.app_accels();
+        for action in application.list_actions() {
+            let name = format!("app.{}", action);
+            let action_accels: Vec<&str> = accels
+                .get(&name)
+                .map(|a| a.iter().map(String::as_str).collect())
+                .unwrap_or_default();
+            application.set_accels_for_action(&name, &action_accels);
+        }
+    }
+
+    /// Reloads the keymap whenever the user changes it.
+    fn watch_keymap(main_win: &Rc<RefCell<Self>>, path: &Path) {
+        let monitor = match gio::File::new_for_path(path)
+            .monitor_file(FileMonitorFlags::NONE, None::<&gio::Cancellable>)
+        {
+            Ok(monitor) => monitor,
+            Err(e) => {
+                warn!("{}: {}", gettext("Failed to watch the keymap"), e);
+                return;
+            }
+        };
+
+        monitor.connect_changed(clone!(main_win => move |_, _, _, event| {
+            match event {
+                FileMonitorEvent::ChangesDoneHint
+                | FileMonitorEvent::Created
+                | FileMonitorEvent::Deleted => {}
+                _ => return,
+            }
+
+            let main_win = main_win.borrow();
+            Self::reload_keymap(&main_win.window, &main_win.state);
+        }));
+
+        main_win.borrow_mut().keymap_monitor = Some(monitor);
+    }
+
+    /// Loads the user's keymap on top of the keybinding preset chosen in the preferences.
+    fn load_keymap(config: &Config) -> Result<Keymap, Error> {
+        let preset = Preset::from_name(&crate::pref_storage::get_keybinding_preset());
+        Keymap::load(&config.keymap_path(), preset)
+    }
+
+    /// Reloads the keymap, e.g. after the user has changed it or chosen another preset.
+    pub fn reload_keymap(window: &ApplicationWindow, state: &RefCell<MainState>) {
+        let keymap = Self::load_keymap(&state.borrow().config.borrow());
+        debug!("{}", gettext("Reloading keymap"));
+        match keymap {
+            Ok(keymap) => {
+                if let Some(app) = window.get_application() {
+                    Self::apply_keymap_accels(&app, &keymap);
+                }
+                let mut state = state.borrow_mut();
+                state.keymap = keymap;
+                state.keymap_revision += 1;
+            }
+            // Keep the old keymap, the user is probably still editing it
+            Err(e) => Self::show_keymap_error(&e),
+        }
+    }
+
+    fn show_keymap_error(e: &Error) {
+        ErrorDialog::new(ErrorMsg {
+            msg: format!("{}: {}", gettext("Couldn't load your keymap"), e),
+            fatal: false,
+        })
+        .show_all();
+    }
+
+    /// Shows the quick open dialog for the folder that's open in the sidebar. Does nothing if
+    /// no folder has been opened yet.
+    fn quick_open(main_win: &Rc<RefCell<Self>>) {
+        let (root, recent_files, window) = {
+            let win = main_win.borrow();
+            let root = win.project_tree.borrow().root.clone();
+            (root, win.recent_files.clone(), win.window.clone())
+        };
+
+        if let Some(root) = root {
+            QuickOpen::new(main_win, &window, &root, &recent_files);
+        } else {
+            debug!("{}", gettext("No folder opened, not showing quick open"));
+        }
+    }
+
+    /// Opens `file_name` in a new tab, or switches to its tab if it's already open.
+    pub fn open_file(main_win: &Rc<RefCell<Self>>, file_name: &str) {
+        Self::open_file_at(main_win, file_name, None);
+    }
+
+    /// Like `open_file`, but also moves the cursor to `position`
+    pub fn open_file_at(main_win: &Rc<RefCell<Self>>, file_name: &str, position: Option<Position>) {
+        let existing = main_win.borrow().edit_view_for_file(Path::new(file_name));
+        if let Some(edit_view) = existing {
+            {
+                let win = main_win.borrow();
+                let idx = win.notebook.page_num(&edit_view.borrow().root_widget);
+                win.notebook.set_current_page(idx);
+                edit_view.borrow().view_item.edit_area.grab_focus();
+            }
+            main_win.borrow_mut().add_recent_file(file_name);
+            if let Some(position) = position {
+                edit_view.borrow_mut().goto_position(position);
+            }
+        } else {
+            main_win.borrow().req_new_view_at(Some(file_name), position);
+        }
+    }
+
+    /// The `EditView` which has `path` open, if there is one. The paths are compared as
+    /// `canonical_path`s, since the file might have been opened under a different path.
+    pub fn edit_view_for_file(&self, path: &Path) -> Option<Rc<RefCell<EditView>>> {
+        let path = canonical_path(path);
+        self.views
+            .values()
+            .find(|ev| {
+                ev.borrow()
+                    .file_name
+                    .as_ref()
+                    .map_or(false, |f| canonical_path(Path::new(f)) == path)
+            })
+            .cloned()
+    }
+
+    /// The text of the open files for searching them, as long as xi has sent us all of it. Files
+    /// with unsaved changes we don't know all of map to `None`, unchanged ones can be read from
+    /// disk instead.
+    pub fn open_buffers(&self) -> HashMap<PathBuf, Option<String>> {
+        let mut buffers = HashMap::new();
+        for ev in self.views.values() {
+            let ev = match ev.try_borrow() {
+                Ok(ev) => ev,
+                Err(_) => continue,
+            };
+            if let Some(ref file_name) = ev.file_name {
+                match ev.contents() {
+                    Some(contents) => {
+                        buffers.insert(canonical_path(Path::new(file_name)), Some(contents));
+                    }
+                    None if !ev.pristine => {
+                        buffers.insert(canonical_path(Path::new(file_name)), None);
+                    }
+                    None => (),
+                }
+            }
+        }
+        buffers
+    }
+
+    /// Moves `file_name` to the front of the recently used files
+    fn add_recent_file(&mut self, file_name: &str) {
+        self.recent_files.retain(|f| f != file_name);
+        self.recent_files.insert(0, file_name.to_string());
+        self.recent_files.truncate(MAX_RECENT_FILES);
+    }
+
     pub fn handle_save_button(main_win: &Rc<RefCell<Self>>) {
         let edit_view = main_win.borrow().get_current_edit_view().clone();
         if edit_view.borrow().file_name.is_some() {
@@ -718,12 +1137,73 @@ impl MainWin {
 
     fn find(main_win: &Rc<RefCell<Self>>) {
         let edit_view = main_win.borrow().get_current_edit_view().clone();
-        edit_view.borrow().start_search();
+        edit_view.borrow_mut().start_search();
+    }
+
+    /// Shows the "Find in Files" panel, searching the folder that's open in the sidebar by default
+    fn find_in_files(main_win: &Rc<RefCell<Self>>) {
+        let (find_in_files, root) = {
+            let win = main_win.borrow();
+            let root = win.project_tree.borrow().root.clone();
+            (win.find_in_files.clone(), root)
+        };
+        find_in_files
+            .borrow()
+            .show(root.as_ref().map(PathBuf::as_path));
+    }
+
+    fn goto_line(main_win: &Rc<RefCell<Self>>) {
+        let edit_view = main_win.borrow().get_current_edit_view().clone();
+        edit_view.borrow().start_goto_line();
     }
 
     fn replace(main_win: &Rc<RefCell<Self>>) {
         let edit_view = main_win.borrow().get_current_edit_view().clone();
-        edit_view.borrow().start_replace();
+        edit_view.borrow_mut().start_replace();
+    }
+
+    fn print(main_win: &Rc<RefCell<Self>>) {
+        let edit_view = main_win.borrow().get_current_edit_view().clone();
+        edit_view.borrow_mut().print();
+    }
+
+    /// Asks where to export the current document to as HTML. Like `save_as` this may call the
+    /// GTK main loop, so there must not be any RefCell borrows out while this function runs.
+    fn export_html(main_win: &Rc<RefCell<Self>>) {
+        let edit_view = main_win.borrow().get_current_edit_view().clone();
+        let window = main_win.borrow().window.clone();
+        let fcn = FileChooserNative::new(
+            Some(gettext("Export as HTML").as_str()),
+            Some(&window),
+            FileChooserAction::Save,
+            Some(gettext("Export").as_str()),
+            Some(gettext("Cancel").as_str()),
+        );
+        fcn.set_transient_for(Some(&window));
+        fcn.set_do_overwrite_confirmation(true);
+
+        let name = edit_view
+            .borrow()
+            .file_name
+            .as_ref()
+            .and_then(|f| Path::new(f).file_stem())
+            .map_or_else(|| gettext("Untitled"), |f| f.to_string_lossy().into_owned());
+        fcn.set_current_name(&format!("{}.html", name));
+
+        fcn.connect_response(clone!(edit_view => move |fcd, res| {
+            if res != ResponseType::Accept {
+                return;
+            }
+            if let Some(file) = fcd.get_filename() {
+                let options = HtmlOptions {
+                    line_numbers: get_html_line_numbers(),
+                    background: get_html_background(),
+                };
+                edit_view.borrow_mut().export_html(file, options);
+            }
+        }));
+
+        fcn.run();
     }
 
     fn get_current_edit_view(&self) -> Rc<RefCell<EditView>> {
@@ -737,7 +1217,12 @@ impl MainWin {
         unreachable!(gettext("Failed to get the current EditView"));
     }
 
-    fn req_new_view(&self, file_name: Option<&str>) {
+    pub fn req_new_view(&self, file_name: Option<&str>) {
+        self.req_new_view_at(file_name, None);
+    }
+
+    /// Requests a new view, which jumps to `position` once it has been loaded
+    fn req_new_view_at(&self, file_name: Option<&str>, position: Option<Position>) {
         let mut params = json!({});
         if let Some(file_name) = file_name {
             params["file_path"] = json!(file_name);
@@ -751,27 +1236,47 @@ impl MainWin {
                 let value = value.clone();
                 shared_queue.add_core_msg(CoreMsg::NewViewReply {
                     file_name: file_name2,
+                    position,
                     value,
                 })
             });
     }
 
-    fn new_view_response(main_win: &Rc<RefCell<Self>>, file_name: Option<String>, value: &Value) {
+    fn new_view_response(
+        main_win: &Rc<RefCell<Self>>,
+        file_name: Option<String>,
+        position: Option<Position>,
+        value: &Value,
+    ) {
         let mut win = main_win.borrow_mut();
 
         // Add all available langs to the syntax_combo_box for the user to select it. We're doing
         // it here because we can be sure that xi-editor has sent available_languages by now.
         let syntax_combo_box: ComboBoxText = win.builder.get_object("syntax_combo_box").unwrap();
 
-        win.state
+        // Only the ones which haven't been added for an earlier view, xi might have sent us
+        // another list since
+        let new_languages: Vec<String> = win
+            .state
             .borrow()
             .avail_languages
             .iter()
-            .filter(|l| l != &&"Plain Text".to_string())
-            .for_each(|lang| syntax_combo_box.append_text(lang));
+            .filter(|lang| *lang != "Plain Text" && !win.listed_languages.contains(*lang))
+            .cloned()
+            .collect();
+        for lang in new_languages {
+            syntax_combo_box.append_text(&lang);
+            win.listed_languages.insert(lang);
+        }
 
         if let Some(view_id) = value.as_str() {
+            if let Some(file_name) = &file_name {
+                win.add_recent_file(file_name);
+            }
             let edit_view = EditView::new(&win.state, &win.core, file_name, view_id);
+            if let Some(position) = position {
+                edit_view.borrow_mut().set_pending_position(position);
+            }
             {
                 let ev = edit_view.borrow();
                 let page_num =
@@ -1,10 +1,12 @@
 use crate::errors::Error;
+use crate::whitespace::DrawWhitespace;
 use gettextrs::gettext;
 use gio::{Settings, SettingsExt, SettingsSchemaSource};
 use log::{debug, error, trace, warn};
 use serde_derive::*;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 use tempfile::tempdir;
 
 /// Wrapper struct around `XiConfig`, it's annoying to pass around path otherwise
@@ -205,6 +207,18 @@ impl Config {
 
         Ok(())
     }
+
+    /// The config dir we give to xi in `client_started`, which the xi config lives in
+    pub fn dir(&self) -> PathBuf {
+        Path::new(&self.path)
+            .parent()
+            .map_or_else(PathBuf::new, Path::to_path_buf)
+    }
+
+    /// The user's keymap, which lives next to the xi config
+    pub fn keymap_path(&self) -> PathBuf {
+        Path::new(&self.path).with_file_name("keymap.toml")
+    }
 }
 
 pub fn get_theme_schema() -> String {
@@ -231,6 +245,103 @@ pub fn set_theme_schema(theme_name: &str) {
     };
 }
 
+pub fn get_follow_system_theme() -> bool {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .map_or_else(
+            || {
+                warn!("Couldn't find GSchema! Defaulting to not following the desktop's style!");
+                false
+            },
+            |_| {
+                Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                    .get_boolean("follow-system-theme")
+            },
+        )
+}
+
+pub fn set_follow_system_theme(val: bool) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_boolean("follow-system-theme", val);
+    };
+}
+
+pub fn get_light_theme_name() -> String {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .and_then(|_| {
+            Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                .get_string("light-theme-name")
+        })
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| {
+            warn!("Couldn't find GSchema! Defaulting to the default light theme.");
+            "InspiredGitHub".to_string()
+        })
+}
+
+pub fn set_light_theme_name(theme_name: &str) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_string("light-theme-name", theme_name);
+    };
+}
+
+pub fn get_dark_theme_name() -> String {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .and_then(|_| {
+            Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                .get_string("dark-theme-name")
+        })
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| {
+            warn!("Couldn't find GSchema! Defaulting to the default dark theme.");
+            "base16-ocean.dark".to_string()
+        })
+}
+
+pub fn set_dark_theme_name(theme_name: &str) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_string("dark-theme-name", theme_name);
+    };
+}
+
+pub fn get_keybinding_preset() -> String {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .and_then(|_| {
+            Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                .get_string("keybinding-preset")
+        })
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| {
+            warn!("Couldn't find GSchema! Defaulting to default keybindings.");
+            "default".to_string()
+        })
+}
+
+pub fn set_keybinding_preset(preset: &str) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_string("keybinding-preset", preset);
+    };
+}
+
 pub fn get_default_monospace_font_schema() -> String {
     SettingsSchemaSource::get_default()
         .and_then(|settings_source| settings_source.lookup("org.gnome.desktop.interface", true))
@@ -259,28 +370,121 @@ pub fn get_default_interface_font_schema() -> String {
         )
 }
 
-pub fn get_draw_trailing_spaces_schema() -> bool {
+pub fn get_draw_whitespace() -> String {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .and_then(|_| {
+            Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                .get_string("draw-whitespace")
+        })
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| {
+            warn!("Couldn't find GSchema! Defaulting to not drawing whitespace!");
+            "none".to_string()
+        })
+}
+
+pub fn set_draw_whitespace(mode: &str) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_string("draw-whitespace", mode);
+    };
+}
+
+/// gxi used to have a switch for drawing trailing spaces, which `draw-whitespace` has replaced.
+/// If the user has turned it on we draw trailing whitespace instead and reset it, so this only
+/// happens once.
+pub fn migrate_draw_trailing_spaces() {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        let settings = Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"));
+        if settings.get_boolean("draw-trailing-spaces") {
+            debug!(
+                "{}",
+                gettext("Moving draw-trailing-spaces over to draw-whitespace")
+            );
+            settings.set_string("draw-whitespace", DrawWhitespace::Trailing.name());
+            settings.reset("draw-trailing-spaces");
+        }
+    };
+}
+
+pub fn get_draw_indent_guides() -> bool {
     SettingsSchemaSource::get_default()
         .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
         .map_or_else(
             || {
-                warn!("Couldn't find GSchema! Defaulting to not drawing tabs!");
+                warn!("Couldn't find GSchema! Defaulting to drawing indent guides!");
+                true
+            },
+            |_| {
+                Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                    .get_boolean("draw-indent-guides")
+            },
+        )
+}
+
+pub fn set_draw_indent_guides(val: bool) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_boolean("draw-indent-guides", val);
+    };
+}
+
+pub fn get_caret_style() -> String {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .and_then(|_| {
+            Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                .get_string("caret-style")
+        })
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| {
+            warn!("Couldn't find GSchema! Defaulting to a bar caret!");
+            "bar".to_string()
+        })
+}
+
+pub fn set_caret_style(style: &str) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_string("caret-style", style);
+    };
+}
+
+pub fn get_show_minimap() -> bool {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .map_or_else(
+            || {
+                warn!("Couldn't find GSchema! Defaulting to not showing a minimap!");
                 false
             },
             |_| {
                 Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
-                    .get_boolean("draw-trailing-spaces")
+                    .get_boolean("show-minimap")
             },
         )
 }
 
-pub fn set_draw_trailing_spaces_schema(val: bool) {
+pub fn set_show_minimap(val: bool) {
     if SettingsSchemaSource::get_default()
         .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
         .is_some()
     {
         Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
-            .set_boolean("draw-trailing-spaces", val);
+            .set_boolean("show-minimap", val);
     };
 }
 
@@ -335,3 +539,128 @@ pub fn get_column_right_margin() -> u32 {
             },
         )
 }
+
+pub fn get_print_line_numbers() -> bool {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .map_or_else(
+            || {
+                warn!("Couldn't find GSchema! Defaulting to not printing line numbers!");
+                false
+            },
+            |_| {
+                Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                    .get_boolean("print-line-numbers")
+            },
+        )
+}
+
+pub fn set_print_line_numbers(val: bool) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_boolean("print-line-numbers", val);
+    };
+}
+
+pub fn get_print_header() -> bool {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .map_or_else(
+            || {
+                warn!("Couldn't find GSchema! Defaulting to printing a header!");
+                true
+            },
+            |_| {
+                Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                    .get_boolean("print-header")
+            },
+        )
+}
+
+pub fn set_print_header(val: bool) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_boolean("print-header", val);
+    };
+}
+
+pub fn get_print_wrap_lines() -> bool {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .map_or_else(
+            || {
+                warn!("Couldn't find GSchema! Defaulting to wrapping printed lines!");
+                true
+            },
+            |_| {
+                Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                    .get_boolean("print-wrap-lines")
+            },
+        )
+}
+
+pub fn set_print_wrap_lines(val: bool) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_boolean("print-wrap-lines", val);
+    };
+}
+
+pub fn get_html_line_numbers() -> bool {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .map_or_else(
+            || {
+                warn!("Couldn't find GSchema! Defaulting to not numbering lines in exported HTML!");
+                false
+            },
+            |_| {
+                Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                    .get_boolean("html-line-numbers")
+            },
+        )
+}
+
+pub fn set_html_line_numbers(val: bool) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_boolean("html-line-numbers", val);
+    };
+}
+
+pub fn get_html_background() -> bool {
+    SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .map_or_else(
+            || {
+                warn!("Couldn't find GSchema! Defaulting to using the theme's background in exported HTML!");
+                true
+            },
+            |_| {
+                Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+                    .get_boolean("html-background")
+            },
+        )
+}
+
+pub fn set_html_background(val: bool) {
+    if SettingsSchemaSource::get_default()
+        .and_then(|settings_source| settings_source.lookup("com.github.Cogitri.gxi", true))
+        .is_some()
+    {
+        Settings::new(crate::globals::APP_ID.unwrap_or("com.github.Cogitri.gxi"))
+            .set_boolean("html-background", val);
+    };
+}
@@ -1,5 +1,7 @@
 use crate::edit_view::EditView;
-use crate::main_win::MainState;
+use crate::errors::{ErrorDialog, ErrorMsg};
+use crate::import;
+use crate::main_win::{MainState, MainWin};
 use crate::pref_storage::*;
 use crate::rpc::Core;
 use gettextrs::gettext;
@@ -28,17 +30,35 @@ impl PrefsWin {
         let font_chooser_widget: FontChooserWidget =
             builder.get_object("font_chooser_widget").unwrap();
         let theme_combo_box: ComboBoxText = builder.get_object("theme_combo_box").unwrap();
+        let import_theme_button: Button = builder.get_object("import_theme_button").unwrap();
+        let follow_system_theme_checkbutton: ToggleButton = builder
+            .get_object("follow_system_theme_checkbutton")
+            .unwrap();
+        let light_theme_combo_box: ComboBoxText =
+            builder.get_object("light_theme_combo_box").unwrap();
+        let dark_theme_combo_box: ComboBoxText =
+            builder.get_object("dark_theme_combo_box").unwrap();
         let tab_stops_checkbutton: ToggleButton =
             builder.get_object("tab_stops_checkbutton").unwrap();
         let scroll_past_end_checkbutton: ToggleButton =
             builder.get_object("scroll_past_end_checkbutton").unwrap();
         let word_wrap_checkbutton: ToggleButton =
             builder.get_object("word_wrap_checkbutton").unwrap();
-        let draw_trailing_spaces_checkbutton: ToggleButton = builder
-            .get_object("draw_trailing_spaces_checkbutton")
-            .unwrap();
+        let whitespace_combo_box: ComboBoxText =
+            builder.get_object("whitespace_combo_box").unwrap();
+        let indent_guides_checkbutton: ToggleButton =
+            builder.get_object("indent_guides_checkbutton").unwrap();
+        let caret_style_combo_box: ComboBoxText =
+            builder.get_object("caret_style_combo_box").unwrap();
+        let minimap_checkbutton: ToggleButton = builder.get_object("minimap_checkbutton").unwrap();
         let margin_checkbutton: ToggleButton = builder.get_object("margin_checkbutton").unwrap();
         let margin_spinbutton: SpinButton = builder.get_object("margin_spinbutton").unwrap();
+        let keybinding_preset_combo_box: ComboBoxText =
+            builder.get_object("keybinding_preset_combo_box").unwrap();
+        let html_line_numbers_checkbutton: ToggleButton =
+            builder.get_object("html_line_numbers_checkbutton").unwrap();
+        let html_background_checkbutton: ToggleButton =
+            builder.get_object("html_background_checkbutton").unwrap();
 
         let xi_config = &main_state.borrow().config;
 
@@ -77,15 +97,40 @@ impl PrefsWin {
 
         {
             let main_state = main_state.borrow();
+            // If we follow the desktop's style the current theme is the light or dark one
+            let selected_theme_name = get_theme_schema();
+            let light_theme_name = get_light_theme_name();
+            let dark_theme_name = get_dark_theme_name();
             for (i, theme_name) in main_state.themes.iter().enumerate() {
                 theme_combo_box.append_text(theme_name);
-                if &main_state.theme_name == theme_name {
+                light_theme_combo_box.append_text(theme_name);
+                dark_theme_combo_box.append_text(theme_name);
+                if &selected_theme_name == theme_name {
                     trace!("{}: {}", gettext("Setting active theme"), i);
                     theme_combo_box.set_active(i as u32);
                 }
+                if &light_theme_name == theme_name {
+                    light_theme_combo_box.set_active(i as u32);
+                }
+                if &dark_theme_name == theme_name {
+                    dark_theme_combo_box.set_active(i as u32);
+                }
             }
         }
 
+        {
+            let theme_combo_boxes = vec![
+                theme_combo_box.clone(),
+                light_theme_combo_box.clone(),
+                dark_theme_combo_box.clone(),
+            ];
+            import_theme_button.connect_clicked(
+                clone!(window, main_state, theme_combo_boxes => move |_| {
+                    Self::import_theme(&window, &main_state, &theme_combo_boxes);
+                }),
+            );
+        }
+
         theme_combo_box.connect_changed(clone!(core, main_state => move |cb|{
             if let Some(theme_name) = cb.get_active_text() {
                 debug!("{} {:?}", gettext("Theme changed to"), &theme_name);
@@ -99,6 +144,42 @@ impl PrefsWin {
             }
         }));
 
+        {
+            let follow = get_follow_system_theme();
+            follow_system_theme_checkbutton.set_active(follow);
+            theme_combo_box.set_sensitive(!follow);
+            light_theme_combo_box.set_sensitive(follow);
+            dark_theme_combo_box.set_sensitive(follow);
+
+            follow_system_theme_checkbutton.connect_toggled(
+                clone!(core, main_state, theme_combo_box, light_theme_combo_box, dark_theme_combo_box => move |toggle_btn| {
+                    let value = toggle_btn.get_active();
+                    debug!("{}: {}", gettext("Following the desktop's style"), value);
+                    set_follow_system_theme(value);
+                    theme_combo_box.set_sensitive(!value);
+                    light_theme_combo_box.set_sensitive(value);
+                    dark_theme_combo_box.set_sensitive(value);
+                    MainWin::apply_theme(&core, &main_state);
+                }),
+            );
+        }
+
+        light_theme_combo_box.connect_changed(clone!(core, main_state => move |cb| {
+            if let Some(theme_name) = cb.get_active_text() {
+                debug!("{} {:?}", gettext("Light theme changed to"), &theme_name);
+                set_light_theme_name(&theme_name);
+                MainWin::apply_theme(&core, &main_state);
+            }
+        }));
+
+        dark_theme_combo_box.connect_changed(clone!(core, main_state => move |cb| {
+            if let Some(theme_name) = cb.get_active_text() {
+                debug!("{} {:?}", gettext("Dark theme changed to"), &theme_name);
+                set_dark_theme_name(&theme_name);
+                MainWin::apply_theme(&core, &main_state);
+            }
+        }));
+
         {
             {
                 scroll_past_end_checkbutton.set_active(xi_config.borrow().config.scroll_past_end);
@@ -145,12 +226,45 @@ impl PrefsWin {
         }
 
         {
-            draw_trailing_spaces_checkbutton.set_active(get_draw_trailing_spaces_schema());
+            whitespace_combo_box.set_active_id(Some(get_draw_whitespace().as_str()));
 
-            draw_trailing_spaces_checkbutton.connect_toggled(move |toggle_btn| {
-                let value = toggle_btn.get_active();
-                set_draw_trailing_spaces_schema(value);
-            });
+            whitespace_combo_box.connect_changed(clone!(edit_view => move |cb| {
+                if let Some(mode) = cb.get_active_id() {
+                    debug!("{}: {}", gettext("Drawing whitespace"), mode);
+                    set_draw_whitespace(&mode);
+                    edit_view.borrow().view_item.edit_area.queue_draw();
+                }
+            }));
+        }
+
+        {
+            indent_guides_checkbutton.set_active(get_draw_indent_guides());
+
+            indent_guides_checkbutton.connect_toggled(clone!(edit_view => move |toggle_btn| {
+                set_draw_indent_guides(toggle_btn.get_active());
+                edit_view.borrow().view_item.edit_area.queue_draw();
+            }));
+        }
+
+        {
+            caret_style_combo_box.set_active_id(Some(get_caret_style().as_str()));
+
+            caret_style_combo_box.connect_changed(clone!(edit_view => move |cb| {
+                if let Some(style) = cb.get_active_id() {
+                    debug!("{}: {}", gettext("Caret style changed to"), style);
+                    set_caret_style(&style);
+                    edit_view.borrow().view_item.edit_area.queue_draw();
+                }
+            }));
+        }
+
+        {
+            minimap_checkbutton.set_active(get_show_minimap());
+
+            minimap_checkbutton.connect_toggled(clone!(edit_view => move |toggle_btn| {
+                set_show_minimap(toggle_btn.get_active());
+                edit_view.borrow().update_minimap_visibility();
+            }));
         }
 
         {
@@ -176,6 +290,38 @@ impl PrefsWin {
             }));
         }
 
+        {
+            keybinding_preset_combo_box.set_active_id(Some(get_keybinding_preset().as_str()));
+
+            keybinding_preset_combo_box.connect_changed(
+                clone!(main_state, edit_view, parent => move |cb| {
+                    if let Some(preset) = cb.get_active_id() {
+                        debug!("{}: {}", gettext("Keybinding preset changed to"), preset);
+                        set_keybinding_preset(&preset);
+                        MainWin::reload_keymap(&parent, &main_state);
+                        // The other tabs catch up once they're shown again
+                        edit_view.borrow_mut().sync_keymap();
+                    }
+                }),
+            );
+        }
+
+        {
+            html_line_numbers_checkbutton.set_active(get_html_line_numbers());
+
+            html_line_numbers_checkbutton.connect_toggled(|toggle_btn| {
+                set_html_line_numbers(toggle_btn.get_active());
+            });
+        }
+
+        {
+            html_background_checkbutton.set_active(get_html_background());
+
+            html_background_checkbutton.connect_toggled(|toggle_btn| {
+                set_html_background(toggle_btn.get_active());
+            });
+        }
+
         let prefs_win = Rc::new(RefCell::new(Self {
             core: core.clone(),
             window: window.clone(),
@@ -186,4 +332,54 @@ impl PrefsWin {
 
         prefs_win
     }
+
+    /// Lets the user pick a theme and imports it into the config dir
+    fn import_theme(
+        window: &Window,
+        main_state: &Rc<RefCell<MainState>>,
+        theme_combo_boxes: &[ComboBoxText],
+    ) {
+        let fcn = FileChooserNative::new(
+            Some(gettext("Import Theme").as_str()),
+            Some(window),
+            FileChooserAction::Open,
+            Some(gettext("Import").as_str()),
+            Some(gettext("Cancel").as_str()),
+        );
+        let filter = FileFilter::new();
+        filter.set_name(Some(gettext("Themes").as_str()));
+        filter.add_pattern("*.tmTheme");
+        fcn.add_filter(&filter);
+
+        let theme_combo_boxes = theme_combo_boxes.to_vec();
+        fcn.connect_response(clone!(main_state => move |fcd, res| {
+            if res != ResponseType::Accept {
+                return;
+            }
+            let file = match fcd.get_filename() {
+                Some(file) => file,
+                None => return,
+            };
+
+            let config_dir = main_state.borrow().config.borrow().dir();
+            match import::import(&config_dir, &file) {
+                Ok(name) => {
+                    debug!("{}: {}", gettext("Imported theme"), name);
+                    let mut main_state = main_state.borrow_mut();
+                    if !main_state.themes.contains(&name) {
+                        for combo_box in &theme_combo_boxes {
+                            combo_box.append_text(&name);
+                        }
+                        main_state.themes.push(name);
+                    }
+                }
+                Err(e) => {
+                    let msg = format!("{} '{}': {}", gettext("Couldn't import"), file.display(), e);
+                    ErrorDialog::new(ErrorMsg { msg, fatal: false }).show_all();
+                }
+            }
+        }));
+
+        fcn.run();
+    }
 }
@@ -0,0 +1,344 @@
+use crate::edit_view::span_attributes;
+use crate::errors::{ErrorDialog, ErrorMsg};
+use crate::html::StyledLine;
+use crate::pref_storage::*;
+use crate::theme::LineStyle;
+use gettextrs::gettext;
+use gtk::*;
+use log::debug;
+use pango::{self, FontDescription, LayoutExt};
+use pangocairo::functions::*;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+/// The space between the line numbers and the text, in chars
+const LINE_NUMBER_SPACING: f64 = 2.0;
+
+/// What the user can choose in the "Text" tab of the print dialog
+#[derive(Clone, Copy, Debug)]
+struct Options {
+    line_numbers: bool,
+    header: bool,
+    wrap_lines: bool,
+}
+
+impl Options {
+    fn load() -> Self {
+        Self {
+            line_numbers: get_print_line_numbers(),
+            header: get_print_header(),
+            wrap_lines: get_print_wrap_lines(),
+        }
+    }
+
+    fn save(self) {
+        set_print_line_numbers(self.line_numbers);
+        set_print_header(self.header);
+        set_print_wrap_lines(self.wrap_lines);
+    }
+}
+
+/// A line of the document with the attributes for its syntax highlighting
+struct PrintLine {
+    number: u64,
+    text: String,
+    attributes: pango::AttrList,
+}
+
+/// The layouts of the lines and which of their (wrapped) lines go onto which page, as
+/// `(line, line of the layout)`
+#[derive(Default)]
+struct Pages {
+    layouts: Vec<pango::Layout>,
+    pages: Vec<Vec<(usize, i32)>>,
+}
+
+/// A snapshot of a document for printing. Printing runs a main loop of its own while the print
+/// dialog is shown, so it mustn't borrow the `EditView`.
+pub struct PrintJob {
+    file_name: Option<String>,
+    font_desc: FontDescription,
+    lines: Vec<PrintLine>,
+}
+
+impl PrintJob {
+    /// Creates a job printing `lines`, highlighted with `styles`
+    pub fn new(
+        file_name: Option<String>,
+        font_desc: FontDescription,
+        lines: Vec<StyledLine>,
+        styles: &HashMap<usize, LineStyle>,
+    ) -> Self {
+        let lines = lines
+            .into_iter()
+            .map(|line| PrintLine {
+                attributes: span_attributes(&line.spans, styles),
+                number: line.number,
+                text: line.text,
+            })
+            .collect();
+        Self {
+            file_name,
+            font_desc,
+            lines,
+        }
+    }
+
+    /// The name of the file without its directory, e.g. for the name of the PDF we print to
+    fn title(&self) -> String {
+        self.file_name
+            .as_ref()
+            .and_then(|f| Path::new(f).file_name())
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_else(|| gettext("Untitled"))
+    }
+
+    /// Shows the print dialog and prints the document if the user wants to
+    pub fn run(self, parent: Option<&Window>) {
+        let title = self.title();
+        let options = Rc::new(Cell::new(Options::load()));
+        let pages = Rc::new(RefCell::new(Pages::default()));
+        let job = Rc::new(self);
+
+        let operation = PrintOperation::new();
+        operation.set_job_name(&title);
+        operation.set_embed_page_setup(true);
+        operation.set_custom_tab_label(Some(gettext("Text").as_str()));
+
+        // Make "Print to File" write a PDF named after the document
+        let settings = PrintSettings::new();
+        let basename = Path::new(&title)
+            .file_stem()
+            .map_or_else(|| title.clone(), |s| s.to_string_lossy().into_owned());
+        settings.set("output-basename", Some(basename.as_str()));
+        settings.set("output-file-format", Some("pdf"));
+        operation.set_print_settings(Some(&settings));
+
+        operation.connect_create_custom_widget(clone!(options => move |_| {
+            create_options_widget(&options).upcast::<glib::Object>()
+        }));
+
+        operation.connect_custom_widget_apply(clone!(options => move |_, _| {
+            options.get().save();
+        }));
+
+        operation.connect_begin_print(clone!(job, options, pages => move |operation, context| {
+            *pages.borrow_mut() = job.paginate(context, options.get());
+            operation.set_n_pages(pages.borrow().pages.len() as i32);
+        }));
+
+        operation.connect_draw_page(clone!(job, options, pages => move |_, context, page| {
+            job.draw_page(context, options.get(), &pages.borrow(), page as usize);
+        }));
+
+        match operation.run(PrintOperationAction::PrintDialog, parent) {
+            Ok(result) => debug!("{}: {:?}", gettext("Printing finished"), result),
+            Err(e) => {
+                ErrorDialog::new(ErrorMsg {
+                    msg: format!("{}: {}", gettext("Failed to print the document"), e),
+                    fatal: false,
+                })
+                .show_all();
+            }
+        }
+    }
+
+    /// A layout for `text` in the document's font, in the resolution of the printer
+    fn create_layout(&self, context: &PrintContext, text: &str) -> Option<pango::Layout> {
+        let layout = context.create_pango_layout()?;
+        layout.set_font_description(&self.font_desc);
+        layout.set_text(text);
+        Some(layout)
+    }
+
+    /// The height of a line and the width of a char
+    fn font_size(&self, context: &PrintContext) -> (f64, f64) {
+        self.create_layout(context, "0")
+            .map_or((12.0, 7.0), |layout| {
+                let (_, extents) = layout.get_pixel_extents();
+                (f64::from(extents.height), f64::from(extents.width))
+            })
+    }
+
+    /// How wide the column of line numbers is, including the space after it
+    fn line_numbers_width(&self, context: &PrintContext, options: Options) -> f64 {
+        if !options.line_numbers {
+            return 0.0;
+        }
+        let (_, char_width) = self.font_size(context);
+        let digits = self.lines.last().map_or(1, |l| l.number.to_string().len());
+        (digits as f64 + LINE_NUMBER_SPACING) * char_width
+    }
+
+    /// The height of the header, including the space below it
+    fn header_height(&self, context: &PrintContext, options: Options) -> f64 {
+        if options.header {
+            self.font_size(context).0 * 2.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Lays out the lines for the page size the user has picked and splits them into pages
+    fn paginate(&self, context: &PrintContext, options: Options) -> Pages {
+        let (line_height, _) = self.font_size(context);
+        let text_width = context.get_width() - self.line_numbers_width(context, options);
+        let text_height = context.get_height() - self.header_height(context, options);
+        let rows_per_page = ((text_height / line_height).floor() as usize).max(1);
+
+        let mut pages = Pages::default();
+        let mut rows = Vec::new();
+        for (ix, line) in self.lines.iter().enumerate() {
+            let layout = match self.create_layout(context, &line.text) {
+                Some(layout) => layout,
+                None => break,
+            };
+            layout.set_attributes(&line.attributes);
+            if options.wrap_lines {
+                layout.set_width((text_width * f64::from(pango::SCALE)) as i32);
+                layout.set_wrap(pango::WrapMode::WordChar);
+            }
+            for row in 0..layout.get_line_count() {
+                rows.push((ix, row));
+            }
+            pages.layouts.push(layout);
+        }
+        pages.pages = rows.chunks(rows_per_page).map(<[_]>::to_vec).collect();
+        // Print an empty page for an empty document
+        if pages.pages.is_empty() {
+            pages.pages.push(Vec::new());
+        }
+        pages
+    }
+
+    fn draw_page(&self, context: &PrintContext, options: Options, pages: &Pages, page: usize) {
+        let cr = match context.get_cairo_context() {
+            Some(cr) => cr,
+            None => return,
+        };
+        let (line_height, char_width) = self.font_size(context);
+        let numbers_width = self.line_numbers_width(context, options);
+        let mut y = self.header_height(context, options);
+
+        if options.header {
+            self.draw_header(context, &cr, page, pages.pages.len());
+        }
+
+        // Don't let lines we don't wrap run off the page
+        cr.rectangle(0.0, 0.0, context.get_width(), context.get_height());
+        cr.clip();
+
+        let mut prev_line = None;
+        for &(ix, row) in pages.pages.get(page).into_iter().flatten() {
+            let layout = &pages.layouts[ix];
+            let layout_line = match layout.get_line_readonly(row) {
+                Some(line) => line,
+                None => continue,
+            };
+            let baseline = y + f64::from(layout.get_baseline()) / f64::from(pango::SCALE);
+
+            // Only number the first row of a wrapped line
+            if options.line_numbers && prev_line != Some(ix) {
+                if let Some(number) =
+                    self.create_layout(context, &self.lines[ix].number.to_string())
+                {
+                    let (_, extents) = number.get_pixel_extents();
+                    cr.set_source_rgb(0.5, 0.5, 0.5);
+                    cr.move_to(
+                        numbers_width - LINE_NUMBER_SPACING * char_width - f64::from(extents.width),
+                        y,
+                    );
+                    show_layout(&cr, &number);
+                }
+            }
+            prev_line = Some(ix);
+
+            cr.set_source_rgb(0.0, 0.0, 0.0);
+            cr.move_to(numbers_width, baseline);
+            show_layout_line(&cr, &layout_line);
+            y += line_height;
+        }
+    }
+
+    /// Draws the file name and the page number above a line at the top of the page
+    fn draw_header(
+        &self,
+        context: &PrintContext,
+        cr: &cairo::Context,
+        page: usize,
+        n_pages: usize,
+    ) {
+        let (line_height, _) = self.font_size(context);
+        let width = context.get_width();
+        let file_name = self.file_name.clone().unwrap_or_else(|| self.title());
+        let page_number = format!(
+            "{} {} {} {}",
+            gettext("Page"),
+            page + 1,
+            gettext("of"),
+            n_pages
+        );
+
+        cr.set_source_rgb(0.0, 0.0, 0.0);
+        if let Some(layout) = self.create_layout(context, &page_number) {
+            let (_, extents) = layout.get_pixel_extents();
+            cr.move_to(width - f64::from(extents.width), 0.0);
+            show_layout(cr, &layout);
+
+            if let Some(name) = self.create_layout(context, &file_name) {
+                // Cut long paths off at the start, the file name is the interesting part
+                name.set_width(
+                    ((width - f64::from(extents.width)) * 0.9 * f64::from(pango::SCALE)) as i32,
+                );
+                name.set_ellipsize(pango::EllipsizeMode::Start);
+                cr.move_to(0.0, 0.0);
+                show_layout(cr, &name);
+            }
+        }
+
+        let rule = (line_height * 1.5).round() + 0.5;
+        cr.set_line_width(0.5);
+        cr.move_to(0.0, rule);
+        cr.line_to(width, rule);
+        cr.stroke();
+    }
+}
+
+/// The "Text" tab of the print dialog
+fn create_options_widget(options: &Rc<Cell<Options>>) -> gtk::Box {
+    let container = gtk::Box::new(Orientation::Vertical, 6);
+    container.set_border_width(12);
+
+    let add_check_button = |label: &str, active: bool, set: fn(&mut Options, bool)| {
+        let check_button = CheckButton::new_with_label(label);
+        check_button.set_active(active);
+        check_button.connect_toggled(clone!(options => move |check_button| {
+            let mut new_options = options.get();
+            set(&mut new_options, check_button.get_active());
+            options.set(new_options);
+        }));
+        container.pack_start(&check_button, false, false, 0);
+    };
+
+    let current = options.get();
+    add_check_button(
+        &gettext("Print line numbers"),
+        current.line_numbers,
+        |o, active| o.line_numbers = active,
+    );
+    add_check_button(
+        &gettext("Print the file name and page numbers"),
+        current.header,
+        |o, active| o.header = active,
+    );
+    add_check_button(
+        &gettext("Wrap long lines"),
+        current.wrap_lines,
+        |o, active| o.wrap_lines = active,
+    );
+
+    container.show_all();
+    container
+}
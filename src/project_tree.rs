@@ -0,0 +1,552 @@
+use crate::errors::{ErrorDialog, ErrorMsg};
+use crate::gitignore::GitIgnore;
+use crate::main_win::MainWin;
+use gdk::EventButton;
+use gettextrs::gettext;
+use gio::{FileExt, FileMonitorEvent, FileMonitorExt};
+use glib::MainContext;
+use gtk::*;
+use log::{debug, error, trace};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+const COLUMN_NAME: u32 = 0;
+const COLUMN_PATH: u32 = 1;
+const COLUMN_ICON: u32 = 2;
+const COLUMN_IS_DIR: u32 = 3;
+
+/// If a file or folder can be given `name`. It has to be a single component of a path in the
+/// current folder, so `.`, `..` and names with a separator are out, as are blank ones.
+fn is_valid_name(name: &str) -> bool {
+    let trimmed = name.trim();
+    !trimmed.is_empty()
+        && trimmed != "."
+        && trimmed != ".."
+        && !name.contains(std::path::MAIN_SEPARATOR)
+}
+
+/// The `ProjectTree` is the sidebar which shows the folder the user has opened. Directories are
+/// only read once the user expands them and are watched via `gio::FileMonitor`s afterwards, so
+/// the tree stays up to date if files change on disk.
+pub struct ProjectTree {
+    pub root: Option<PathBuf>,
+    pub container: ScrolledWindow,
+    pub tree_view: TreeView,
+    tree_store: TreeStore,
+    gitignore: Option<GitIgnore>,
+    monitors: HashMap<PathBuf, gio::FileMonitor>,
+    changes_tx: glib::Sender<PathBuf>,
+    window: ApplicationWindow,
+}
+
+impl ProjectTree {
+    pub fn new(window: &ApplicationWindow) -> Rc<RefCell<Self>> {
+        let tree_store = TreeStore::new(&[Type::String, Type::String, Type::String, Type::Bool]);
+        let tree_view = TreeView::new_with_model(&tree_store);
+        tree_view.set_headers_visible(false);
+        tree_view.set_enable_search(true);
+        tree_view.set_search_column(COLUMN_NAME as i32);
+
+        let column = TreeViewColumn::new();
+        let icon_cell = CellRendererPixbuf::new();
+        let name_cell = CellRendererText::new();
+        column.pack_start(&icon_cell, false);
+        column.pack_start(&name_cell, true);
+        column.add_attribute(&icon_cell, "icon-name", COLUMN_ICON as i32);
+        column.add_attribute(&name_cell, "text", COLUMN_NAME as i32);
+        tree_view.append_column(&column);
+
+        let container = ScrolledWindow::new(None::<&Adjustment>, None::<&Adjustment>);
+        container.set_policy(PolicyType::Never, PolicyType::Automatic);
+        container.set_size_request(200, -1);
+        container.add(&tree_view);
+        container.show_all();
+
+        // FileMonitors notify us about changed directories via this channel
+        let (changes_tx, changes_rx) = MainContext::channel::<PathBuf>(glib::PRIORITY_DEFAULT);
+
+        let project_tree = Rc::new(RefCell::new(Self {
+            root: None,
+            container,
+            tree_view: tree_view.clone(),
+            tree_store,
+            gitignore: None,
+            monitors: HashMap::new(),
+            changes_tx,
+            window: window.clone(),
+        }));
+
+        changes_rx.attach(
+            &MainContext::default(),
+            clone!(project_tree => move |dir| {
+                if let Ok(mut tree) = project_tree.try_borrow_mut() {
+                    tree.refresh_dir(&dir);
+                }
+                glib::source::Continue(true)
+            }),
+        );
+
+        // Only read directories once the user actually wants to see their contents
+        tree_view.connect_test_expand_row(clone!(project_tree => move |_, iter, _| {
+            // This is also emitted when we expand rows ourselves during refresh_dir, in which
+            // case the tree is already borrowed and takes care of populating the row.
+            if let Ok(mut tree) = project_tree.try_borrow_mut() {
+                tree.expand(iter);
+            }
+            Inhibit(false)
+        }));
+
+        project_tree
+    }
+
+    /// Sets up event listeners which need access to the `MainWin`.
+    pub fn connect_events(project_tree: &Rc<RefCell<Self>>, main_win: &Rc<RefCell<MainWin>>) {
+        let tree_view = project_tree.borrow().tree_view.clone();
+
+        tree_view.connect_row_activated(clone!(project_tree, main_win => move |tv, path, _| {
+            let entry = project_tree.borrow().entry_at(path);
+            if let Some((file, is_dir)) = entry {
+                if is_dir {
+                    if tv.row_expanded(path) {
+                        tv.collapse_row(path);
+                    } else {
+                        tv.expand_row(path, false);
+                    }
+                } else {
+                    debug!("{}: {:?}", gettext("Opening file from project tree"), file);
+                    MainWin::open_file(&main_win, &file.to_string_lossy());
+                }
+            }
+        }));
+
+        tree_view.connect_button_press_event(clone!(project_tree => move |tv, eb| {
+            if eb.get_button() != 3 {
+                return Inhibit(false);
+            }
+
+            let (x, y) = eb.get_position();
+            match tv.get_path_at_pos(x as i32, y as i32) {
+                Some((Some(path), _, _, _)) => tv.get_selection().select_path(&path),
+                _ => tv.get_selection().unselect_all(),
+            }
+            Self::show_context_menu(&project_tree, eb);
+            Inhibit(true)
+        }));
+    }
+
+    /// Shows the folder at `path` in the tree, replacing the previously opened folder.
+    pub fn open_folder(&mut self, path: &Path) {
+        debug!("{}: {:?}", gettext("Opening folder"), path);
+
+        self.tree_store.clear();
+        self.monitors.clear();
+        self.root = Some(path.to_path_buf());
+        self.gitignore = Some(GitIgnore::new(path));
+        self.populate(None, path);
+    }
+
+    /// Returns the path and whether it's a directory for a row of the tree
+    fn entry_at(&self, path: &TreePath) -> Option<(PathBuf, bool)> {
+        let iter = self.tree_store.get_iter(path)?;
+        self.entry_of(&iter)
+    }
+
+    fn entry_of(&self, iter: &TreeIter) -> Option<(PathBuf, bool)> {
+        let path = self
+            .tree_store
+            .get_value(iter, COLUMN_PATH as i32)
+            .get::<String>()
+            .filter(|p| !p.is_empty())?;
+        let is_dir = self
+            .tree_store
+            .get_value(iter, COLUMN_IS_DIR as i32)
+            .get::<bool>()
+            .unwrap_or(false);
+
+        Some((PathBuf::from(path), is_dir))
+    }
+
+    /// The entry the user has currently selected in the tree
+    fn selected_entry(&self) -> Option<(PathBuf, bool)> {
+        let (_, iter) = self.tree_view.get_selection().get_selected()?;
+        self.entry_of(&iter)
+    }
+
+    /// Adds the (non-ignored) contents of `dir` to the tree below `parent`. Directories get an
+    /// empty placeholder child so GTK draws an expander for them.
+    fn populate(&mut self, parent: Option<&TreeIter>, dir: &Path) {
+        let entries = match self.gitignore {
+            Some(ref mut gitignore) => gitignore.read_dir(dir),
+            None => return,
+        };
+
+        for (path, is_dir) in entries {
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let path_str = path.to_string_lossy().into_owned();
+            let icon = if is_dir {
+                "folder-symbolic"
+            } else {
+                "text-x-generic-symbolic"
+            };
+
+            let iter = self.tree_store.insert_with_values(
+                parent,
+                None,
+                &[COLUMN_NAME, COLUMN_PATH, COLUMN_ICON, COLUMN_IS_DIR],
+                &[&name, &path_str, &icon, &is_dir],
+            );
+            if is_dir {
+                self.tree_store
+                    .insert_with_values(Some(&iter), None, &[COLUMN_NAME], &[&""]);
+            }
+        }
+
+        self.watch(dir);
+    }
+
+    /// Called when the user expands a directory, replaces the placeholder with the directory's
+    /// contents.
+    fn expand(&mut self, iter: &TreeIter) {
+        if let Some(child) = self.tree_store.iter_children(Some(iter)) {
+            if self.entry_of(&child).is_some() {
+                // Already populated
+                return;
+            }
+            self.tree_store.remove(&child);
+        }
+
+        if let Some((dir, true)) = self.entry_of(iter) {
+            trace!("{}: {:?}", gettext("Expanding directory"), dir);
+            self.populate(Some(iter), &dir);
+        }
+    }
+
+    /// Starts watching `dir` for changes, if we don't do so already.
+    fn watch(&mut self, dir: &Path) {
+        if self.monitors.contains_key(dir) {
+            return;
+        }
+
+        match gio::File::new_for_path(dir)
+            .monitor_directory(gio::FileMonitorFlags::NONE, None::<&gio::Cancellable>)
+        {
+            Ok(monitor) => {
+                let changes_tx = self.changes_tx.clone();
+                let dir_buf = dir.to_path_buf();
+                monitor.connect_changed(move |_, _, _, event| match event {
+                    FileMonitorEvent::Created
+                    | FileMonitorEvent::Deleted
+                    | FileMonitorEvent::MovedIn
+                    | FileMonitorEvent::MovedOut
+                    | FileMonitorEvent::Renamed => {
+                        changes_tx
+                            .send(dir_buf.clone())
+                            .unwrap_or_else(|e| error!("{}", e.to_string()));
+                    }
+                    _ => (),
+                });
+                self.monitors.insert(dir.to_path_buf(), monitor);
+            }
+            Err(e) => error!(
+                "{} {:?}: {}",
+                gettext("Failed to watch directory"),
+                dir,
+                e.to_string()
+            ),
+        }
+    }
+
+    /// Finds the row of `path` in the tree. Only finds rows which have been populated already.
+    fn iter_for_path(&self, path: &Path) -> Option<TreeIter> {
+        let root = self.root.as_ref()?;
+        let rel_path = path.strip_prefix(root).ok()?;
+
+        let mut parent: Option<TreeIter> = None;
+        let mut current = root.clone();
+        for component in rel_path.components() {
+            current.push(component);
+            let iter = self.tree_store.iter_children(parent.as_ref())?;
+            loop {
+                if self.entry_of(&iter).map(|(p, _)| p).as_ref() == Some(&current) {
+                    break;
+                }
+                if !self.tree_store.iter_next(&iter) {
+                    return None;
+                }
+            }
+            parent = Some(iter);
+        }
+
+        parent
+    }
+
+    /// Collects the directories below `parent` which are expanded, parents before their
+    /// children.
+    fn expanded_dirs(&self, parent: Option<&TreeIter>, expanded: &mut Vec<PathBuf>) {
+        let child = match self.tree_store.iter_children(parent) {
+            Some(child) => child,
+            None => return,
+        };
+        loop {
+            let is_expanded = self
+                .tree_store
+                .get_path(&child)
+                .map_or(false, |tree_path| self.tree_view.row_expanded(&tree_path));
+            if is_expanded {
+                if let Some((path, _)) = self.entry_of(&child) {
+                    expanded.push(path);
+                    self.expanded_dirs(Some(&child), expanded);
+                }
+            }
+            if !self.tree_store.iter_next(&child) {
+                break;
+            }
+        }
+    }
+
+    /// Re-reads the contents of `dir`, keeping the subdirectories expanded which were expanded
+    /// before.
+    pub fn refresh_dir(&mut self, dir: &Path) {
+        // Stop watching directories which have been deleted or moved away
+        self.monitors
+            .retain(|path, _| !path.starts_with(dir) || path.is_dir());
+        // The .gitignore might have changed
+        if let Some(ref mut gitignore) = self.gitignore {
+            gitignore.reload(dir);
+        }
+
+        let is_root = self.root.as_ref().map(|r| r.as_path()) == Some(dir);
+        let parent = if is_root {
+            None
+        } else {
+            match self.iter_for_path(dir) {
+                Some(iter) => Some(iter),
+                None => return,
+            }
+        };
+        trace!("{}: {:?}", gettext("Refreshing directory"), dir);
+
+        let mut expanded = Vec::new();
+        self.expanded_dirs(parent.as_ref(), &mut expanded);
+        if let Some(child) = self.tree_store.iter_children(parent.as_ref()) {
+            while self.tree_store.remove(&child) {}
+        }
+
+        self.populate(parent.as_ref(), dir);
+
+        for path in expanded {
+            if let Some(iter) = self.iter_for_path(&path) {
+                self.expand(&iter);
+                if let Some(tree_path) = self.tree_store.get_path(&iter) {
+                    self.tree_view.expand_row(&tree_path, false);
+                }
+            }
+        }
+    }
+
+    fn show_context_menu(project_tree: &Rc<RefCell<Self>>, eb: &EventButton) {
+        let menu = Menu::new();
+        let new_file_item = MenuItem::new_with_label(&gettext("New File…"));
+        let new_folder_item = MenuItem::new_with_label(&gettext("New Folder…"));
+        let rename_item = MenuItem::new_with_label(&gettext("Rename…"));
+        let delete_item = MenuItem::new_with_label(&gettext("Delete"));
+
+        let has_selection = project_tree.borrow().selected_entry().is_some();
+        rename_item.set_sensitive(has_selection);
+        delete_item.set_sensitive(has_selection);
+
+        new_file_item.connect_activate(clone!(project_tree => move |_| {
+            Self::create_entry(&project_tree, false);
+        }));
+        new_folder_item.connect_activate(clone!(project_tree => move |_| {
+            Self::create_entry(&project_tree, true);
+        }));
+        rename_item.connect_activate(clone!(project_tree => move |_| {
+            Self::rename_entry(&project_tree);
+        }));
+        delete_item.connect_activate(clone!(project_tree => move |_| {
+            Self::delete_entry(&project_tree);
+        }));
+
+        menu.append(&new_file_item);
+        menu.append(&new_folder_item);
+        menu.append(&SeparatorMenuItem::new());
+        menu.append(&rename_item);
+        menu.append(&delete_item);
+        menu.set_attach_widget(Some(&project_tree.borrow().tree_view));
+        menu.show_all();
+        menu.popup_easy(eb.get_button(), eb.get_time());
+    }
+
+    /// Asks the user for a file name. Returns `None` if the dialog has been cancelled. The user
+    /// can't confirm names `is_valid_name` rejects.
+    /// This calls the GTK main loop, so there must not be any RefCell borrows out while it runs.
+    fn ask_name(window: &ApplicationWindow, title: &str, initial: &str) -> Option<String> {
+        let dialog = Dialog::new_with_buttons(
+            Some(title),
+            Some(window),
+            DialogFlags::MODAL | DialogFlags::DESTROY_WITH_PARENT,
+            &[
+                (&gettext("Cancel"), ResponseType::Cancel),
+                (&gettext("OK"), ResponseType::Accept),
+            ],
+        );
+        dialog.set_default_response(ResponseType::Accept);
+
+        let entry = Entry::new();
+        entry.set_text(initial);
+        entry.set_activates_default(true);
+        entry.set_margin_start(12);
+        entry.set_margin_end(12);
+        let error_label = Label::new(None);
+        error_label.set_margin_start(12);
+        error_label.set_margin_end(12);
+        let content_area = dialog.get_content_area();
+        content_area.pack_start(&entry, false, false, 12);
+        content_area.pack_start(&error_label, false, false, 0);
+
+        dialog.set_response_sensitive(ResponseType::Accept, is_valid_name(initial));
+        entry.connect_changed(clone!(dialog, error_label => move |entry| {
+            let name = entry.get_text().map(|s| s.to_string()).unwrap_or_default();
+            let valid = is_valid_name(&name);
+            dialog.set_response_sensitive(ResponseType::Accept, valid);
+            if valid {
+                error_label.set_text("");
+            } else {
+                error_label.set_text(&gettext(
+                    "The name can't be empty, '.' or '..', or contain a path separator",
+                ));
+            }
+        }));
+        dialog.show_all();
+
+        let response = ResponseType::from(dialog.run());
+        let name = entry.get_text().map(|s| s.to_string());
+        dialog.destroy();
+
+        if response == ResponseType::Accept {
+            name.filter(|n| is_valid_name(n))
+        } else {
+            None
+        }
+    }
+
+    fn create_entry(project_tree: &Rc<RefCell<Self>>, is_dir: bool) {
+        let (window, dir) = {
+            let tree = project_tree.borrow();
+            let dir = match tree.selected_entry() {
+                Some((path, true)) => path,
+                Some((path, false)) => path.parent().map(Path::to_path_buf).unwrap_or(path),
+                None => match tree.root {
+                    Some(ref root) => root.clone(),
+                    None => return,
+                },
+            };
+            (tree.window.clone(), dir)
+        };
+
+        let title = if is_dir {
+            gettext("New Folder")
+        } else {
+            gettext("New File")
+        };
+
+        if let Some(name) = Self::ask_name(&window, &title, "") {
+            let path = dir.join(name);
+            let res = if is_dir {
+                std::fs::create_dir(&path)
+            } else {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .map(|_| ())
+            };
+
+            match res {
+                Ok(_) => project_tree.borrow_mut().refresh_dir(&dir),
+                Err(e) => Self::show_error(&gettext("Couldn't create"), &path, &e),
+            }
+        }
+    }
+
+    fn rename_entry(project_tree: &Rc<RefCell<Self>>) {
+        let (window, path) = {
+            let tree = project_tree.borrow();
+            match tree.selected_entry() {
+                Some((path, _)) => (tree.window.clone(), path),
+                None => return,
+            }
+        };
+        let old_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        if let Some(new_name) = Self::ask_name(&window, &gettext("Rename"), &old_name) {
+            if let Some(dir) = path.parent() {
+                match std::fs::rename(&path, dir.join(new_name)) {
+                    Ok(_) => project_tree.borrow_mut().refresh_dir(dir),
+                    Err(e) => Self::show_error(&gettext("Couldn't rename"), &path, &e),
+                }
+            }
+        }
+    }
+
+    fn delete_entry(project_tree: &Rc<RefCell<Self>>) {
+        let (window, path, is_dir) = {
+            let tree = project_tree.borrow();
+            match tree.selected_entry() {
+                Some((path, is_dir)) => (tree.window.clone(), path, is_dir),
+                None => return,
+            }
+        };
+
+        let confirm_dialog = MessageDialog::new(
+            Some(&window),
+            DialogFlags::MODAL,
+            MessageType::Question,
+            ButtonsType::OkCancel,
+            &format!(
+                "{} '{}'?",
+                gettext("Permanently delete"),
+                path.to_string_lossy()
+            ),
+        );
+        let response = ResponseType::from(confirm_dialog.run());
+        confirm_dialog.destroy();
+
+        if response != ResponseType::Ok {
+            return;
+        }
+
+        let res = if is_dir {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+
+        match res {
+            Ok(_) => {
+                if let Some(dir) = path.parent() {
+                    project_tree.borrow_mut().refresh_dir(dir);
+                }
+            }
+            Err(e) => Self::show_error(&gettext("Couldn't delete"), &path, &e),
+        }
+    }
+
+    fn show_error(msg: &str, path: &Path, e: &std::io::Error) {
+        let err_msg = format!("{} '{}': {}", msg, path.to_string_lossy(), e.to_string());
+        ErrorDialog::new(ErrorMsg {
+            msg: err_msg,
+            fatal: false,
+        })
+        .show_all();
+    }
+}
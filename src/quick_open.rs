@@ -0,0 +1,238 @@
+use crate::fuzzy;
+use crate::gitignore::GitIgnore;
+use crate::main_win::MainWin;
+use gdk::enums::key;
+use gettextrs::gettext;
+use glib::MainContext;
+use gtk::*;
+use log::{debug, trace};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::thread;
+
+const COLUMN_NAME: u32 = 0;
+const COLUMN_PATH: u32 = 1;
+/// We don't want to display thousands of rows, nobody is going to scroll through them anyway
+const MAX_RESULTS: usize = 100;
+
+/// `path` relative to `root`, the way we show it to the user
+fn to_rel(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The quick open dialog (Ctrl+P) which lets the user fuzzy search for files in the folder
+/// that's open in the sidebar.
+pub struct QuickOpen {
+    window: Window,
+    tree_view: TreeView,
+    list_store: ListStore,
+    root: PathBuf,
+    /// The paths of the files relative to the root of the folder, these are what we match against
+    candidates: Vec<String>,
+    paths: Vec<PathBuf>,
+    /// The recently used files (relative to the root), most recently used first
+    recent: Vec<String>,
+    /// What the user has typed into the search entry so far
+    query: String,
+}
+
+impl QuickOpen {
+    pub fn new(
+        main_win: &Rc<RefCell<MainWin>>,
+        parent: &ApplicationWindow,
+        root: &Path,
+        recent: &[String],
+    ) -> Rc<RefCell<Self>> {
+        let recent = recent
+            .iter()
+            .map(|p| Path::new(p))
+            .filter(|p| p.starts_with(root))
+            .map(|p| to_rel(root, p))
+            .collect();
+
+        let window = Window::new(WindowType::Toplevel);
+        window.set_title(&gettext("Open File"));
+        window.set_transient_for(parent);
+        window.set_modal(true);
+        window.set_type_hint(gdk::WindowTypeHint::Dialog);
+        window.set_position(WindowPosition::CenterOnParent);
+        window.set_default_size(550, 400);
+
+        let search_entry = SearchEntry::new();
+        search_entry.set_placeholder_text(gettext("Search for files").as_str());
+
+        let list_store = ListStore::new(&[Type::String, Type::String]);
+        let tree_view = TreeView::new_with_model(&list_store);
+        tree_view.set_headers_visible(false);
+        tree_view.set_can_focus(false);
+        let column = TreeViewColumn::new();
+        let cell = CellRendererText::new();
+        column.pack_start(&cell, true);
+        column.add_attribute(&cell, "text", COLUMN_NAME as i32);
+        tree_view.append_column(&column);
+
+        let scrolled_window = ScrolledWindow::new(None::<&Adjustment>, None::<&Adjustment>);
+        scrolled_window.set_policy(PolicyType::Never, PolicyType::Automatic);
+        scrolled_window.add(&tree_view);
+
+        let vbox = Box::new(Orientation::Vertical, 6);
+        vbox.set_border_width(6);
+        vbox.pack_start(&search_entry, false, false, 0);
+        vbox.pack_start(&scrolled_window, true, true, 0);
+        window.add(&vbox);
+
+        let quick_open = Rc::new(RefCell::new(Self {
+            window: window.clone(),
+            tree_view: tree_view.clone(),
+            list_store,
+            root: root.to_path_buf(),
+            candidates: Vec::new(),
+            paths: Vec::new(),
+            recent,
+            query: String::new(),
+        }));
+
+        // Walking a big folder takes a while, so don't block the UI with it
+        let (tx, rx) = MainContext::channel::<Vec<PathBuf>>(glib::PRIORITY_DEFAULT);
+        let walk_root = root.to_path_buf();
+        thread::spawn(move || {
+            // Sending only fails if the dialog is gone already
+            let _ = tx.send(GitIgnore::new(&walk_root).walk());
+        });
+        rx.attach(
+            &MainContext::default(),
+            clone!(quick_open => move |paths| {
+                debug!("{}: {}", gettext("Files found for quick open"), paths.len());
+                quick_open.borrow_mut().set_paths(paths);
+                glib::source::Continue(false)
+            }),
+        );
+
+        search_entry.connect_search_changed(clone!(quick_open => move |w| {
+            let query = w.get_text().map(|s| s.to_string()).unwrap_or_default();
+            let mut quick_open = quick_open.borrow_mut();
+            quick_open.query = query;
+            quick_open.update_results();
+        }));
+
+        search_entry.connect_activate(clone!(quick_open, main_win => move |_| {
+            Self::open_selected(&quick_open, &main_win);
+        }));
+
+        search_entry.connect_stop_search(clone!(window => move |_| {
+            window.destroy();
+        }));
+
+        // The focus stays in the search entry, so we have to move the selection ourselves
+        search_entry.connect_key_press_event(clone!(quick_open => move |_, ek| {
+            match ek.get_keyval() {
+                key::Up | key::KP_Up => {
+                    quick_open.borrow().move_selection(false);
+                    Inhibit(true)
+                }
+                key::Down | key::KP_Down => {
+                    quick_open.borrow().move_selection(true);
+                    Inhibit(true)
+                }
+                _ => Inhibit(false),
+            }
+        }));
+
+        tree_view.connect_row_activated(clone!(quick_open, main_win => move |_, _, _| {
+            Self::open_selected(&quick_open, &main_win);
+        }));
+
+        window.show_all();
+        search_entry.grab_focus();
+
+        quick_open
+    }
+
+    /// Sets the files we match against once the worker thread has found them
+    fn set_paths(&mut self, paths: Vec<PathBuf>) {
+        self.candidates = paths.iter().map(|p| to_rel(&self.root, p)).collect();
+        self.paths = paths;
+        self.update_results();
+    }
+
+    /// Fills the list with the best matches for what the user has typed
+    fn update_results(&self) {
+        trace!("{}: {}", gettext("Quick open query"), self.query);
+        self.list_store.clear();
+
+        for i in fuzzy::rank(&self.query, &self.candidates, &self.recent)
+            .into_iter()
+            .take(MAX_RESULTS)
+        {
+            self.list_store.insert_with_values(
+                None,
+                &[COLUMN_NAME, COLUMN_PATH],
+                &[
+                    &self.candidates[i],
+                    &self.paths[i].to_string_lossy().into_owned(),
+                ],
+            );
+        }
+
+        if let Some(iter) = self.list_store.get_iter_first() {
+            self.tree_view.get_selection().select_iter(&iter);
+            if let Some(path) = self.list_store.get_path(&iter) {
+                self.tree_view.scroll_to_cell(
+                    Some(&path),
+                    None::<&TreeViewColumn>,
+                    false,
+                    0.0,
+                    0.0,
+                );
+            }
+        }
+    }
+
+    /// Selects the next (or previous) result
+    fn move_selection(&self, down: bool) {
+        let path = match self.tree_view.get_selection().get_selected() {
+            Some((model, iter)) => match model.get_path(&iter) {
+                Some(path) => path,
+                None => return,
+            },
+            None => return,
+        };
+
+        if down {
+            path.next();
+        } else if !path.prev() {
+            return;
+        }
+
+        if self.list_store.get_iter(&path).is_some() {
+            self.tree_view
+                .set_cursor(&path, None::<&TreeViewColumn>, false);
+            self.tree_view
+                .scroll_to_cell(Some(&path), None::<&TreeViewColumn>, false, 0.0, 0.0);
+        }
+    }
+
+    fn open_selected(quick_open: &Rc<RefCell<Self>>, main_win: &Rc<RefCell<MainWin>>) {
+        let (path, window) = {
+            let quick_open = quick_open.borrow();
+            let path = quick_open
+                .tree_view
+                .get_selection()
+                .get_selected()
+                .and_then(|(model, iter)| {
+                    model.get_value(&iter, COLUMN_PATH as i32).get::<String>()
+                });
+            (path, quick_open.window.clone())
+        };
+
+        if let Some(path) = path {
+            debug!("{}: {}", gettext("Quick opening"), path);
+            window.destroy();
+            MainWin::open_file(main_win, &path);
+        }
+    }
+}
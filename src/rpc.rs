@@ -304,6 +304,26 @@ impl Core {
     pub fn select_all(&self, view_id: &str) {
         self.send_edit_cmd(view_id, "select_all", &json!({}))
     }
+    pub fn indent(&self, view_id: &str) {
+        self.send_edit_cmd(view_id, "indent", &json!({}))
+    }
+    pub fn outdent(&self, view_id: &str) {
+        self.send_edit_cmd(view_id, "outdent", &json!({}))
+    }
+    pub fn add_selection_above(&self, view_id: &str) {
+        self.send_edit_cmd(view_id, "add_selection_above", &json!({}))
+    }
+    pub fn add_selection_below(&self, view_id: &str) {
+        self.send_edit_cmd(view_id, "add_selection_below", &json!({}))
+    }
+    /// splits the selections into one selection per line
+    pub fn selection_into_lines(&self, view_id: &str) {
+        self.send_edit_cmd(view_id, "selection_into_lines", &json!({}))
+    }
+    /// drops all selections but the first one and turns it into a caret
+    pub fn collapse_selections(&self, view_id: &str) {
+        self.send_edit_cmd(view_id, "collapse_selections", &json!({}))
+    }
 
     /// moves the cursor to a point (click)
     pub fn gesture_point_select(&self, view_id: &str, line: u64, col: u64) {
@@ -338,6 +358,23 @@ impl Core {
             }),
         )
     }
+    /// adds a caret at a point, e.g. for every line of a rectangular selection
+    pub fn gesture_add_sel(&self, view_id: &str, line: u64, col: u64) {
+        self.send_edit_cmd(
+            view_id,
+            "gesture",
+            &json!({
+                "line": line,
+                "col": col,
+                "ty": {
+                    "select": {
+                        "granularity": "point",
+                        "multi": true,
+                    },
+                },
+            }),
+        )
+    }
     /// modifies the selection to include a point (shift+click)
     pub fn gesture_range_select(&self, view_id: &str, line: u64, col: u64) {
         self.send_edit_cmd(
@@ -407,6 +444,12 @@ impl Core {
         self.send_edit_cmd(view_id, "scroll", &json!([first, last]))
     }
 
+    /// Asks xi to send us the lines from `first` to `last` (exclusive), even if they aren't
+    /// close to the visible region.
+    pub fn request_lines(&self, view_id: &str, first: u64, last: u64) {
+        self.send_edit_cmd(view_id, "request_lines", &json!([first, last]))
+    }
+
     pub fn drag(&self, view_id: &str, line: u64, col: u64) {
         self.send_edit_cmd(
             view_id,
@@ -485,7 +528,14 @@ impl Core {
     ///
     /// If `chars` is `None` and there is an active selection, returns
     /// the string value used for the search, else returns `Null`.
-    pub fn find(&self, view_id: &str, chars: &str, case_sensitive: bool, regex: Option<bool>) {
+    pub fn find(
+        &self,
+        view_id: &str,
+        chars: &str,
+        case_sensitive: bool,
+        regex: Option<bool>,
+        whole_words: Option<bool>,
+    ) {
         self.send_edit_cmd(
             view_id,
             "find",
@@ -493,6 +543,7 @@ impl Core {
                 "chars": chars,
                 "case_sensitive": case_sensitive,
                 "regex": regex,
+                "whole_words": whole_words,
             }),
         )
     }
@@ -0,0 +1,175 @@
+use crate::html::StyledLine;
+use crate::theme::{color_from_u32, LineStyle};
+use std::collections::HashMap;
+use std::fmt::Write;
+use syntect::highlighting::{Color, ThemeSettings};
+
+/// The colours of an RTF document. RTF refers to colours by their index in a table in the
+/// header, where 0 is the reader's default colour.
+struct ColorTable(Vec<Color>);
+
+impl ColorTable {
+    fn index(&mut self, color: Color) -> usize {
+        let color = Color { a: 255, ..color };
+        match self.0.iter().position(|c| *c == color) {
+            Some(ix) => ix + 1,
+            None => {
+                self.0.push(color);
+                self.0.len()
+            }
+        }
+    }
+
+    fn header(&self) -> String {
+        let mut header = String::from("{\\colortbl;");
+        for c in &self.0 {
+            let _ = write!(header, "\\red{}\\green{}\\blue{};", c.r, c.g, c.b);
+        }
+        header.push('}');
+        header
+    }
+}
+
+/// Escapes `text` for RTF. Chars outside of ASCII are written as UTF-16 code units, which RTF
+/// wants as signed numbers.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '{' | '}' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\line\n"),
+            '\r' => (),
+            '\t' => escaped.push_str("\\tab "),
+            c if c.is_ascii() => escaped.push(c),
+            c => {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    let _ = write!(escaped, "\\u{}?", *unit as i16);
+                }
+            }
+        }
+    }
+    escaped
+}
+
+/// The control words which switch to `style`
+fn control_words(style: &LineStyle, colors: &mut ColorTable) -> String {
+    let mut words = String::new();
+    if let Some(fg) = style.fg_color {
+        let _ = write!(words, "\\cf{}", colors.index(color_from_u32(fg)));
+    }
+    if let Some(bg) = style.bg_color {
+        let _ = write!(words, "\\highlight{}", colors.index(color_from_u32(bg)));
+    }
+    if style.weight.map_or(false, |w| w >= 600) {
+        words.push_str("\\b");
+    }
+    if style.italic == Some(true) {
+        words.push_str("\\i");
+    }
+    if style.underline == Some(true) {
+        words.push_str("\\ul");
+    }
+    words
+}
+
+/// An RTF document of `selection` for the clipboard, in the font and with the syntax highlighting
+/// of the `EditView`. `font_size` is in points.
+pub fn document(
+    selection: &StyledLine,
+    styles: &HashMap<usize, LineStyle>,
+    theme: &ThemeSettings,
+    font_family: &str,
+    font_size: i32,
+) -> String {
+    let mut colors = ColorTable(Vec::new());
+    let mut defaults = String::new();
+    if let Some(fg) = theme.foreground {
+        let _ = write!(defaults, "\\cf{}", colors.index(fg));
+    }
+    if let Some(bg) = theme.background {
+        let _ = write!(defaults, "\\highlight{}", colors.index(bg));
+    }
+
+    let text = &selection.text;
+    let mut body = String::new();
+    let mut ix = 0;
+    for &(start, end, id) in &selection.spans {
+        if start < ix {
+            continue;
+        }
+        let (before, styled) = match (text.get(ix..start), text.get(start..end)) {
+            (Some(before), Some(styled)) => (before, styled),
+            _ => continue,
+        };
+        body.push_str(&escape(before));
+        match styles.get(&id) {
+            Some(style) => {
+                let words = control_words(style, &mut colors);
+                let _ = write!(body, "{{{} {}}}", words, escape(styled));
+            }
+            None => body.push_str(&escape(styled)),
+        }
+        ix = end;
+    }
+    body.push_str(&escape(text.get(ix..).unwrap_or_default()));
+
+    format!(
+        "{{\\rtf1\\ansi\\deff0{{\\fonttbl{{\\f0\\fmodern {};}}}}{}\n\\f0\\fs{}{} {}}}",
+        escape(font_family).replace(';', ""),
+        colors.header(),
+        font_size * 2,
+        defaults,
+        body
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("{a}\\\tb\r\n"), "\\{a\\}\\\\\\tab b\\line\n");
+        assert_eq!(escape("ä😀"), "\\u228?\\u-10179?\\u-8704?");
+    }
+
+    #[test]
+    fn test_document() {
+        let mut styles = HashMap::new();
+        styles.insert(
+            2,
+            LineStyle {
+                fg_color: Some(0xffff_8000),
+                bg_color: None,
+                weight: Some(700),
+                italic: None,
+                underline: None,
+            },
+        );
+        let selection = StyledLine {
+            number: 0,
+            text: "fn {}".to_string(),
+            spans: vec![(0, 2, 2), (3, 4, 3)],
+        };
+        let theme = ThemeSettings {
+            foreground: Some(Color {
+                r: 255,
+                g: 128,
+                b: 0,
+                a: 255,
+            }),
+            ..ThemeSettings::default()
+        };
+
+        assert_eq!(
+            document(&selection, &styles, &theme, "Mono", 11),
+            "{\\rtf1\\ansi\\deff0{\\fonttbl{\\f0\\fmodern Mono;}}\
+             {\\colortbl;\\red255\\green128\\blue0;}\n\
+             \\f0\\fs22\\cf1 {\\cf1\\b fn} \\{\\}}"
+        );
+    }
+}
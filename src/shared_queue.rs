@@ -1,3 +1,4 @@
+use crate::goto_line::Position;
 use crossbeam_deque::Injector;
 use gettextrs::gettext;
 use log::trace;
@@ -13,6 +14,8 @@ pub enum CoreMsg {
     },
     NewViewReply {
         file_name: Option<String>,
+        /// Where to put the cursor once the file has been opened
+        position: Option<Position>,
         value: Value,
     },
 }
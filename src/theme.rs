@@ -79,6 +79,12 @@ pub fn set_margin_source_color(cr: &cairo::Context, color: Option<Color>) {
     set_source_color(cr, source_color);
 }
 
+/// Checks if a colour is dark, e.g. to tell if a theme with it as background is a dark one
+pub fn is_dark(c: Color) -> bool {
+    let luminance = 0.299 * f64::from(c.r) + 0.587 * f64::from(c.g) + 0.114 * f64::from(c.b);
+    luminance < 128.0
+}
+
 /// Explode an u32 into its individual RGBA values
 pub fn color_from_u32(c: u32) -> Color {
     Color {
@@ -93,3 +99,18 @@ pub fn color_from_u32(c: u32) -> Color {
 pub fn u32_from_color(c: Color) -> u32 {
     (u32::from(c.a) << 24) | (u32::from(c.r) << 16) | (u32::from(c.g) << 8) | u32::from(c.b)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_dark() {
+        let color = |r, g, b| Color { r, g, b, a: 255 };
+        assert!(is_dark(color(0x2b, 0x30, 0x3b)));
+        assert!(!is_dark(color(0xff, 0xff, 0xff)));
+        assert!(!is_dark(color(0xfd, 0xf6, 0xe3)));
+        // Saturated blue is dark, even though one of its channels is at full brightness
+        assert!(is_dark(color(0, 0, 0xff)));
+    }
+}
@@ -0,0 +1,1439 @@
+use crate::buffer::{Buffer, Pos};
+use crate::keymap::{KeyChord, Modifiers};
+use std::cmp::{max, min};
+
+/// The mode the Vim layer is in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Normal,
+    Insert,
+    Visual,
+    VisualLine,
+}
+
+/// A key press as seen by the Vim layer
+#[derive(Clone, Debug, PartialEq)]
+pub struct VimKey {
+    pub chord: KeyChord,
+    /// The char the key produces, if any
+    pub ch: Option<char>,
+}
+
+/// How yanked or deleted text should be put into the register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegisterKind {
+    Chars,
+    Lines,
+    /// Whole lines at the end of the document, which have been cut together with the line
+    /// ending before them (since there's no line ending after them)
+    LastLines,
+}
+
+/// What the `EditView` should do in response to a key press
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Run a command of the keymap, e.g. `move_down` or `undo`
+    Command(&'static str),
+    /// Move the cursor to the position, dropping the selection
+    MoveTo(Pos),
+    /// Select the text between the two positions
+    Select(Pos, Pos),
+    /// Cut the selection and hand the text to `Vim::set_register`
+    Cut(RegisterKind),
+    /// Copy the selection and hand the text to `Vim::set_register`
+    Copy(RegisterKind),
+    /// Insert text at the cursor
+    Insert(String),
+    /// Handle the key like it'd be handled without the Vim layer, e.g. in insert mode
+    Passthrough(VimKey),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Delete,
+    Change,
+    Yank,
+    Indent,
+    Outdent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordForward,
+    WordBackward,
+    WordEnd,
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    /// `gg`, goes to the line of the count or the first line
+    FirstLine,
+    /// `G`, goes to the line of the count or the last line
+    LastLine,
+    /// `f`, `F`, `t` and `T`
+    Find {
+        c: char,
+        forward: bool,
+        till: bool,
+    },
+}
+
+impl Motion {
+    fn linewise(self) -> bool {
+        match self {
+            Motion::Up | Motion::Down | Motion::FirstLine | Motion::LastLine => true,
+            _ => false,
+        }
+    }
+
+    /// Inclusive motions include the char they end on when used with an operator
+    fn inclusive(self) -> bool {
+        match self {
+            Motion::WordEnd | Motion::LineEnd => true,
+            Motion::Find { forward, .. } => forward,
+            _ => false,
+        }
+    }
+}
+
+/// A command that is waiting for another key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Pending {
+    G,
+    Find { forward: bool, till: bool },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Class {
+    Space,
+    Word,
+    Punctuation,
+    /// An empty line, which counts as a word of its own
+    EmptyLine,
+}
+
+fn class_of(c: char) -> Class {
+    if c.is_whitespace() {
+        Class::Space
+    } else if c.is_alphanumeric() || c == '_' {
+        Class::Word
+    } else {
+        Class::Punctuation
+    }
+}
+
+fn text<B: Buffer + ?Sized>(buf: &B, line: u64) -> &str {
+    buf.line(line).unwrap_or("")
+}
+
+fn len<B: Buffer + ?Sized>(buf: &B, line: u64) -> u64 {
+    text(buf, line).len() as u64
+}
+
+fn next_boundary(text: &str, col: u64) -> u64 {
+    text[col as usize..]
+        .chars()
+        .next()
+        .map_or(col, |c| col + c.len_utf8() as u64)
+}
+
+fn prev_boundary(text: &str, col: u64) -> u64 {
+    text[..col as usize]
+        .chars()
+        .next_back()
+        .map_or(col, |c| col - c.len_utf8() as u64)
+}
+
+/// The column of the last char of a line, which is as far as the cursor goes in normal mode
+fn last_col(text: &str) -> u64 {
+    prev_boundary(text, text.len() as u64)
+}
+
+fn first_non_blank(text: &str) -> u64 {
+    text.find(|c: char| !c.is_whitespace())
+        .unwrap_or_else(|| last_col(text) as usize) as u64
+}
+
+/// Keeps `col` inside of `text` and on a char boundary
+fn clamp_col(text: &str, col: u64, allow_end: bool) -> u64 {
+    let max_col = if allow_end {
+        text.len() as u64
+    } else {
+        last_col(text)
+    };
+    let mut col = min(col, max_col) as usize;
+    while !text.is_char_boundary(col) {
+        col -= 1;
+    }
+    col as u64
+}
+
+/// The end of a line counts as a position too, it's the line's line ending
+fn class_at<B: Buffer + ?Sized>(buf: &B, pos: Pos) -> Class {
+    let text = text(buf, pos.line);
+    if text.is_empty() {
+        Class::EmptyLine
+    } else {
+        text[pos.col as usize..]
+            .chars()
+            .next()
+            .map_or(Class::Space, class_of)
+    }
+}
+
+fn next_pos<B: Buffer + ?Sized>(buf: &B, pos: Pos) -> Option<Pos> {
+    let text = text(buf, pos.line);
+    if pos.col < text.len() as u64 {
+        Some(Pos::new(pos.line, next_boundary(text, pos.col)))
+    } else if pos.line + 1 < buf.n_lines() {
+        Some(Pos::new(pos.line + 1, 0))
+    } else {
+        None
+    }
+}
+
+fn prev_pos<B: Buffer + ?Sized>(buf: &B, pos: Pos) -> Option<Pos> {
+    if pos.col > 0 {
+        Some(Pos::new(
+            pos.line,
+            prev_boundary(text(buf, pos.line), pos.col),
+        ))
+    } else if pos.line > 0 {
+        Some(Pos::new(pos.line - 1, len(buf, pos.line - 1)))
+    } else {
+        None
+    }
+}
+
+fn word_forward<B: Buffer + ?Sized>(buf: &B, pos: Pos) -> Pos {
+    let mut pos = pos;
+    let class = class_at(buf, pos);
+
+    if class != Class::Space {
+        // Skip the rest of the current word
+        loop {
+            match next_pos(buf, pos) {
+                Some(next) => pos = next,
+                None => return pos,
+            }
+            if class == Class::EmptyLine || class_at(buf, pos) != class {
+                break;
+            }
+        }
+    }
+
+    while class_at(buf, pos) == Class::Space {
+        match next_pos(buf, pos) {
+            Some(next) => pos = next,
+            None => return pos,
+        }
+    }
+
+    pos
+}
+
+fn word_end<B: Buffer + ?Sized>(buf: &B, pos: Pos) -> Pos {
+    let mut pos = match next_pos(buf, pos) {
+        Some(next) => next,
+        None => return pos,
+    };
+
+    while class_at(buf, pos) == Class::Space || class_at(buf, pos) == Class::EmptyLine {
+        match next_pos(buf, pos) {
+            Some(next) => pos = next,
+            None => return pos,
+        }
+    }
+
+    let class = class_at(buf, pos);
+    while let Some(next) = next_pos(buf, pos) {
+        if class_at(buf, next) != class {
+            break;
+        }
+        pos = next;
+    }
+
+    pos
+}
+
+fn word_backward<B: Buffer + ?Sized>(buf: &B, pos: Pos) -> Pos {
+    let mut pos = match prev_pos(buf, pos) {
+        Some(prev) => prev,
+        None => return pos,
+    };
+
+    while class_at(buf, pos) == Class::Space {
+        match prev_pos(buf, pos) {
+            Some(prev) => pos = prev,
+            None => return pos,
+        }
+    }
+
+    let class = class_at(buf, pos);
+    if class == Class::EmptyLine {
+        return pos;
+    }
+
+    while let Some(prev) = prev_pos(buf, pos) {
+        if class_at(buf, prev) != class {
+            break;
+        }
+        pos = prev;
+    }
+
+    pos
+}
+
+/// Where `motion` takes the cursor if it's at `pos`. Returns `None` if the motion fails, e.g.
+/// if `f` can't find the char. `for_operator` allows the cursor to go past the last char of
+/// the line, so `dl` can delete it.
+fn apply_motion<B: Buffer + ?Sized>(
+    buf: &B,
+    motion: Motion,
+    pos: Pos,
+    count: u64,
+    explicit_count: bool,
+    for_operator: bool,
+) -> Option<Pos> {
+    let last_line = buf.n_lines().saturating_sub(1);
+    let line_text = text(buf, pos.line);
+
+    let target = match motion {
+        Motion::Left => {
+            let mut col = pos.col;
+            for _ in 0..count {
+                col = prev_boundary(line_text, col);
+            }
+            Pos::new(pos.line, col)
+        }
+        Motion::Right => {
+            let mut col = pos.col;
+            for _ in 0..count {
+                col = next_boundary(line_text, col);
+            }
+            Pos::new(pos.line, clamp_col(line_text, col, for_operator))
+        }
+        Motion::Up | Motion::Down => {
+            let line = if motion == Motion::Up {
+                pos.line.saturating_sub(count)
+            } else {
+                min(pos.line + count, last_line)
+            };
+            Pos::new(line, clamp_col(text(buf, line), pos.col, false))
+        }
+        Motion::WordForward => (0..count).fold(pos, |p, _| word_forward(buf, p)),
+        Motion::WordBackward => (0..count).fold(pos, |p, _| word_backward(buf, p)),
+        Motion::WordEnd => (0..count).fold(pos, |p, _| word_end(buf, p)),
+        Motion::LineStart => Pos::new(pos.line, 0),
+        Motion::FirstNonBlank => Pos::new(pos.line, first_non_blank(line_text)),
+        Motion::LineEnd => {
+            let line = min(pos.line + count - 1, last_line);
+            Pos::new(line, last_col(text(buf, line)))
+        }
+        Motion::FirstLine | Motion::LastLine => {
+            let line = if explicit_count {
+                min(count - 1, last_line)
+            } else if motion == Motion::FirstLine {
+                0
+            } else {
+                last_line
+            };
+            Pos::new(line, first_non_blank(text(buf, line)))
+        }
+        Motion::Find { c, forward, till } => {
+            let mut col = pos.col as usize;
+            for _ in 0..count {
+                col = if forward {
+                    let start = next_boundary(line_text, col as u64) as usize;
+                    // `t` would stay on the same char if it's already in front of `c`
+                    let start = if till {
+                        next_boundary(line_text, start as u64) as usize
+                    } else {
+                        start
+                    };
+                    start + line_text.get(start..)?.find(c)?
+                } else {
+                    // `T` would stay on the same char if it's already behind `c`
+                    let end = if till {
+                        prev_boundary(line_text, col as u64) as usize
+                    } else {
+                        col
+                    };
+                    line_text.get(..end)?.rfind(c)?
+                };
+            }
+            let col = col as u64;
+            let col = match (till, forward) {
+                (true, true) => prev_boundary(line_text, col),
+                (true, false) => next_boundary(line_text, col),
+                _ => col,
+            };
+            Pos::new(pos.line, col)
+        }
+    };
+
+    Some(target)
+}
+
+/// A modal editing layer like Vim's. It turns key presses into `Action`s for the `EditView`.
+/// It only knows about the text through the `Buffer` it's given, so it can be tested on its own.
+#[derive(Debug)]
+pub struct Vim {
+    mode: Mode,
+    count: Option<u64>,
+    /// The count typed before the operator, e.g. the 2 in `2d3w`
+    operator_count: Option<u64>,
+    operator: Option<Operator>,
+    pending: Option<Pending>,
+    visual_anchor: Pos,
+    visual_cursor: Pos,
+    register: Option<(String, RegisterKind)>,
+    /// The keys of the command we're currently processing, for `.`
+    keys: Vec<VimKey>,
+    /// The keys of the last command that changed the text
+    last_change: Vec<VimKey>,
+    /// If we're in insert mode because of a change we want to repeat with `.`
+    recording_insert: bool,
+    replaying: bool,
+    /// Where we've moved the cursor to ourselves. xi-editor takes a moment to tell us about the
+    /// new cursor position, so we use this until the `EditView` calls `cursor_synced`.
+    expected_cursor: Option<Pos>,
+    /// If we've typed a char on the cursor's line in insert mode, so the cursor isn't at the start
+    /// of the line even if xi-editor hasn't told us yet (or we're replaying the keys with `.`)
+    typed_on_line: bool,
+}
+
+impl Default for Vim {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Normal,
+            count: None,
+            operator_count: None,
+            operator: None,
+            pending: None,
+            visual_anchor: Pos::new(0, 0),
+            visual_cursor: Pos::new(0, 0),
+            register: None,
+            keys: Vec::new(),
+            last_change: Vec::new(),
+            recording_insert: false,
+            replaying: false,
+            expected_cursor: None,
+            typed_on_line: false,
+        }
+    }
+}
+
+impl Vim {
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Called when xi-editor has told us where the cursor is
+    pub fn cursor_synced(&mut self) {
+        self.expected_cursor = None;
+    }
+
+    /// Puts text the `EditView` cut or copied because of an `Action` into the register
+    pub fn set_register(&mut self, text: String, kind: RegisterKind) {
+        let (text, kind) = match kind {
+            RegisterKind::Chars => (text, kind),
+            RegisterKind::Lines | RegisterKind::LastLines => {
+                let mut text = if kind == RegisterKind::LastLines && text.starts_with('\n') {
+                    text[1..].to_string()
+                } else {
+                    text
+                };
+                if !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                (text, RegisterKind::Lines)
+            }
+        };
+        self.register = Some((text, kind));
+    }
+
+    fn reset(&mut self) {
+        self.count = None;
+        self.operator_count = None;
+        self.operator = None;
+        self.pending = None;
+    }
+
+    /// A command has been completed, remember its keys if it changed the text
+    fn finish(&mut self, changed: bool) {
+        if changed && !self.replaying {
+            if self.mode == Mode::Insert {
+                // Keep recording until the user leaves insert mode
+                self.recording_insert = true;
+                return;
+            }
+            self.last_change = self.keys.clone();
+        }
+        self.keys.clear();
+    }
+
+    /// Handles a key press. `cursor` is where the `EditView` thinks the cursor is.
+    pub fn handle_key<B: Buffer + ?Sized>(
+        &mut self,
+        key: VimKey,
+        cursor: Option<Pos>,
+        buf: &B,
+    ) -> Vec<Action> {
+        let cursor = self.expected_cursor.or(cursor);
+        match self.mode {
+            Mode::Insert => self.handle_insert_key(key, cursor),
+            Mode::Normal => self.handle_normal_key(key, cursor, buf),
+            Mode::Visual | Mode::VisualLine => self.handle_visual_key(key, buf),
+        }
+    }
+
+    fn is_escape(key: &VimKey) -> bool {
+        key.chord.key == "Escape" || (key.chord.mods.ctrl && key.chord.key == "bracketleft")
+    }
+
+    /// Records text the input method has inserted in insert mode, so `.` can repeat it
+    pub fn record_text(&mut self, text: &str) {
+        if self.mode != Mode::Insert {
+            return;
+        }
+        self.typed_on_line = true;
+        if !self.recording_insert || self.replaying {
+            return;
+        }
+        for c in text.chars() {
+            self.keys.push(VimKey {
+                chord: KeyChord::new(&c.to_string(), Modifiers::default()),
+                ch: Some(c),
+            });
+        }
+    }
+
+    fn handle_insert_key(&mut self, key: VimKey, cursor: Option<Pos>) -> Vec<Action> {
+        self.expected_cursor = None;
+        if !self.replaying && self.recording_insert {
+            self.keys.push(key.clone());
+        }
+
+        if Self::is_escape(&key) {
+            self.mode = Mode::Normal;
+            if self.recording_insert {
+                self.recording_insert = false;
+                self.last_change = self.keys.clone();
+            }
+            self.keys.clear();
+            // Like Vim, step back onto the last inserted char, but don't leave the line
+            let at_line_start = !self.typed_on_line && cursor.map_or(true, |c| c.col == 0);
+            self.typed_on_line = false;
+            if at_line_start {
+                Vec::new()
+            } else {
+                vec![Action::Command("move_left")]
+            }
+        } else {
+            let mods = key.chord.mods;
+            self.typed_on_line = !mods.ctrl
+                && !mods.alt
+                && !mods.super_
+                && key.ch.map_or(false, |c| c == '\t' || !c.is_control());
+            vec![Action::Passthrough(key)]
+        }
+    }
+
+    /// Turns a key into a motion, returns `None` if the key isn't a motion
+    fn motion_for_key(&mut self, key: &VimKey) -> Option<Motion> {
+        if key.chord.mods.ctrl || key.chord.mods.alt || key.chord.mods.super_ {
+            return None;
+        }
+
+        let motion = match key.chord.key.as_str() {
+            "Left" | "BackSpace" => Motion::Left,
+            "Right" => Motion::Right,
+            "Up" => Motion::Up,
+            "Down" | "Return" => Motion::Down,
+            "Home" => Motion::LineStart,
+            "End" => Motion::LineEnd,
+            _ => match key.ch? {
+                'h' => Motion::Left,
+                'l' | ' ' => Motion::Right,
+                'k' => Motion::Up,
+                'j' => Motion::Down,
+                'w' => Motion::WordForward,
+                'b' => Motion::WordBackward,
+                'e' => Motion::WordEnd,
+                '0' if self.count.is_none() => Motion::LineStart,
+                '^' => Motion::FirstNonBlank,
+                '$' => Motion::LineEnd,
+                'G' => Motion::LastLine,
+                _ => return None,
+            },
+        };
+
+        Some(motion)
+    }
+
+    /// Handles keys which need another key, like the `g` of `gg` or the char after `f`
+    fn handle_pending(&mut self, pending: Pending, key: &VimKey) -> Option<Motion> {
+        match (pending, key.ch) {
+            (Pending::G, Some('g')) => Some(Motion::FirstLine),
+            (Pending::Find { forward, till }, Some(c)) if !c.is_control() => {
+                Some(Motion::Find { c, forward, till })
+            }
+            _ => None,
+        }
+    }
+
+    fn total_count(&self) -> (u64, bool) {
+        let explicit = self.count.is_some() || self.operator_count.is_some();
+        (
+            self.operator_count.unwrap_or(1) * self.count.unwrap_or(1),
+            explicit,
+        )
+    }
+
+    fn handle_normal_key<B: Buffer + ?Sized>(
+        &mut self,
+        key: VimKey,
+        cursor: Option<Pos>,
+        buf: &B,
+    ) -> Vec<Action> {
+        if !self.replaying {
+            self.keys.push(key.clone());
+        }
+
+        if Self::is_escape(&key) {
+            self.reset();
+            self.finish(false);
+            return Vec::new();
+        }
+
+        if let Some(pending) = self.pending.take() {
+            return match self.handle_pending(pending, &key) {
+                Some(motion) => self.run_motion(motion, cursor, buf),
+                None => {
+                    self.reset();
+                    self.finish(false);
+                    Vec::new()
+                }
+            };
+        }
+
+        let ch = key
+            .ch
+            .filter(|_| !key.chord.mods.ctrl && !key.chord.mods.alt);
+
+        // Counts
+        if let Some(digit) = ch.and_then(|c| c.to_digit(10)) {
+            if digit != 0 || self.count.is_some() {
+                self.count = Some(self.count.unwrap_or(0) * 10 + u64::from(digit));
+                return Vec::new();
+            }
+        }
+
+        if let Some(motion) = self.motion_for_key(&key) {
+            return self.run_motion(motion, cursor, buf);
+        }
+
+        let operator = match ch {
+            Some('d') => Some(Operator::Delete),
+            Some('c') => Some(Operator::Change),
+            Some('y') => Some(Operator::Yank),
+            Some('>') => Some(Operator::Indent),
+            Some('<') => Some(Operator::Outdent),
+            _ => None,
+        };
+        if let Some(operator) = operator {
+            if self.operator == Some(operator) {
+                // `dd`, `cc`, `yy`, `>>`: operate on `count` lines
+                let (count, _) = self.total_count();
+                return match cursor {
+                    Some(cursor) => {
+                        let last = min(cursor.line + count - 1, buf.n_lines().saturating_sub(1));
+                        self.run_operator(operator, cursor.line, last, buf)
+                    }
+                    None => {
+                        self.reset();
+                        self.finish(false);
+                        Vec::new()
+                    }
+                };
+            } else if self.operator.is_none() {
+                self.operator = Some(operator);
+                self.operator_count = self.count.take();
+                return Vec::new();
+            }
+            self.reset();
+            self.finish(false);
+            return Vec::new();
+        }
+
+        match ch {
+            Some('g') => {
+                self.pending = Some(Pending::G);
+                return Vec::new();
+            }
+            Some('f') | Some('F') | Some('t') | Some('T') => {
+                let c = ch.unwrap();
+                self.pending = Some(Pending::Find {
+                    forward: c == 'f' || c == 't',
+                    till: c == 't' || c == 'T',
+                });
+                return Vec::new();
+            }
+            _ => (),
+        }
+
+        // Everything below doesn't take an operator
+        if self.operator.is_some() {
+            self.reset();
+            self.finish(false);
+            return Vec::new();
+        }
+
+        if key.chord.mods.ctrl {
+            let actions = match key.chord.key.as_str() {
+                "r" => vec![Action::Command("redo")],
+                _ => vec![Action::Passthrough(key)],
+            };
+            self.reset();
+            self.finish(false);
+            return actions;
+        }
+
+        let (count, _) = self.total_count();
+        let mut changed = true;
+        let actions = match (ch, key.chord.key.as_str()) {
+            (Some('x'), _) | (_, "Delete") => {
+                return self.run_operator_motion(Operator::Delete, Motion::Right, cursor, buf);
+            }
+            (Some('X'), _) => {
+                return self.run_operator_motion(Operator::Delete, Motion::Left, cursor, buf);
+            }
+            (Some('D'), _) => {
+                return self.run_operator_motion(Operator::Delete, Motion::LineEnd, cursor, buf);
+            }
+            (Some('C'), _) => {
+                return self.run_operator_motion(Operator::Change, Motion::LineEnd, cursor, buf);
+            }
+            (Some('Y'), _) => match cursor {
+                Some(cursor) => {
+                    let last = min(cursor.line + count - 1, buf.n_lines().saturating_sub(1));
+                    return self.run_operator(Operator::Yank, cursor.line, last, buf);
+                }
+                None => Vec::new(),
+            },
+            (Some('i'), _) => {
+                self.mode = Mode::Insert;
+                Vec::new()
+            }
+            (Some('a'), _) => {
+                self.mode = Mode::Insert;
+                match cursor {
+                    Some(cursor) if cursor.col >= len(buf, cursor.line) => Vec::new(),
+                    _ => vec![Action::Command("move_right")],
+                }
+            }
+            (Some('I'), _) => {
+                self.mode = Mode::Insert;
+                match cursor {
+                    Some(cursor) => vec![Action::MoveTo(Pos::new(
+                        cursor.line,
+                        first_non_blank(text(buf, cursor.line)),
+                    ))],
+                    None => vec![Action::Command("move_to_left_end_of_line")],
+                }
+            }
+            (Some('A'), _) => {
+                self.mode = Mode::Insert;
+                vec![Action::Command("move_to_right_end_of_line")]
+            }
+            (Some('o'), _) => {
+                self.mode = Mode::Insert;
+                vec![
+                    Action::Command("move_to_right_end_of_line"),
+                    Action::Command("insert_newline"),
+                ]
+            }
+            (Some('O'), _) => {
+                self.mode = Mode::Insert;
+                vec![
+                    Action::Command("move_to_left_end_of_line"),
+                    Action::Command("insert_newline"),
+                    Action::Command("move_up"),
+                ]
+            }
+            (Some('p'), _) | (Some('P'), _) => self.paste(ch == Some('p'), count, cursor, buf),
+            (Some('u'), _) => {
+                changed = false;
+                vec![Action::Command("undo")]
+            }
+            (Some('v'), _) | (Some('V'), _) => {
+                changed = false;
+                match cursor {
+                    Some(cursor) => {
+                        self.mode = if ch == Some('v') {
+                            Mode::Visual
+                        } else {
+                            Mode::VisualLine
+                        };
+                        self.visual_anchor = cursor;
+                        self.visual_cursor = cursor;
+                        vec![self.visual_selection(buf)]
+                    }
+                    None => Vec::new(),
+                }
+            }
+            (Some('.'), _) => {
+                self.reset();
+                self.keys.clear();
+                return self.repeat(cursor, buf);
+            }
+            _ => {
+                changed = false;
+                Vec::new()
+            }
+        };
+
+        if self.mode == Mode::Insert {
+            self.expected_cursor = None;
+        }
+        self.reset();
+        self.finish(changed);
+        actions
+    }
+
+    fn paste<B: Buffer + ?Sized>(
+        &mut self,
+        after: bool,
+        count: u64,
+        cursor: Option<Pos>,
+        buf: &B,
+    ) -> Vec<Action> {
+        let (text, kind) = match self.register {
+            Some((ref text, kind)) => (text.repeat(count as usize), kind),
+            None => return Vec::new(),
+        };
+        self.expected_cursor = None;
+
+        match (kind, after) {
+            (RegisterKind::Chars, true) => {
+                let mut actions = Vec::new();
+                // There's no char to paste after on an empty line
+                if cursor.map_or(true, |c| len(buf, c.line) > 0) {
+                    actions.push(Action::Command("move_right"));
+                }
+                actions.push(Action::Insert(text));
+                actions
+            }
+            (RegisterKind::Chars, false) => vec![Action::Insert(text)],
+            (_, true) => vec![
+                Action::Command("move_to_right_end_of_line"),
+                Action::Insert(format!("\n{}", text.trim_end_matches('\n'))),
+            ],
+            (_, false) => {
+                let mut actions = vec![
+                    Action::Command("move_to_left_end_of_line"),
+                    Action::Insert(text.clone()),
+                ];
+                // Put the cursor on the first pasted line
+                for _ in 0..text.lines().count() {
+                    actions.push(Action::Command("move_up"));
+                }
+                actions
+            }
+        }
+    }
+
+    fn repeat<B: Buffer + ?Sized>(&mut self, cursor: Option<Pos>, buf: &B) -> Vec<Action> {
+        let keys = self.last_change.clone();
+        self.replaying = true;
+        let mut actions = Vec::new();
+        for key in keys {
+            actions.extend(self.handle_key(key, cursor, buf));
+        }
+        self.replaying = false;
+        actions
+    }
+
+    fn run_motion<B: Buffer + ?Sized>(
+        &mut self,
+        motion: Motion,
+        cursor: Option<Pos>,
+        buf: &B,
+    ) -> Vec<Action> {
+        if let Some(operator) = self.operator {
+            return self.run_operator_motion(operator, motion, cursor, buf);
+        }
+
+        let (count, explicit_count) = self.total_count();
+        self.reset();
+        self.finish(false);
+
+        let cursor = match cursor {
+            // xi-editor remembers the column when moving up and down, so let it do that
+            Some(_) if motion == Motion::Up || motion == Motion::Down => None,
+            cursor => cursor,
+        };
+
+        match cursor {
+            Some(cursor) => match apply_motion(buf, motion, cursor, count, explicit_count, false) {
+                Some(target) => {
+                    self.expected_cursor = Some(target);
+                    vec![Action::MoveTo(target)]
+                }
+                None => Vec::new(),
+            },
+            // We don't know where the cursor is (it's not in the LineCache), so ask xi to move it
+            None => {
+                self.expected_cursor = None;
+                let command = match motion {
+                    Motion::Left => "move_left",
+                    Motion::Right => "move_right",
+                    Motion::Up => "move_up",
+                    Motion::Down => "move_down",
+                    Motion::WordForward | Motion::WordEnd => "move_word_right",
+                    Motion::WordBackward => "move_word_left",
+                    Motion::LineStart | Motion::FirstNonBlank => "move_to_left_end_of_line",
+                    Motion::LineEnd => "move_to_right_end_of_line",
+                    Motion::FirstLine if !explicit_count => "move_to_beginning_of_document",
+                    Motion::LastLine if !explicit_count => "move_to_end_of_document",
+                    Motion::FirstLine | Motion::LastLine => {
+                        let line = min(count - 1, buf.n_lines().saturating_sub(1));
+                        return vec![Action::MoveTo(Pos::new(line, 0))];
+                    }
+                    Motion::Find { .. } => return Vec::new(),
+                };
+                (0..count).map(|_| Action::Command(command)).collect()
+            }
+        }
+    }
+
+    fn run_operator_motion<B: Buffer + ?Sized>(
+        &mut self,
+        operator: Operator,
+        motion: Motion,
+        cursor: Option<Pos>,
+        buf: &B,
+    ) -> Vec<Action> {
+        let (count, explicit_count) = self.total_count();
+        let cursor = match cursor {
+            Some(cursor) => cursor,
+            None => {
+                self.reset();
+                self.finish(false);
+                return Vec::new();
+            }
+        };
+
+        // `cw` behaves like `ce`, unless the cursor is on whitespace
+        let motion = if operator == Operator::Change
+            && motion == Motion::WordForward
+            && class_at(buf, cursor) != Class::Space
+        {
+            Motion::WordEnd
+        } else {
+            motion
+        };
+
+        // `e` moves at least one char, so `ce` on the last char of a word would change two words
+        let target = if motion == Motion::WordEnd
+            && operator == Operator::Change
+            && next_pos(buf, cursor).map_or(true, |n| class_at(buf, n) != class_at(buf, cursor))
+            && count == 1
+        {
+            Some(cursor)
+        } else {
+            apply_motion(buf, motion, cursor, count, explicit_count, true)
+        };
+
+        let target = match target {
+            Some(target) => target,
+            None => {
+                self.reset();
+                self.finish(false);
+                return Vec::new();
+            }
+        };
+
+        if motion.linewise() {
+            let first = min(cursor.line, target.line);
+            let last = max(cursor.line, target.line);
+            return self.run_operator(operator, first, last, buf);
+        }
+
+        let (start, mut end) = if target < cursor {
+            (target, cursor)
+        } else {
+            (cursor, target)
+        };
+        if motion.inclusive() {
+            end = Pos::new(end.line, next_boundary(text(buf, end.line), end.col));
+        } else if end.col == 0 && end.line > start.line {
+            // `dw` on the last word of a line shouldn't join it with the next line
+            end = Pos::new(end.line - 1, len(buf, end.line - 1));
+        }
+
+        self.apply_operator(operator, start, end, RegisterKind::Chars)
+    }
+
+    /// Runs `operator` on whole lines
+    fn run_operator<B: Buffer + ?Sized>(
+        &mut self,
+        operator: Operator,
+        first: u64,
+        last: u64,
+        buf: &B,
+    ) -> Vec<Action> {
+        let n_lines = buf.n_lines();
+        let (start, end, kind) = match operator {
+            Operator::Delete if last + 1 >= n_lines && first > 0 => (
+                Pos::new(first - 1, len(buf, first - 1)),
+                Pos::new(last, len(buf, last)),
+                RegisterKind::LastLines,
+            ),
+            Operator::Delete | Operator::Yank if last + 1 < n_lines => (
+                Pos::new(first, 0),
+                Pos::new(last + 1, 0),
+                RegisterKind::Lines,
+            ),
+            // Changing lines leaves an empty line behind, indenting doesn't need the line ending
+            _ => (
+                Pos::new(first, 0),
+                Pos::new(last, len(buf, last)),
+                RegisterKind::Lines,
+            ),
+        };
+
+        self.apply_operator(operator, start, end, kind)
+    }
+
+    fn apply_operator(
+        &mut self,
+        operator: Operator,
+        start: Pos,
+        end: Pos,
+        kind: RegisterKind,
+    ) -> Vec<Action> {
+        let linewise = kind != RegisterKind::Chars;
+        let line_start = Pos::new(start.line, 0);
+        let select = Action::Select(start, end);
+
+        let (actions, changed) = match operator {
+            Operator::Delete => {
+                self.expected_cursor = Some(if kind == RegisterKind::LastLines {
+                    line_start
+                } else {
+                    start
+                });
+                (vec![select, Action::Cut(kind)], true)
+            }
+            Operator::Change => {
+                self.mode = Mode::Insert;
+                self.expected_cursor = None;
+                (vec![select, Action::Cut(kind)], true)
+            }
+            Operator::Yank => {
+                let cursor = if linewise { line_start } else { start };
+                self.expected_cursor = Some(cursor);
+                (
+                    vec![select, Action::Copy(kind), Action::MoveTo(cursor)],
+                    false,
+                )
+            }
+            Operator::Indent | Operator::Outdent => {
+                self.expected_cursor = Some(line_start);
+                let command = if operator == Operator::Indent {
+                    "indent"
+                } else {
+                    "outdent"
+                };
+                (
+                    vec![
+                        Action::Select(line_start, end),
+                        Action::Command(command),
+                        Action::MoveTo(line_start),
+                    ],
+                    true,
+                )
+            }
+        };
+
+        // Visual mode operators end visual mode
+        if self.mode != Mode::Insert {
+            self.mode = Mode::Normal;
+        }
+        self.reset();
+        self.finish(changed);
+        actions
+    }
+
+    /// The selection of visual mode, which includes the char under the cursor
+    fn visual_range<B: Buffer + ?Sized>(&self, buf: &B) -> (Pos, Pos) {
+        let (start, end) = if self.visual_cursor < self.visual_anchor {
+            (self.visual_cursor, self.visual_anchor)
+        } else {
+            (self.visual_anchor, self.visual_cursor)
+        };
+        let end = Pos::new(end.line, next_boundary(text(buf, end.line), end.col));
+        (start, end)
+    }
+
+    fn visual_selection<B: Buffer + ?Sized>(&self, buf: &B) -> Action {
+        if self.mode == Mode::VisualLine {
+            let first = min(self.visual_anchor.line, self.visual_cursor.line);
+            let last = max(self.visual_anchor.line, self.visual_cursor.line);
+            Action::Select(Pos::new(first, 0), Pos::new(last, len(buf, last)))
+        } else {
+            let (start, end) = self.visual_range(buf);
+            Action::Select(start, end)
+        }
+    }
+
+    fn handle_visual_key<B: Buffer + ?Sized>(&mut self, key: VimKey, buf: &B) -> Vec<Action> {
+        if Self::is_escape(&key)
+            || (self.mode == Mode::Visual && key.ch == Some('v'))
+            || (self.mode == Mode::VisualLine && key.ch == Some('V'))
+        {
+            self.mode = Mode::Normal;
+            self.reset();
+            self.expected_cursor = Some(self.visual_cursor);
+            return vec![Action::MoveTo(self.visual_cursor)];
+        }
+
+        if let Some(pending) = self.pending.take() {
+            if let Some(motion) = self.handle_pending(pending, &key) {
+                return self.move_visual_cursor(motion, buf);
+            }
+            self.reset();
+            return Vec::new();
+        }
+
+        if key.chord.mods.ctrl {
+            return vec![Action::Passthrough(key)];
+        }
+
+        if let Some(digit) = key.ch.and_then(|c| c.to_digit(10)) {
+            if digit != 0 || self.count.is_some() {
+                self.count = Some(self.count.unwrap_or(0) * 10 + u64::from(digit));
+                return Vec::new();
+            }
+        }
+
+        if let Some(motion) = self.motion_for_key(&key) {
+            return self.move_visual_cursor(motion, buf);
+        }
+
+        let operator = match key.ch {
+            Some('d') | Some('x') => Operator::Delete,
+            Some('c') | Some('s') => Operator::Change,
+            Some('y') => Operator::Yank,
+            Some('>') => Operator::Indent,
+            Some('<') => Operator::Outdent,
+            Some('g') => {
+                self.pending = Some(Pending::G);
+                return Vec::new();
+            }
+            Some('f') | Some('F') | Some('t') | Some('T') => {
+                let c = key.ch.unwrap();
+                self.pending = Some(Pending::Find {
+                    forward: c == 'f' || c == 't',
+                    till: c == 't' || c == 'T',
+                });
+                return Vec::new();
+            }
+            Some('o') => {
+                std::mem::swap(&mut self.visual_anchor, &mut self.visual_cursor);
+                return vec![self.visual_selection(buf)];
+            }
+            Some('v') => {
+                self.mode = Mode::Visual;
+                return vec![self.visual_selection(buf)];
+            }
+            Some('V') => {
+                self.mode = Mode::VisualLine;
+                return vec![self.visual_selection(buf)];
+            }
+            _ => {
+                self.reset();
+                return Vec::new();
+            }
+        };
+
+        // Visual mode changes can't be repeated with `.`
+        self.keys.clear();
+        let replaying = self.replaying;
+        self.replaying = true;
+        let actions = if self.mode == Mode::VisualLine {
+            let first = min(self.visual_anchor.line, self.visual_cursor.line);
+            let last = max(self.visual_anchor.line, self.visual_cursor.line);
+            self.run_operator(operator, first, last, buf)
+        } else {
+            let (start, end) = self.visual_range(buf);
+            self.apply_operator(operator, start, end, RegisterKind::Chars)
+        };
+        self.replaying = replaying;
+        actions
+    }
+
+    fn move_visual_cursor<B: Buffer + ?Sized>(&mut self, motion: Motion, buf: &B) -> Vec<Action> {
+        let count = self.count.unwrap_or(1);
+        let explicit_count = self.count.is_some();
+        self.reset();
+
+        if let Some(target) = apply_motion(
+            buf,
+            motion,
+            self.visual_cursor,
+            count,
+            explicit_count,
+            false,
+        ) {
+            self.visual_cursor = target;
+        }
+        vec![self.visual_selection(buf)]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct TestBuffer(Vec<&'static str>);
+
+    impl Buffer for TestBuffer {
+        fn line(&self, n: u64) -> Option<&str> {
+            self.0.get(n as usize).cloned()
+        }
+
+        fn n_lines(&self) -> u64 {
+            self.0.len() as u64
+        }
+    }
+
+    fn keys(s: &str) -> Vec<VimKey> {
+        s.chars()
+            .map(|c| VimKey {
+                chord: KeyChord::new(
+                    &match c {
+                        '\x1b' => "Escape".to_string(),
+                        c => c.to_string(),
+                    },
+                    Modifiers::default(),
+                ),
+                ch: if c == '\x1b' { None } else { Some(c) },
+            })
+            .collect()
+    }
+
+    /// Feeds `input` to `vim` and returns all actions
+    fn run(vim: &mut Vim, input: &str, cursor: Pos, buf: &TestBuffer) -> Vec<Action> {
+        keys(input)
+            .into_iter()
+            .flat_map(|key| vim.handle_key(key, Some(cursor), buf))
+            .collect()
+    }
+
+    #[test]
+    fn test_motions() {
+        let buf = TestBuffer(vec!["fn main() {", "    let x = 1;", "", "}"]);
+        let p = Pos::new;
+
+        assert_eq!(word_forward(&buf, p(0, 0)), p(0, 3));
+        assert_eq!(word_forward(&buf, p(0, 3)), p(0, 7));
+        assert_eq!(word_forward(&buf, p(0, 10)), p(1, 4));
+        assert_eq!(word_forward(&buf, p(1, 13)), p(2, 0));
+        assert_eq!(word_end(&buf, p(0, 0)), p(0, 1));
+        assert_eq!(word_end(&buf, p(0, 1)), p(0, 6));
+        assert_eq!(word_backward(&buf, p(1, 4)), p(0, 10));
+        assert_eq!(word_backward(&buf, p(3, 0)), p(2, 0));
+        assert_eq!(word_backward(&buf, p(0, 3)), p(0, 0));
+
+        let find = |c, forward, till| Motion::Find { c, forward, till };
+        assert_eq!(
+            apply_motion(&buf, find('(', true, false), p(0, 0), 1, false, false),
+            Some(p(0, 7))
+        );
+        assert_eq!(
+            apply_motion(&buf, find('(', true, true), p(0, 0), 1, false, false),
+            Some(p(0, 6))
+        );
+        assert_eq!(
+            apply_motion(&buf, find('f', false, false), p(0, 7), 1, false, false),
+            Some(p(0, 0))
+        );
+        assert_eq!(
+            apply_motion(&buf, find('z', true, false), p(0, 0), 1, false, false),
+            None
+        );
+        assert_eq!(
+            apply_motion(&buf, Motion::LineEnd, p(1, 0), 1, false, false),
+            Some(p(1, 13))
+        );
+        assert_eq!(
+            apply_motion(&buf, Motion::LastLine, p(0, 0), 2, true, false),
+            Some(p(1, 4))
+        );
+    }
+
+    #[test]
+    fn test_operators() {
+        let buf = TestBuffer(vec!["foo bar baz", "second line"]);
+        let mut vim = Vim::default();
+
+        assert_eq!(
+            run(&mut vim, "dw", Pos::new(0, 4), &buf),
+            vec![
+                Action::Select(Pos::new(0, 4), Pos::new(0, 8)),
+                Action::Cut(RegisterKind::Chars)
+            ]
+        );
+        vim.cursor_synced();
+
+        // `dw` on the last word doesn't join the lines
+        assert_eq!(
+            run(&mut vim, "dw", Pos::new(0, 8), &buf),
+            vec![
+                Action::Select(Pos::new(0, 8), Pos::new(0, 11)),
+                Action::Cut(RegisterKind::Chars)
+            ]
+        );
+        vim.cursor_synced();
+
+        assert_eq!(
+            run(&mut vim, "2dd", Pos::new(0, 4), &buf)[0],
+            Action::Select(Pos::new(0, 0), Pos::new(1, 11))
+        );
+        vim.cursor_synced();
+
+        assert_eq!(
+            run(&mut vim, "d2e", Pos::new(0, 0), &buf)[0],
+            Action::Select(Pos::new(0, 0), Pos::new(0, 7))
+        );
+        vim.cursor_synced();
+
+        // `cw` works like `ce` and enters insert mode
+        assert_eq!(
+            run(&mut vim, "cw", Pos::new(0, 0), &buf)[0],
+            Action::Select(Pos::new(0, 0), Pos::new(0, 3))
+        );
+        assert_eq!(vim.mode(), Mode::Insert);
+        assert_eq!(
+            run(&mut vim, "x\x1b", Pos::new(0, 0), &buf),
+            vec![
+                Action::Passthrough(keys("x").remove(0)),
+                Action::Command("move_left")
+            ]
+        );
+        assert_eq!(vim.mode(), Mode::Normal);
+
+        // `.` repeats the whole change, including the inserted text
+        assert_eq!(
+            run(&mut vim, ".", Pos::new(1, 0), &buf),
+            vec![
+                Action::Select(Pos::new(1, 0), Pos::new(1, 6)),
+                Action::Cut(RegisterKind::Chars),
+                Action::Passthrough(keys("x").remove(0)),
+                Action::Command("move_left")
+            ]
+        );
+
+        assert_eq!(
+            run(&mut vim, "yy", Pos::new(0, 5), &buf),
+            vec![
+                Action::Select(Pos::new(0, 0), Pos::new(1, 0)),
+                Action::Copy(RegisterKind::Lines),
+                Action::MoveTo(Pos::new(0, 0))
+            ]
+        );
+        vim.set_register("foo bar baz\n".to_string(), RegisterKind::Lines);
+        assert_eq!(
+            run(&mut vim, "p", Pos::new(0, 0), &buf),
+            vec![
+                Action::Command("move_to_right_end_of_line"),
+                Action::Insert("\nfoo bar baz".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_visual() {
+        let buf = TestBuffer(vec!["foo bar baz", "second line"]);
+        let mut vim = Vim::default();
+
+        assert_eq!(
+            run(&mut vim, "vw", Pos::new(0, 0), &buf),
+            vec![
+                Action::Select(Pos::new(0, 0), Pos::new(0, 1)),
+                Action::Select(Pos::new(0, 0), Pos::new(0, 5))
+            ]
+        );
+        assert_eq!(vim.mode(), Mode::Visual);
+        assert_eq!(
+            run(&mut vim, "d", Pos::new(0, 0), &buf),
+            vec![
+                Action::Select(Pos::new(0, 0), Pos::new(0, 5)),
+                Action::Cut(RegisterKind::Chars)
+            ]
+        );
+        assert_eq!(vim.mode(), Mode::Normal);
+        vim.cursor_synced();
+
+        assert_eq!(
+            run(&mut vim, "Vj>", Pos::new(0, 3), &buf).last(),
+            Some(&Action::MoveTo(Pos::new(0, 0)))
+        );
+        assert_eq!(vim.mode(), Mode::Normal);
+    }
+
+    #[test]
+    fn test_record_text() {
+        let buf = TestBuffer(vec!["foo"]);
+        let mut vim = Vim::default();
+
+        run(&mut vim, "a", Pos::new(0, 0), &buf);
+        vim.record_text("ä");
+        run(&mut vim, "\x1b", Pos::new(0, 0), &buf);
+        assert_eq!(
+            run(&mut vim, ".", Pos::new(0, 0), &buf),
+            vec![
+                Action::Command("move_right"),
+                Action::Passthrough(keys("ä").remove(0)),
+                Action::Command("move_left")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escape_at_line_start() {
+        let buf = TestBuffer(vec!["foo"]);
+        let mut vim = Vim::default();
+
+        assert_eq!(run(&mut vim, "i\x1b", Pos::new(0, 0), &buf), vec![]);
+        assert_eq!(vim.mode(), Mode::Normal);
+        assert_eq!(
+            run(&mut vim, "i\x1b", Pos::new(0, 2), &buf),
+            vec![Action::Command("move_left")]
+        );
+        // The cursor has moved past the inserted char even if xi-editor hasn't told us yet
+        assert_eq!(
+            run(&mut vim, "ix\x1b", Pos::new(0, 0), &buf),
+            vec![
+                Action::Passthrough(keys("x").remove(0)),
+                Action::Command("move_left")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_register() {
+        let mut vim = Vim::default();
+        vim.set_register("\nlast".to_string(), RegisterKind::LastLines);
+        assert_eq!(
+            vim.register,
+            Some(("last\n".to_string(), RegisterKind::Lines))
+        );
+    }
+}
@@ -0,0 +1,116 @@
+/// Which whitespace the `EditView` draws markers for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrawWhitespace {
+    None,
+    /// Whitespace at the end of a line
+    Trailing,
+    /// The indentation of a line
+    Leading,
+    /// All whitespace, including line endings
+    All,
+}
+
+impl DrawWhitespace {
+    /// Returns the mode with the `name` used in GSettings, falling back to `DrawWhitespace::None`
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "trailing" => DrawWhitespace::Trailing,
+            "leading" => DrawWhitespace::Leading,
+            "all" => DrawWhitespace::All,
+            _ => DrawWhitespace::None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DrawWhitespace::None => "none",
+            DrawWhitespace::Trailing => "trailing",
+            DrawWhitespace::Leading => "leading",
+            DrawWhitespace::All => "all",
+        }
+    }
+}
+
+/// The kinds of whitespace we draw a marker for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Marker {
+    Space,
+    Tab,
+    NonBreakingSpace,
+    LineEnding,
+}
+
+impl Marker {
+    fn from_char(c: char) -> Option<Self> {
+        match c {
+            ' ' => Some(Marker::Space),
+            '\t' => Some(Marker::Tab),
+            '\u{a0}' | '\u{202f}' => Some(Marker::NonBreakingSpace),
+            _ => None,
+        }
+    }
+}
+
+/// The whitespace in `text`, a line including its line ending, which should get a marker with
+/// `mode`. Returns the byte index of each marker. The line ending is marked at its first byte
+/// and only in `DrawWhitespace::All`.
+pub fn markers(text: &str, mode: DrawWhitespace) -> Vec<(usize, Marker)> {
+    if mode == DrawWhitespace::None {
+        return Vec::new();
+    }
+
+    let content = text.trim_end_matches(|c| c == '\n' || c == '\r');
+    let is_blank = |c: char| Marker::from_char(c).is_some();
+    let leading_end = content.len() - content.trim_start_matches(is_blank).len();
+    let trailing_start = content.trim_end_matches(is_blank).len();
+
+    let mut markers: Vec<_> = content
+        .char_indices()
+        .filter(|(ix, _)| match mode {
+            DrawWhitespace::Trailing => *ix >= trailing_start,
+            DrawWhitespace::Leading => *ix < leading_end,
+            _ => true,
+        })
+        .filter_map(|(ix, c)| Some((ix, Marker::from_char(c)?)))
+        .collect();
+    if mode == DrawWhitespace::All && content.len() < text.len() {
+        markers.push((content.len(), Marker::LineEnding));
+    }
+    markers
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_markers() {
+        let text = "\t x\u{a0}y  \r\n";
+        let markers = |mode| {
+            super::markers(text, mode)
+                .into_iter()
+                .map(|(ix, _)| ix)
+                .collect::<Vec<_>>()
+        };
+
+        assert!(markers(DrawWhitespace::None).is_empty());
+        assert_eq!(markers(DrawWhitespace::Leading), vec![0, 1]);
+        assert_eq!(markers(DrawWhitespace::Trailing), vec![6, 7]);
+        assert_eq!(
+            super::markers(text, DrawWhitespace::All),
+            vec![
+                (0, Marker::Tab),
+                (1, Marker::Space),
+                (3, Marker::NonBreakingSpace),
+                (6, Marker::Space),
+                (7, Marker::Space),
+                (8, Marker::LineEnding),
+            ]
+        );
+
+        // A blank line is both leading and trailing whitespace
+        assert_eq!(super::markers("  ", DrawWhitespace::Leading).len(), 2);
+        assert_eq!(super::markers("  \n", DrawWhitespace::Trailing).len(), 2);
+        assert_eq!(DrawWhitespace::from_name("all").name(), "all");
+    }
+}